@@ -1,10 +1,206 @@
-use anyhow::anyhow;
-use serde::Deserialize;
+use anyhow::{anyhow, bail, ensure, Context};
+use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 
 pub const EXTERNAL_KIT_DIRECTORY: &str = "build/external-kits";
 pub const EXTERNAL_KIT_METADATA: &str = "build/external-kits/external-kit-metadata.json";
 
+/// Buildsys writes a file here, named for the source file, each time a source is fetched from its
+/// upstream URL instead of the lookaside cache.
+pub const UPSTREAM_SOURCES_DIRECTORY: &str = "build/state/upstream-sources";
+
+/// Buildsys writes a [`FetchedSource`] as JSON to `<package>/<file name>` here for each source file
+/// that a package build uses.
+pub const FETCHED_SOURCES_DIRECTORY: &str = "build/state/fetched-sources";
+
+/// A source file used by a package build, as recorded by buildsys.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub struct FetchedSource {
+    /// The name of the file in the package directory.
+    pub name: String,
+    /// The upstream URL given for the file in the package's `Cargo.toml`.
+    pub url: String,
+    pub origin: SourceOrigin,
+    /// The URL that the file was downloaded or copied from, for a lookaside cache or upstream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetched_from: Option<String>,
+    pub sha256: String,
+    pub sha512: String,
+}
+
+/// Where buildsys found a source file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceOrigin {
+    /// The file was already in the package directory from an earlier build.
+    Existing,
+    /// The file was copied from the shared cache.
+    SharedCache,
+    LookasideCache,
+    Upstream,
+}
+
+/// The names by which image features are enabled in `Cargo.toml`.
+pub const IMAGE_FEATURES: [&str; 6] = [
+    "grub-set-private-var",
+    "systemd-networkd",
+    "unified-cgroup-hierarchy",
+    "xfs-data-partition",
+    "uefi-secure-boot",
+    "fips",
+];
+
+/// How a variant's image is partitioned. A `split` image has a separate data disk, and a `unified`
+/// image has the data partition on the OS disk.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionPlan {
+    Split,
+    Unified,
+}
+
+impl FromStr for PartitionPlan {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "split" => Ok(Self::Split),
+            "unified" => Ok(Self::Unified),
+            _ => bail!(
+                "Unknown partition plan '{}', expected 'split' or 'unified'",
+                s
+            ),
+        }
+    }
+}
+
+impl Display for PartitionPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Split => write!(f, "split"),
+            Self::Unified => write!(f, "unified"),
+        }
+    }
+}
+
+/// The sizes in GiB and partition plan of a variant's image, with the same keys as
+/// `[package.metadata.build-variant.image-layout]` in the variant's `Cargo.toml`, each of them
+/// optional. A layout from the `[variant.<name>.image-layout]` section of Twoliter.toml or from
+/// `--image-layout` only holds the settings that it overrides. Twoliter passes it to buildsys and
+/// pubsys in `BUILDSYS_IMAGE_LAYOUT`, and this is the one parser for that value.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ImageLayout {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os_image_size_gib: Option<u16>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_image_size_gib: Option<u16>,
+
+    /// The size of the published image, which is at least the OS and data sizes together.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publish_image_size_hint_gib: Option<u16>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partition_plan: Option<PartitionPlan>,
+}
+
+impl ImageLayout {
+    /// Whether the layout overrides nothing.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// This layout with the settings that `other` has taking the place of its own.
+    pub fn overlay(&self, other: &Self) -> Self {
+        Self {
+            os_image_size_gib: other.os_image_size_gib.or(self.os_image_size_gib),
+            data_image_size_gib: other.data_image_size_gib.or(self.data_image_size_gib),
+            publish_image_size_hint_gib: other
+                .publish_image_size_hint_gib
+                .or(self.publish_image_size_hint_gib),
+            partition_plan: other.partition_plan.or(self.partition_plan),
+        }
+    }
+
+    /// Checks that the sizes are at least 1 GiB.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let sizes = [
+            ("os-image-size-gib", self.os_image_size_gib),
+            ("data-image-size-gib", self.data_image_size_gib),
+            (
+                "publish-image-size-hint-gib",
+                self.publish_image_size_hint_gib,
+            ),
+        ];
+        for (key, size) in sizes {
+            ensure!(
+                size != Some(0),
+                "The image layout setting '{}' must be a whole number of GiB greater than 0",
+                key
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Parses `key=value`, or several separated by commas, as given with `--image-layout`.
+impl FromStr for ImageLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut layout = Self::default();
+        for item in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, value) = item.split_once('=').context(format!(
+                "Unable to parse image layout setting '{}', expected 'key=value'",
+                item
+            ))?;
+            let size = || {
+                value.parse::<u16>().context(format!(
+                    "Unable to parse image layout setting '{}', expected a whole number of GiB",
+                    item
+                ))
+            };
+            match key {
+                "os-image-size-gib" => layout.os_image_size_gib = Some(size()?),
+                "data-image-size-gib" => layout.data_image_size_gib = Some(size()?),
+                "publish-image-size-hint-gib" => layout.publish_image_size_hint_gib = Some(size()?),
+                "partition-plan" => layout.partition_plan = Some(value.parse()?),
+                _ => bail!(
+                    "Unknown image layout setting '{}', expected one of: os-image-size-gib, \
+                    data-image-size-gib, publish-image-size-hint-gib, partition-plan",
+                    key
+                ),
+            }
+        }
+        layout.validate()?;
+        Ok(layout)
+    }
+}
+
+/// Formats the settings that the layout has as `key=value,key=value`, the form that buildsys reads
+/// from `BUILDSYS_IMAGE_LAYOUT`.
+impl Display for ImageLayout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut settings = Vec::new();
+        if let Some(size) = self.os_image_size_gib {
+            settings.push(format!("os-image-size-gib={}", size));
+        }
+        if let Some(size) = self.data_image_size_gib {
+            settings.push(format!("data-image-size-gib={}", size));
+        }
+        if let Some(size) = self.publish_image_size_hint_gib {
+            settings.push(format!("publish-image-size-hint-gib={}", size));
+        }
+        if let Some(plan) = self.partition_plan {
+            settings.push(format!("partition-plan={}", plan));
+        }
+        write!(f, "{}", settings.join(","))
+    }
+}
+
 #[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub enum DockerArchitecture {
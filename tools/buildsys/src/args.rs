@@ -15,11 +15,16 @@ use url::Url;
 /// variable changes. The build type is represented with bit flags so that we can easily list
 /// multiple build types for a single variable. See `[BuildType]` and `[rerun_for_envs]` below to
 /// see how this list is used.
-const REBUILD_VARS: [(&str, u8); 16] = [
+const REBUILD_VARS: [(&str, u8); 21] = [
     ("BUILDSYS_ARCH", PACKAGE | KIT | VARIANT),
+    ("BUILDSYS_BUILD_ARGS", PACKAGE | KIT | VARIANT),
     ("BUILDSYS_CACERTS_BUNDLE_OVERRIDE", VARIANT),
+    ("BUILDSYS_DOCKER_NETWORK", PACKAGE | KIT | VARIANT),
     ("BUILDSYS_KITS_DIR", KIT),
     ("BUILDSYS_EXTERNAL_KITS_DIR", PACKAGE | KIT | VARIANT),
+    ("BUILDSYS_HOST_CONTAINERS", VARIANT),
+    ("BUILDSYS_IMAGE_FEATURES", VARIANT),
+    ("BUILDSYS_IMAGE_LAYOUT", VARIANT),
     ("BUILDSYS_NAME", VARIANT),
     ("BUILDSYS_OUTPUT_DIR", VARIANT),
     ("BUILDSYS_OUTPUT_GENERATION_ID", PACKAGE | KIT | VARIANT),
@@ -84,6 +89,21 @@ pub(crate) struct Common {
     #[arg(long, env = "BUILDSYS_VERSION_FULL")]
     pub(crate) version_full: String,
 
+    /// The network for `docker build`, e.g. `none`, `host` or the name of a docker network. The
+    /// value `default` leaves the choice to docker. When absent, package and kit builds use `none`
+    /// and variant builds use `host`.
+    #[arg(long, env = "BUILDSYS_DOCKER_NETWORK")]
+    pub(crate) docker_network: Option<String>,
+
+    /// Extra `KEY=VALUE` build args for `docker build`, separated by newlines in the environment.
+    /// They cannot replace the build args that buildsys sets itself.
+    #[arg(
+        long = "build-arg",
+        env = "BUILDSYS_BUILD_ARGS",
+        value_delimiter = '\n'
+    )]
+    pub(crate) build_args: Vec<String>,
+
     #[arg(long, env = "CARGO_MANIFEST_DIR")]
     pub(crate) cargo_manifest_dir: PathBuf,
 
@@ -115,12 +135,23 @@ pub(crate) struct BuildPackageArgs {
     #[arg(long, env = "BUILDSYS_SOURCES_DIR")]
     pub(crate) sources_dir: PathBuf,
 
-    #[arg(long, env = "BUILDSYS_LOOKASIDE_CACHE")]
-    pub(crate) lookaside_cache: Url,
+    /// Lookaside cache URLs, separated by commas, which are tried in order.
+    #[arg(
+        long,
+        env = "BUILDSYS_LOOKASIDE_CACHE",
+        value_delimiter = ',',
+        required = true
+    )]
+    pub(crate) lookaside_cache: Vec<Url>,
 
     #[arg(long, env = "BUILDSYS_UPSTREAM_SOURCE_FALLBACK")]
     pub(crate) upstream_source_fallback: String,
 
+    /// A directory, which may be shared between projects, where verified source files are stored
+    /// by their hash so that each one is only downloaded once.
+    #[arg(long, env = "BUILDSYS_SHARED_CACHE")]
+    pub(crate) shared_cache: Option<PathBuf>,
+
     #[command(flatten)]
     pub(crate) common: Common,
 }
@@ -176,6 +207,21 @@ pub(crate) struct BuildVariantArgs {
     #[arg(long, env = "BUILDSYS_VARIANT_FLAVOR")]
     pub(crate) variant_flavor: String,
 
+    /// Image feature overrides in the form `name=on,name=off`, applied on top of the features in
+    /// the variant's `Cargo.toml`.
+    #[arg(long, env = "BUILDSYS_IMAGE_FEATURES")]
+    pub(crate) image_features: Option<String>,
+
+    /// Image layout overrides in the form `key=value,key=value`, applied on top of the layout in
+    /// the variant's `Cargo.toml`.
+    #[arg(long, env = "BUILDSYS_IMAGE_LAYOUT")]
+    pub(crate) image_layout: Option<String>,
+
+    /// Host container images in the form `name=image name=image`, from `[variant.<name>]` in
+    /// Twoliter.toml.
+    #[arg(long, env = "BUILDSYS_HOST_CONTAINERS")]
+    pub(crate) host_containers: Option<String>,
+
     #[arg(long, env = "BUILDSYS_VERSION_BUILD")]
     pub(crate) version_build: String,
 
@@ -195,6 +241,16 @@ pub(crate) struct RepackVariantArgs {
     #[arg(long, env = "BUILDSYS_VARIANT")]
     pub(crate) variant: String,
 
+    /// Image feature overrides in the form `name=on,name=off`, applied on top of the features in
+    /// the variant's `Cargo.toml`.
+    #[arg(long, env = "BUILDSYS_IMAGE_FEATURES")]
+    pub(crate) image_features: Option<String>,
+
+    /// Image layout overrides in the form `key=value,key=value`, applied on top of the layout in
+    /// the variant's `Cargo.toml`.
+    #[arg(long, env = "BUILDSYS_IMAGE_LAYOUT")]
+    pub(crate) image_layout: Option<String>,
+
     #[arg(long, env = "BUILDSYS_VERSION_BUILD")]
     pub(crate) version_build: String,
 
@@ -1,5 +1,5 @@
 use bottlerocket_variant::Variant;
-use buildsys::manifest::ManifestInfo;
+use buildsys::manifest::{apply_image_feature_overrides, ManifestInfo};
 use snafu::ResultExt;
 use std::path::PathBuf;
 use std::{env, process};
@@ -15,31 +15,60 @@ fn main() {
 }
 
 /// Read `BUILDSYS_VARIANT` from the environment, parse into its components, and emit related
-/// environment variables to set (or export). Do the same for features defined in the variant
-/// manifest.
+/// environment variables to set (or export). Components that are already set in the environment
+/// are kept, and the variant name only needs to be parsed if some are missing. Do the same for
+/// features defined in the variant manifest, with any overrides from `BUILDSYS_IMAGE_FEATURES`
+/// applied. The manifest is `BUILDSYS_VARIANT_MANIFEST` if it is set, like everywhere else in
+/// Makefile.toml, and otherwise `variants/<BUILDSYS_VARIANT>/Cargo.toml` in `BUILDSYS_ROOT_DIR`.
 fn run() -> Result<()> {
     let env = getenv("BUILDSYS_VARIANT")?;
-    let variant = Variant::new(&env).context(error::VariantParseSnafu)?;
-    println!("BUILDSYS_VARIANT_PLATFORM={}", variant.platform());
-    println!("BUILDSYS_VARIANT_RUNTIME={}", variant.runtime());
-    println!("BUILDSYS_VARIANT_FAMILY={}", variant.family());
+    let platform = given("BUILDSYS_VARIANT_PLATFORM");
+    let runtime = given("BUILDSYS_VARIANT_RUNTIME");
+    let family = given("BUILDSYS_VARIANT_FAMILY");
+    let flavor = given("BUILDSYS_VARIANT_FLAVOR");
+    let (platform, runtime, family, flavor) = match (platform, runtime, family) {
+        (Some(platform), Some(runtime), Some(family)) => (platform, runtime, family, flavor),
+        (platform, runtime, family) => {
+            let variant = Variant::new(&env).context(error::VariantParseSnafu)?;
+            (
+                platform.unwrap_or_else(|| variant.platform().to_string()),
+                runtime.unwrap_or_else(|| variant.runtime().to_string()),
+                family.unwrap_or_else(|| variant.family().to_string()),
+                flavor.or_else(|| variant.variant_flavor().map(str::to_string)),
+            )
+        }
+    };
+    println!("BUILDSYS_VARIANT_PLATFORM={}", platform);
+    println!("BUILDSYS_VARIANT_RUNTIME={}", runtime);
+    println!("BUILDSYS_VARIANT_FAMILY={}", family);
     println!(
         "BUILDSYS_VARIANT_FLAVOR={}",
-        variant.variant_flavor().unwrap_or("''")
+        flavor.as_deref().unwrap_or("''")
     );
-    let manifest = PathBuf::from(getenv("BUILDSYS_ROOT_DIR")?)
-        .join("variants")
-        .join(&env)
-        .join("Cargo.toml");
+    let manifest = match given("BUILDSYS_VARIANT_MANIFEST") {
+        Some(manifest) => PathBuf::from(manifest),
+        None => PathBuf::from(getenv("BUILDSYS_ROOT_DIR")?)
+            .join("variants")
+            .join(&env)
+            .join("Cargo.toml"),
+    };
     let variant_manifest = ManifestInfo::new(manifest).context(error::ManifestParseSnafu)?;
-    if let Some(image_features) = variant_manifest.image_features() {
-        for image_feature in image_features {
-            println!("export BUILDSYS_VARIANT_IMAGE_FEATURE_{}=1", image_feature);
-        }
+    let mut image_features = variant_manifest.image_features().unwrap_or_default();
+    if let Ok(overrides) = env::var("BUILDSYS_IMAGE_FEATURES") {
+        apply_image_feature_overrides(&mut image_features, &overrides)
+            .context(error::ManifestParseSnafu)?;
+    }
+    for image_feature in image_features {
+        println!("export BUILDSYS_VARIANT_IMAGE_FEATURE_{}=1", image_feature);
     }
     Ok(())
 }
 
+/// Retrieve a variable that may have been set in the environment, treating an empty value as unset.
+fn given(var: &str) -> Option<String> {
+    env::var(var).ok().filter(|value| !value.is_empty())
+}
+
 /// Retrieve a variable that we expect to be set in the environment.
 fn getenv(var: &str) -> Result<String> {
     env::var(var).context(error::EnvironmentSnafu { var })
@@ -8,8 +8,8 @@ pub(crate) mod error;
 
 use crate::args::{BuildKitArgs, BuildPackageArgs, BuildVariantArgs, RepackVariantArgs};
 use buildsys::manifest::{
-    ExternalKitMetadataView, ImageFeature, ImageFormat, ImageLayout, Manifest, PartitionPlan,
-    SupportedArch,
+    apply_image_feature_overrides, apply_image_layout_overrides, ExternalKitMetadataView,
+    ImageFeature, ImageFormat, ImageLayout, Manifest, PartitionPlan, SupportedArch,
 };
 use buildsys::BuildType;
 use buildsys_config::EXTERNAL_KIT_METADATA;
@@ -23,6 +23,7 @@ use sha2::{Digest, Sha512};
 use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::HashSet;
 use std::env;
+use std::ffi::OsString;
 use std::fs::{self, read_dir, File};
 use std::num::NonZeroU16;
 use std::path::{Path, PathBuf};
@@ -99,6 +100,8 @@ struct CommonBuildArgs {
     nocache: String,
     token: String,
     cleanup: OutputCleanup,
+    network: Option<String>,
+    extra_build_args: Vec<String>,
 }
 
 impl CommonBuildArgs {
@@ -107,6 +110,8 @@ impl CommonBuildArgs {
         sdk: String,
         arch: SupportedArch,
         cleanup: OutputCleanup,
+        network: Option<String>,
+        extra_build_args: Vec<String>,
     ) -> Self {
         let mut d = Sha512::new();
         d.update(root.as_ref().display().to_string());
@@ -122,6 +127,11 @@ impl CommonBuildArgs {
             nocache,
             token,
             cleanup,
+            network,
+            extra_build_args: extra_build_args
+                .into_iter()
+                .filter(|arg| !arg.is_empty())
+                .collect(),
         }
     }
 }
@@ -138,8 +148,6 @@ struct PackageBuildArgs {
 impl KitBuildArgs {
     fn build_args(&self) -> Vec<String> {
         let mut args = Vec::new();
-        args.push("--network".into());
-        args.push("none".into());
         args.build_arg("KIT", &self.kit);
         args.build_arg("PACKAGE_DEPENDENCIES", self.package_dependencies.join(" "));
         args.build_arg("BUILD_ID", &self.version_build);
@@ -164,8 +172,6 @@ struct KitBuildArgs {
 impl crate::builder::PackageBuildArgs {
     fn build_args(&self) -> Vec<String> {
         let mut args = Vec::new();
-        args.push("--network".into());
-        args.push("none".into());
         args.build_arg("KIT_DEPENDENCIES", self.kit_dependencies.join(" "));
         args.build_arg(
             "EXTERNAL_KIT_DEPENDENCIES",
@@ -185,6 +191,7 @@ struct VariantBuildArgs {
     external_kit_dependencies: Vec<String>,
     data_image_publish_size_gib: i32,
     data_image_size_gib: String,
+    host_containers: String,
     image_features: HashSet<ImageFeature>,
     image_format: String,
     kernel_parameters: String,
@@ -206,14 +213,13 @@ struct VariantBuildArgs {
 impl VariantBuildArgs {
     fn build_args(&self) -> Vec<String> {
         let mut args = Vec::new();
-        args.push("--network".into());
-        args.push("host".into());
         args.build_arg(
             "DATA_IMAGE_PUBLISH_SIZE_GIB",
             self.data_image_publish_size_gib.to_string(),
         );
         args.build_arg("BUILD_ID", &self.version_build);
         args.build_arg("DATA_IMAGE_SIZE_GIB", &self.data_image_size_gib);
+        args.build_arg("HOST_CONTAINERS", &self.host_containers);
         args.build_arg("IMAGE_FORMAT", &self.image_format);
         args.build_arg("IMAGE_NAME", &self.name);
         args.build_arg("KERNEL_PARAMETERS", &self.kernel_parameters);
@@ -260,8 +266,6 @@ struct RepackVariantBuildArgs {
 impl RepackVariantBuildArgs {
     fn build_args(&self) -> Vec<String> {
         let mut args = Vec::new();
-        args.push("--network".into());
-        args.push("host".into());
         args.build_arg(
             "DATA_IMAGE_PUBLISH_SIZE_GIB",
             self.data_image_publish_size_gib.to_string(),
@@ -301,6 +305,15 @@ impl TargetBuildArgs {
             TargetBuildArgs::Repack(_) => BuildType::Repack,
         }
     }
+
+    /// The network used by `docker build` unless another one is configured. Package and kit builds
+    /// have no network access, while image builds need it.
+    fn default_network(&self) -> &'static str {
+        match self {
+            TargetBuildArgs::Package(_) | TargetBuildArgs::Kit(_) => "none",
+            TargetBuildArgs::Variant(_) | TargetBuildArgs::Repack(_) => "host",
+        }
+    }
 }
 
 pub(crate) struct DockerBuild {
@@ -321,8 +334,8 @@ impl DockerBuild {
     /// Create a new `DockerBuild` that can build a package.
     pub(crate) fn new_package(args: BuildPackageArgs, manifest: &Manifest) -> Result<Self> {
         let package = manifest.info().package_name();
-        let per_package_dir = format!("{}/{}", args.packages_dir.display(), package).into();
-        let old_package_dir = format!("{}", args.packages_dir.display()).into();
+        let per_package_dir = args.packages_dir.join(package);
+        let old_package_dir = args.packages_dir.clone();
 
         Ok(Self {
             dockerfile: args.common.tools_dir.join("build.Dockerfile"),
@@ -345,6 +358,8 @@ impl DockerBuild {
                 args.common.sdk_image,
                 args.common.arch,
                 OutputCleanup::BeforeBuild,
+                args.common.docker_network,
+                args.common.build_args,
             ),
             target_build_args: TargetBuildArgs::Package(PackageBuildArgs {
                 package: package.to_string(),
@@ -385,6 +400,8 @@ impl DockerBuild {
                 args.common.sdk_image,
                 args.common.arch,
                 OutputCleanup::BeforeBuild,
+                args.common.docker_network,
+                args.common.build_args,
             ),
             target_build_args: TargetBuildArgs::Kit(KitBuildArgs {
                 kit: kit.to_string(),
@@ -401,7 +418,8 @@ impl DockerBuild {
 
     /// Create a new `DockerBuild` that can build a variant image.
     pub(crate) fn new_variant(args: BuildVariantArgs, manifest: &Manifest) -> Result<Self> {
-        let image_layout = manifest.info().image_layout().cloned().unwrap_or_default();
+        let image_features = image_features(manifest, args.image_features.as_deref())?;
+        let image_layout = image_layout(manifest, args.image_layout.as_deref())?;
         let ImageLayout {
             os_image_size_gib,
             data_image_size_gib,
@@ -433,6 +451,8 @@ impl DockerBuild {
                 args.common.sdk_image,
                 args.common.arch,
                 OutputCleanup::BeforeBuild,
+                args.common.docker_network,
+                args.common.build_args,
             ),
             target_build_args: TargetBuildArgs::Variant(VariantBuildArgs {
                 package_dependencies: manifest.package_dependencies().context(error::GraphSnafu)?,
@@ -442,7 +462,8 @@ impl DockerBuild {
                     .list(),
                 data_image_publish_size_gib,
                 data_image_size_gib: data_image_size_gib.to_string(),
-                image_features: manifest.info().image_features().unwrap_or_default(),
+                host_containers: args.host_containers.unwrap_or_default(),
+                image_features,
                 image_format: match manifest.info().image_format() {
                     Some(ImageFormat::Raw) | None => "raw",
                     Some(ImageFormat::Qcow2) => "qcow2",
@@ -484,7 +505,8 @@ impl DockerBuild {
 
     /// Create a new `DockerBuild` that can repackage a variant image.
     pub(crate) fn repack_variant(args: RepackVariantArgs, manifest: &Manifest) -> Result<Self> {
-        let image_layout = manifest.info().image_layout().cloned().unwrap_or_default();
+        let image_features = image_features(manifest, args.image_features.as_deref())?;
+        let image_layout = image_layout(manifest, args.image_layout.as_deref())?;
         let ImageLayout {
             os_image_size_gib,
             data_image_size_gib,
@@ -516,11 +538,13 @@ impl DockerBuild {
                 args.common.sdk_image,
                 args.common.arch,
                 OutputCleanup::None,
+                args.common.docker_network,
+                args.common.build_args,
             ),
             target_build_args: TargetBuildArgs::Repack(RepackVariantBuildArgs {
                 data_image_publish_size_gib,
                 data_image_size_gib: data_image_size_gib.to_string(),
-                image_features: manifest.info().image_features().unwrap_or_default(),
+                image_features,
                 image_format: match manifest.info().image_format() {
                     Some(ImageFormat::Raw) | None => "raw",
                     Some(ImageFormat::Qcow2) => "qcow2",
@@ -564,23 +588,26 @@ impl DockerBuild {
             OutputCleanup::None => (),
         }
 
-        let mut build = format!(
-            "build {context} \
-            --target {target} \
-            --tag {tag} \
-            --file {dockerfile}",
-            context = self.context.display(),
-            dockerfile = self.dockerfile.display(),
-            target = self.target,
-            tag = self.tag,
-        )
-        .split_string();
-
-        build.extend(self.build_args());
-        build.extend(self.secrets_args.clone());
+        // Paths are passed as they are, rather than split on spaces with the rest of the command.
+        let mut build: Vec<OsString> = vec![
+            "build".into(),
+            self.context.clone().into(),
+            "--target".into(),
+            self.target.clone().into(),
+            "--tag".into(),
+            self.tag.clone().into(),
+            "--file".into(),
+            self.dockerfile.clone().into(),
+        ];
+        build.extend(self.build_args().into_iter().map(OsString::from));
+        build.extend(self.secrets_args.iter().map(OsString::from));
 
         let create = format!("create --name {} {} true", self.tag, self.tag).split_string();
-        let cp = format!("cp {}:/output/. {}", self.tag, marker_dir.display()).split_string();
+        let cp: Vec<OsString> = vec![
+            "cp".into(),
+            format!("{}:/output/.", self.tag).into(),
+            marker_dir.clone().into(),
+        ];
         let rm = format!("rm --force {}", self.tag).split_string();
         let rmi = format!("rmi --force {}", self.tag).split_string();
 
@@ -624,12 +651,22 @@ impl DockerBuild {
     }
 
     fn build_args(&self) -> Vec<String> {
-        let mut args = match &self.target_build_args {
+        // The build args given by the user come first, so that the ones set here win.
+        let mut args = Vec::new();
+        for arg in &self.common_build_args.extra_build_args {
+            args.push("--build-arg".to_string());
+            args.push(arg.clone());
+        }
+        args.extend(match &self.target_build_args {
             TargetBuildArgs::Package(p) => p.build_args(),
             TargetBuildArgs::Kit(k) => k.build_args(),
             TargetBuildArgs::Variant(v) => v.build_args(),
             TargetBuildArgs::Repack(r) => r.build_args(),
-        };
+        });
+        args.extend(network_args(
+            self.common_build_args.network.as_deref(),
+            self.target_build_args.default_network(),
+        ));
         args.build_arg("ARCH", self.common_build_args.arch.to_string());
         args.build_arg("GOARCH", self.common_build_args.arch.goarch());
         args.build_arg("SDK", &self.common_build_args.sdk);
@@ -640,10 +677,39 @@ impl DockerBuild {
     }
 }
 
+/// The image features enabled in the variant's manifest, with any overrides applied.
+fn image_features(manifest: &Manifest, overrides: Option<&str>) -> Result<HashSet<ImageFeature>> {
+    let mut features = manifest.info().image_features().unwrap_or_default();
+    if let Some(overrides) = overrides {
+        apply_image_feature_overrides(&mut features, overrides)
+            .context(error::ImageFeatureOverrideSnafu)?;
+    }
+    Ok(features)
+}
+
+/// The image layout in the variant's manifest, with any overrides applied.
+fn image_layout(manifest: &Manifest, overrides: Option<&str>) -> Result<ImageLayout> {
+    let mut layout = manifest.info().image_layout().cloned().unwrap_or_default();
+    if let Some(overrides) = overrides {
+        apply_image_layout_overrides(&mut layout, overrides)
+            .context(error::ImageLayoutOverrideSnafu)?;
+    }
+    Ok(layout)
+}
+
+/// Returns the `--network` argument for `docker build`. The `network` configured by the user wins
+/// over the `default` for the kind of build, and `default` means that docker chooses.
+fn network_args(network: Option<&str>, default: &str) -> Vec<String> {
+    match network.unwrap_or(default) {
+        "default" => Vec::new(),
+        network => vec!["--network".to_string(), network.to_string()],
+    }
+}
+
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
 /// Run `docker` with the specified arguments.
-fn docker(args: &[String], retry: Retry) -> Result<Output> {
+fn docker(args: &[OsString], retry: Retry) -> Result<Output> {
     let mut max_attempts: u16 = 1;
     let mut retry_messages: &[&Regex] = &[];
     if let Retry::Yes { attempts, messages } = retry {
@@ -669,7 +735,11 @@ fn docker(args: &[String], retry: Retry) -> Result<Output> {
         ensure!(
             retry_messages.iter().any(|m| m.is_match(&stdout)) && attempt < max_attempts,
             error::DockerExecutionSnafu {
-                args: &args.join(" ")
+                args: args
+                    .iter()
+                    .map(|arg| arg.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(" ")
             }
         );
 
@@ -759,9 +829,7 @@ fn create_marker_dir(
         BuildType::Repack => "variants",
     };
 
-    let path = [&state_dir.display().to_string(), arch, prefix, name]
-        .iter()
-        .collect();
+    let path = state_dir.join(arch).join(prefix).join(name);
 
     fs::create_dir_all(&path).context(error::DirectoryCreateSnafu { path: &path })?;
 
@@ -965,27 +1033,65 @@ impl BuildSecret for Vec<String> {
     {
         self.push("--secret".to_string());
         self.push(format!(
-            "type={},id={},src={}",
+            "type={},id={},{}",
             typ.as_ref(),
             id.as_ref(),
-            src.as_ref()
+            csv_field(format!("src={}", src.as_ref()))
         ));
     }
 }
 
-/// Helper trait for splitting a string on spaces into owned Strings.
+/// Quotes `field` if it has a comma or a quote in it. Docker parses `--secret` as CSV, so a comma
+/// in a path would otherwise start a new field.
+fn csv_field(field: String) -> String {
+    if field.contains([',', '"']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+/// Helper trait for splitting a string on spaces into arguments for a command.
 ///
-/// If you need an element with internal spaces, you should handle that separately, for example
-/// with BuildArg.
+/// If you need an element with internal spaces, such as a path, you should handle that separately,
+/// for example with BuildArg.
 trait SplitString {
-    fn split_string(&self) -> Vec<String>;
+    fn split_string(&self) -> Vec<OsString>;
 }
 
 impl<S> SplitString for S
 where
     S: AsRef<str>,
 {
-    fn split_string(&self) -> Vec<String> {
-        self.as_ref().split(' ').map(String::from).collect()
+    fn split_string(&self) -> Vec<OsString> {
+        self.as_ref().split(' ').map(OsString::from).collect()
     }
 }
+
+#[test]
+fn test_network_args() {
+    assert_eq!(network_args(None, "none"), ["--network", "none"]);
+    assert_eq!(network_args(None, "host"), ["--network", "host"]);
+    assert_eq!(
+        network_args(Some("proxied"), "none"),
+        ["--network", "proxied"]
+    );
+    assert_eq!(network_args(Some("none"), "host"), ["--network", "none"]);
+    assert!(network_args(Some("default"), "host").is_empty());
+}
+
+#[test]
+fn test_build_secret() {
+    let mut args = Vec::new();
+    args.build_secret("file", "root.json", "/home/me/My Project/roles/root.json");
+    args.build_secret("file", "ca-bundle.crt", "/certs/a,b \"c\".crt");
+    assert_eq!(
+        args,
+        [
+            "--secret",
+            "type=file,id=root.json,src=/home/me/My Project/roles/root.json",
+            "--secret",
+            "type=file,id=ca-bundle.crt,\"src=/certs/a,b \"\"c\"\".crt\""
+        ]
+    );
+}
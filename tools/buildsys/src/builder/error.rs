@@ -68,6 +68,12 @@ pub(crate) enum Error {
     #[snafu(display("Failed to create build arguments due to a dependency error: {source}"))]
     Graph { source: buildsys::manifest::Error },
 
+    #[snafu(display("Failed to apply image feature overrides: {source}"))]
+    ImageFeatureOverride { source: buildsys::manifest::Error },
+
+    #[snafu(display("Failed to apply image layout overrides: {source}"))]
+    ImageLayoutOverride { source: buildsys::manifest::Error },
+
     #[snafu(display(
         "Failed to create build arguments due to an error reading external kit metadata: {source}"
     ))]
@@ -8,47 +8,77 @@ given the (name, url, hash) data that uniquely identifies each file.
 
 It implements a two-tier approach to retrieval: files are first pulled from the
 "lookaside" cache and only fetched from the upstream site if that access fails.
+Several lookaside caches may be given, e.g. a local mirror and then the public
+cache. They are tried in the order given, and upstream is only tried after all
+of them fail.
+
+When a shared cache directory is configured, verified files are also kept there by
+their hash, so that projects on the same machine only download each file once.
 
 */
 pub(crate) mod error;
 use error::Result;
 
 use buildsys::manifest;
+use buildsys_config::{FetchedSource, SourceOrigin};
 use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
-use sha2::{Digest, Sha512};
+use sha2::{Digest, Sha256, Sha512};
 use snafu::{ensure, OptionExt, ResultExt};
-use std::fs::{self, File};
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufWriter};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use url::Url;
 
+/// A lock file in the shared cache older than this was left behind by a build that did not finish.
+const STALE_LOCK: Duration = Duration::from_secs(60 * 60);
+
 pub(crate) struct LookasideCache {
     /// The version string to include in HTTP headers.
     version: String,
 
-    /// The lookaside cache base URL for source tarballs.
-    lookaside_cache: Url,
+    /// The lookaside cache base URLs for source tarballs, in the order they are tried.
+    lookaside_caches: Vec<Url>,
 
     /// Whether we are allowed to pull sources from upstream URLs. When this is false, it can be
     /// overridden by `upstream-fallback` in the manifest.
     upstream_fallback: bool,
+
+    /// The directory where each fetch from an upstream URL is recorded so that it can be reported
+    /// at the end of the build.
+    upstream_sources_dir: PathBuf,
+
+    /// A directory, which may be shared between projects, where verified files are stored by their
+    /// hash.
+    shared_cache: Option<PathBuf>,
+
+    /// The directory where each source file that the package uses is recorded, so that Twoliter
+    /// can write a manifest of the sources after the build.
+    fetched_sources_dir: PathBuf,
 }
 
 impl LookasideCache {
     pub(crate) fn new(
         version: impl AsRef<str>,
-        lookaside_cache: Url,
+        lookaside_caches: Vec<Url>,
         upstream_fallback: bool,
+        upstream_sources_dir: impl Into<PathBuf>,
+        shared_cache: Option<PathBuf>,
+        fetched_sources_dir: impl Into<PathBuf>,
     ) -> Self {
         Self {
             version: version.as_ref().to_string(),
-            lookaside_cache,
+            lookaside_caches,
             upstream_fallback,
+            upstream_sources_dir: upstream_sources_dir.into(),
+            shared_cache,
+            fetched_sources_dir: fetched_sources_dir.into(),
         }
     }
 
     /// Fetch files stored out-of-tree and ensure they match the stored hash.
     pub(crate) fn fetch(&self, files: &[manifest::ExternalFile]) -> Result<()> {
+        Self::clear_fetched_sources(&self.fetched_sources_dir)?;
         for f in files {
             let url_file_name = Self::extract_file_name(&f.url)?;
             let path = &f.path.as_ref().unwrap_or(&url_file_name);
@@ -60,7 +90,10 @@ impl LookasideCache {
             let hash = &f.sha512;
             if path.is_file() {
                 match Self::verify_file(path, hash) {
-                    Ok(_) => continue,
+                    Ok(_) => {
+                        self.record_fetch(f, path, SourceOrigin::Existing, None)?;
+                        continue;
+                    }
                     Err(e) => {
                         println!("{}", e);
                         fs::remove_file(path).context(error::ExternalFileDeleteSnafu { path })?;
@@ -71,21 +104,19 @@ impl LookasideCache {
             let name = &path.display().to_string();
             let tmp = PathBuf::from(format!(".{}", name));
 
-            // first check the lookaside cache
-            let mut url = self.lookaside_cache.clone();
-            url.path_segments_mut()
-                .map_err(|_| {
-                    error::UrlPathSegmentsSnafu {
-                        url: self.lookaside_cache.clone(),
-                    }
-                    .build()
-                })?
-                .extend([name, hash, name]);
-            let url = url.to_string();
-            match self.fetch_file(&url, &tmp, hash) {
-                Ok(_) => {
+            if self.restore_shared(&tmp, hash)? {
+                fs::rename(&tmp, path).context(error::ExternalFileRenameSnafu { path: &tmp })?;
+                self.record_fetch(f, path, SourceOrigin::SharedCache, None)?;
+                continue;
+            }
+
+            // first check the lookaside caches
+            match self.fetch_lookaside(&tmp, name, hash) {
+                Ok(url) => {
                     fs::rename(&tmp, path)
                         .context(error::ExternalFileRenameSnafu { path: &tmp })?;
+                    self.store_shared(path, name, hash);
+                    self.record_fetch(f, path, SourceOrigin::LookasideCache, Some(&url))?;
                     continue;
                 }
                 Err(e) => {
@@ -96,6 +127,9 @@ impl LookasideCache {
                         self.fetch_file(&f.url, &tmp, hash)?;
                         fs::rename(&tmp, path)
                             .context(error::ExternalFileRenameSnafu { path: &tmp })?;
+                        self.record_upstream_fetch(name, &f.url)?;
+                        self.store_shared(path, name, hash);
+                        self.record_fetch(f, path, SourceOrigin::Upstream, Some(&f.url))?;
                     } else {
                         // we failed to fetch from the lookaside cache, and we cannot fall back to
                         // upstream sources, so we should not continue, we need to return the error
@@ -108,11 +142,182 @@ impl LookasideCache {
         Ok(())
     }
 
+    /// Fetches the file from each lookaside cache in turn until one has it, and returns the URL that
+    /// it was fetched from. If none have it, the error is the one from the last cache.
+    fn fetch_lookaside(&self, path: &Path, name: &str, hash: &str) -> Result<String> {
+        let mut lookaside_caches = self.lookaside_caches.iter().peekable();
+        while let Some(lookaside_cache) = lookaside_caches.next() {
+            let mut url = lookaside_cache.clone();
+            url.path_segments_mut()
+                .map_err(|_| {
+                    error::UrlPathSegmentsSnafu {
+                        url: lookaside_cache.clone(),
+                    }
+                    .build()
+                })?
+                .extend([name, hash, name]);
+            match self.fetch_file(url.as_str(), path, hash) {
+                Ok(_) => return Ok(url.to_string()),
+                Err(e) if lookaside_caches.peek().is_some() => {
+                    println!("Error fetching from lookaside cache: {}", e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        error::NoLookasideCacheSnafu.fail()
+    }
+
+    /// The path of the file with the SHA-512 `hash` in the shared cache.
+    fn shared_path(&self, hash: &str) -> Option<PathBuf> {
+        Some(self.shared_cache.as_ref()?.join("sources").join(hash))
+    }
+
+    /// Copies the file with the SHA-512 `hash` from the shared cache to `path`. Returns `false` if
+    /// there is no shared cache, or no intact entry in it. The entry's modification time is updated
+    /// so that pruning the cache evicts the least recently used entries first.
+    fn restore_shared(&self, path: &Path, hash: &str) -> Result<bool> {
+        let Some(cached) = self.shared_path(hash).filter(|cached| cached.is_file()) else {
+            return Ok(false);
+        };
+        fs::copy(&cached, path).context(error::ExternalFileCopySnafu { path: &cached })?;
+        if Self::verify_file(path, hash).is_err() {
+            fs::remove_file(path).context(error::ExternalFileDeleteSnafu { path })?;
+            return Ok(false);
+        }
+        // This only affects the order of eviction, so failures are not important.
+        let _ = OpenOptions::new()
+            .write(true)
+            .open(&cached)
+            .and_then(|f| f.set_modified(SystemTime::now()));
+        Ok(true)
+    }
+
+    /// Adds the verified file at `path` to the shared cache, warning rather than failing if it
+    /// cannot be written.
+    fn store_shared(&self, path: &Path, name: &str, hash: &str) {
+        if let Err(e) = self.try_store_shared(path, hash) {
+            println!(
+                "cargo:warning=Unable to add '{}' to the shared cache: {}",
+                name, e
+            );
+        }
+    }
+
+    /// The entry is written under a temporary name and renamed into place, so readers never see a
+    /// partial file. A lock file keeps concurrent builds from writing the same entry at once; if
+    /// another build holds the lock, this build leaves the entry to it.
+    fn try_store_shared(&self, path: &Path, hash: &str) -> Result<()> {
+        let Some(cached) = self.shared_path(hash).filter(|cached| !cached.is_file()) else {
+            return Ok(());
+        };
+        let dir = cached.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dir).context(error::SharedCacheSnafu { path: dir })?;
+
+        let lock = dir.join(format!(".{}.lock", hash));
+        match OpenOptions::new().write(true).create_new(true).open(&lock) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let stale = fs::metadata(&lock)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok())
+                    .is_some_and(|age| age > STALE_LOCK);
+                if stale {
+                    let _ = fs::remove_file(&lock);
+                }
+                return Ok(());
+            }
+            Err(e) => return Err(e).context(error::SharedCacheSnafu { path: lock }),
+        }
+
+        let tmp = dir.join(format!(".{}.{}", hash, std::process::id()));
+        let result = fs::copy(path, &tmp).and_then(|_| fs::rename(&tmp, &cached));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        let _ = fs::remove_file(&lock);
+        result.context(error::SharedCacheSnafu { path: cached })
+    }
+
+    /// Makes a fetch from upstream visible: cargo shows the warning, and the record is counted by
+    /// twoliter once the build is done.
+    fn record_upstream_fetch(&self, name: &str, url: &str) -> Result<()> {
+        println!(
+            "cargo:warning=Fetched '{}' from upstream source {}",
+            name, url
+        );
+        let dir = &self.upstream_sources_dir;
+        fs::create_dir_all(dir).context(error::UpstreamRecordSnafu { path: dir })?;
+        let path = dir.join(name);
+        fs::write(&path, format!("{}\n", url)).context(error::UpstreamRecordSnafu { path })
+    }
+
+    /// Removes what an earlier build recorded in `dir` about the sources of a package, so that
+    /// sources it no longer uses are not reported.
+    pub(crate) fn clear_fetched_sources(dir: &Path) -> Result<()> {
+        match fs::remove_dir_all(dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).context(error::FetchedSourceClearSnafu { path: dir })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Records that the package uses the source file `f`, which is at `path`.
+    fn record_fetch(
+        &self,
+        f: &manifest::ExternalFile,
+        path: &Path,
+        origin: SourceOrigin,
+        fetched_from: Option<&str>,
+    ) -> Result<()> {
+        let name = path.display().to_string();
+        let mut file = File::open(path).context(error::ExternalFileOpenSnafu { path })?;
+        let mut sha256 = Sha256::new();
+        io::copy(&mut file, &mut sha256).context(error::ExternalFileLoadSnafu { path })?;
+        let source = FetchedSource {
+            name: name.clone(),
+            url: f.url.clone(),
+            origin,
+            fetched_from: fetched_from.map(str::to_string),
+            sha256: hex::encode(sha256.finalize()),
+            sha512: f.sha512.clone(),
+        };
+        let json = serde_json::to_string_pretty(&source).context(error::FetchedSourceJsonSnafu)?;
+        let dir = &self.fetched_sources_dir;
+        fs::create_dir_all(dir).context(error::FetchedSourceRecordSnafu { path: dir })?;
+        let path = dir.join(name);
+        fs::write(&path, json).context(error::FetchedSourceRecordSnafu { path })
+    }
+
     /// Retrieves a file from the specified URL and write it to the given path,
     /// then verifies the contents against the SHA-512 hash provided.
     fn fetch_file<P: AsRef<Path>>(&self, url: &str, path: P, hash: &str) -> Result<()> {
         let path = path.as_ref();
 
+        match Url::parse(url) {
+            // A local directory of sources, e.g. for offline builds.
+            Ok(parsed) if parsed.scheme() == "file" => {
+                let source = parsed
+                    .to_file_path()
+                    .ok()
+                    .context(error::ExternalFileLocalPathSnafu { url })?;
+                fs::copy(&source, path).context(error::ExternalFileCopySnafu { path: &source })?;
+            }
+            _ => self.download_file(url, path)?,
+        }
+
+        match Self::verify_file(path, hash) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                fs::remove_file(path).context(error::ExternalFileDeleteSnafu { path })?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Retrieves a file over HTTP(S) and writes it to the given path.
+    fn download_file(&self, url: &str, path: &Path) -> Result<()> {
         let mut headers = HeaderMap::new();
         headers.insert(
             USER_AGENT,
@@ -141,15 +346,7 @@ impl LookasideCache {
         let mut f = BufWriter::new(f);
         resp.copy_to(&mut f)
             .context(error::ExternalFileSaveSnafu { path })?;
-        drop(f);
-
-        match Self::verify_file(path, hash) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                fs::remove_file(path).context(error::ExternalFileDeleteSnafu { path })?;
-                Err(e)
-            }
-        }
+        Ok(())
     }
 
     fn extract_file_name(url: &str) -> Result<PathBuf> {
@@ -24,6 +24,12 @@ pub(crate) enum Error {
         status: reqwest::StatusCode,
     },
 
+    #[snafu(display("Lookaside cache URL '{}' is not a valid local path", url))]
+    ExternalFileLocalPath { url: String },
+
+    #[snafu(display("Failed to copy file '{}': {}", path.display(), source))]
+    ExternalFileCopy { path: PathBuf, source: io::Error },
+
     #[snafu(display("Failed to open file '{}': {}", path.display(), source))]
     ExternalFileOpen { path: PathBuf, source: io::Error },
 
@@ -45,6 +51,24 @@ pub(crate) enum Error {
     #[snafu(display("Failed to delete file '{}': {}", path.display(), source))]
     ExternalFileDelete { path: PathBuf, source: io::Error },
 
+    #[snafu(display("Failed to record upstream fetch in '{}': {}", path.display(), source))]
+    UpstreamRecord { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to record fetched source in '{}': {}", path.display(), source))]
+    FetchedSourceRecord { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to clear fetched sources in '{}': {}", path.display(), source))]
+    FetchedSourceClear { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to serialize fetched source: {}", source))]
+    FetchedSourceJson { source: serde_json::Error },
+
+    #[snafu(display("Failed to write '{}' to the shared cache: {}", path.display(), source))]
+    SharedCache { path: PathBuf, source: io::Error },
+
+    #[snafu(display("No lookaside cache was given"))]
+    NoLookasideCache,
+
     #[snafu(display("Failed to get path segments from URL '{}'", url))]
     UrlPathSegments { url: String },
 }
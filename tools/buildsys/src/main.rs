@@ -20,7 +20,9 @@ use crate::args::{
 };
 use crate::builder::DockerBuild;
 use buildsys::manifest::{BundleModule, Manifest, ManifestInfo, SupportedArch};
-use buildsys_config::EXTERNAL_KIT_METADATA;
+use buildsys_config::{
+    EXTERNAL_KIT_METADATA, FETCHED_SOURCES_DIRECTORY, UPSTREAM_SOURCES_DIRECTORY,
+};
 use cache::LookasideCache;
 use clap::Parser;
 use gomod::GoMod;
@@ -135,11 +137,19 @@ fn build_package(args: BuildPackageArgs) -> Result<()> {
     // Check for a deprecated key and error if it is detected.
     ensure_package_is_not_variant_sensitive(&manifest, &manifest_path)?;
 
+    let fetched_sources_dir = args
+        .common
+        .root_dir
+        .join(FETCHED_SOURCES_DIRECTORY)
+        .join(manifest.info().package_name());
     if let Some(files) = manifest.info().external_files() {
         let lookaside_cache = LookasideCache::new(
             &args.common.version_full,
             args.lookaside_cache.clone(),
             args.upstream_source_fallback == "true",
+            args.common.root_dir.join(UPSTREAM_SOURCES_DIRECTORY),
+            args.shared_cache.clone(),
+            &fetched_sources_dir,
         );
         lookaside_cache
             .fetch(files)
@@ -161,6 +171,10 @@ fn build_package(args: BuildPackageArgs) -> Result<()> {
                 }
             }
         }
+    } else {
+        // The package may have had sources in an earlier build.
+        LookasideCache::clear_fetched_sources(&fetched_sources_dir)
+            .context(error::ExternalFileFetchSnafu)?;
     }
 
     if let Some(groups) = manifest.info().source_groups() {
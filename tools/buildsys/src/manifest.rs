@@ -229,7 +229,7 @@ fips = true
 mod error;
 
 use crate::BuildType;
-use buildsys_config::EXTERNAL_KIT_METADATA;
+use buildsys_config::{EXTERNAL_KIT_METADATA, IMAGE_FEATURES};
 use guppy::graph::{DependencyDirection, PackageGraph, PackageLink, PackageMetadata};
 use guppy::{CargoMetadata, PackageId};
 use serde::{Deserialize, Serialize};
@@ -782,9 +782,70 @@ impl TryFrom<String> for ImageFeature {
             "xfs-data-partition" => Ok(ImageFeature::XfsDataPartition),
             "uefi-secure-boot" => Ok(ImageFeature::UefiSecureBoot),
             "fips" => Ok(ImageFeature::Fips),
-            _ => error::ParseImageFeatureSnafu { what: s }.fail()?,
+            _ => error::ParseImageFeatureSnafu {
+                what: s,
+                valid: IMAGE_FEATURES.join(", "),
+            }
+            .fail()?,
+        }
+    }
+}
+
+/// Applies overrides in the form `key=value,key=value` to an image layout, where each key is one of
+/// the settings of `image-layout`, e.g. `os-image-size-gib=4,partition-plan=unified`. This is how
+/// Twoliter passes the image layout from Twoliter.toml and `--image-layout` to buildsys and pubsys.
+/// The overrides are parsed by [`buildsys_config::ImageLayout`], the same as in Twoliter.
+pub fn apply_image_layout_overrides(layout: &mut ImageLayout, overrides: &str) -> Result<()> {
+    let overrides: buildsys_config::ImageLayout = overrides.parse().map_err(|e| {
+        error::ParseImageLayoutOverrideSnafu {
+            what: overrides,
+            reason: format!("{:#}", e),
         }
+        .build()
+    })?;
+    if let Some(size) = overrides.os_image_size_gib {
+        layout.os_image_size_gib = ImageSize(size);
+    }
+    if let Some(size) = overrides.data_image_size_gib {
+        layout.data_image_size_gib = ImageSize(size);
+    }
+    if let Some(size) = overrides.publish_image_size_hint_gib {
+        layout.publish_image_size_hint_gib = ImageSize(size);
+    }
+    if let Some(plan) = overrides.partition_plan {
+        layout.partition_plan = match plan {
+            buildsys_config::PartitionPlan::Split => PartitionPlan::Split,
+            buildsys_config::PartitionPlan::Unified => PartitionPlan::Unified,
+        };
     }
+    Ok(())
+}
+
+/// Applies overrides in the form `name=on,name=off` to a set of image features. A name given
+/// without a value is turned on. This is how `twoliter build variant --image-feature` passes its
+/// overrides to buildsys.
+pub fn apply_image_feature_overrides(
+    features: &mut HashSet<ImageFeature>,
+    overrides: &str,
+) -> Result<()> {
+    for item in overrides
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+    {
+        let (name, state) = item.split_once('=').unwrap_or((item, "on"));
+        let feature = ImageFeature::try_from(name.to_string())?;
+        match state {
+            "on" => {
+                features.insert(feature);
+            }
+            "off" => {
+                features.remove(&feature);
+            }
+            _ => error::ParseImageFeatureOverrideSnafu { what: item }.fail()?,
+        }
+    }
+    Ok(())
 }
 
 impl fmt::Display for ImageFeature {
@@ -871,6 +932,71 @@ mod test {
         output_path
     }
 
+    #[test]
+    fn test_image_feature_names() {
+        for name in IMAGE_FEATURES {
+            ImageFeature::try_from(name.to_string()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_apply_image_feature_overrides() {
+        let mut features = HashSet::from([ImageFeature::Fips, ImageFeature::SystemdNetworkd]);
+        apply_image_feature_overrides(
+            &mut features,
+            "fips=off, uefi-secure-boot=on,xfs-data-partition",
+        )
+        .unwrap();
+        assert_eq!(
+            features,
+            HashSet::from([
+                ImageFeature::SystemdNetworkd,
+                ImageFeature::UefiSecureBoot,
+                ImageFeature::XfsDataPartition,
+            ])
+        );
+        assert!(apply_image_feature_overrides(&mut features, "fips=yes").is_err());
+        assert!(apply_image_feature_overrides(&mut features, "erofs=on").is_err());
+    }
+
+    #[test]
+    fn test_apply_image_layout_overrides() {
+        let mut layout = ImageLayout::default();
+        apply_image_layout_overrides(
+            &mut layout,
+            "os-image-size-gib=4, partition-plan=unified,publish-image-size-hint-gib=30",
+        )
+        .unwrap();
+        assert_eq!(layout.publish_image_sizes_gib(), (30, -1));
+        assert_eq!(layout.data_image_size_gib.to_string(), "1");
+        assert!(apply_image_layout_overrides(&mut layout, "os-image-size-gib=0").is_err());
+        assert!(apply_image_layout_overrides(&mut layout, "partition-plan=mirrored").is_err());
+        assert!(apply_image_layout_overrides(&mut layout, "os-image-size=4").is_err());
+    }
+
+    #[test]
+    fn test_invalid_image_feature() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("Cargo.toml");
+        fs::write(
+            &path,
+            r#"[package]
+name = "my-variant"
+version = "0.1.0"
+
+[package.metadata.build-variant.image-features]
+systemd-networked = true
+"#,
+        )
+        .unwrap();
+        let err = ManifestInfo::new(&path).unwrap_err().to_string();
+        assert!(err.contains(&path.display().to_string()), "{}", err);
+        assert!(err.contains("'systemd-networked'"), "{}", err);
+        for name in IMAGE_FEATURES {
+            assert!(err.contains(name), "{}", err);
+        }
+    }
+
     #[test]
     fn test_package_list_pkg_g() {
         let manifest_path = cargo_manifest("pkg-g");
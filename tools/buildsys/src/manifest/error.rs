@@ -39,8 +39,18 @@ pub(super) enum Error {
         source: serde_json::Error,
     },
 
-    #[snafu(display("Failed to parse image feature '{}'", what))]
-    ParseImageFeature { what: String },
+    // This is raised while deserializing, so the manifest path is added by `ManifestFileLoad`.
+    #[snafu(display("Failed to parse image feature '{}', expected one of: {}", what, valid))]
+    ParseImageFeature { what: String, valid: String },
+
+    #[snafu(display(
+        "Failed to parse image feature override '{}', expected 'name=on' or 'name=off'",
+        what
+    ))]
+    ParseImageFeatureOverride { what: String },
+
+    #[snafu(display("Failed to parse image layout overrides '{}': {}", what, reason))]
+    ParseImageLayoutOverride { what: String, reason: String },
 
     #[snafu(display(
         "The cargo package we are building, '{name}', could not be found in the graph"
@@ -0,0 +1,77 @@
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+const VARIANT_MANIFEST: &str = r#"
+[package]
+name = "aws-dev"
+version = "0.1.0"
+edition = "2021"
+publish = false
+
+[package.metadata.build-variant.image-features]
+grub-set-private-var = true
+uefi-secure-boot = true
+"#;
+
+/// Runs `bottlerocket-variant` for the variant `aws-dev` with the environment in `vars`, and returns
+/// what it printed.
+fn bottlerocket_variant(vars: &[(&str, &str)]) -> String {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_bottlerocket-variant"));
+    for (var, _) in std::env::vars().filter(|(var, _)| var.starts_with("BUILDSYS_")) {
+        command.env_remove(var);
+    }
+    let output = command
+        .env("BUILDSYS_VARIANT", "aws-dev")
+        .envs(vars.iter().copied())
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn variant_manifest_from_env() {
+    let temp_dir = TempDir::new().unwrap();
+    let manifest = temp_dir.path().join("elsewhere").join("Cargo.toml");
+    fs::create_dir_all(manifest.parent().unwrap()).unwrap();
+    fs::write(&manifest, VARIANT_MANIFEST).unwrap();
+
+    // The root directory has no variants, so the manifest can only be found through the variable.
+    let output = bottlerocket_variant(&[
+        ("BUILDSYS_ROOT_DIR", temp_dir.path().to_str().unwrap()),
+        ("BUILDSYS_VARIANT_MANIFEST", manifest.to_str().unwrap()),
+    ]);
+    assert!(
+        output.contains("BUILDSYS_VARIANT_PLATFORM=aws\n"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("export BUILDSYS_VARIANT_IMAGE_FEATURE_UEFI_SECURE_BOOT=1\n"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn variant_manifest_in_root_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let manifest = temp_dir.path().join("variants/aws-dev/Cargo.toml");
+    fs::create_dir_all(manifest.parent().unwrap()).unwrap();
+    fs::write(&manifest, VARIANT_MANIFEST).unwrap();
+
+    let output = bottlerocket_variant(&[
+        ("BUILDSYS_ROOT_DIR", temp_dir.path().to_str().unwrap()),
+        ("BUILDSYS_VARIANT_MANIFEST", ""),
+    ]);
+    assert!(
+        output.contains("export BUILDSYS_VARIANT_IMAGE_FEATURE_GRUB_SET_PRIVATE_VAR=1\n"),
+        "{}",
+        output
+    );
+}
@@ -50,6 +50,10 @@ pub(crate) struct AmiArgs {
     #[arg(short = 'v', long)]
     variant_manifest: PathBuf,
 
+    /// Image layout overrides in the form `key=value,key=value`, as given to buildsys
+    #[arg(long)]
+    image_layout: Option<String>,
+
     /// Path to the UEFI data
     #[arg(short = 'e', long)]
     uefi_data: PathBuf,
@@ -40,11 +40,16 @@ async fn _register_image(
         },
     )?;
 
-    let image_layout = variant_manifest
-        .image_layout()
-        .context(error::MissingImageLayoutSnafu {
-            path: &ami_args.variant_manifest,
-        })?;
+    let mut image_layout =
+        *variant_manifest
+            .image_layout()
+            .context(error::MissingImageLayoutSnafu {
+                path: &ami_args.variant_manifest,
+            })?;
+    if let Some(overrides) = &ami_args.image_layout {
+        manifest::apply_image_layout_overrides(&mut image_layout, overrides)
+            .context(error::ImageLayoutOverrideSnafu)?;
+    }
 
     let (os_volume_size, data_volume_size) = image_layout.publish_image_sizes_gib();
 
@@ -304,6 +309,9 @@ mod error {
         #[snafu(display("Could not find image layout for {}", path.display()))]
         MissingImageLayout { path: PathBuf },
 
+        #[snafu(display("Failed to apply image layout overrides: {}", source))]
+        ImageLayoutOverride { source: buildsys::manifest::Error },
+
         #[snafu(display("Image response in {} did not include image ID", region))]
         MissingImageId { region: String },
 
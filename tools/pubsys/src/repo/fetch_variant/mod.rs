@@ -12,7 +12,7 @@ use tempfile::tempdir_in;
 use tough::{Prefix, Repository, RepositoryLoader, TargetName};
 use url::Url;
 
-use buildsys::manifest::{ImageFormat, ManifestInfo, PartitionPlan};
+use buildsys::manifest::{apply_image_layout_overrides, ImageFormat, ManifestInfo, PartitionPlan};
 
 /// fetching and downdloaing the image targets of a given variant
 #[derive(Debug, Parser)]
@@ -52,6 +52,10 @@ pub(crate) struct FetchVariantArgs {
     #[arg(long)]
     /// The manifest of the variant
     variant_manifest: PathBuf,
+
+    #[arg(long)]
+    /// Image layout overrides in the form `key=value,key=value`, as given to buildsys
+    image_layout: Option<String>,
 }
 
 /// Download targets
@@ -121,6 +125,7 @@ async fn fetch_variant(
     outdir: &PathBuf,
     filename_prefix: &str,
     variant_manifest: &PathBuf,
+    image_layout_overrides: Option<&str>,
     variant: &str,
 ) -> Result<(), Error> {
     // Load the repository
@@ -137,9 +142,13 @@ async fn fetch_variant(
 
     let manifest_info = ManifestInfo::new(variant_manifest).context(error::ManifestParseSnafu)?;
 
-    let image_layout = manifest_info
+    let mut image_layout = *manifest_info
         .image_layout()
         .context(error::MissingImageLayoutSnafu { variant })?;
+    if let Some(overrides) = image_layout_overrides {
+        apply_image_layout_overrides(&mut image_layout, overrides)
+            .context(error::ManifestParseSnafu)?;
+    }
     let image_format = manifest_info.image_format();
     let image_ext = match image_format {
         Some(ImageFormat::Raw) | None => "img.lz4",
@@ -204,6 +213,7 @@ pub(crate) async fn run(args: &Args, fetch_variant_args: &FetchVariantArgs) -> R
         &versioned_outdir,
         &fetch_variant_args.filename_prefix,
         &fetch_variant_args.variant_manifest,
+        fetch_variant_args.image_layout.as_deref(),
         &fetch_variant_args.variant,
     )
     .await
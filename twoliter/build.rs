@@ -10,15 +10,43 @@ Prepare and package embedded tools in a tarball to be included with Twoliter.
 use bytes::BufMut;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
+use sha2::{Digest, Sha256};
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::time::SystemTime;
 use std::{env, fs};
 
 const DATA_INPUT_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/embedded");
 
+/// The packages whose binaries are embedded, and the variable that passes the version of each to
+/// the main compilation.
+const EMBEDDED_PACKAGES: [(&str, &str); 5] = [
+    ("buildsys", "TWOLITER_BUILDSYS_VERSION"),
+    ("pubsys", "TWOLITER_PUBSYS_VERSION"),
+    ("pubsys-setup", "TWOLITER_PUBSYS_SETUP_VERSION"),
+    ("testsys", "TWOLITER_TESTSYS_VERSION"),
+    ("tuftool", "TWOLITER_TUFTOOL_VERSION"),
+];
+
+/// The variables through which cargo gives the paths of the embedded binaries, in the order that
+/// they are hashed.
+const EMBEDDED_BINARIES: [&str; 6] = [
+    "CARGO_BIN_FILE_BUILDSYS_bottlerocket-variant",
+    "CARGO_BIN_FILE_BUILDSYS",
+    "CARGO_BIN_FILE_PUBSYS",
+    "CARGO_BIN_FILE_PUBSYS_SETUP",
+    "CARGO_BIN_FILE_TESTSYS",
+    "CARGO_BIN_FILE_TUFTOOL",
+];
+
+/// What the commit is recorded as when it cannot be found.
+const UNKNOWN: &str = "unknown";
+
 fn main() {
     let paths = Paths::new();
     println!("cargo:rerun-if-changed={}", paths.data_input_dir.display());
+    record_versions();
+    record_commit();
 
     let _ = fs::remove_dir_all(&paths.prep_dir);
     fs::create_dir_all(&paths.prep_dir).expect(&format!(
@@ -61,9 +89,127 @@ fn main() {
         "Unable to write to file '{}'",
         paths.tar_gz.display()
     ));
+    record_tools_hash(tar_gz_data);
     println!("Done at {:?}", SystemTime::now());
 }
 
+/// Passes a hash of everything that Twoliter embeds, the tarball `tar_gz_data` and the binaries, to
+/// the main compilation as `TWOLITER_TOOLS_SHA256`. Installed tools are only reused when they were
+/// installed from the same content.
+fn record_tools_hash(tar_gz_data: &[u8]) {
+    let mut hasher = Sha256::new();
+    hasher.update(tar_gz_data.len().to_le_bytes());
+    hasher.update(tar_gz_data);
+    for var in EMBEDDED_BINARIES {
+        let path = env::var(var).expect(&format!("The variable '{}' is not set", var));
+        println!("cargo:rerun-if-changed={}", path);
+        let data = fs::read(&path).expect(&format!("Unable to read file '{}'", path));
+        hasher.update(data.len().to_le_bytes());
+        hasher.update(&data);
+    }
+    println!(
+        "cargo:rustc-env=TWOLITER_TOOLS_SHA256={}",
+        hex::encode(hasher.finalize())
+    );
+}
+
+/// Passes the versions of the embedded packages to the main compilation. A version that cannot be
+/// found is left out.
+fn record_versions() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // The published crate has a lock file of its own, a checkout has the workspace's.
+    let lock_file = [
+        manifest_dir.join("Cargo.lock"),
+        manifest_dir.join("../Cargo.lock"),
+    ]
+    .into_iter()
+    .find(|path| path.is_file());
+    if let Some(lock_file) = lock_file {
+        println!("cargo:rerun-if-changed={}", lock_file.display());
+        let lock = fs::read_to_string(&lock_file)
+            .expect(&format!("Unable to read file '{}'", lock_file.display()));
+        for (package, var) in EMBEDDED_PACKAGES {
+            if let Some(version) = locked_version(&lock, package) {
+                println!("cargo:rustc-env={}={}", var, version);
+            }
+        }
+    }
+}
+
+/// Passes the commit that Twoliter is built from, and whether there were uncommitted changes to
+/// it, to the main compilation as `TWOLITER_GIT_SHA` and `TWOLITER_GIT_DIRTY`. Both are `unknown`
+/// when they cannot be found, e.g. when git is not installed.
+fn record_commit() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let (sha, dirty) = vcs_info(manifest_dir)
+        .or_else(|| git_commit(manifest_dir))
+        .unwrap_or_else(|| (UNKNOWN.to_string(), UNKNOWN.to_string()));
+    println!("cargo:rustc-env=TWOLITER_GIT_SHA={}", sha);
+    println!("cargo:rustc-env=TWOLITER_GIT_DIRTY={}", dirty);
+}
+
+/// The commit that `cargo package` recorded when the crate was published, which is the only record
+/// of it in a crate tarball.
+fn vcs_info(manifest_dir: &Path) -> Option<(String, String)> {
+    let path = manifest_dir.join(".cargo_vcs_info.json");
+    let info: serde_json::Value = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    let sha = info["git"]["sha1"].as_str()?.to_string();
+    // cargo only records `dirty` when it is true.
+    let dirty = info["git"]["dirty"].as_bool().unwrap_or(false);
+    Some((sha, dirty.to_string()))
+}
+
+/// The commit that the checkout Twoliter is built from is at, and whether tracked files have
+/// changed since.
+fn git_commit(manifest_dir: &Path) -> Option<(String, String)> {
+    let sha = git(manifest_dir, &["rev-parse", "HEAD"])?;
+    let dirty = git(
+        manifest_dir,
+        &["status", "--porcelain", "--untracked-files=no"],
+    )
+    .map(|changes| (!changes.is_empty()).to_string())
+    .unwrap_or_else(|| UNKNOWN.to_string());
+
+    // Build again when HEAD moves, whether to another branch or to a new commit on this one, and
+    // when changes are staged. Edits that are not staged yet do not run this script again.
+    let mut watched = vec!["HEAD".to_string(), "index".to_string()];
+    watched.extend(git(manifest_dir, &["symbolic-ref", "-q", "HEAD"]));
+    for name in watched {
+        if let Some(path) = git(manifest_dir, &["rev-parse", "--git-path", &name]) {
+            let path = manifest_dir.join(path);
+            if path.exists() {
+                println!("cargo:rerun-if-changed={}", path.display());
+            }
+        }
+    }
+    Some((sha, dirty))
+}
+
+/// The version of `package` in the lock file `lock`. Lock files list the name and then the version
+/// of each package.
+fn locked_version(lock: &str, package: &str) -> Option<String> {
+    let name = format!("name = \"{}\"", package);
+    let mut lines = lock.lines();
+    lines.find(|line| *line == name)?;
+    lines
+        .next()?
+        .strip_prefix("version = \"")?
+        .strip_suffix('"')
+        .map(str::to_string)
+}
+
+/// Runs git in `dir` and returns what it printed, or `None` if git is missing or fails.
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    output.status.success().then(|| stdout.trim().to_string())
+}
+
 struct Paths {
     /// The directory where our scripts, Makefile.toml etc. are located.
     data_input_dir: PathBuf,
@@ -0,0 +1,298 @@
+/*!
+
+Building for an architecture other than the host's runs the target's binaries under emulation, which
+needs a `binfmt_misc` handler, usually from qemu-user-static. Without one, the build fails deep into
+a package with `exec format error`. Before a cross-architecture build, Twoliter looks in
+`/proc/sys/fs/binfmt_misc` for a handler that runs ELF binaries for the target, and when there is
+none it prints the command that registers one, or runs it with `--setup-binfmt`.
+
+Native builds are not checked, and neither are macOS hosts, where Docker Desktop takes care of
+emulation inside of its own VM.
+
+!*/
+
+use crate::common::fs;
+use anyhow::{bail, ensure, Context, Result};
+use log::{info, warn};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+use tokio::process::Command;
+
+const BINFMT_MISC: &str = "/proc/sys/fs/binfmt_misc";
+
+/// Registers qemu-user-static handlers for every architecture that it supports.
+pub(crate) const SETUP_COMMAND: [&str; 8] = [
+    "docker",
+    "run",
+    "--rm",
+    "--privileged",
+    "multiarch/qemu-user-static",
+    "--reset",
+    "-p",
+    "yes",
+];
+
+/// A `binfmt_misc` handler, as described by its file in `/proc/sys/fs/binfmt_misc`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(crate) struct Handler {
+    pub(crate) enabled: bool,
+    pub(crate) interpreter: String,
+    pub(crate) offset: usize,
+    pub(crate) magic: Vec<u8>,
+    pub(crate) mask: Option<Vec<u8>>,
+}
+
+impl Handler {
+    /// Parses a handler file. Handlers that match on file extension have no magic and never match
+    /// an ELF binary.
+    pub(crate) fn parse(contents: &str) -> Result<Self> {
+        let mut handler = Handler::default();
+        for line in contents.lines() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "enabled" => handler.enabled = true,
+                "disabled" => handler.enabled = false,
+                "interpreter" => handler.interpreter = value.to_string(),
+                "offset" => {
+                    handler.offset = value
+                        .parse()
+                        .context(format!("Invalid binfmt_misc offset '{}'", value))?
+                }
+                "magic" => {
+                    handler.magic = hex::decode(value).context("Invalid binfmt_misc magic")?
+                }
+                "mask" => {
+                    handler.mask = Some(hex::decode(value).context("Invalid binfmt_misc mask")?)
+                }
+                _ => {}
+            }
+        }
+        Ok(handler)
+    }
+
+    /// Whether the handler is enabled and runs 64-bit little-endian ELF binaries for `machine`, an
+    /// ELF `e_machine` value.
+    pub(crate) fn runs(&self, machine: u16) -> bool {
+        let mut header = [0u8; 20];
+        header[..4].copy_from_slice(b"\x7fELF");
+        header[4] = 2; // 64-bit
+        header[5] = 1; // little-endian
+        header[6] = 1; // version
+        header[16] = 2; // executable
+        header[18..].copy_from_slice(&machine.to_le_bytes());
+        if !self.enabled || self.offset != 0 || self.magic.len() < header.len() {
+            return false;
+        }
+        // The handler must look at the machine, or it would run binaries for any architecture.
+        let mask = |i: usize| self.mask.as_ref().map_or(0xff, |mask| mask[i]);
+        if self
+            .mask
+            .as_ref()
+            .is_some_and(|mask| mask.len() < self.magic.len())
+            || mask(18) == 0
+            || mask(19) == 0
+        {
+            return false;
+        }
+        header
+            .iter()
+            .enumerate()
+            .all(|(i, byte)| byte & mask(i) == self.magic[i] & mask(i))
+    }
+}
+
+/// The ELF `e_machine` of binaries for a Bottlerocket architecture.
+fn elf_machine(arch: &str) -> Option<u16> {
+    match arch {
+        "x86_64" => Some(0x3e),
+        "aarch64" => Some(0xb7),
+        _ => None,
+    }
+}
+
+/// Whether building for `arch` on this host needs a `binfmt_misc` handler.
+pub(crate) fn needs_emulation(arch: &str) -> bool {
+    !cfg!(target_os = "macos") && arch != std::env::consts::ARCH
+}
+
+/// Whether a handler in `dir` runs binaries for `arch`. `binfmt_misc` that is not mounted, or that
+/// is turned off, has none.
+pub(crate) async fn has_handler(dir: &Path, arch: &str) -> Result<bool> {
+    let Some(machine) = elf_machine(arch) else {
+        bail!(
+            "Unable to check emulation for unknown architecture '{}'",
+            arch
+        );
+    };
+    let Ok(status) = fs::read_to_string(dir.join("status")).await else {
+        return Ok(false);
+    };
+    if status.trim() != "enabled" {
+        return Ok(false);
+    }
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .context(format!("Unable to read '{}'", dir.display()))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context(format!("Unable to read '{}'", dir.display()))?
+    {
+        if matches!(entry.file_name().to_str(), Some("register" | "status")) {
+            continue;
+        }
+        let Ok(contents) = fs::read_to_string(entry.path()).await else {
+            continue;
+        };
+        if Handler::parse(&contents).is_ok_and(|handler| handler.runs(machine)) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Makes sure that binaries for `arch` can run before a build for it starts. When they cannot,
+/// registers the handlers if `setup` is given and the user agrees, fails if `strict`, and otherwise
+/// warns with the command that registers them.
+pub(crate) async fn preflight(arch: &str, setup: bool, strict: bool) -> Result<()> {
+    // An architecture that is not known here is left for the build to reject.
+    if !needs_emulation(arch)
+        || elf_machine(arch).is_none()
+        || has_handler(Path::new(BINFMT_MISC), arch).await?
+    {
+        return Ok(());
+    }
+    let problem = format!(
+        "Building for {} on {} needs emulation, but no binfmt_misc handler for {} binaries is \
+        registered. The build would fail with 'exec format error'",
+        arch,
+        std::env::consts::ARCH,
+        arch
+    );
+    let command = SETUP_COMMAND.join(" ");
+    if setup {
+        ensure!(
+            confirm(&command)?,
+            "{}. Register a handler with '{}'",
+            problem,
+            command
+        );
+        info!("Registering binfmt_misc handlers with '{}'", command);
+        let status = Command::new(SETUP_COMMAND[0])
+            .args(&SETUP_COMMAND[1..])
+            .status()
+            .await
+            .context("Unable to run docker")?;
+        ensure!(
+            status.success(),
+            "Unable to register binfmt_misc handlers, '{}' exited with {}",
+            command,
+            status
+        );
+        ensure!(
+            has_handler(Path::new(BINFMT_MISC), arch).await?,
+            "No binfmt_misc handler for {} was registered by '{}'",
+            arch,
+            command
+        );
+        return Ok(());
+    }
+    if strict {
+        bail!(
+            "{}. Register one with '{}', or run Twoliter with --setup-binfmt",
+            problem,
+            command
+        );
+    }
+    warn!(
+        "{}. Register one with '{}', or run Twoliter with --setup-binfmt",
+        problem, command
+    );
+    Ok(())
+}
+
+/// Asks before running `command`, which starts a privileged container. There is nobody to ask
+/// without a terminal, so the answer is no.
+fn confirm(command: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        warn!(
+            "Not running '{}' without a terminal to confirm it on",
+            command
+        );
+        return Ok(false);
+    }
+    eprint!(
+        "Registering binfmt_misc handlers runs a privileged container:\n  {}\nContinue? [y/N] ",
+        command
+    );
+    std::io::stderr()
+        .flush()
+        .context("Unable to write the prompt")?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .context("Unable to read the answer")?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const QEMU_AARCH64: &str = "\
+enabled
+interpreter /usr/bin/qemu-aarch64-static
+flags: F
+offset 0
+magic 7f454c460201010000000000000000000200b700
+mask ffffffffffffff00fffffffffffffffffeffffff
+";
+
+    #[test]
+    fn test_parse_handler() {
+        let handler = Handler::parse(QEMU_AARCH64).unwrap();
+        assert!(handler.enabled);
+        assert_eq!(handler.interpreter, "/usr/bin/qemu-aarch64-static");
+        assert_eq!(handler.offset, 0);
+        assert_eq!(handler.magic.len(), 20);
+        assert!(handler.runs(0xb7));
+        assert!(!handler.runs(0x3e));
+
+        let disabled = Handler::parse(&QEMU_AARCH64.replace("enabled", "disabled")).unwrap();
+        assert!(!disabled.runs(0xb7));
+
+        // Matches on extension, e.g. for Windows executables.
+        let extension =
+            Handler::parse("enabled\ninterpreter /usr/bin/wine\nflags: \nextension .exe\n")
+                .unwrap();
+        assert!(!extension.runs(0xb7));
+
+        assert!(Handler::parse("enabled\nmagic not-hex\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_has_handler() {
+        let dir = tempfile::TempDir::new().unwrap();
+        // Not mounted.
+        assert!(!has_handler(dir.path(), "aarch64").await.unwrap());
+
+        fs::write(dir.path().join("status"), "enabled\n")
+            .await
+            .unwrap();
+        fs::write(dir.path().join("register"), "").await.unwrap();
+        assert!(!has_handler(dir.path(), "aarch64").await.unwrap());
+
+        fs::write(dir.path().join("qemu-aarch64"), QEMU_AARCH64)
+            .await
+            .unwrap();
+        assert!(has_handler(dir.path(), "aarch64").await.unwrap());
+        assert!(!has_handler(dir.path(), "x86_64").await.unwrap());
+        assert!(has_handler(dir.path(), "riscv64").await.is_err());
+
+        fs::write(dir.path().join("status"), "disabled\n")
+            .await
+            .unwrap();
+        assert!(!has_handler(dir.path(), "aarch64").await.unwrap());
+    }
+}
@@ -1,9 +1,23 @@
-use crate::common::{exec_log, BUILDSYS_OUTPUT_GENERATION_ID};
-use anyhow::{bail, Result};
-use log::trace;
-use std::path::PathBuf;
+use crate::common::fs;
+use crate::common::{
+    exec_capture, exec_interactive, exec_log, exec_prefixed, redact, BUILDSYS_OUTPUT_GENERATION_ID,
+};
+use anyhow::{bail, ensure, Context, Result};
+use log::{debug, info, trace, warn};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
 use tokio::process::Command;
 
+/// The version of cargo-make that Twoliter is tested with, and that `--bootstrap-tools` installs.
+pub(crate) const CARGO_MAKE_VERSION: &str = "0.37.9";
+
+/// Where `--bootstrap-tools` installs cargo-make, relative to the project directory.
+const CARGO_MAKE_BOOTSTRAP_DIR: &str = "build/cargo-home";
+
 /// A struct used to invoke `cargo make` tasks with `twoliter`'s `Makefile.toml`.
 /// ```rust
 /// # use crate::project::Project;
@@ -43,7 +57,209 @@ use tokio::process::Command;
 pub struct CargoMake {
     makefile_path: Option<PathBuf>,
     project_dir: Option<PathBuf>,
-    args: Vec<String>,
+    env: BuildEnv,
+    capture_path: Option<PathBuf>,
+    bootstrap_tools: bool,
+    print_env: bool,
+    output_prefix: Option<String>,
+    interactive: bool,
+}
+
+/// Where a variable in a [`BuildEnv`] came from. The sources are listed from lowest to highest
+/// precedence, so a value from a later source replaces a value for the same key from an earlier one.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub(crate) enum EnvSource {
+    /// Build system variables passed through from Twoliter's own environment.
+    PassThrough,
+    /// Build system variables read from a file given with `--env-file`.
+    EnvFile,
+    /// Variables that Twoliter sets for every invocation of a command.
+    Core,
+    /// Variables that Twoliter sets only when a flag or a setting in `Twoliter.toml` asks for them.
+    Optional,
+    /// Values that the user gave explicitly for this invocation.
+    Override,
+}
+
+impl Display for EnvSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvSource::PassThrough => write!(f, "the environment"),
+            EnvSource::EnvFile => write!(f, "an env file"),
+            EnvSource::Core => write!(f, "twoliter"),
+            EnvSource::Optional => write!(f, "an optional setting"),
+            EnvSource::Override => write!(f, "a user override"),
+        }
+    }
+}
+
+/// The environment variables passed to `cargo make`, each tagged with its [`EnvSource`]. When a key
+/// is given more than once, the value from the source with the highest precedence is used, and
+/// within a source the last value wins.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BuildEnv {
+    vars: Vec<(EnvSource, String, String)>,
+}
+
+/// A key whose value from one source was replaced by a different value from a source with higher
+/// precedence.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct EnvConflict {
+    key: String,
+    overridden: EnvSource,
+    winner: EnvSource,
+}
+
+impl BuildEnv {
+    /// Add a variable that Twoliter sets for every invocation of a command.
+    pub(crate) fn core(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.push(EnvSource::Core, key, value)
+    }
+
+    /// Add a variable that is only set when a flag or setting asks for it.
+    pub(crate) fn optional(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.push(EnvSource::Optional, key, value)
+    }
+
+    /// Add a value that the user gave explicitly. These take precedence over everything else.
+    pub(crate) fn user_override(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.push(EnvSource::Override, key, value)
+    }
+
+    /// Add the build system variables found in `vars`, which is usually `std::env::vars()`. Other
+    /// variables are ignored, and variables whose values have moved to `Twoliter.toml` are an
+    /// error.
+    pub(crate) fn pass_through(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<()> {
+        for (key, value) in vars {
+            // To avoid confusion, environment variables whose values have been moved to
+            // Twoliter.toml are expressly disallowed here.
+            check_for_disallowed_var(&key)?;
+            if is_build_system_env(&key) {
+                trace!("Passing env var {} to cargo make", key);
+                self.push(EnvSource::PassThrough, key, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the build system variables read from an env file. Other variables are ignored with a
+    /// warning, and variables whose values have moved to `Twoliter.toml` are an error.
+    pub(crate) fn env_file(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<()> {
+        for (key, value) in vars {
+            check_for_disallowed_var(&key)?;
+            if is_build_system_env(&key) {
+                self.push(EnvSource::EnvFile, key, value);
+            } else {
+                warn!(
+                    "Ignoring '{}' from the env file because it is not a build system variable",
+                    key
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, source: EnvSource, key: impl Into<String>, value: impl Into<String>) {
+        self.vars.push((source, key.into(), value.into()));
+    }
+
+    /// Returns the final value of each variable, warning about each key whose value was replaced by
+    /// a source with higher precedence.
+    pub(crate) fn resolve(&self) -> BTreeMap<String, String> {
+        let (env, conflicts) = self.resolve_conflicts();
+        for conflict in conflicts {
+            warn!(
+                "The value of '{}' from {} was overridden by {}",
+                conflict.key, conflict.overridden, conflict.winner
+            );
+        }
+        env
+    }
+
+    /// Lists the final value of each variable, one `KEY=VALUE` line per variable followed by where
+    /// the value came from. The values of secret-looking variables are hidden.
+    pub(crate) fn describe(&self) -> String {
+        let (resolved, _) = self.resolve_sources();
+        let mut out = String::new();
+        for (key, (source, value)) in resolved {
+            out.push_str(&format!(
+                "{}={}  # from {}\n",
+                key,
+                redact(key, value),
+                source
+            ));
+        }
+        out
+    }
+
+    fn resolve_conflicts(&self) -> (BTreeMap<String, String>, Vec<EnvConflict>) {
+        let (resolved, conflicts) = self.resolve_sources();
+        let env = resolved
+            .into_iter()
+            .map(|(key, (_, value))| (key.to_string(), value.to_string()))
+            .collect();
+        (env, conflicts)
+    }
+
+    fn resolve_sources(&self) -> (BTreeMap<&str, (EnvSource, &str)>, Vec<EnvConflict>) {
+        let mut vars: Vec<_> = self.vars.iter().collect();
+        // The sort is stable, so the order in which values were given is kept within a source.
+        vars.sort_by_key(|(source, _, _)| *source);
+        let mut resolved: BTreeMap<&str, (EnvSource, &str)> = BTreeMap::new();
+        let mut conflicts = Vec::new();
+        for (source, key, value) in vars {
+            if let Some((previous, previous_value)) = resolved.insert(key, (*source, value)) {
+                if previous < *source && previous_value != value {
+                    conflicts.push(EnvConflict {
+                        key: key.clone(),
+                        overridden: previous,
+                        winner: *source,
+                    });
+                }
+            }
+        }
+        (resolved, conflicts)
+    }
+}
+
+/// A description of the `cargo make` invocation that a [`CargoMake`] would run. This is built by
+/// the same code that runs the command, so it can be used to troubleshoot what a build would do.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(crate) struct Explanation {
+    /// The final environment passed to `cargo make`, sorted by key. When a key is given more than
+    /// once, the value that `cargo make` would see is the one that is kept.
+    pub(crate) env: BTreeMap<String, String>,
+    /// The arguments passed to `cargo`, starting with `make`.
+    pub(crate) args: Vec<String>,
+}
+
+impl Explanation {
+    /// Returns a copy with the values of secret-looking environment variables hidden, suitable for
+    /// printing.
+    pub(crate) fn redacted(&self) -> Self {
+        let env = self
+            .env
+            .iter()
+            .map(|(key, value)| (key.clone(), redact(key, value).to_string()))
+            .collect();
+        let args = self
+            .args
+            .iter()
+            .map(
+                |arg| match arg.strip_prefix("-e=").and_then(|kv| kv.split_once('=')) {
+                    Some((key, value)) => format!("-e={}={}", key, redact(key, value)),
+                    None => arg.clone(),
+                },
+            )
+            .collect();
+        Self { env, args }
+    }
 }
 
 impl CargoMake {
@@ -74,30 +290,103 @@ impl CargoMake {
         self
     }
 
+    /// Write the output of `cargo make` to a file instead of the console. Only the name of each task
+    /// is printed as it starts.
+    pub(crate) fn capture<P>(mut self, capture_path: Option<P>) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.capture_path = capture_path.map(Into::into);
+        self
+    }
+
+    /// Install cargo-make into the project's `build/cargo-home` if it is missing, instead of failing
+    /// with instructions for installing it.
+    pub(crate) fn bootstrap_tools(mut self, bootstrap_tools: bool) -> Self {
+        self.bootstrap_tools = bootstrap_tools;
+        self
+    }
+
+    /// Print the environment passed to `cargo make`, and where each value came from, before running
+    /// it. The environment is always logged at debug level.
+    pub(crate) fn print_env(mut self, print_env: bool) -> Self {
+        self.print_env = print_env;
+        self
+    }
+
+    /// Start each line of output with `[prefix] `, for commands that run alongside others. Has no
+    /// effect on output that is captured to a file.
+    pub(crate) fn output_prefix<S>(mut self, prefix: Option<S>) -> Self
+    where
+        S: Into<String>,
+    {
+        self.output_prefix = prefix.map(Into::into);
+        self
+    }
+
+    /// Attach `cargo make` to the terminal so that tasks can prompt for input. This wins over
+    /// [`CargoMake::capture`] and [`CargoMake::output_prefix`].
+    pub(crate) fn interactive(mut self, interactive: bool) -> Self {
+        self.interactive = interactive;
+        self
+    }
+
     /// Specify environment variables that should be applied for this comand
     pub(crate) fn env<S1, S2>(mut self, key: S1, value: S2) -> Self
     where
         S1: Into<String>,
         S2: Into<String>,
     {
-        self.args
-            .push(format!("-e={}={}", key.into(), value.into()));
+        self.env.core(key, value);
         self
     }
 
-    /// Add multiple env variables as `(key, value)` tuples.
-    pub(crate) fn envs<S1, S2>(mut self, key_value_pairs: impl Iterator<Item = (S1, S2)>) -> Self
+    /// Add multiple optional env variables as `(key, value)` tuples. These take precedence over
+    /// variables given with `env`.
+    pub(crate) fn optional_envs<S1, S2>(
+        mut self,
+        key_value_pairs: impl IntoIterator<Item = (S1, S2)>,
+    ) -> Self
     where
         S1: Into<String>,
         S2: Into<String>,
     {
         for (key, value) in key_value_pairs {
-            self.args
-                .push(format!("-e={}={}", key.into(), value.into()));
+            self.env.optional(key, value);
         }
         self
     }
 
+    /// Add the build system variables from `--env-file`, see [`read_env_file`]. These take
+    /// precedence only over variables passed through from Twoliter's own environment.
+    pub(crate) fn env_file_vars(mut self, vars: Vec<(String, String)>) -> Result<Self> {
+        self.env.env_file(vars)?;
+        Ok(self)
+    }
+
+    /// Specify an environment variable that the user gave explicitly. This takes precedence over
+    /// all other sources.
+    pub(crate) fn override_env<S1, S2>(mut self, key: S1, value: S2) -> Self
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        self.env.user_override(key, value);
+        self
+    }
+
+    /// Returns the environment that `cargo make` would see, including the variables passed through
+    /// from Twoliter's own environment.
+    pub(crate) fn resolved_env(&self) -> Result<BTreeMap<String, String>> {
+        Ok(self.build_env()?.resolve())
+    }
+
+    fn build_env(&self) -> Result<BuildEnv> {
+        let mut env = self.env.clone();
+        env.pass_through(std::env::vars())?;
+        Ok(env)
+    }
+
     /// Execute the `cargo make` task
     pub(crate) async fn exec<S>(&self, task: S) -> Result<()>
     where
@@ -113,43 +402,197 @@ impl CargoMake {
         S2: Into<String>,
         I: IntoIterator<Item = S2>,
     {
+        let explanation = self.explain_with_args(task, args)?;
+        let description = self.build_env()?.describe();
+        if self.print_env {
+            print!("{}", description);
+        } else {
+            debug!("Environment passed to cargo make:\n{}", description);
+        }
+        let mut command = Command::new("cargo");
+        command.env("PATH", self.preflight().await?);
+        command.args(explanation.args);
+        if self.interactive {
+            return exec_interactive(&mut command).await;
+        }
+        match (&self.capture_path, &self.output_prefix) {
+            (Some(path), _) => exec_capture(&mut command, path, is_task_start).await,
+            (None, Some(prefix)) => exec_prefixed(&mut command, prefix).await,
+            (None, None) => exec_log(&mut command).await,
+        }
+    }
+
+    /// Makes sure that `cargo make` can run and returns the `PATH` to run it with. A cargo-make that
+    /// `--bootstrap-tools` installed in the project comes first in the `PATH`, so it is preferred
+    /// over one on the system.
+    async fn preflight(&self) -> Result<OsString> {
+        let project_dir = self
+            .project_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("."));
+        let root = project_dir.join(CARGO_MAKE_BOOTSTRAP_DIR);
+        let path = prepend_path(&root.join("bin"), env::var_os("PATH"))?;
+        if has_cargo_make(&path).await {
+            return Ok(path);
+        }
+        if !self.bootstrap_tools {
+            bail!(
+                "cargo-make is not installed. It is a cargo subcommand that Twoliter uses to run \
+                builds, install it with 'cargo install cargo-make --locked --version {}', or run \
+                Twoliter with '--bootstrap-tools' to install it into '{}'",
+                CARGO_MAKE_VERSION,
+                root.display()
+            );
+        }
+        info!(
+            "Installing cargo-make {} into '{}'",
+            CARGO_MAKE_VERSION,
+            root.display()
+        );
         exec_log(
             Command::new("cargo")
-                .arg("make")
-                .arg("--disable-check-for-updates")
-                .args(
-                    self.makefile_path.iter().flat_map(|path| {
-                        vec!["--makefile".to_string(), path.display().to_string()]
-                    }),
-                )
-                .args(
-                    self.project_dir
-                        .iter()
-                        .flat_map(|path| vec!["--cwd".to_string(), path.display().to_string()]),
-                )
-                .args(build_system_env_vars()?)
-                .args(&self.args)
-                .arg(task.into())
-                .args(args.into_iter().map(Into::into)),
+                .args(["install", "cargo-make", "--locked", "--version"])
+                .arg(CARGO_MAKE_VERSION)
+                .arg("--root")
+                .arg(&root),
         )
         .await
+        .context("Unable to install cargo-make")?;
+        Ok(path)
+    }
+
+    /// Describe the environment and arguments that `exec` would use for the `cargo make` task
+    /// without running anything.
+    pub(crate) fn explain<S>(&self, task: S) -> Result<Explanation>
+    where
+        S: Into<String>,
+    {
+        self.explain_with_args(task, Vec::<String>::new())
+    }
+
+    /// Describe the environment and arguments that `exec_with_args` would use for the `cargo make`
+    /// task without running anything.
+    pub(crate) fn explain_with_args<S1, S2, I>(&self, task: S1, args: I) -> Result<Explanation>
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+        I: IntoIterator<Item = S2>,
+    {
+        let env = self.resolved_env()?;
+
+        let mut command_args = vec![
+            "make".to_string(),
+            "--disable-check-for-updates".to_string(),
+        ];
+        for path in &self.makefile_path {
+            command_args.push("--makefile".to_string());
+            command_args.push(path_var(path)?);
+        }
+        for path in &self.project_dir {
+            command_args.push("--cwd".to_string());
+            command_args.push(path_var(path)?);
+        }
+        command_args.extend(
+            env.iter()
+                .map(|(key, value)| format!("-e={}={}", key, value)),
+        );
+        command_args.push(task.into());
+        command_args.extend(args.into_iter().map(Into::into));
+
+        Ok(Explanation {
+            env,
+            args: command_args,
+        })
     }
 }
 
-fn build_system_env_vars() -> Result<Vec<String>> {
-    let mut args = Vec::new();
-    for (key, val) in std::env::vars() {
-        if is_build_system_env(key.as_str()) {
-            trace!("Passing env var {} to cargo make", key);
-            args.push("-e".to_string());
-            args.push(format!("{}={}", key, val));
+/// Returns `path` as text for `cargo make`, as an argument or the value of a variable. Each path is
+/// passed on whole, so spaces and other characters in it are fine, but cargo make only takes UTF-8
+/// text, and the Makefile mounts paths into containers as `SRC:DST` and puts directories on the
+/// `PATH`, which a `:` in a path would break. Such paths are an error here rather than a confusing
+/// failure later in the build.
+pub(crate) fn path_var(path: impl AsRef<Path>) -> Result<String> {
+    let path = path.as_ref();
+    let text = path.to_str().context(format!(
+        "The path '{}' is not valid UTF-8, which cargo make cannot use, move it to a path that is",
+        path.display()
+    ))?;
+    ensure!(
+        !text.contains(':'),
+        "The path '{}' contains a ':', which docker cannot mount, move it to a path without one",
+        text
+    );
+    Ok(text.to_string())
+}
+
+/// Reads the `KEY=VALUE` lines of the env file at `path`, or nothing if `path` is `None`.
+pub(crate) async fn read_env_file(path: Option<&Path>) -> Result<Vec<(String, String)>> {
+    let Some(path) = path else {
+        return Ok(Vec::new());
+    };
+    let contents = fs::read_to_string(path).await?;
+    parse_env_file(&contents).context(format!("Unable to parse env file '{}'", path.display()))
+}
+
+/// Parses `KEY=VALUE` lines. Blank lines and lines starting with `#` are skipped, a leading
+/// `export` is allowed, and quotes around a value are removed.
+fn parse_env_file(contents: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("Line {} is not of the form KEY=VALUE", number + 1);
+        };
+        let key = key.trim();
+        ensure!(
+            !key.is_empty() && !key.contains(char::is_whitespace),
+            "Line {} has an invalid variable name '{}'",
+            number + 1,
+            key
+        );
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| {
+                value
+                    .strip_prefix(*quote)
+                    .and_then(|value| value.strip_suffix(*quote))
+            })
+            .unwrap_or(value);
+        vars.push((key.to_string(), value.to_string()));
+    }
+    Ok(vars)
+}
+
+/// Returns `true` if `cargo make --version` succeeds with the given `PATH`.
+async fn has_cargo_make(path: &OsStr) -> bool {
+    Command::new("cargo")
+        .args(["make", "--version"])
+        .env("PATH", path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .is_ok_and(|status| status.success())
+}
 
-        // To avoid confusion, environment variables whose values have been moved to
-        // Twoliter.toml are expressly disallowed here.
-        check_for_disallowed_var(&key)?;
+/// Returns `path` with `dir` at the front.
+fn prepend_path(dir: &Path, path: Option<OsString>) -> Result<OsString> {
+    let mut dirs = vec![dir.to_path_buf()];
+    if let Some(path) = path {
+        dirs.extend(env::split_paths(&path));
     }
-    Ok(args)
+    env::join_paths(dirs).context(format!("Unable to add '{}' to the PATH", dir.display()))
+}
+
+/// Returns `true` for the line that `cargo make` prints when it starts running a task, e.g.
+/// `[cargo-make] INFO - Running Task: build-kit`.
+fn is_task_start(line: &str) -> bool {
+    line.starts_with("[cargo-make]") && line.contains(" Running Task: ")
 }
 
 /// A list of environment variables that don't conform to naming conventions but need to be passed
@@ -206,6 +649,135 @@ fn check_for_disallowed_var(key: &str) -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_explain() {
+    use crate::common::REDACTED;
+
+    let explanation = CargoMake::new("example.com/sdk:v1")
+        .unwrap()
+        .makefile("/tools/Makefile.toml")
+        .project_dir("/project")
+        .env("BUILDSYS_ARCH", "aarch64")
+        .env("PUBLISH_REPO_TOKEN", "hunter2")
+        .env("BUILDSYS_ARCH", "x86_64")
+        .explain("build")
+        .unwrap();
+
+    // The last value given for a key wins, just as it does for cargo make.
+    assert_eq!(explanation.env.get("BUILDSYS_ARCH").unwrap(), "x86_64");
+    assert_eq!(
+        explanation.env.get("TLPRIVATE_SDK_IMAGE").unwrap(),
+        "example.com/sdk:v1"
+    );
+    assert_eq!(
+        &explanation.args[0..2],
+        ["make", "--disable-check-for-updates"]
+    );
+    assert!(explanation
+        .args
+        .windows(2)
+        .any(|w| w == ["--makefile", "/tools/Makefile.toml"]));
+    assert!(explanation
+        .args
+        .windows(2)
+        .any(|w| w == ["--cwd", "/project"]));
+    assert_eq!(explanation.args.last().unwrap(), "build");
+
+    let redacted = explanation.redacted();
+    assert_eq!(redacted.env.get("PUBLISH_REPO_TOKEN").unwrap(), REDACTED);
+    assert!(!redacted.args.iter().any(|arg| arg.contains("hunter2")));
+}
+
+#[test]
+fn test_is_task_start() {
+    assert!(is_task_start("[cargo-make] INFO - Running Task: build-kit"));
+    assert!(!is_task_start(
+        "[cargo-make] INFO - Build Done in 3.21 seconds."
+    ));
+    assert!(!is_task_start("   Compiling buildsys v0.1.0"));
+}
+
+#[test]
+fn test_build_env_precedence() {
+    let mut env = BuildEnv::default();
+    env.user_override("CARGO_HOME", "/user");
+    env.optional("GOPROXY", "off");
+    env.core("BUILDSYS_ARCH", "x86_64");
+    env.core("CARGO_HOME", "/core");
+    env.pass_through([
+        ("BUILDSYS_ARCH".to_string(), "aarch64".to_string()),
+        ("GOPROXY".to_string(), "direct".to_string()),
+        ("BUILDSYS_JOBS".to_string(), "4".to_string()),
+        ("PATH".to_string(), "/usr/bin".to_string()),
+    ])
+    .unwrap();
+    env.core("BUILDSYS_VARIANT", "aws-dev");
+    env.core("BUILDSYS_VARIANT", "aws-k8s");
+    env.optional("BUILDSYS_JOBS", "4");
+
+    let (resolved, conflicts) = env.resolve_conflicts();
+    let resolved: Vec<_> = resolved
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    assert_eq!(
+        resolved,
+        [
+            ("BUILDSYS_ARCH", "x86_64"),
+            ("BUILDSYS_JOBS", "4"),
+            ("BUILDSYS_VARIANT", "aws-k8s"),
+            ("CARGO_HOME", "/user"),
+            ("GOPROXY", "off"),
+        ]
+    );
+
+    // Values replaced within a source, or by the same value, are not conflicts.
+    let conflict = |key: &str, overridden, winner| EnvConflict {
+        key: key.to_string(),
+        overridden,
+        winner,
+    };
+    assert_eq!(
+        conflicts,
+        [
+            conflict("BUILDSYS_ARCH", EnvSource::PassThrough, EnvSource::Core),
+            conflict("GOPROXY", EnvSource::PassThrough, EnvSource::Optional),
+            conflict("CARGO_HOME", EnvSource::Core, EnvSource::Override),
+        ]
+    );
+}
+
+#[test]
+fn test_build_env_describe() {
+    use crate::common::REDACTED;
+
+    let mut env = BuildEnv::default();
+    env.core("BUILDSYS_ARCH", "x86_64");
+    env.pass_through([
+        ("PUBLISH_REPO_TOKEN".to_string(), "hunter2".to_string()),
+        ("HOME".to_string(), "/root".to_string()),
+    ])
+    .unwrap();
+    env.user_override("BUILDSYS_ARCH", "aarch64");
+
+    assert_eq!(
+        env.describe(),
+        format!(
+            "BUILDSYS_ARCH=aarch64  # from a user override\n\
+            PUBLISH_REPO_TOKEN={}  # from the environment\n",
+            REDACTED
+        )
+    );
+}
+
+#[test]
+fn test_build_env_disallowed_var() {
+    let mut env = BuildEnv::default();
+    assert!(env
+        .pass_through([("BUILDSYS_REGISTRY".to_string(), "example.com".to_string())])
+        .is_err());
+}
+
 #[test]
 fn test_is_build_system_env() {
     assert!(is_build_system_env(
@@ -234,3 +806,95 @@ fn test_check_for_disallowed_var() {
     assert!(check_for_disallowed_var("BUILDSYS_OUTPUT_GENERATION_ID").is_err());
     assert!(check_for_disallowed_var("BUILDSYS_FOO").is_ok());
 }
+
+#[test]
+fn test_prepend_path() {
+    let path = prepend_path(
+        Path::new("/project/build/cargo-home/bin"),
+        Some(OsString::from("/usr/local/bin:/usr/bin")),
+    )
+    .unwrap();
+    assert_eq!(
+        path,
+        OsString::from("/project/build/cargo-home/bin:/usr/local/bin:/usr/bin")
+    );
+    let path = prepend_path(Path::new("/project/build/cargo-home/bin"), None).unwrap();
+    assert_eq!(path, OsString::from("/project/build/cargo-home/bin"));
+    assert!(prepend_path(Path::new("/a:b"), None).is_err());
+}
+
+#[tokio::test]
+async fn test_has_cargo_make() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::TempDir::new().unwrap();
+    assert!(!has_cargo_make(dir.path().as_os_str()).await);
+
+    // A stand-in for cargo that only knows the `make` subcommand.
+    let cargo = dir.path().join("cargo");
+    std::fs::write(&cargo, "#!/bin/sh\n[ \"$1\" = make ]\n").unwrap();
+    std::fs::set_permissions(&cargo, std::fs::Permissions::from_mode(0o755)).unwrap();
+    assert!(has_cargo_make(dir.path().as_os_str()).await);
+}
+
+#[test]
+fn test_parse_env_file() {
+    let vars = parse_env_file(
+        "# The cache for this machine\n\
+        \n\
+        BUILDSYS_LOOKASIDE_CACHE=https://cache.example.com\n\
+        export PUBLISH_REGIONS = \"us-west-2,us-east-1\"\n\
+        BUILDSYS_NAME='my os'\n\
+        BUILDSYS_EMPTY=\n",
+    )
+    .unwrap();
+    assert_eq!(
+        vars,
+        [
+            ("BUILDSYS_LOOKASIDE_CACHE", "https://cache.example.com"),
+            ("PUBLISH_REGIONS", "us-west-2,us-east-1"),
+            ("BUILDSYS_NAME", "my os"),
+            ("BUILDSYS_EMPTY", ""),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+    );
+    let err = parse_env_file("BUILDSYS_ARCH=x86_64\nnot a variable\n").unwrap_err();
+    assert!(err.to_string().contains("Line 2"), "{}", err);
+    assert!(parse_env_file("MY VAR=1").is_err());
+
+    let mut env = BuildEnv::default();
+    env.pass_through([("BUILDSYS_NAME".to_string(), "shell".to_string())])
+        .unwrap();
+    env.env_file([
+        ("BUILDSYS_NAME".to_string(), "file".to_string()),
+        ("HOME".to_string(), "/root".to_string()),
+    ])
+    .unwrap();
+    env.core("BUILDSYS_ARCH", "x86_64");
+    let resolved = env.resolve();
+    assert_eq!(resolved["BUILDSYS_NAME"], "file");
+    assert!(!resolved.contains_key("HOME"));
+}
+
+#[test]
+fn test_path_var() {
+    use std::os::unix::ffi::OsStrExt;
+
+    assert_eq!(
+        path_var("/home/me/My Projects/prøject").unwrap(),
+        "/home/me/My Projects/prøject"
+    );
+    let err = path_var(Path::new(OsStr::from_bytes(b"/home/me/caf\xe9")))
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("not valid UTF-8"), "{}", err);
+    let err = path_var("/mnt/c:/project").unwrap_err().to_string();
+    assert!(err.contains("contains a ':'"), "{}", err);
+
+    let err = CargoMake::default()
+        .project_dir("/mnt/c:/project")
+        .explain("build")
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("/mnt/c:/project"), "{}", err);
+}
@@ -0,0 +1,277 @@
+/*!
+
+Checksums for build artifacts. After a build, a `SHA256SUMS` file in the format written by coreutils'
+`sha256sum` is placed in the output directory, along with `SHA256SUMS.json` which also records the
+size and content type of each artifact. Downstream mirrors can use either to verify what they
+fetched.
+
+!*/
+
+use crate::common::fs;
+use crate::kit::INPUTS_SHA256;
+use crate::provenance::{PROVENANCE, PROVENANCE_SIG};
+use crate::sbom::{SBOM_CYCLONEDX, SBOM_SPDX};
+use anyhow::{Context, Result};
+use async_walkdir::WalkDir;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use tokio::task::spawn_blocking;
+
+pub(crate) const SHA256SUMS: &str = "SHA256SUMS";
+pub(crate) const SHA256SUMS_JSON: &str = "SHA256SUMS.json";
+
+/// The maximum number of files that are hashed at the same time.
+const HASH_CONCURRENCY: usize = 8;
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Checksums {
+    pub(crate) artifacts: Vec<Artifact>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Artifact {
+    /// The path of the artifact relative to the directory holding `SHA256SUMS`.
+    pub(crate) path: String,
+    pub(crate) size: u64,
+    pub(crate) sha256: String,
+    pub(crate) content_type: String,
+}
+
+/// A problem found when verifying a directory against its `SHA256SUMS`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Mismatch {
+    Missing { path: String },
+    Changed { path: String, expected: String },
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mismatch::Missing { path } => write!(f, "{}: missing", path),
+            Mismatch::Changed { path, expected } => {
+                write!(
+                    f,
+                    "{}: checksum does not match, expected {}",
+                    path, expected
+                )
+            }
+        }
+    }
+}
+
+/// Hashes every file below `dir` and writes `SHA256SUMS` and `SHA256SUMS.json` into `dir`,
+/// replacing any that were there before.
+pub(crate) async fn write_checksums(dir: impl AsRef<Path>) -> Result<Checksums> {
+    let dir = dir.as_ref();
+    let checksums = compute(dir).await?;
+    let mut sums = String::new();
+    for artifact in &checksums.artifacts {
+        sums.push_str(&format!("{}  {}\n", artifact.sha256, artifact.path));
+    }
+    fs::write(dir.join(SHA256SUMS), sums).await?;
+    let json = serde_json::to_string_pretty(&checksums).context("Unable to serialize checksums")?;
+    fs::write(dir.join(SHA256SUMS_JSON), json).await?;
+    Ok(checksums)
+}
+
+/// Checks the files in `dir` against the `SHA256SUMS` file found there and returns any that are
+/// missing or have changed.
+pub(crate) async fn verify_checksums(dir: impl AsRef<Path>) -> Result<Vec<Mismatch>> {
+    let dir = dir.as_ref();
+    let sums = fs::read_to_string(dir.join(SHA256SUMS)).await?;
+    let expected = parse_sums(&sums).context(format!(
+        "Unable to parse '{}'",
+        dir.join(SHA256SUMS).display()
+    ))?;
+    stream::iter(expected)
+        .map(|(expected, path)| async move {
+            let file = dir.join(&path);
+            if !file.is_file() {
+                return Ok(Some(Mismatch::Missing { path }));
+            }
+            let (actual, _) = hash_file(file).await?;
+            Ok(if actual == expected {
+                None
+            } else {
+                Some(Mismatch::Changed { path, expected })
+            })
+        })
+        .buffered(HASH_CONCURRENCY)
+        .try_filter_map(|mismatch| async move { Ok(mismatch) })
+        .try_collect()
+        .await
+}
+
+/// Hashes every file below `dir` except for previously written checksum and provenance files and
+/// the record of a kit's inputs. Artifacts are sorted by path.
+pub(crate) async fn compute(dir: &Path) -> Result<Checksums> {
+    let mut paths = Vec::new();
+    let mut entries = WalkDir::new(dir);
+    while let Some(entry) = entries.next().await {
+        let entry = entry.context(format!("Unable to list the files in '{}'", dir.display()))?;
+        let file_type = entry.file_type().await.context(format!(
+            "Unable to read the file type of '{}'",
+            entry.path().display()
+        ))?;
+        if !file_type.is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let relative = path
+            .strip_prefix(dir)
+            .context(format!(
+                "Expected '{}' to be inside '{}'",
+                path.display(),
+                dir.display()
+            ))?
+            .to_string_lossy()
+            .to_string();
+        if ![
+            SHA256SUMS,
+            SHA256SUMS_JSON,
+            INPUTS_SHA256,
+            PROVENANCE,
+            PROVENANCE_SIG,
+            SBOM_SPDX,
+            SBOM_CYCLONEDX,
+        ]
+        .contains(&relative.as_str())
+        {
+            paths.push(relative);
+        }
+    }
+    paths.sort();
+
+    let artifacts = stream::iter(paths)
+        .map(|path| async move {
+            let (sha256, size) = hash_file(dir.join(&path)).await?;
+            let content_type = content_type(&path).to_string();
+            Ok::<_, anyhow::Error>(Artifact {
+                path,
+                size,
+                sha256,
+                content_type,
+            })
+        })
+        .buffered(HASH_CONCURRENCY)
+        .try_collect()
+        .await?;
+    Ok(Checksums { artifacts })
+}
+
+/// Returns the hex-encoded sha256 of the file at `path` and its size in bytes.
+pub(crate) async fn hash_file(path: PathBuf) -> Result<(String, u64)> {
+    spawn_blocking(move || {
+        let mut file =
+            std::fs::File::open(&path).context(format!("Unable to open '{}'", path.display()))?;
+        let mut hasher = Sha256::new();
+        let size = std::io::copy(&mut file, &mut hasher)
+            .context(format!("Unable to read '{}'", path.display()))?;
+        Ok((hex::encode(hasher.finalize()), size))
+    })
+    .await
+    .context("Unable to join a hashing task")?
+}
+
+/// Parses lines in the format written by `sha256sum`, returning `(checksum, path)` pairs.
+fn parse_sums(sums: &str) -> Result<Vec<(String, String)>> {
+    sums.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (checksum, path) = line
+                .split_once(' ')
+                .context(format!("Malformed checksum line '{}'", line))?;
+            // The second separator character is ' ' for text mode and '*' for binary mode.
+            let path = path
+                .strip_prefix(' ')
+                .or_else(|| path.strip_prefix('*'))
+                .context(format!("Malformed checksum line '{}'", line))?;
+            Ok((checksum.to_string(), path.to_string()))
+        })
+        .collect()
+}
+
+fn content_type(path: &str) -> &'static str {
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("rpm") => "application/x-rpm",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("gz") => "application/gzip",
+        Some("lz4") => "application/x-lz4",
+        Some("tar") => "application/x-tar",
+        Some("qcow2") => "application/x-qemu-disk",
+        Some("vmdk") => "application/x-vmdk",
+        _ => "application/octet-stream",
+    }
+}
+
+#[tokio::test]
+async fn test_write_and_verify_checksums() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::create_dir_all(dir.join("x86_64")).await.unwrap();
+    fs::write(dir.join("x86_64/pkg-a.rpm"), "a").await.unwrap();
+    fs::write(dir.join("x86_64/pkg-b.rpm"), "b").await.unwrap();
+    fs::write(dir.join("image.img.lz4"), "").await.unwrap();
+
+    let checksums = write_checksums(dir).await.unwrap();
+    let paths: Vec<_> = checksums
+        .artifacts
+        .iter()
+        .map(|a| a.path.as_str())
+        .collect();
+    assert_eq!(
+        paths,
+        ["image.img.lz4", "x86_64/pkg-a.rpm", "x86_64/pkg-b.rpm"]
+    );
+    assert_eq!(checksums.artifacts[1].content_type, "application/x-rpm");
+    assert_eq!(checksums.artifacts[1].size, 1);
+
+    let sums = fs::read_to_string(dir.join(SHA256SUMS)).await.unwrap();
+    assert_eq!(
+        sums.lines().next().unwrap(),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  image.img.lz4"
+    );
+    let json = fs::read_to_string(dir.join(SHA256SUMS_JSON)).await.unwrap();
+    assert_eq!(serde_json::from_str::<Checksums>(&json).unwrap(), checksums);
+    assert!(verify_checksums(dir).await.unwrap().is_empty());
+
+    // Writing again does not include the checksum files themselves.
+    assert_eq!(write_checksums(dir).await.unwrap(), checksums);
+
+    fs::write(dir.join("x86_64/pkg-a.rpm"), "changed")
+        .await
+        .unwrap();
+    fs::remove_file(dir.join("x86_64/pkg-b.rpm")).await.unwrap();
+    let mismatches = verify_checksums(dir).await.unwrap();
+    assert_eq!(
+        mismatches,
+        [
+            Mismatch::Changed {
+                path: "x86_64/pkg-a.rpm".to_string(),
+                expected: checksums.artifacts[1].sha256.clone(),
+            },
+            Mismatch::Missing {
+                path: "x86_64/pkg-b.rpm".to_string()
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_parse_sums() {
+    let sums = "abc  a.rpm\ndef *dir/b file.img\n\n";
+    assert_eq!(
+        parse_sums(sums).unwrap(),
+        [
+            ("abc".to_string(), "a.rpm".to_string()),
+            ("def".to_string(), "dir/b file.img".to_string()),
+        ]
+    );
+    assert!(parse_sums("abc").is_err());
+}
@@ -1,155 +1,2036 @@
 use super::build_clean::BuildClean;
-use crate::cargo_make::CargoMake;
-use crate::common::fs;
-use crate::lock::Lock;
-use crate::project;
+use super::build_summary::{BuildSummary, KitsSummary};
+use super::kit_schedule::{KitSchedule, KitStatus};
+use super::output::OutputFormat;
+use crate::binfmt;
+use crate::cargo_make::{path_var, read_env_file, CargoMake};
+use crate::checksums::{self, write_checksums};
+use crate::cmd::{ArchArg, DeprecatedProjectPath, GlobalArgs};
+use crate::common::{exec, fs};
+use crate::docker::{DockerNetwork, ImageInspector, PullPolicy};
+use crate::filesystem;
+use crate::graph::DependencyGraph;
+use crate::infra;
+use crate::kit::{self, KitManifest, INPUTS_SHA256};
+use crate::lock::{local_digest, pull_for_arch, Lock, TWOLITER_LOCK};
+use crate::ownership::fix_ownership;
+use crate::project::{Project, VariantConfig, TWOLITER_TOML};
+use crate::provenance::{self, write_provenance, BuildMetadata};
+use crate::sbom::{KitSbom, SbomFormat};
+use crate::style::{self, HEADING};
 use crate::tools::install_tools;
-use anyhow::{Context, Result};
+use crate::variant::{
+    ImageFeatureOverride, ImageFeatures, ImageLayout, VariantManifest, VariantParts,
+};
+use anyhow::{bail, ensure, Context, Result};
+use buildsys_config::{FetchedSource, FETCHED_SOURCES_DIRECTORY, UPSTREAM_SOURCES_DIRECTORY};
 use clap::Parser;
-use std::path::PathBuf;
-use tempfile::TempDir;
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::process::Command;
+use tokio::time::sleep;
+use url::Url;
+
+/// Where the sources used by the packages in the build directory are listed after each build.
+const SOURCES_MANIFEST: &str = "build/sources-manifest.json";
+
+/// How often `build kit --watch` looks for changes to the kit's inputs.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the inputs must stay unchanged before `build kit --watch` builds again.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The fingerprint of the inputs of the kit at `manifest_path`, for `build kit --watch`. A file can
+/// disappear while the inputs are read, e.g. when an editor replaces it, so the error is logged and
+/// the inputs are read again after [`WATCH_INTERVAL`] rather than ending the watch.
+async fn watched_fingerprint(project: &Project, manifest_path: &Path) -> String {
+    loop {
+        match kit::inputs_fingerprint(project, manifest_path).await {
+            Ok(fingerprint) => return fingerprint,
+            Err(e) => warn!("Unable to check the inputs of the kit for changes: {:#}", e),
+        }
+        sleep(WATCH_INTERVAL).await;
+    }
+}
+
+/// Copies cargo's files from the target directory `from` to `to` wherever `to` has no copy or an
+/// older one. The `kits` directory, which holds the target directories of `build kits`, and cargo's
+/// lock files are left out.
+async fn update_target_dir(from: &Path, to: &Path) -> Result<()> {
+    let kits = from.join("kits");
+    fs::update_dir_filtered(from, to, move |path| {
+        path != kits && path.file_name().is_some_and(|name| name != ".cargo-lock")
+    })
+    .await
+}
 
 #[derive(Debug, Parser)]
 pub(crate) enum BuildCommand {
     Clean(BuildClean),
     Kit(BuildKit),
-    Variant(BuildVariant),
+    Kits(BuildKits),
+    Variant(Box<BuildVariant>),
 }
 
 impl BuildCommand {
-    pub(crate) async fn run(self) -> Result<()> {
+    pub(crate) async fn run(self, global: &GlobalArgs) -> Result<()> {
         match self {
-            BuildCommand::Clean(command) => command.run().await,
-            BuildCommand::Kit(command) => command.run().await,
-            BuildCommand::Variant(command) => command.run().await,
+            BuildCommand::Clean(command) => command.run(global).await,
+            BuildCommand::Kit(mut command) => {
+                apply_deprecated_lookaside_cache(
+                    command.deprecated_lookaside_cache.take(),
+                    &mut command.lookaside_cache,
+                );
+                command.run(global).await
+            }
+            BuildCommand::Kits(command) => command.run(global).await,
+            BuildCommand::Variant(mut command) => {
+                apply_deprecated_lookaside_cache(
+                    command.deprecated_lookaside_cache.take(),
+                    &mut command.lookaside_cache,
+                );
+                command.run(global).await
+            }
         }
     }
 }
 
+/// Puts a lookaside cache that was given the deprecated way, as a positional argument after the kit
+/// or variant, in front of those given with `--lookaside-cache`.
+fn apply_deprecated_lookaside_cache(deprecated: Option<String>, lookaside_cache: &mut Vec<String>) {
+    if let Some(cache) = deprecated {
+        warn!(
+            "Giving the lookaside cache as a positional argument is deprecated and will be removed \
+            in a future release, use '--lookaside-cache {}' instead",
+            cache
+        );
+        lookaside_cache.insert(0, cache);
+    }
+}
+
+const BUILD_KIT_EXAMPLES: &str = "\
+Examples:
+  # Build a kit for aarch64, trying a local copy of the lookaside cache before the public one
+  twoliter build kit core-kit --arch aarch64 --lookaside-cache /srv/lookaside --lookaside-cache https://cache.bottlerocket.aws
+
+  # Build without network access, from the sources in a local lookaside cache
+  twoliter build kit core-kit --arch x86_64 --offline --lookaside-cache /srv/lookaside
+
+  # Build again whenever the kit's packages change
+  twoliter build kit core-kit --arch x86_64 --watch";
+
 /// Build a Bottlerocket variant image.
 #[derive(Debug, Parser)]
+#[clap(after_help = BUILD_KIT_EXAMPLES)]
 pub(crate) struct BuildKit {
-    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
-    #[clap(long = "project-path")]
-    pub(crate) project_path: Option<PathBuf>,
+    #[clap(flatten)]
+    pub(crate) project_path: DeprecatedProjectPath,
 
-    /// The architecture to build for.
-    #[clap(long = "arch", default_value = "x86_64")]
-    pub(crate) arch: String,
+    #[clap(flatten)]
+    pub(crate) arch: ArchArg,
 
     /// The name of the kit to build.
     pub(crate) kit: String,
 
-    /// The URL to the lookaside cache where sources are stored to avoid pulling them from upstream.
-    /// Defaults to https://cache.bottlerocket.aws
-    pub(crate) lookaside_cache: Option<String>,
+    /// The URL to a lookaside cache where sources are stored to avoid pulling them from upstream.
+    /// A local directory may be given as a path instead. May be repeated, e.g. a local mirror and
+    /// then the public cache, and the caches are tried in that order before any upstream fallback.
+    /// Overrides `lookaside-caches` in the `[build]` section of Twoliter.toml. Defaults to
+    /// https://cache.bottlerocket.aws
+    #[clap(long = "lookaside-cache")]
+    pub(crate) lookaside_cache: Vec<String>,
+
+    /// Deprecated, give `--lookaside-cache` instead.
+    #[clap(value_name = "LOOKASIDE_CACHE", hide = true)]
+    pub(crate) deprecated_lookaside_cache: Option<String>,
 
     /// If sources are not found in the lookaside cache, this flag will cause buildsys to pull them
     /// from the upstream URL found in a package's `Cargo.toml`.
     #[clap(long = "upstream-source-fallback")]
     pub(crate) upstream_source_fallback: bool,
+
+    /// Skip writing SHA256SUMS for the build outputs.
+    #[clap(long = "no-checksums")]
+    pub(crate) no_checksums: bool,
+
+    /// Forbid network access. Requires a local lookaside cache, disables upstream source fallback,
+    /// and only uses the SDK and kit images that are already present locally.
+    #[clap(long = "offline", conflicts_with = "upstream_source_fallback")]
+    pub(crate) offline: bool,
+
+    /// The docker network for build steps, e.g. `none`, `host`, `default` or the name of a docker
+    /// network. Overrides `network` in the `[build]` section of Twoliter.toml. A network of `none`
+    /// implies `--offline`.
+    #[clap(long = "network")]
+    pub(crate) network: Option<DockerNetwork>,
+
+    /// Build the kit even if its inputs have not changed since it was last built.
+    #[clap(long = "force")]
+    pub(crate) force: bool,
+
+    /// After building, keep watching the kit's inputs and build it again when they change. Stop
+    /// with Ctrl-C.
+    #[clap(long = "watch")]
+    pub(crate) watch: bool,
+
+    /// Path to the kit's Cargo.toml, for kits that are not in `kits/<KIT>` of the project.
+    #[clap(long = "manifest-path")]
+    pub(crate) manifest_path: Option<PathBuf>,
+
+    /// Search the `sources` directory for go modules even if the list in Twoliter.lock looks up
+    /// to date.
+    #[clap(long = "refresh-go-modules")]
+    pub(crate) refresh_go_modules: bool,
+
+    /// Read build system variables, such as `BUILDSYS_*` and `PUBLISH_*`, from `KEY=VALUE` lines in
+    /// this file. Blank lines and lines starting with `#` are skipped.
+    #[clap(long = "env-file")]
+    pub(crate) env_file: Option<PathBuf>,
+
+    /// Appended to the release version of what is built, e.g. `pr-123`, so that it can be told
+    /// apart from a mainline build. Overrides `tag-suffix` in the `[build]` section of Twoliter.toml.
+    #[clap(long = "tag-suffix")]
+    pub(crate) tag_suffix: Option<String>,
+
+    /// A `KEY=VALUE` build arg to pass to the `docker build` commands that buildsys runs, in
+    /// addition to its own, which it cannot replace. Can be given more than once.
+    #[clap(long = "build-arg", value_parser = parse_build_arg)]
+    pub(crate) build_args: Vec<String>,
+
+    /// Take the SDK from this registry instead of the vendor's, e.g. to try a staged or mirrored
+    /// SDK. Neither Twoliter.toml nor Twoliter.lock is changed.
+    #[clap(long = "registry")]
+    pub(crate) registry: Option<String>,
+
+    /// When to pull the SDK image: `always` to pick up a tag that moved upstream, `missing`, or
+    /// `never`, which also requires the kits in Twoliter.lock to be present locally. Overrides
+    /// `pull` in the `[build]` section of Twoliter.toml. Offline builds never pull.
+    #[clap(long = "pull", value_enum)]
+    pub(crate) pull: Option<PullPolicy>,
+
+    /// Build with this SDK image instead of the one in Twoliter.lock, e.g. `bottlerocket-sdk:dev`
+    /// for an SDK that was built locally. It is used as it is when present locally, and is only
+    /// pulled when it is not.
+    #[clap(long = "sdk", conflicts_with = "registry")]
+    pub(crate) sdk: Option<String>,
+
+    /// Print the summary of the build as `text`, `json` or `toml`. Overrides the global
+    /// `--format`.
+    #[clap(long = "format", value_enum)]
+    pub(crate) format: Option<OutputFormat>,
+
+    /// Write a software bill of materials listing the kit's packages and their versions, as
+    /// `sbom.spdx.json` or `sbom.cdx.json` next to the kit's RPM repository.
+    #[clap(long = "sbom", value_enum)]
+    pub(crate) sbom: Option<SbomFormat>,
 }
 
 impl BuildKit {
-    pub(super) async fn run(&self) -> Result<()> {
-        let project = project::load_or_find_project(self.project_path.clone()).await?;
-        let lock = Lock::load(&project).await?;
+    /// The options for building `kit` for `arch` as if nothing else had been given on the command
+    /// line, for commands that need the same environment as a build.
+    pub(crate) fn with_defaults(arch: &str, kit: &str) -> Self {
+        Self {
+            project_path: DeprecatedProjectPath::default(),
+            arch: ArchArg::new(arch),
+            kit: kit.to_string(),
+            lookaside_cache: Vec::new(),
+            deprecated_lookaside_cache: None,
+            upstream_source_fallback: false,
+            no_checksums: false,
+            offline: false,
+            network: None,
+            force: false,
+            watch: false,
+            manifest_path: None,
+            refresh_go_modules: false,
+            env_file: None,
+            tag_suffix: None,
+            build_args: Vec::new(),
+            registry: None,
+            pull: None,
+            sdk: None,
+            format: None,
+            sbom: None,
+        }
+    }
+
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let project = self.project_path.apply(global).load_project().await?;
+        preflight(&project, self.arch.get(), global).await?;
+        if self.watch {
+            return self.watch(global).await;
+        }
+        self.build(global, self.force).await
+    }
+
+    /// Builds the kit, then builds it again each time its inputs settle after a change. A failed
+    /// build is reported and the watch goes on, so that the next change can fix it.
+    async fn watch(&self, global: &GlobalArgs) -> Result<()> {
+        let project = self.project_path.apply(global).load_project().await?;
+        let manifest_path = self.manifest_path(&project).await?;
+        let mut force = self.force;
+        loop {
+            if let Err(e) = self.build(global, force).await {
+                error!("{:?}", e);
+            }
+            force = false;
+            info!(
+                "Watching kit '{}' for changes, press Ctrl-C to stop",
+                self.kit
+            );
+            let mut fingerprint = watched_fingerprint(&project, &manifest_path).await;
+            loop {
+                sleep(WATCH_INTERVAL).await;
+                let current = watched_fingerprint(&project, &manifest_path).await;
+                if current != fingerprint {
+                    fingerprint = current;
+                    break;
+                }
+            }
+            // Editors and version control often write several files in a row, so wait for the
+            // changes to stop before building.
+            loop {
+                sleep(WATCH_DEBOUNCE).await;
+                let current = watched_fingerprint(&project, &manifest_path).await;
+                if current == fingerprint {
+                    break;
+                }
+                fingerprint = current;
+            }
+            info!("The inputs of kit '{}' changed", self.kit);
+        }
+    }
+
+    async fn build(&self, global: &GlobalArgs, force: bool) -> Result<()> {
+        let start = Instant::now();
+        let started = SystemTime::now();
+        let project = self.project_path.apply(global).load_project().await?;
+        // Catch a broken kit manifest before cargo reports it as a generic build failure.
+        KitManifest::load_path(self.manifest_path(&project).await?).await?;
+        let offline = is_offline(
+            self.offline,
+            self.upstream_source_fallback,
+            docker_network(&self.network, &project),
+        )?;
+        let lock = load_lock(
+            &project,
+            self.arch.get(),
+            offline,
+            global.frozen(),
+            &lookaside_caches(&self.lookaside_cache, &project),
+            self.registry.as_deref(),
+            self.sdk.as_deref(),
+            pull_policy(self.pull, project.pull_policy(), offline, global.frozen())?,
+            global.images(),
+        )
+        .await?;
+        let kit_dir = self.kit_dir(&project);
+        let inputs_hash = self.inputs_hash(&project, &lock, global.frozen()).await?;
+        if !force && is_up_to_date(&kit_dir, &inputs_hash).await {
+            info!(
+                "Kit '{}' for {} is up to date, use --force to build it anyway",
+                self.kit,
+                self.arch.get()
+            );
+            self.write_sbom(&project, &kit_dir).await?;
+            let sdk_digest = sdk_digest(&lock, global.images()).await;
+            BuildSummary::kit(&self.kit, self.arch.get(), &kit_dir, start.elapsed())
+                .sdk(&lock.sdk.source, sdk_digest.as_deref())
+                .print(self.format.unwrap_or(global.format()))?;
+            return Ok(());
+        }
+        // A failed build can leave old and new artifacts mixed together.
+        let inputs_file = kit_dir.join(INPUTS_SHA256);
+        if inputs_file.exists() {
+            fs::remove_file(&inputs_file).await?;
+        }
+
+        // Keeps `build clean` out until the build is done.
+        let _build_lock = global.lock_build(&project).await?;
+        let project_lock = global.lock_project(&project).await?;
         let toolsdir = project.project_dir().join("build/tools");
         install_tools(&toolsdir).await?;
-        let makefile_path = toolsdir.join("Makefile.toml");
+        clear_upstream_fetches(&project, self.upstream_source_fallback).await?;
+        let mut result = self
+            .cargo_make(&project, &lock, global.frozen())
+            .await?
+            .bootstrap_tools(global.bootstrap_tools())
+            .exec("build-kit-setup")
+            .await;
+        // cargo keeps builds that share a target directory from running at the same time, and the
+        // build lock keeps `build clean` waiting, so other commands may go on with their own steps
+        // while the kit builds.
+        drop(project_lock);
+        if result.is_ok() {
+            result = self
+                .cargo_make(&project, &lock, global.frozen())
+                .await?
+                .bootstrap_tools(global.bootstrap_tools())
+                .exec("build-kit-only")
+                .await;
+        }
+        let _project_lock = global.lock_project(&project).await?;
+        report_upstream_fetches(&project).await?;
+        write_sources_manifest(&project).await?;
+        if let Err(e) = fix_ownership(&project, &lock.sdk.source).await {
+            warn!("{:#}", e);
+        }
+        result?;
+        self.finish(&kit_dir, &inputs_hash).await?;
+        self.write_sbom(&project, &kit_dir).await?;
+        let sdk_digest = sdk_digest(&lock, global.images()).await;
+        BuildSummary::kit(&self.kit, self.arch.get(), &kit_dir, start.elapsed())
+            .sdk(&lock.sdk.source, sdk_digest.as_deref())
+            .packages_built(&project.project_dir().join("build/rpms"), started)
+            .await?
+            .print(self.format.unwrap_or(global.format()))
+    }
+
+    /// Builds the kit while `build kits` builds others, after the lock has been loaded, the tools
+    /// installed and the `build-kit-setup` task run once for all of them. The kit has its own cargo
+    /// target directory so that it does not wait on the others' cargo lock, and its output is
+    /// prefixed with its name. Returns whether the kit was built rather than up to date.
+    async fn build_alongside(
+        &self,
+        global: &GlobalArgs,
+        project: &Project,
+        lock: &Lock,
+        force: bool,
+    ) -> Result<bool> {
+        let kit_dir = self.kit_dir(project);
+        let inputs_hash = self.inputs_hash(project, lock, global.frozen()).await?;
+        if !force && is_up_to_date(&kit_dir, &inputs_hash).await {
+            return Ok(false);
+        }
+        let inputs_file = kit_dir.join(INPUTS_SHA256);
+        if inputs_file.exists() {
+            fs::remove_file(&inputs_file).await?;
+        }
+        let shared_dir = project.project_dir().join("target").join(self.arch.get());
+        let target_dir = shared_dir.join("kits").join(&self.kit);
+        // Start from what earlier builds left in the shared target directory so that cargo only
+        // rebuilds what changed, and hand the results back to the builds that use it.
+        if shared_dir.is_dir() {
+            update_target_dir(&shared_dir, &target_dir).await?;
+        }
+        let result = self
+            .cargo_make(project, lock, global.frozen())
+            .await?
+            .env("BUILDSYS_KIT_TARGET_DIR", path_var(&target_dir)?)
+            .output_prefix(Some(&self.kit))
+            .bootstrap_tools(global.bootstrap_tools())
+            .exec("build-kit-only")
+            .await;
+        if let Err(e) = fix_ownership(project, &lock.sdk.source).await {
+            warn!("{:#}", e);
+        }
+        result?;
+        update_target_dir(&target_dir, &shared_dir).await?;
+        self.finish(&kit_dir, &inputs_hash).await?;
+        Ok(true)
+    }
+
+    /// Where the kit's RPM repository is written, `build/kits/<KIT>/<ARCH>`.
+    fn kit_dir(&self, project: &Project) -> PathBuf {
+        project
+            .project_dir()
+            .join("build/kits")
+            .join(&self.kit)
+            .join(self.arch.get())
+    }
+
+    /// Records a successful build of the kit in `kit_dir` from inputs with the hash `inputs_hash`.
+    async fn finish(&self, kit_dir: &Path, inputs_hash: &str) -> Result<()> {
+        if !self.no_checksums {
+            write_checksums(kit_dir).await?;
+        }
+        fs::write(kit_dir.join(INPUTS_SHA256), inputs_hash).await?;
+        Ok(())
+    }
+
+    /// Writes the SBOM of the kit in `kit_dir` if `--sbom` was given.
+    async fn write_sbom(&self, project: &Project, kit_dir: &Path) -> Result<()> {
+        let Some(format) = self.sbom else {
+            return Ok(());
+        };
+        let manifest = KitManifest::load_path(self.manifest_path(project).await?).await?;
+        let vendor = manifest.vendor().context(format!(
+            "The kit '{}' has no vendor to name as the supplier in its SBOM",
+            self.kit
+        ))?;
+        let version = project.image_version(self.tag_suffix.as_deref())?;
+        let path = KitSbom::load(kit_dir, &self.kit, &version, vendor, self.arch.get())
+            .await?
+            .write(kit_dir, format)
+            .await?;
+        info!(
+            "Wrote the SBOM of kit '{}' to '{}'",
+            self.kit,
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// The hash of everything that goes into the kit, see [`kit::inputs_hash`]. Along with the
+    /// files, this is the environment that cargo make is given, wherever each variable came from,
+    /// apart from the ones that may change on every run without changing the build.
+    async fn inputs_hash(&self, project: &Project, lock: &Lock, frozen: bool) -> Result<String> {
+        let mut context = vec![
+            format!("twoliter {}", env!("CARGO_PKG_VERSION")),
+            format!("release-version {}", project.release_version()),
+            format!("sdk {}@{}", lock.sdk.source, lock.sdk.digest),
+        ];
+        for kit in &lock.kit {
+            context.push(format!("kit {}@{}", kit.source, kit.digest));
+        }
+        let env = self
+            .cargo_make(project, lock, frozen)
+            .await?
+            .resolved_env()?;
+        for (key, value) in env {
+            if !UNHASHED_ENV.contains(&key.as_str()) {
+                context.push(format!("env {}={}", key, value));
+            }
+        }
+        let context: Vec<_> = context.iter().map(String::as_str).collect();
+        kit::inputs_hash(project, &self.manifest_path(project).await?, &context).await
+    }
+
+    /// The kit's Cargo.toml, from `--manifest-path` or else `kits/<KIT>/Cargo.toml`.
+    async fn manifest_path(&self, project: &Project) -> Result<PathBuf> {
+        match &self.manifest_path {
+            // The path is relative to where Twoliter runs, but cargo make runs in the project.
+            Some(path) => fs::canonicalize(path).await.context(format!(
+                "Unable to find the kit manifest '{}' given with --manifest-path",
+                path.display()
+            )),
+            None => Ok(KitManifest::path_for(project, &self.kit)),
+        }
+    }
 
+    /// Assemble the `cargo make` invocation for this build without running it.
+    pub(crate) async fn cargo_make(
+        &self,
+        project: &Project,
+        lock: &Lock,
+        frozen: bool,
+    ) -> Result<CargoMake> {
         let mut optional_envs = Vec::new();
 
-        if let Some(lookaside_cache) = &self.lookaside_cache {
-            optional_envs.push(("BUILDSYS_LOOKASIDE_CACHE", lookaside_cache))
+        if self.manifest_path.is_some() {
+            optional_envs.push((
+                "BUILDSYS_KIT_MANIFEST",
+                path_var(self.manifest_path(project).await?)?,
+            ))
         }
 
-        CargoMake::new(&lock.sdk.source)?
-            .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
-            .env("BUILDSYS_ARCH", &self.arch)
-            .env("BUILDSYS_KIT", &self.kit)
-            .env("BUILDSYS_VERSION_IMAGE", project.release_version())
-            .env("GO_MODULES", project.find_go_modules().await?.join(" "))
-            .env(
-                "BUILDSYS_UPSTREAM_SOURCE_FALLBACK",
-                self.upstream_source_fallback.to_string(),
+        self.shared_options()
+            .cargo_make(
+                project,
+                lock,
+                frozen,
+                ("BUILDSYS_KIT", &self.kit),
+                optional_envs,
             )
-            .envs(optional_envs.into_iter())
-            .makefile(makefile_path)
-            .project_dir(project.project_dir())
-            .exec("build-kit")
             .await
     }
+
+    /// The settings of this build that variant builds also pass to `cargo make`.
+    fn shared_options(&self) -> SharedBuildOptions<'_> {
+        SharedBuildOptions {
+            arch: self.arch.get(),
+            lookaside_cache: &self.lookaside_cache,
+            network: &self.network,
+            build_args: &self.build_args,
+            env_file: self.env_file.as_deref(),
+            tag_suffix: self.tag_suffix.as_deref(),
+            refresh_go_modules: self.refresh_go_modules,
+            upstream_source_fallback: self.upstream_source_fallback,
+            offline: self.offline,
+        }
+    }
+}
+
+const BUILD_KITS_EXAMPLES: &str = "\
+Examples:
+  # Build the kits that changed since the develop branch, four at a time
+  twoliter build kits --arch aarch64 --changed-since origin/develop --jobs-kits 4 --lookaside-cache https://cache.bottlerocket.aws";
+
+/// Build the kits in this project. Kits that do not depend on each other, and have no packages in
+/// common, are built at the same time.
+#[derive(Debug, Parser)]
+#[clap(after_help = BUILD_KITS_EXAMPLES)]
+pub(crate) struct BuildKits {
+    #[clap(flatten)]
+    pub(crate) project_path: DeprecatedProjectPath,
+
+    #[clap(flatten)]
+    pub(crate) arch: ArchArg,
+
+    /// Only build the kits with a package, kit or source that changed since this git ref, e.g.
+    /// `origin/develop`. Kits that depend on a changed kit are built too. Changes to Twoliter.toml
+    /// or Twoliter.lock cause every kit to be built.
+    #[clap(long = "changed-since")]
+    pub(crate) changed_since: Option<String>,
+
+    /// The URL to a lookaside cache where sources are stored to avoid pulling them from upstream.
+    /// A local directory may be given as a path instead. May be repeated, e.g. a local mirror and
+    /// then the public cache, and the caches are tried in that order before any upstream fallback.
+    /// Overrides `lookaside-caches` in the `[build]` section of Twoliter.toml. Defaults to
+    /// https://cache.bottlerocket.aws
+    #[clap(long = "lookaside-cache")]
+    pub(crate) lookaside_cache: Vec<String>,
+
+    /// If sources are not found in the lookaside cache, this flag will cause buildsys to pull them
+    /// from the upstream URL found in a package's `Cargo.toml`.
+    #[clap(long = "upstream-source-fallback")]
+    pub(crate) upstream_source_fallback: bool,
+
+    /// Skip writing SHA256SUMS for the build outputs.
+    #[clap(long = "no-checksums")]
+    pub(crate) no_checksums: bool,
+
+    /// Forbid network access. Requires a local lookaside cache, disables upstream source fallback,
+    /// and only uses the SDK and kit images that are already present locally.
+    #[clap(long = "offline", conflicts_with = "upstream_source_fallback")]
+    pub(crate) offline: bool,
+
+    /// The docker network for build steps, e.g. `none`, `host`, `default` or the name of a docker
+    /// network. Overrides `network` in the `[build]` section of Twoliter.toml. A network of `none`
+    /// implies `--offline`.
+    #[clap(long = "network")]
+    pub(crate) network: Option<DockerNetwork>,
+
+    /// Build each kit even if its inputs have not changed since it was last built.
+    #[clap(long = "force")]
+    pub(crate) force: bool,
+
+    /// The most kits to build at the same time. Each kit that is built alongside others has its own
+    /// cargo target directory, under `target/<ARCH>/kits`, and its output lines start with its name.
+    /// With 1, kits are built one after another, sharing the usual target directory.
+    #[clap(long = "jobs-kits", default_value_t = 2, value_parser = clap::value_parser!(u16).range(1..))]
+    pub(crate) jobs_kits: u16,
+
+    /// Search the `sources` directory for go modules even if the list in Twoliter.lock looks up
+    /// to date.
+    #[clap(long = "refresh-go-modules")]
+    pub(crate) refresh_go_modules: bool,
+
+    /// Read build system variables, such as `BUILDSYS_*` and `PUBLISH_*`, from `KEY=VALUE` lines in
+    /// this file. Blank lines and lines starting with `#` are skipped.
+    #[clap(long = "env-file")]
+    pub(crate) env_file: Option<PathBuf>,
+
+    /// Appended to the release version of what is built, e.g. `pr-123`, so that it can be told
+    /// apart from a mainline build. Overrides `tag-suffix` in the `[build]` section of Twoliter.toml.
+    #[clap(long = "tag-suffix")]
+    pub(crate) tag_suffix: Option<String>,
+
+    /// A `KEY=VALUE` build arg to pass to the `docker build` commands that buildsys runs, in
+    /// addition to its own, which it cannot replace. Can be given more than once.
+    #[clap(long = "build-arg", value_parser = parse_build_arg)]
+    pub(crate) build_args: Vec<String>,
+
+    /// Take the SDK from this registry instead of the vendor's, e.g. to try a staged or mirrored
+    /// SDK. Neither Twoliter.toml nor Twoliter.lock is changed.
+    #[clap(long = "registry")]
+    pub(crate) registry: Option<String>,
+
+    /// When to pull the SDK image: `always` to pick up a tag that moved upstream, `missing`, or
+    /// `never`, which also requires the kits in Twoliter.lock to be present locally. Overrides
+    /// `pull` in the `[build]` section of Twoliter.toml. Offline builds never pull.
+    #[clap(long = "pull", value_enum)]
+    pub(crate) pull: Option<PullPolicy>,
+
+    /// Build with this SDK image instead of the one in Twoliter.lock, e.g. `bottlerocket-sdk:dev`
+    /// for an SDK that was built locally. It is used as it is when present locally, and is only
+    /// pulled when it is not.
+    #[clap(long = "sdk", conflicts_with = "registry")]
+    pub(crate) sdk: Option<String>,
+
+    /// Print the summary of the build as `text`, `json` or `toml`. Overrides the global
+    /// `--format`.
+    #[clap(long = "format", value_enum)]
+    pub(crate) format: Option<OutputFormat>,
+}
+
+impl BuildKits {
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let project = self.project_path.apply(global).load_project().await?;
+        let mut kits = kit::local_kits(&project).await?;
+        if let Some(git_ref) = &self.changed_since {
+            kits = changed_kits(&project, kits, git_ref).await?;
+            if kits.is_empty() {
+                info!("No kits were affected by changes since '{}'", git_ref);
+                return Ok(());
+            }
+            info!(
+                "Building the kits affected by changes since '{}': {}",
+                git_ref,
+                kits.join(", ")
+            );
+        }
+        // Each kit is built from the project that was already found, wherever it came from.
+        let kit_global = global.with_project_path(project.filepath());
+        if self.jobs_kits == 1 || kits.len() == 1 {
+            for kit in kits {
+                self.build_kit(kit).run(&kit_global).await?;
+            }
+            return Ok(());
+        }
+        self.build_scheduled(&kit_global, &project, kits).await
+    }
+
+    /// Builds the `kits`, running up to `--jobs-kits` of them at once in the order that a
+    /// [`KitSchedule`] allows. A kit that fails stops the kits that depend on it from starting, but
+    /// the others carry on, and the status of each kit is summarized at the end.
+    async fn build_scheduled(
+        &self,
+        global: &GlobalArgs,
+        project: &Project,
+        kits: Vec<String>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let builds: BTreeMap<String, BuildKit> = kits
+            .iter()
+            .map(|kit| (kit.clone(), self.build_kit(kit.clone())))
+            .collect();
+        let mut crates = BTreeMap::new();
+        for kit in &kits {
+            crates.insert(kit.clone(), kit::crate_dirs(project, kit).await?);
+        }
+        let kits_dir = fs::canonicalize(project.project_dir().join(kit::KITS_DIRECTORY)).await?;
+        let mut schedule = KitSchedule::new(crates, &kits_dir);
+
+        // Everything that the kits share is prepared once, before any of them start.
+        let first = &builds[&kits[0]];
+        for build in builds.values() {
+            KitManifest::load_path(build.manifest_path(project).await?).await?;
+        }
+        let offline = is_offline(
+            self.offline,
+            self.upstream_source_fallback,
+            docker_network(&self.network, project),
+        )?;
+        let lock = load_lock(
+            project,
+            self.arch.get(),
+            offline,
+            global.frozen(),
+            &lookaside_caches(&self.lookaside_cache, project),
+            self.registry.as_deref(),
+            self.sdk.as_deref(),
+            pull_policy(self.pull, project.pull_policy(), offline, global.frozen())?,
+            global.images(),
+        )
+        .await?;
+        // Keeps `build clean` out until every kit is built.
+        let _build_lock = global.lock_build(project).await?;
+        let project_lock = global.lock_project(project).await?;
+        install_tools(&project.project_dir().join("build/tools")).await?;
+        clear_upstream_fetches(project, self.upstream_source_fallback).await?;
+        first
+            .cargo_make(project, &lock, global.frozen())
+            .await?
+            .bootstrap_tools(global.bootstrap_tools())
+            .exec("build-kit-setup")
+            .await?;
+        drop(project_lock);
+
+        let jobs = usize::from(self.jobs_kits);
+        let mut running = FuturesUnordered::new();
+        loop {
+            for kit in schedule.start(jobs)? {
+                info!(
+                    "Building kit '{}' for {}",
+                    style::stderr().paint(HEADING, &kit),
+                    self.arch.get()
+                );
+                let build = &builds[&kit];
+                let lock = &lock;
+                running.push(async move {
+                    let start = Instant::now();
+                    let result = build
+                        .build_alongside(global, project, lock, self.force)
+                        .await;
+                    (kit, start.elapsed(), result)
+                });
+            }
+            let Some((kit, elapsed, result)) = running.next().await else {
+                break;
+            };
+            let status = match result {
+                Ok(true) => KitStatus::Built(elapsed),
+                Ok(false) => KitStatus::UpToDate,
+                Err(e) => {
+                    error!("[{}] {:?}", style::stderr().paint(HEADING, &kit), e);
+                    KitStatus::Failed(elapsed)
+                }
+            };
+            schedule.finish(&kit, status);
+        }
+        let _project_lock = global.lock_project(project).await?;
+        report_upstream_fetches(project).await?;
+        write_sources_manifest(project).await?;
+        KitsSummary::new(self.arch.get(), start.elapsed(), schedule.status())
+            .print(self.format.unwrap_or(global.format()))?;
+
+        let failed: Vec<_> = schedule
+            .status()
+            .iter()
+            .filter(|(_, status)| matches!(status, KitStatus::Failed(_)))
+            .map(|(kit, _)| kit.as_str())
+            .collect();
+        ensure!(
+            failed.is_empty(),
+            "Unable to build the kits {}",
+            failed.join(", ")
+        );
+        Ok(())
+    }
+
+    /// The options for building `kit` on its own.
+    fn build_kit(&self, kit: String) -> BuildKit {
+        BuildKit {
+            project_path: DeprecatedProjectPath::default(),
+            arch: self.arch.clone(),
+            kit,
+            lookaside_cache: self.lookaside_cache.clone(),
+            deprecated_lookaside_cache: None,
+            upstream_source_fallback: self.upstream_source_fallback,
+            no_checksums: self.no_checksums,
+            offline: self.offline,
+            network: self.network.clone(),
+            force: self.force,
+            watch: false,
+            manifest_path: None,
+            refresh_go_modules: self.refresh_go_modules,
+            env_file: self.env_file.clone(),
+            tag_suffix: self.tag_suffix.clone(),
+            build_args: self.build_args.clone(),
+            registry: self.registry.clone(),
+            pull: self.pull,
+            sdk: self.sdk.clone(),
+            format: self.format,
+            sbom: None,
+        }
+    }
 }
 
+/// The variables given to cargo make that are left out of the hash of a kit's inputs: the time of
+/// the build, which CI may set for every run, and where to find the tools, which is a path on this
+/// machine.
+const UNHASHED_ENV: [&str; 2] = ["BUILDSYS_TIMESTAMP", "TWOLITER_TOOLS_DIR"];
+
+/// Whether the kit in `kit_dir` was built from inputs with the hash `inputs_hash` and still has its
+/// RPM repository.
+async fn is_up_to_date(kit_dir: &Path, inputs_hash: &str) -> bool {
+    let recorded = fs::read_to_string(kit_dir.join(INPUTS_SHA256)).await;
+    recorded.is_ok_and(|recorded| recorded.trim() == inputs_hash)
+        && kit_dir.join("repodata").join("repomd.xml").is_file()
+}
+
+/// Returns the `kits` with an input that changed since `git_ref`, including files that git does not
+/// track yet, and the kits that depend on them. Files that buildsys fetches are not changes.
+async fn changed_kits(project: &Project, kits: Vec<String>, git_ref: &str) -> Result<Vec<String>> {
+    let project_dir = fs::canonicalize(project.project_dir()).await?;
+    let diff = git(
+        &project_dir,
+        &["diff", "--name-only", "--relative", git_ref],
+    )
+    .await?;
+    let untracked = git(
+        &project_dir,
+        &["ls-files", "--others", "--exclude-standard"],
+    )
+    .await?;
+    let mut changed: Vec<PathBuf> = diff
+        .lines()
+        .chain(untracked.lines())
+        .map(|path| project_dir.join(path))
+        .collect();
+    let project_files = [
+        project_dir.join(TWOLITER_TOML),
+        project_dir.join(TWOLITER_LOCK),
+    ];
+    if changed.iter().any(|path| project_files.contains(path)) {
+        return Ok(kits);
+    }
+
+    let mut inputs = BTreeMap::new();
+    let mut fetched = BTreeSet::new();
+    for kit in &kits {
+        let manifest_path = KitManifest::path_for(project, kit);
+        inputs.insert(
+            kit.clone(),
+            kit::manifest_inputs(project, &manifest_path).await?,
+        );
+        fetched.extend(kit::manifest_fetched_files(project, &manifest_path).await?);
+    }
+    changed.retain(|path| !fetched.contains(path));
+    let affected = kit::affected_kits(&inputs, &changed);
+
+    let graph = DependencyGraph::load(project).await?;
+    let dependents: BTreeSet<_> = affected
+        .iter()
+        .flat_map(|kit| graph.all_dependents(kit))
+        .collect();
+    Ok(kits
+        .into_iter()
+        .filter(|kit| affected.contains(kit) || dependents.contains(kit))
+        .collect())
+}
+
+/// Runs git in `dir` and returns what it printed.
+async fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = exec(Command::new("git").args(args).current_dir(dir), true)
+        .await
+        .context(format!("Unable to run 'git {}'", args.join(" ")))?;
+    Ok(output.unwrap_or_default())
+}
+
+const BUILD_VARIANT_EXAMPLES: &str = "\
+Examples:
+  # Build a variant for aarch64, falling back to upstream for sources missing from the cache
+  twoliter build variant aws-k8s-1.31 --arch aarch64 --lookaside-cache https://cache.bottlerocket.aws --upstream-source-fallback
+
+  # Build against an SDK that was built locally
+  twoliter build variant aws-dev --arch x86_64 --sdk bottlerocket-sdk:dev";
+
 /// Build a Bottlerocket variant image.
 #[derive(Debug, Parser)]
+#[clap(after_help = BUILD_VARIANT_EXAMPLES)]
 pub(crate) struct BuildVariant {
-    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
-    #[clap(long = "project-path")]
-    project_path: Option<PathBuf>,
+    #[clap(flatten)]
+    pub(crate) project_path: DeprecatedProjectPath,
 
-    /// The architecture to build for.
-    #[clap(long = "arch", default_value = "x86_64")]
-    arch: String,
+    #[clap(flatten)]
+    pub(crate) arch: ArchArg,
 
     /// The variant to build.
-    variant: String,
+    pub(crate) variant: String,
+
+    /// The URL to a lookaside cache where sources are stored to avoid pulling them from upstream.
+    /// A local directory may be given as a path instead. May be repeated, e.g. a local mirror and
+    /// then the public cache, and the caches are tried in that order before any upstream fallback.
+    /// Overrides `lookaside-caches` in the `[build]` section of Twoliter.toml. Defaults to
+    /// https://cache.bottlerocket.aws
+    #[clap(long = "lookaside-cache")]
+    pub(crate) lookaside_cache: Vec<String>,
 
-    /// The URL to the lookaside cache where sources are stored to avoid pulling them from upstream.
-    /// Defaults to https://cache.bottlerocket.aws
-    lookaside_cache: Option<String>,
+    /// Deprecated, give `--lookaside-cache` instead.
+    #[clap(value_name = "LOOKASIDE_CACHE", hide = true)]
+    pub(crate) deprecated_lookaside_cache: Option<String>,
 
     /// If sources are not found in the lookaside cache, this flag will cause buildsys to pull them
     /// from the upstream URL found in a package's `Cargo.toml`.
     #[clap(long = "upstream-source-fallback")]
-    upstream_source_fallback: bool,
+    pub(crate) upstream_source_fallback: bool,
+
+    /// Skip writing SHA256SUMS for the build outputs.
+    #[clap(long = "no-checksums")]
+    pub(crate) no_checksums: bool,
+
+    /// Forbid network access. Requires a local lookaside cache, disables upstream source fallback,
+    /// and only uses the SDK and kit images that are already present locally.
+    #[clap(long = "offline", conflicts_with = "upstream_source_fallback")]
+    pub(crate) offline: bool,
+
+    /// The docker network for build steps, e.g. `none`, `host`, `default` or the name of a docker
+    /// network. Overrides `network` in the `[build]` section of Twoliter.toml. A network of `none`
+    /// implies `--offline`.
+    #[clap(long = "network")]
+    pub(crate) network: Option<DockerNetwork>,
+
+    /// Turn an image feature on or off for this build, overriding the variant's `Cargo.toml`,
+    /// e.g. `--image-feature fips` or `--image-feature systemd-networkd=off`. May be repeated.
+    #[clap(long = "image-feature")]
+    pub(crate) image_features: Vec<ImageFeatureOverride>,
+
+    /// Override a setting of the variant's image layout for this build, e.g.
+    /// `--image-layout os-image-size-gib=4` or `--image-layout partition-plan=unified`. Overrides
+    /// `[variant.<name>.image-layout]` in Twoliter.toml. May be repeated.
+    #[clap(long = "image-layout")]
+    pub(crate) image_layout: Vec<ImageLayout>,
 
     /// Path to the Infra.toml file
     #[clap(long)]
-    infra_toml: Option<PathBuf>,
+    pub(crate) infra_toml: Option<PathBuf>,
+
+    /// The variant's platform, e.g. `aws`. Overrides `variant-platform` in the `[variant.<name>]`
+    /// section of Twoliter.toml. Derived from the variant name when neither is given.
+    #[clap(long)]
+    pub(crate) variant_platform: Option<String>,
+
+    /// The variant's runtime, e.g. `k8s`. Overrides `variant-runtime` in Twoliter.toml.
+    #[clap(long)]
+    pub(crate) variant_runtime: Option<String>,
+
+    /// The variant's family, e.g. `aws-k8s`. Overrides `variant-family` in Twoliter.toml.
+    #[clap(long)]
+    pub(crate) variant_family: Option<String>,
+
+    /// The variant's flavor, e.g. `nvidia`. Overrides `variant-flavor` in Twoliter.toml.
+    #[clap(long)]
+    pub(crate) variant_flavor: Option<String>,
+
+    /// Path to the variant's Cargo.toml, for variants that are not in `variants/<VARIANT>` of the
+    /// project.
+    #[clap(long)]
+    pub(crate) variant_manifest: Option<PathBuf>,
+
+    /// Skip writing the SLSA provenance of the images.
+    #[clap(long = "no-provenance")]
+    pub(crate) no_provenance: bool,
+
+    /// Sign the provenance of the images with cosign, using this key. The key may be a file or a
+    /// KMS URI such as `awskms:///<ARN>`.
+    #[clap(long = "sign-provenance", conflicts_with = "no_provenance")]
+    pub(crate) sign_provenance: Option<String>,
+
+    /// Search the `sources` directory for go modules even if the list in Twoliter.lock looks up
+    /// to date.
+    #[clap(long = "refresh-go-modules")]
+    pub(crate) refresh_go_modules: bool,
+
+    /// Read build system variables, such as `BUILDSYS_*` and `PUBLISH_*`, from `KEY=VALUE` lines in
+    /// this file. Blank lines and lines starting with `#` are skipped.
+    #[clap(long = "env-file")]
+    pub(crate) env_file: Option<PathBuf>,
+
+    /// Appended to the release version of what is built, e.g. `pr-123`, so that it can be told
+    /// apart from a mainline build. Overrides `tag-suffix` in the `[build]` section of Twoliter.toml.
+    #[clap(long = "tag-suffix")]
+    pub(crate) tag_suffix: Option<String>,
+
+    /// A `KEY=VALUE` build arg to pass to the `docker build` commands that buildsys runs, in
+    /// addition to its own, which it cannot replace. Can be given more than once.
+    #[clap(long = "build-arg", value_parser = parse_build_arg)]
+    pub(crate) build_args: Vec<String>,
+
+    /// Take the SDK from this registry instead of the vendor's, e.g. to try a staged or mirrored
+    /// SDK. Neither Twoliter.toml nor Twoliter.lock is changed.
+    #[clap(long = "registry")]
+    pub(crate) registry: Option<String>,
+
+    /// When to pull the SDK image: `always` to pick up a tag that moved upstream, `missing`, or
+    /// `never`, which also requires the kits in Twoliter.lock to be present locally. Overrides
+    /// `pull` in the `[build]` section of Twoliter.toml. Offline builds never pull.
+    #[clap(long = "pull", value_enum)]
+    pub(crate) pull: Option<PullPolicy>,
+
+    /// Build with this SDK image instead of the one in Twoliter.lock, e.g. `bottlerocket-sdk:dev`
+    /// for an SDK that was built locally. It is used as it is when present locally, and is only
+    /// pulled when it is not.
+    #[clap(long = "sdk", conflicts_with = "registry")]
+    pub(crate) sdk: Option<String>,
+
+    /// Print the summary of the build as `text`, `json` or `toml`. Overrides the global
+    /// `--format`.
+    #[clap(long = "format", value_enum)]
+    pub(crate) format: Option<OutputFormat>,
 }
 
 impl BuildVariant {
-    pub(super) async fn run(&self) -> Result<()> {
-        let project = project::load_or_find_project(self.project_path.clone()).await?;
-        let lock = Lock::load(&project).await?;
+    /// The options for building `variant` for `arch` as if nothing else had been given on the
+    /// command line, for commands that need the same environment as a build.
+    pub(crate) fn with_defaults(arch: &str, variant: &str) -> Self {
+        Self {
+            project_path: DeprecatedProjectPath::default(),
+            arch: ArchArg::new(arch),
+            variant: variant.to_string(),
+            lookaside_cache: Vec::new(),
+            deprecated_lookaside_cache: None,
+            upstream_source_fallback: false,
+            no_checksums: false,
+            offline: false,
+            network: None,
+            image_features: Vec::new(),
+            image_layout: Vec::new(),
+            infra_toml: None,
+            variant_platform: None,
+            variant_runtime: None,
+            variant_family: None,
+            variant_flavor: None,
+            variant_manifest: None,
+            no_provenance: false,
+            sign_provenance: None,
+            refresh_go_modules: false,
+            env_file: None,
+            tag_suffix: None,
+            build_args: Vec::new(),
+            registry: None,
+            pull: None,
+            sdk: None,
+            format: None,
+        }
+    }
+
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let start = Instant::now();
+        let started = SystemTime::now();
+        let project = self.project_path.apply(global).load_project().await?;
+        preflight(&project, self.arch.get(), global).await?;
+        // pubsys reads Infra.toml after the build, so catch mistakes in it before building.
+        if let Some(infra_toml) = &self.infra_toml {
+            infra::validate(infra_toml, &project.project_dir()).await?;
+        }
+        let image_layout = self.image_layout(&project);
+        if !image_layout.is_empty() {
+            let manifest =
+                VariantManifest::load_path(self.variant_manifest(&project).await?).await?;
+            manifest.check_image_layout(&image_layout).context(format!(
+                "Invalid image layout for variant '{}'",
+                self.variant
+            ))?;
+        }
+        let offline = is_offline(
+            self.offline,
+            self.upstream_source_fallback,
+            docker_network(&self.network, &project),
+        )?;
+        let lock = load_lock(
+            &project,
+            self.arch.get(),
+            offline,
+            global.frozen(),
+            &lookaside_caches(&self.lookaside_cache, &project),
+            self.registry.as_deref(),
+            self.sdk.as_deref(),
+            pull_policy(self.pull, project.pull_policy(), offline, global.frozen())?,
+            global.images(),
+        )
+        .await?;
+        // Building a variant stages the RPMs of its kits and builds the env image as it goes, so it
+        // holds the lock throughout.
+        let _project_lock = global.lock_project(&project).await?;
         let toolsdir = project.project_dir().join("build/tools");
         install_tools(&toolsdir).await?;
-        let makefile_path = toolsdir.join("Makefile.toml");
-        // A temporary directory in the `build` directory
-        let build_temp_dir = TempDir::new_in(project.project_dir())
-            .context("Unable to create a tempdir for Twoliter's build")?;
+        // A temporary directory for Twoliter's build, in the project directory by default
+        let build_temp_dir = project.temp_dir().await?;
         let packages_dir = build_temp_dir.path().join("sdk_rpms");
         fs::create_dir_all(&packages_dir).await?;
 
+        clear_upstream_fetches(&project, self.upstream_source_fallback).await?;
+        let result = self
+            .cargo_make(&project, &lock, global.frozen())
+            .await?
+            .bootstrap_tools(global.bootstrap_tools())
+            .exec("build")
+            .await;
+        report_upstream_fetches(&project).await?;
+        write_sources_manifest(&project).await?;
+        if let Err(e) = fix_ownership(&project, &lock.sdk.source).await {
+            warn!("{:#}", e);
+        }
+        result?;
+        let latest = project
+            .project_dir()
+            .join("build/images")
+            .join(format!("{}-{}", self.arch.get(), self.variant))
+            .join("latest");
+        // The summary reports a missing `latest` link rather than failing the build.
+        let images_dir = fs::canonicalize(&latest).await.unwrap_or(latest);
+        if images_dir.is_dir() {
+            let manifest =
+                VariantManifest::load_path(self.variant_manifest(&project).await?).await?;
+            ImageFeatures::new(&manifest, &self.image_features)
+                .write(&images_dir)
+                .await?;
+        }
+        let checksums = if self.no_checksums {
+            None
+        } else {
+            Some(write_checksums(&images_dir).await?)
+        };
+        if !self.no_provenance && images_dir.is_dir() {
+            let checksums = match checksums {
+                Some(checksums) => checksums,
+                None => checksums::compute(&images_dir).await?,
+            };
+            let metadata = self
+                .build_metadata(&project, &lock, global.images())
+                .await?;
+            let statement = provenance::statement(&checksums, &metadata)?;
+            write_provenance(&images_dir, &statement, self.sign_provenance.as_deref()).await?;
+        }
+
+        let sdk_digest = sdk_digest(&lock, global.images()).await;
+        BuildSummary::variant(&self.variant, self.arch.get(), &images_dir, start.elapsed())
+            .await?
+            .sdk(&lock.sdk.source, sdk_digest.as_deref())
+            .packages_built(&project.project_dir().join("build/rpms"), started)
+            .await?
+            .print(self.format.unwrap_or(global.format()))
+    }
+
+    /// The variant's platform, runtime, family and flavor from the command line, then Twoliter.toml,
+    /// then the variant's name.
+    fn variant_parts(&self, project: &Project) -> Result<VariantParts> {
+        let config = project.variant(&self.variant);
+        let config = VariantConfig {
+            variant_platform: self.variant_platform.clone().or(config.variant_platform),
+            variant_runtime: self.variant_runtime.clone().or(config.variant_runtime),
+            variant_family: self.variant_family.clone().or(config.variant_family),
+            variant_flavor: self.variant_flavor.clone().or(config.variant_flavor),
+            host_containers: config.host_containers,
+            image_layout: config.image_layout,
+        };
+        VariantParts::resolve(&self.variant, &config)
+    }
+
+    /// The image layout overrides from Twoliter.toml, then from `--image-layout` in order.
+    fn image_layout(&self, project: &Project) -> ImageLayout {
+        self.image_layout
+            .iter()
+            .fold(project.variant(&self.variant).image_layout, |layout, o| {
+                layout.overlay(o)
+            })
+    }
+
+    /// Collects what went into the build for its provenance.
+    async fn build_metadata(
+        &self,
+        project: &Project,
+        lock: &Lock,
+        images: &ImageInspector,
+    ) -> Result<BuildMetadata> {
+        let mut parameters = BTreeMap::from([
+            ("arch".to_string(), self.arch.get().to_string()),
+            ("variant".to_string(), self.variant.clone()),
+            (
+                "version".to_string(),
+                project.image_version(self.tag_suffix.as_deref())?,
+            ),
+            (
+                "upstream-source-fallback".to_string(),
+                self.upstream_source_fallback.to_string(),
+            ),
+            ("offline".to_string(), self.offline.to_string()),
+        ]);
+        let lookaside_caches = lookaside_caches(&self.lookaside_cache, project);
+        if !lookaside_caches.is_empty() {
+            parameters.insert("lookaside-cache".to_string(), lookaside_caches.join(","));
+        }
+        if !self.image_features.is_empty() {
+            let overrides: Vec<_> = self.image_features.iter().map(|o| o.to_string()).collect();
+            parameters.insert("image-features".to_string(), overrides.join(","));
+        }
+        let image_layout = self.image_layout(project);
+        if !image_layout.is_empty() {
+            parameters.insert("image-layout".to_string(), image_layout.to_string());
+        }
+        for (key, value) in self.variant_parts(project)?.envs() {
+            parameters.insert(key.to_lowercase().replace('_', "-"), value);
+        }
+        for (name, image) in lock.host_containers(project, &self.variant)? {
+            parameters.insert(format!("host-container.{}", name), image);
+        }
+
+        // A project that is not a git checkout has no commit to record.
+        let dir = project.project_dir();
+        let git = match git(&dir, &["rev-parse", "HEAD"]).await {
+            Ok(commit) => {
+                let uri = git(&dir, &["config", "--get", "remote.origin.url"])
+                    .await
+                    .unwrap_or_default();
+                let uri = match uri.trim() {
+                    "" => dir.display().to_string(),
+                    uri => uri.to_string(),
+                };
+                Some((uri, commit.trim().to_string()))
+            }
+            Err(_) => None,
+        };
+
+        Ok(BuildMetadata {
+            parameters,
+            images: lock.local_images(project, self.arch.get(), images).await?,
+            git,
+        })
+    }
+
+    /// The variant's Cargo.toml, from `--variant-manifest` or else `variants/<VARIANT>/Cargo.toml`.
+    async fn variant_manifest(&self, project: &Project) -> Result<PathBuf> {
+        match &self.variant_manifest {
+            // The path is relative to where Twoliter runs, but cargo make runs in the project.
+            Some(path) => fs::canonicalize(path).await.context(format!(
+                "Unable to find the variant manifest '{}' given with --variant-manifest",
+                path.display()
+            )),
+            None => Ok(VariantManifest::path_for(project, &self.variant)),
+        }
+    }
+
+    /// Assemble the `cargo make` invocation for this build without running it.
+    pub(crate) async fn cargo_make(
+        &self,
+        project: &Project,
+        lock: &Lock,
+        frozen: bool,
+    ) -> Result<CargoMake> {
         let mut optional_envs = Vec::new();
 
-        if let Some(lookaside_cache) = &self.lookaside_cache {
-            optional_envs.push(("BUILDSYS_LOOKASIDE_CACHE", lookaside_cache.to_string()))
+        if !self.image_features.is_empty() {
+            let overrides: Vec<_> = self.image_features.iter().map(|o| o.to_string()).collect();
+            optional_envs.push(("BUILDSYS_IMAGE_FEATURES", overrides.join(",")))
+        }
+
+        let image_layout = self.image_layout(project);
+        if !image_layout.is_empty() {
+            optional_envs.push(("BUILDSYS_IMAGE_LAYOUT", image_layout.to_string()))
         }
 
         if let Some(infra_toml) = &self.infra_toml {
+            optional_envs.push(("PUBLISH_INFRA_CONFIG_PATH", path_var(infra_toml)?))
+        }
+
+        if self.variant_manifest.is_some() {
             optional_envs.push((
-                "PUBLISH_INFRA_CONFIG_PATH",
-                infra_toml.display().to_string(),
+                "BUILDSYS_VARIANT_MANIFEST",
+                path_var(self.variant_manifest(project).await?)?,
             ))
         }
 
-        CargoMake::new(&lock.sdk.source)?
-            .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
-            .env("BUILDSYS_ARCH", &self.arch)
-            .env("BUILDSYS_VARIANT", &self.variant)
-            .env("BUILDSYS_VERSION_IMAGE", project.release_version())
-            .env("GO_MODULES", project.find_go_modules().await?.join(" "))
+        optional_envs.extend(self.variant_parts(project)?.envs());
+
+        if let Some(host_containers) = lock.host_containers_env(project, &self.variant)? {
+            optional_envs.push(("BUILDSYS_HOST_CONTAINERS", host_containers))
+        }
+
+        self.shared_options()
+            .cargo_make(
+                project,
+                lock,
+                frozen,
+                ("BUILDSYS_VARIANT", &self.variant),
+                optional_envs,
+            )
+            .await
+    }
+
+    /// The settings of this build that kit builds also pass to `cargo make`.
+    fn shared_options(&self) -> SharedBuildOptions<'_> {
+        SharedBuildOptions {
+            arch: self.arch.get(),
+            lookaside_cache: &self.lookaside_cache,
+            network: &self.network,
+            build_args: &self.build_args,
+            env_file: self.env_file.as_deref(),
+            tag_suffix: self.tag_suffix.as_deref(),
+            refresh_go_modules: self.refresh_go_modules,
+            upstream_source_fallback: self.upstream_source_fallback,
+            offline: self.offline,
+        }
+    }
+}
+
+/// The settings that kit and variant builds pass to `cargo make` in the same way.
+struct SharedBuildOptions<'a> {
+    arch: &'a str,
+    lookaside_cache: &'a [String],
+    network: &'a Option<DockerNetwork>,
+    build_args: &'a [String],
+    env_file: Option<&'a Path>,
+    tag_suffix: Option<&'a str>,
+    refresh_go_modules: bool,
+    upstream_source_fallback: bool,
+    offline: bool,
+}
+
+impl SharedBuildOptions<'_> {
+    /// Assemble the `cargo make` invocation that builds `target`, a pair such as
+    /// `("BUILDSYS_KIT", "core-kit")`, with `optional_envs` that only that kind of build sets.
+    async fn cargo_make(
+        &self,
+        project: &Project,
+        lock: &Lock,
+        frozen: bool,
+        target: (&str, &str),
+        mut optional_envs: Vec<(&'static str, String)>,
+    ) -> Result<CargoMake> {
+        let toolsdir = project.project_dir().join("build/tools");
+        let makefile_path = toolsdir.join("Makefile.toml");
+
+        let lookaside_caches = lookaside_caches(self.lookaside_cache, project);
+        if let Some(lookaside_cache) = lookaside_cache_env(&lookaside_caches).await? {
+            optional_envs.push(("BUILDSYS_LOOKASIDE_CACHE", lookaside_cache))
+        }
+
+        let network = docker_network(self.network, project);
+        if let Some(network) = network {
+            optional_envs.push(("BUILDSYS_DOCKER_NETWORK", network.to_string()))
+        }
+
+        if !self.build_args.is_empty() {
+            optional_envs.push(("BUILDSYS_BUILD_ARGS", self.build_args.join("\n")))
+        }
+
+        if let Some(shared_cache) = project.shared_cache() {
+            optional_envs.push(("BUILDSYS_SHARED_CACHE", path_var(shared_cache)?))
+        }
+
+        let env_file = read_env_file(self.env_file).await?;
+        Ok(CargoMake::new(&lock.sdk.source)?
+            .env_file_vars(env_file)?
+            .env("TWOLITER_TOOLS_DIR", path_var(&toolsdir)?)
+            .env("BUILDSYS_ARCH", self.arch)
+            .env(target.0, target.1)
+            .env(
+                "BUILDSYS_VERSION_IMAGE",
+                project.image_version(self.tag_suffix)?,
+            )
+            .env(
+                "GO_MODULES",
+                project
+                    .exclude_go_modules(
+                        lock.go_modules(project, self.refresh_go_modules, frozen)
+                            .await?,
+                    )?
+                    .join(" "),
+            )
             .env(
                 "BUILDSYS_UPSTREAM_SOURCE_FALLBACK",
                 self.upstream_source_fallback.to_string(),
             )
-            .envs(optional_envs.into_iter())
+            .optional_envs(optional_envs)
+            .optional_envs(offline_envs(
+                self.offline || network.is_some_and(DockerNetwork::is_none),
+            ))
             .makefile(makefile_path)
-            .project_dir(project.project_dir())
-            .exec("build")
-            .await
+            .project_dir(project.project_dir()))
+    }
+}
+
+/// Warns that sources may come from upstream and removes the records of upstream fetches left by an
+/// earlier build, so that only fetches made by this build are reported.
+async fn clear_upstream_fetches(project: &Project, upstream_source_fallback: bool) -> Result<()> {
+    if upstream_source_fallback {
+        warn!(
+            "Upstream source fallback is enabled. Sources that are missing from the lookaside \
+            cache will be fetched from their upstream URLs, so this build may not be reproducible."
+        );
+    }
+    let dir = project.project_dir().join(UPSTREAM_SOURCES_DIRECTORY);
+    if dir.is_dir() {
+        fs::remove_dir_all(&dir).await?;
+    }
+    Ok(())
+}
+
+/// Warns about any sources that buildsys fetched from upstream during the build.
+async fn report_upstream_fetches(project: &Project) -> Result<()> {
+    let fetched = entry_names(&project.project_dir().join(UPSTREAM_SOURCES_DIRECTORY)).await?;
+    if !fetched.is_empty() {
+        warn!(
+            "{} source file(s) were fetched from upstream instead of the lookaside cache: {}",
+            fetched.len(),
+            fetched.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// A source file in `build/sources-manifest.json`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct PackageSource {
+    package: String,
+    #[serde(flatten)]
+    source: FetchedSource,
+}
+
+/// Writes `build/sources-manifest.json`, which lists the source files of every package that has
+/// been built since the build directory was last cleaned, with their URLs and hashes.
+async fn write_sources_manifest(project: &Project) -> Result<()> {
+    let sources = fetched_sources(&project.project_dir().join(FETCHED_SOURCES_DIRECTORY)).await?;
+    let json = serde_json::to_string_pretty(&serde_json::json!({ "sources": sources }))
+        .context("Unable to serialize the sources manifest")?;
+    fs::write(project.project_dir().join(SOURCES_MANIFEST), json).await
+}
+
+/// The source files recorded in `dir` by buildsys, in a directory for each package, sorted by
+/// package and then file name.
+async fn fetched_sources(dir: &Path) -> Result<Vec<PackageSource>> {
+    let mut sources = Vec::new();
+    for package in entry_names(dir).await? {
+        let package_dir = dir.join(&package);
+        for name in entry_names(&package_dir).await? {
+            let path = package_dir.join(name);
+            let source = serde_json::from_str(&fs::read_to_string(&path).await?)
+                .context(format!("Unable to parse '{}'", path.display()))?;
+            sources.push(PackageSource {
+                package: package.clone(),
+                source,
+            });
+        }
+    }
+    Ok(sources)
+}
+
+/// The names of the entries in `dir`, sorted, or none if it does not exist.
+async fn entry_names(dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    if !dir.is_dir() {
+        return Ok(names);
+    }
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .context(format!("Unable to read directory '{}'", dir.display()))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context(format!("Unable to read directory '{}'", dir.display()))?
+    {
+        names.push(entry.file_name().to_string_lossy().to_string());
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// The docker network given on the command line, or else the one configured in Twoliter.toml.
+/// Checks, before a build for `arch` starts, for problems that would otherwise break it late: the
+/// directories that it writes to being on filesystems known to break builds, and a host that cannot
+/// run binaries for `arch`. These are warnings unless `--strict-preflight` was given.
+async fn preflight(project: &Project, arch: &str, global: &GlobalArgs) -> Result<()> {
+    binfmt::preflight(arch, global.setup_binfmt(), global.strict_preflight()).await?;
+    let project_dir = project.project_dir();
+    let dirs = [
+        project_dir.clone(),
+        project_dir.join("build"),
+        project.temp_dir_base(),
+    ];
+    filesystem::preflight(&dirs, global.strict_preflight()).await
+}
+
+/// Checks that a `--build-arg` is `KEY=VALUE` with a key that docker accepts. Values are passed to
+/// buildsys one per line, so they cannot contain a newline.
+fn parse_build_arg(arg: &str) -> Result<String> {
+    let Some((key, value)) = arg.split_once('=') else {
+        bail!("Expected KEY=VALUE, got '{}'", arg);
+    };
+    ensure!(
+        key.chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        "Invalid build arg name '{}', use letters, digits and underscores",
+        key
+    );
+    ensure!(
+        !value.contains('\n'),
+        "The value of build arg '{}' contains a newline",
+        key
+    );
+    Ok(arg.to_string())
+}
+
+fn docker_network<'a>(
+    cli: &'a Option<DockerNetwork>,
+    project: &'a Project,
+) -> Option<&'a DockerNetwork> {
+    cli.as_ref().or_else(|| project.docker_network())
+}
+
+/// A build is offline if `--offline` was given or if build steps have no network. Upstream source
+/// fallback cannot work without a network.
+fn is_offline(
+    offline: bool,
+    upstream_source_fallback: bool,
+    network: Option<&DockerNetwork>,
+) -> Result<bool> {
+    let no_network = network.is_some_and(DockerNetwork::is_none);
+    ensure!(
+        !(no_network && upstream_source_fallback),
+        "--upstream-source-fallback cannot be used when the build network is 'none'"
+    );
+    Ok(offline || no_network)
+}
+
+/// The pull policy from the command line, or else from Twoliter.toml. Offline and frozen builds
+/// never pull, and an offline build cannot be asked to pull always.
+fn pull_policy(
+    cli: Option<PullPolicy>,
+    configured: Option<PullPolicy>,
+    offline: bool,
+    frozen: bool,
+) -> Result<PullPolicy> {
+    let policy = cli.or(configured);
+    if offline {
+        ensure!(
+            policy != Some(PullPolicy::Always),
+            "Images cannot always be pulled in an offline build, use '--pull never' or leave it out"
+        );
+        return Ok(PullPolicy::Never);
+    }
+    if frozen {
+        if policy == Some(PullPolicy::Always) {
+            warn!("Not pulling images with --frozen, only the images present locally are used");
+        }
+        return Ok(PullPolicy::Never);
+    }
+    Ok(policy.unwrap_or_default())
+}
+
+/// Loads `Twoliter.lock`. When `offline`, `frozen` or never pulling, the lock must already exist and
+/// the images it refers to are checked to be available without network access. Otherwise the SDK
+/// is pulled as `pull` says. When `offline`, the lookaside caches must be local too.
+#[allow(clippy::too_many_arguments)]
+async fn load_lock(
+    project: &Project,
+    arch: &str,
+    offline: bool,
+    frozen: bool,
+    lookaside_caches: &[String],
+    registry: Option<&str>,
+    sdk: Option<&str>,
+    pull: PullPolicy,
+    images: &ImageInspector,
+) -> Result<Lock> {
+    if offline {
+        check_offline_lookaside_caches(lookaside_caches)?;
+    }
+    let local_only = offline || frozen || pull == PullPolicy::Never;
+    let mut lock = if local_only {
+        Lock::load_existing(project).await?
+    } else {
+        Lock::load(project).await?
+    };
+    if let Some(registry) = registry {
+        lock = lock.with_registry(registry);
+        info!("Using the SDK from {}", lock.sdk.source);
     }
+    if let Some(sdk) = sdk {
+        lock = with_local_sdk(lock, sdk, arch, pull, images).await?;
+    } else {
+        if pull == PullPolicy::Always {
+            info!("Pulling the SDK {}", lock.sdk.source);
+        }
+        let present = images.exists(&lock.sdk.source).await?;
+        if pull.should_pull(present) {
+            lock.pull_sdk(arch, images).await?;
+        }
+    }
+    if local_only {
+        lock.ensure_local(project, arch, images).await?;
+    }
+    Ok(lock)
+}
+
+/// The digest of the SDK for a build summary, if the SDK is present locally. An up-to-date kit is
+/// not rebuilt, so the SDK may never have been pulled.
+async fn sdk_digest(lock: &Lock, images: &ImageInspector) -> Option<String> {
+    local_digest(&lock.sdk.source, images).await.ok()
+}
+
+/// Uses the image `sdk` in place of the SDK in Twoliter.lock, e.g. one that was built locally and was
+/// never pushed. It is only pulled when it is not present locally. Its image ID stands in for the
+/// digest, so that kits are built again when the image changes.
+async fn with_local_sdk(
+    lock: Lock,
+    sdk: &str,
+    arch: &str,
+    pull: PullPolicy,
+    images: &ImageInspector,
+) -> Result<Lock> {
+    if !images.exists(sdk).await? {
+        ensure!(
+            pull.should_pull(false),
+            "The SDK image {} is not present locally",
+            sdk
+        );
+        // The SDK is not the one that Twoliter.lock pins, so it is pulled by its tag.
+        pull_for_arch(sdk, arch, images).await.context(format!(
+            "The SDK image {} is neither present locally nor able to be pulled",
+            sdk
+        ))?;
+    }
+    let id = images
+        .inspect(sdk)
+        .await?
+        .and_then(|image| image["Id"].as_str().map(str::to_string))
+        .context(format!("Unable to find the image ID of the SDK {}", sdk))?;
+    info!("Using the SDK {} ({})", sdk, id);
+    Ok(lock.with_sdk(sdk, &id))
+}
+
+/// An offline build must be given lookaside caches that are local directories or `file://` URLs.
+fn check_offline_lookaside_caches(lookaside_caches: &[String]) -> Result<()> {
+    ensure!(
+        !lookaside_caches.is_empty(),
+        "An offline build requires a lookaside cache, please provide the path to a local directory \
+        of sources"
+    );
+    for lookaside_cache in lookaside_caches {
+        if let Ok(url) = Url::parse(lookaside_cache) {
+            ensure!(
+                url.scheme() == "file",
+                "An offline build requires local lookaside caches, but '{}' is a remote URL",
+                lookaside_cache
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The lookaside caches from the command line, or else from Twoliter.toml.
+fn lookaside_caches(lookaside_cache: &[String], project: &Project) -> Vec<String> {
+    if lookaside_cache.is_empty() {
+        project.lookaside_caches()
+    } else {
+        lookaside_cache.to_vec()
+    }
+}
+
+/// Environment variables that stop cargo and go from reaching the network during an offline build.
+fn offline_envs(offline: bool) -> Vec<(&'static str, &'static str)> {
+    if offline {
+        vec![("CARGO_NET_OFFLINE", "true"), ("GOPROXY", "off")]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Returns `lookaside_cache` unchanged if it is a URL. Otherwise it is taken to be the path to a
+/// local directory of sources, relative to the current directory, and is converted to an absolute
+/// `file://` URL. It is an error if the directory does not exist.
+async fn lookaside_cache_url(lookaside_cache: &str) -> Result<String> {
+    if Url::parse(lookaside_cache).is_ok() {
+        return Ok(lookaside_cache.to_string());
+    }
+    let path = fs::canonicalize(Path::new(lookaside_cache))
+        .await
+        .context(format!(
+            "The lookaside cache '{}' is neither a URL nor an existing directory",
+            lookaside_cache
+        ))?;
+    Url::from_file_path(&path)
+        .map(String::from)
+        .ok()
+        .context(format!(
+            "Unable to convert '{}' to a file:// URL",
+            path.display()
+        ))
+}
+
+/// The value of `BUILDSYS_LOOKASIDE_CACHE`, the URL of each lookaside cache separated by commas in
+/// the order they are tried, if there are any.
+async fn lookaside_cache_env(lookaside_caches: &[String]) -> Result<Option<String>> {
+    if lookaside_caches.is_empty() {
+        return Ok(None);
+    }
+    let mut urls = Vec::new();
+    for lookaside_cache in lookaside_caches {
+        let url = lookaside_cache_url(lookaside_cache).await?;
+        ensure!(
+            !url.contains(','),
+            "The lookaside cache '{}' may not contain a comma, which separates lookaside caches",
+            lookaside_cache
+        );
+        urls.push(url);
+    }
+    Ok(Some(urls.join(",")))
+}
+
+#[tokio::test]
+async fn test_fetched_sources() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let dir = temp_dir.path().join("fetched-sources");
+    assert!(fetched_sources(&dir).await.unwrap().is_empty());
+
+    let record = |name: &str| FetchedSource {
+        name: name.to_string(),
+        url: format!("https://example.com/{}", name),
+        origin: buildsys_config::SourceOrigin::LookasideCache,
+        fetched_from: Some(format!("https://cache.example.com/{}", name)),
+        sha256: "0".repeat(64),
+        sha512: "0".repeat(128),
+    };
+    for (package, name) in [("libfoo", "foo.tar.xz"), ("bar", "bar.tar.gz")] {
+        let package_dir = dir.join(package);
+        fs::create_dir_all(&package_dir).await.unwrap();
+        fs::write(
+            package_dir.join(name),
+            serde_json::to_string(&record(name)).unwrap(),
+        )
+        .await
+        .unwrap();
+    }
+    let sources = fetched_sources(&dir).await.unwrap();
+    assert_eq!(sources.len(), 2);
+    assert_eq!(sources[0].package, "bar");
+    let json = serde_json::to_value(&sources[1]).unwrap();
+    assert_eq!(json["package"], "libfoo");
+    assert_eq!(json["name"], "foo.tar.xz");
+    assert_eq!(json["origin"], "lookaside-cache");
+    assert_eq!(json["sha256"], "0".repeat(64));
+}
+
+#[tokio::test]
+async fn test_lookaside_cache_url() {
+    assert_eq!(
+        lookaside_cache_url("https://cache.example.com")
+            .await
+            .unwrap(),
+        "https://cache.example.com"
+    );
+    assert_eq!(
+        lookaside_cache_url("file:///srv/cache").await.unwrap(),
+        "file:///srv/cache"
+    );
+
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let cache = temp_dir.path().join("my cache");
+    fs::create_dir_all(&cache).await.unwrap();
+    let url = lookaside_cache_url(cache.to_str().unwrap()).await.unwrap();
+    let expected = Url::from_file_path(cache.canonicalize().unwrap()).unwrap();
+    assert_eq!(url, expected.as_str());
+    assert!(url.starts_with("file:///") && url.ends_with("my%20cache"));
+
+    let missing = temp_dir.path().join("missing");
+    assert!(lookaside_cache_url(missing.to_str().unwrap())
+        .await
+        .is_err());
+
+    let caches = [
+        cache.to_str().unwrap().to_string(),
+        "https://cache.bottlerocket.aws".to_string(),
+    ];
+    assert_eq!(
+        lookaside_cache_env(&caches).await.unwrap().unwrap(),
+        format!("{},https://cache.bottlerocket.aws", expected)
+    );
+    assert_eq!(lookaside_cache_env(&[]).await.unwrap(), None);
+    assert!(
+        lookaside_cache_env(&["https://cache.example.com/a,b".to_string()])
+            .await
+            .is_err()
+    );
+}
+
+#[test]
+fn test_check_offline_lookaside_caches() {
+    let caches =
+        |caches: &[&str]| -> Vec<String> { caches.iter().map(|c| c.to_string()).collect() };
+    assert!(check_offline_lookaside_caches(&[]).is_err());
+    assert!(check_offline_lookaside_caches(&caches(&["https://cache.bottlerocket.aws"])).is_err());
+    assert!(check_offline_lookaside_caches(&caches(&["file:///srv/cache"])).is_ok());
+    assert!(check_offline_lookaside_caches(&caches(&["./cache"])).is_ok());
+    assert!(check_offline_lookaside_caches(&caches(&[
+        "./cache",
+        "https://cache.bottlerocket.aws"
+    ]))
+    .is_err());
+    assert_eq!(
+        offline_envs(true),
+        [("CARGO_NET_OFFLINE", "true"), ("GOPROXY", "off")]
+    );
+    assert!(offline_envs(false).is_empty());
+}
+
+#[test]
+fn test_is_offline() {
+    let none = "none".parse::<DockerNetwork>().unwrap();
+    let host = "host".parse::<DockerNetwork>().unwrap();
+    assert!(!is_offline(false, false, None).unwrap());
+    assert!(!is_offline(false, true, Some(&host)).unwrap());
+    assert!(is_offline(true, false, Some(&host)).unwrap());
+    assert!(is_offline(false, false, Some(&none)).unwrap());
+    assert!(is_offline(false, true, Some(&none)).is_err());
+}
+
+#[test]
+fn test_pull_policy() {
+    use PullPolicy::*;
+    assert_eq!(pull_policy(None, None, false, false).unwrap(), Missing);
+    assert_eq!(
+        pull_policy(None, Some(Always), false, false).unwrap(),
+        Always
+    );
+    assert_eq!(
+        pull_policy(Some(Never), Some(Always), false, false).unwrap(),
+        Never
+    );
+    assert_eq!(pull_policy(None, None, true, false).unwrap(), Never);
+    assert_eq!(pull_policy(None, Some(Always), false, true).unwrap(), Never);
+    assert!(pull_policy(Some(Always), None, true, false).is_err());
+    assert!(pull_policy(None, Some(Always), true, false).is_err());
+}
+
+#[tokio::test]
+async fn test_with_local_sdk() {
+    use crate::lock::LockedImage;
+    use crate::schema_version::SchemaVersion;
+    use std::collections::HashMap;
+
+    let sdk = LockedImage {
+        name: "bottlerocket-sdk".to_string(),
+        version: semver::Version::new(1, 2, 3),
+        vendor: "bottlerocket".to_string(),
+        source: "public.ecr.aws/bottlerocket/bottlerocket-sdk:v1.2.3".to_string(),
+        digest: "abc=".to_string(),
+        manifest: Vec::new(),
+    };
+    let lock = Lock {
+        schema_version: SchemaVersion,
+        release_version: "1.0.0".to_string(),
+        sdk,
+        kit: Vec::new(),
+        digest: "def=".to_string(),
+        go_modules: None,
+        host_containers: Default::default(),
+    };
+    let images = ImageInspector::with_results(HashMap::from([(
+        "bottlerocket-sdk:dev".to_string(),
+        Some(serde_json::json!({ "Id": "sha256:1234" })),
+    )]));
+    let local = with_local_sdk(
+        lock.clone(),
+        "bottlerocket-sdk:dev",
+        "x86_64",
+        PullPolicy::Always,
+        &images,
+    )
+    .await
+    .unwrap();
+    assert_eq!(local.sdk.source, "bottlerocket-sdk:dev");
+    assert_eq!(local.sdk.digest, "sha256:1234");
+    assert_eq!(local.sdk.name, "bottlerocket-sdk");
+    assert_eq!(local.digest, lock.digest);
+
+    let err = with_local_sdk(
+        lock,
+        "bottlerocket-sdk:missing",
+        "x86_64",
+        PullPolicy::Never,
+        &images,
+    )
+    .await
+    .unwrap_err();
+    assert!(err.to_string().contains("not present locally"));
+}
+
+#[tokio::test]
+async fn test_upstream_fetches() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let dir = temp_dir.path().join("upstream-sources");
+    assert!(entry_names(&dir).await.unwrap().is_empty());
+    fs::create_dir_all(&dir).await.unwrap();
+    fs::write(dir.join("v1.2.tar.gz"), "https://example.com/v1.2.tar.gz\n")
+        .await
+        .unwrap();
+    fs::write(
+        dir.join("patch.tar.xz"),
+        "https://example.com/patch.tar.xz\n",
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        entry_names(&dir).await.unwrap(),
+        ["patch.tar.xz", "v1.2.tar.gz"]
+    );
+}
+
+#[test]
+fn test_parse_build_arg() {
+    assert_eq!(parse_build_arg("FOO=bar").unwrap(), "FOO=bar");
+    assert_eq!(parse_build_arg("_FOO_2=a=b").unwrap(), "_FOO_2=a=b");
+    assert_eq!(parse_build_arg("EMPTY=").unwrap(), "EMPTY=");
+    assert!(parse_build_arg("FOO").is_err());
+    assert!(parse_build_arg("=bar").is_err());
+    assert!(parse_build_arg("2FOO=bar").is_err());
+    assert!(parse_build_arg("FOO-BAR=baz").is_err());
+    assert!(parse_build_arg("FOO=a\nb").is_err());
+
+    let args = BuildVariant::try_parse_from([
+        "variant",
+        "aws-dev",
+        "--build-arg",
+        "A=1",
+        "--build-arg",
+        "B=2",
+    ])
+    .unwrap();
+    assert_eq!(args.build_args, ["A=1", "B=2"]);
+    assert!(BuildVariant::try_parse_from(["variant", "aws-dev", "--build-arg", "A"]).is_err());
+}
+
+#[test]
+fn test_deprecated_lookaside_cache() {
+    let mut args = BuildKit::try_parse_from([
+        "kit",
+        "core-kit",
+        "https://cache.example.com",
+        "--lookaside-cache",
+        "/srv/lookaside",
+    ])
+    .unwrap();
+    apply_deprecated_lookaside_cache(
+        args.deprecated_lookaside_cache.take(),
+        &mut args.lookaside_cache,
+    );
+    assert_eq!(
+        args.lookaside_cache,
+        ["https://cache.example.com", "/srv/lookaside"]
+    );
+
+    let mut args =
+        BuildVariant::try_parse_from(["variant", "aws-dev", "--lookaside-cache", "/srv/lookaside"])
+            .unwrap();
+    apply_deprecated_lookaside_cache(
+        args.deprecated_lookaside_cache.take(),
+        &mut args.lookaside_cache,
+    );
+    assert_eq!(args.lookaside_cache, ["/srv/lookaside"]);
+}
+
+#[tokio::test]
+async fn test_kit_inputs_hash_env() {
+    use crate::lock::LockedImage;
+    use crate::schema_version::SchemaVersion;
+    use crate::test::copy_project_to_temp_dir;
+
+    let temp_dir = copy_project_to_temp_dir("local-kit").await;
+    let project = Project::load(temp_dir.path().join(TWOLITER_TOML))
+        .await
+        .unwrap();
+    let lock = Lock {
+        schema_version: SchemaVersion,
+        release_version: project.release_version().to_string(),
+        sdk: LockedImage {
+            name: "bottlerocket-sdk".to_string(),
+            version: semver::Version::new(1, 2, 3),
+            vendor: "bottlerocket".to_string(),
+            source: "public.ecr.aws/bottlerocket/bottlerocket-sdk:v1.2.3".to_string(),
+            digest: "abc=".to_string(),
+            manifest: Vec::new(),
+        },
+        kit: Vec::new(),
+        digest: "def=".to_string(),
+        go_modules: None,
+        host_containers: Default::default(),
+    };
+    let env_file = temp_dir.path().join("build.env");
+    let hash = |env: &'static str| {
+        let (project, lock, env_file) = (&project, &lock, &env_file);
+        async move {
+            fs::write(env_file, env).await.unwrap();
+            let args = BuildKit::try_parse_from([
+                "kit",
+                "extra-2-kit",
+                "--env-file",
+                env_file.to_str().unwrap(),
+            ])
+            .unwrap();
+            args.inputs_hash(project, lock, false).await.unwrap()
+        }
+    };
+
+    let before = hash("").await;
+    assert_eq!(before, hash("BUILDSYS_TIMESTAMP=1700000000\n").await);
+    assert_ne!(before, hash("BUILDSYS_VERSION_BUILD=abc1234\n").await);
 }
@@ -1,30 +1,38 @@
-use crate::cargo_make::CargoMake;
-use crate::lock::Lock;
-use crate::project;
+use crate::cargo_make::{path_var, CargoMake};
+use crate::cmd::{DeprecatedProjectPath, GlobalArgs};
+use crate::ownership::fix_ownership;
 use crate::tools;
 use anyhow::Result;
 use clap::Parser;
-use std::path::PathBuf;
+use log::warn;
 
 #[derive(Debug, Parser)]
 pub(crate) struct BuildClean {
-    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
-    #[clap(long = "project-path")]
-    project_path: Option<PathBuf>,
+    #[clap(flatten)]
+    project_path: DeprecatedProjectPath,
 }
 
 impl BuildClean {
-    pub(super) async fn run(&self) -> Result<()> {
-        let project = project::load_or_find_project(self.project_path.clone()).await?;
-        let lock = Lock::load(&project).await?;
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let project = self.project_path.apply(global).load_project().await?;
+        let lock = global.load_lock(&project).await?;
+        // Wait for the builds that are running, so that their files are not removed under them.
+        let _clean_lock = global.lock_clean(&project).await?;
+        let _project_lock = global.lock_project(&project).await?;
         let toolsdir = project.project_dir().join("build/tools");
-        tools::install_tools(&toolsdir).await?;
+        // The clean tasks only need Makefile.toml, and they remove the tools afterwards anyway.
+        tools::install_components(&toolsdir, &[tools::SCRIPTS]).await?;
         let makefile_path = toolsdir.join("Makefile.toml");
+        // Files left behind by an earlier build may belong to root and fail the clean.
+        if let Err(e) = fix_ownership(&project, &lock.sdk.source).await {
+            warn!("{:#}", e);
+        }
 
         CargoMake::new(&lock.sdk.source)?
-            .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
+            .env("TWOLITER_TOOLS_DIR", path_var(&toolsdir)?)
             .makefile(makefile_path)
             .project_dir(project.project_dir())
+            .bootstrap_tools(global.bootstrap_tools())
             .exec("clean")
             .await?;
 
@@ -0,0 +1,504 @@
+use crate::cmd::kit_schedule::KitStatus;
+use crate::cmd::output::{self, OutputFormat};
+use crate::common::fs;
+use crate::logging::log_with;
+use crate::style::{self, Styler, ERROR, HEADING, SUCCESS, WARNING};
+use anyhow::{Context, Result};
+use async_walkdir::WalkDir;
+use futures::StreamExt;
+use log::{info, Level};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Prints a summary as `text`, which is logged at info level, or as a `json` object or `toml` on
+/// standard output. TOML has no null, so the fields without a value are left out of it.
+fn print_summary(
+    format: OutputFormat,
+    value: serde_json::Value,
+    text: impl FnOnce() -> String,
+) -> Result<()> {
+    match format {
+        OutputFormat::Text => info!("{}", text().trim_end()),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string(&value).context("Unable to serialize the summary")?
+        ),
+        OutputFormat::Toml => {
+            output::print(format, "summary", &without_nulls(value), |_| String::new())?
+        }
+    }
+    Ok(())
+}
+
+/// `value` without the fields of its objects that are null.
+fn without_nulls(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(fields) => fields
+            .into_iter()
+            .filter(|(_, value)| !value.is_null())
+            .map(|(key, value)| (key, without_nulls(value)))
+            .collect(),
+        serde_json::Value::Array(values) => values.into_iter().map(without_nulls).collect(),
+        value => value,
+    }
+}
+
+/// A short description of what a successful build produced and where to find it, printed after the
+/// output of `cargo make`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct BuildSummary {
+    /// What was built, e.g. `variant aws-dev`.
+    target: String,
+    arch: String,
+    elapsed: Duration,
+    /// The artifacts a build is expected to produce, with the path of each one that was found.
+    artifacts: Vec<(&'static str, Option<PathBuf>)>,
+    /// Where the build wrote its output.
+    output: PathBuf,
+    /// The SDK image that the build ran in, with the digest of the local image if it is known.
+    sdk: Option<String>,
+    /// The number of package RPMs written during the build.
+    packages_built: usize,
+}
+
+impl BuildSummary {
+    /// Looks for the RPM repository of a kit in `kit_dir`, e.g. `build/kits/<kit>/<arch>`.
+    pub(crate) fn kit(kit: &str, arch: &str, kit_dir: &Path, elapsed: Duration) -> Self {
+        let repomd = kit_dir.join("repodata").join("repomd.xml");
+        let repo = repomd.is_file().then(|| kit_dir.to_path_buf());
+        Self {
+            target: format!("kit {}", kit),
+            arch: arch.to_string(),
+            elapsed,
+            artifacts: vec![("RPM repo", repo)],
+            output: kit_dir.to_path_buf(),
+            sdk: None,
+            packages_built: 0,
+        }
+    }
+
+    /// Looks for the key artifacts of a variant in `images_dir`, e.g.
+    /// `build/images/<arch>-<variant>/latest`.
+    pub(crate) async fn variant(
+        variant: &str,
+        arch: &str,
+        images_dir: &Path,
+        elapsed: Duration,
+    ) -> Result<Self> {
+        let files = if images_dir.is_dir() {
+            list_files(images_dir).await?
+        } else {
+            Vec::new()
+        };
+        let find = |matches: fn(&str) -> bool| {
+            files
+                .iter()
+                .find(|name| matches(name))
+                .map(|name| images_dir.join(name))
+        };
+        Ok(Self {
+            target: format!("variant {}", variant),
+            arch: arch.to_string(),
+            elapsed,
+            artifacts: vec![
+                ("disk image", find(is_os_image)),
+                ("migrations", find(|name| name.ends_with("-migrations.tar"))),
+                (
+                    "kmod kit",
+                    find(|name| name.contains("-kmod-kit-v") && name.ends_with(".tar.xz")),
+                ),
+            ],
+            output: images_dir.to_path_buf(),
+            sdk: None,
+            packages_built: 0,
+        })
+    }
+
+    /// Records the SDK that the build ran in, with the digest of the local image, see
+    /// [`local_digest`](crate::lock::local_digest).
+    pub(crate) fn sdk(mut self, source: &str, digest: Option<&str>) -> Self {
+        self.sdk = Some(match digest {
+            Some(digest) => format!("{}@{}", source, digest),
+            None => source.to_string(),
+        });
+        self
+    }
+
+    /// Counts the package RPMs in `packages_dir`, e.g. `build/rpms`, that were written at or after
+    /// `started`.
+    pub(crate) async fn packages_built(
+        mut self,
+        packages_dir: &Path,
+        started: SystemTime,
+    ) -> Result<Self> {
+        self.packages_built = 0;
+        if !packages_dir.is_dir() {
+            return Ok(self);
+        }
+        let mut entries = WalkDir::new(packages_dir);
+        while let Some(entry) = entries.next().await {
+            let entry = entry.context(format!(
+                "Unable to list the files in '{}'",
+                packages_dir.display()
+            ))?;
+            if !entry.file_name().to_string_lossy().ends_with(".rpm") {
+                continue;
+            }
+            let modified = fs::metadata(entry.path()).await?.modified();
+            if modified.is_ok_and(|modified| modified >= started) {
+                self.packages_built += 1;
+            }
+        }
+        Ok(self)
+    }
+
+    /// Prints the summary in `format` and warns about expected artifacts that are missing, which
+    /// usually means that the build is partially misconfigured.
+    pub(crate) fn print(&self, format: OutputFormat) -> Result<()> {
+        print_summary(format, self.json(), || self.render(style::stderr()))?;
+        for (label, _) in self.artifacts.iter().filter(|(_, path)| path.is_none()) {
+            log_with!(
+                Level::Warn,
+                { build = self.target, arch = self.arch, artifact = label },
+                "The build of {} succeeded but no {} was found",
+                self.target,
+                label
+            );
+        }
+        Ok(())
+    }
+
+    fn json(&self) -> serde_json::Value {
+        let artifacts: serde_json::Map<_, _> = self
+            .artifacts
+            .iter()
+            .map(|(label, path)| (label.to_string(), json!(path)))
+            .collect();
+        json!({
+            "target": self.target,
+            "arch": self.arch,
+            "sdk": self.sdk,
+            "packages-built": self.packages_built,
+            "elapsed-seconds": self.elapsed.as_secs(),
+            "output": self.output,
+            "artifacts": artifacts,
+        })
+    }
+}
+
+impl BuildSummary {
+    /// The summary as lines of text, styled with `styler`.
+    fn render(&self, styler: Styler) -> String {
+        let mut text = format!(
+            "{} {} for {} in {}\n",
+            styler.paint(SUCCESS, "Built"),
+            styler.paint(HEADING, &self.target),
+            self.arch,
+            format_elapsed(self.elapsed)
+        );
+        text += &format!("  output: {}\n", self.output.display());
+        if let Some(sdk) = &self.sdk {
+            text += &format!("  SDK: {}\n", sdk);
+        }
+        text += &format!("  packages built: {}\n", self.packages_built);
+        for (label, path) in &self.artifacts {
+            match path {
+                Some(path) => text += &format!("  {}: {}\n", label, path.display()),
+                None => text += &format!("  {}: {}\n", label, styler.paint(WARNING, "missing")),
+            }
+        }
+        text
+    }
+}
+
+impl Display for BuildSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.render(Styler::new(false)))
+    }
+}
+
+/// How each kit fared when `build kits` built several kits at the same time.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct KitsSummary {
+    arch: String,
+    elapsed: Duration,
+    kits: Vec<(String, KitStatus)>,
+}
+
+impl KitsSummary {
+    pub(crate) fn new(arch: &str, elapsed: Duration, kits: &BTreeMap<String, KitStatus>) -> Self {
+        Self {
+            arch: arch.to_string(),
+            elapsed,
+            kits: kits
+                .iter()
+                .map(|(kit, status)| (kit.clone(), *status))
+                .collect(),
+        }
+    }
+
+    /// Prints the summary in `format`.
+    pub(crate) fn print(&self, format: OutputFormat) -> Result<()> {
+        print_summary(format, self.json(), || self.render(style::stderr()))?;
+        Ok(())
+    }
+
+    fn json(&self) -> serde_json::Value {
+        let kits: Vec<_> = self
+            .kits
+            .iter()
+            .map(|(kit, status)| {
+                let elapsed = match status {
+                    KitStatus::Built(elapsed) | KitStatus::Failed(elapsed) => {
+                        Some(elapsed.as_secs())
+                    }
+                    _ => None,
+                };
+                json!({
+                    "kit": kit,
+                    "status": status.to_string(),
+                    "elapsed-seconds": elapsed,
+                })
+            })
+            .collect();
+        json!({
+            "arch": self.arch,
+            "elapsed-seconds": self.elapsed.as_secs(),
+            "kits": kits,
+        })
+    }
+}
+
+impl KitsSummary {
+    /// The summary as lines of text, styled with `styler`.
+    fn render(&self, styler: Styler) -> String {
+        let mut text = format!(
+            "Finished building kits for {} in {}\n",
+            self.arch,
+            format_elapsed(self.elapsed)
+        );
+        for (kit, status) in &self.kits {
+            let kit = styler.paint(HEADING, kit);
+            let status = match status {
+                KitStatus::Built(elapsed) => styler
+                    .paint(SUCCESS, format!("built in {}", format_elapsed(*elapsed)))
+                    .to_string(),
+                KitStatus::Failed(elapsed) => styler
+                    .paint(ERROR, format!("failed after {}", format_elapsed(*elapsed)))
+                    .to_string(),
+                KitStatus::Skipped => styler
+                    .paint(WARNING, "skipped, a kit that it depends on failed")
+                    .to_string(),
+                status => status.to_string(),
+            };
+            text += &format!("  {}: {}\n", kit, status);
+        }
+        text
+    }
+}
+
+impl Display for KitsSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.render(Styler::new(false)))
+    }
+}
+
+/// The names of the regular files in `dir`, sorted. Symlinks are skipped because the images
+/// directory holds unversioned links alongside each versioned file.
+async fn list_files(dir: &Path) -> Result<Vec<String>> {
+    let context = || format!("Unable to read directory '{}'", dir.display());
+    let mut names = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await.with_context(context)?;
+    while let Some(entry) = entries.next_entry().await.with_context(context)? {
+        let metadata = fs::symlink_metadata(entry.path()).await?;
+        if metadata.is_file() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// The OS disk image, as opposed to the data image, in any of the formats that buildsys writes.
+fn is_os_image(name: &str) -> bool {
+    let image = [".img", ".img.lz4", ".vmdk", ".qcow2"]
+        .iter()
+        .any(|extension| name.ends_with(extension));
+    image && !name.contains("-data.")
+}
+
+fn format_elapsed(elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs();
+    match (seconds / 3600, seconds % 3600 / 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, s) => format!("{}h {}m {}s", h, m, s),
+    }
+}
+
+#[tokio::test]
+async fn test_variant_summary() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let name = "bottlerocket-aws-dev-x86_64-1.20.0-abcdef";
+    for file in [
+        format!("{}.img.lz4", name),
+        format!("{}-data.img.lz4", name),
+        format!("{}-migrations.tar", name),
+    ] {
+        fs::write(dir.join(file), "").await.unwrap();
+    }
+    std::os::unix::fs::symlink(
+        dir.join(format!("{}.img.lz4", name)),
+        dir.join("bottlerocket-aws-dev-x86_64.img.lz4"),
+    )
+    .unwrap();
+
+    let summary = BuildSummary::variant("aws-dev", "x86_64", dir, Duration::from_secs(754))
+        .await
+        .unwrap();
+    assert_eq!(
+        summary.artifacts,
+        [
+            ("disk image", Some(dir.join(format!("{}.img.lz4", name)))),
+            (
+                "migrations",
+                Some(dir.join(format!("{}-migrations.tar", name)))
+            ),
+            ("kmod kit", None),
+        ]
+    );
+    let text = summary.to_string();
+    assert!(text.starts_with("Built variant aws-dev for x86_64 in 12m 34s\n"));
+    assert!(text.ends_with("  kmod kit: missing\n"));
+    assert!(!text.contains('\x1b'));
+
+    let colored = summary.render(Styler::new(true));
+    assert!(colored.starts_with("\x1b[32mBuilt\x1b[0m \x1b[1mvariant aws-dev\x1b[0m for x86_64"));
+    assert!(colored.ends_with("  kmod kit: \x1b[33mmissing\x1b[0m\n"));
+}
+
+#[tokio::test]
+async fn test_kit_summary() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let summary = BuildSummary::kit("core-kit", "aarch64", dir, Duration::from_secs(5));
+    assert_eq!(summary.artifacts, [("RPM repo", None)]);
+
+    fs::create_dir_all(dir.join("repodata")).await.unwrap();
+    fs::write(dir.join("repodata/repomd.xml"), "")
+        .await
+        .unwrap();
+    let summary = BuildSummary::kit("core-kit", "aarch64", dir, Duration::from_secs(5));
+    assert_eq!(summary.artifacts, [("RPM repo", Some(dir.to_path_buf()))]);
+}
+
+#[tokio::test]
+async fn test_summary_packages_and_json() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let packages_dir = temp_dir.path().join("rpms");
+    fs::create_dir_all(packages_dir.join("glibc"))
+        .await
+        .unwrap();
+    fs::write(packages_dir.join("glibc/old.x86_64.rpm"), "")
+        .await
+        .unwrap();
+    let old = std::fs::File::options()
+        .write(true)
+        .open(packages_dir.join("glibc/old.x86_64.rpm"))
+        .unwrap();
+    old.set_modified(SystemTime::UNIX_EPOCH).unwrap();
+    let started = SystemTime::now() - Duration::from_secs(1);
+    fs::write(packages_dir.join("glibc/new.x86_64.rpm"), "")
+        .await
+        .unwrap();
+    fs::write(packages_dir.join("glibc/new.spec"), "")
+        .await
+        .unwrap();
+
+    let summary = BuildSummary::kit(
+        "core-kit",
+        "x86_64",
+        temp_dir.path(),
+        Duration::from_secs(5),
+    )
+    .sdk("example.com/bottlerocket-sdk:v0.42.0", Some("sha256:abc"))
+    .packages_built(&packages_dir, started)
+    .await
+    .unwrap();
+    assert_eq!(summary.packages_built, 1);
+    let text = summary.to_string();
+    assert!(text.contains("  SDK: example.com/bottlerocket-sdk:v0.42.0@sha256:abc\n"));
+    assert!(text.contains("  packages built: 1\n"));
+
+    let json = summary.json();
+    assert_eq!(json["target"], "kit core-kit");
+    assert_eq!(json["packages-built"], 1);
+    assert_eq!(json["elapsed-seconds"], 5);
+    assert_eq!(
+        json["sdk"],
+        "example.com/bottlerocket-sdk:v0.42.0@sha256:abc"
+    );
+    assert_eq!(json["artifacts"]["RPM repo"], serde_json::Value::Null);
+
+    let summary = BuildSummary::kit("core-kit", "x86_64", temp_dir.path(), Duration::ZERO)
+        .sdk("example.com/bottlerocket-sdk:v0.42.0", None);
+    assert_eq!(
+        summary.sdk.as_deref(),
+        Some("example.com/bottlerocket-sdk:v0.42.0")
+    );
+}
+
+#[test]
+fn test_format_elapsed() {
+    assert_eq!(format_elapsed(Duration::from_millis(9_900)), "9s");
+    assert_eq!(format_elapsed(Duration::from_secs(61)), "1m 1s");
+    assert_eq!(format_elapsed(Duration::from_secs(3_723)), "1h 2m 3s");
+}
+
+#[test]
+fn test_kits_summary() {
+    let summary = KitsSummary::new(
+        "x86_64",
+        Duration::from_secs(90),
+        &BTreeMap::from([
+            (
+                "core-kit".to_string(),
+                KitStatus::Built(Duration::from_secs(60)),
+            ),
+            ("extra-1-kit".to_string(), KitStatus::UpToDate),
+            (
+                "extra-2-kit".to_string(),
+                KitStatus::Failed(Duration::from_secs(3)),
+            ),
+            ("extra-3-kit".to_string(), KitStatus::Skipped),
+        ]),
+    );
+    assert_eq!(
+        summary.to_string(),
+        "Finished building kits for x86_64 in 1m 30s\n  \
+        core-kit: built in 1m 0s\n  \
+        extra-1-kit: up to date\n  \
+        extra-2-kit: failed after 3s\n  \
+        extra-3-kit: skipped, a kit that it depends on failed\n"
+    );
+    assert!(!summary.render(Styler::new(false)).contains('\x1b'));
+    assert!(summary
+        .render(Styler::new(true))
+        .contains("\x1b[1mextra-2-kit\x1b[0m: \x1b[1m\x1b[31mfailed after 3s\x1b[0m\n"));
+    let json = summary.json();
+    assert_eq!(json["kits"][0]["status"], "built");
+    assert_eq!(json["kits"][0]["elapsed-seconds"], 60);
+    assert_eq!(json["kits"][1]["elapsed-seconds"], serde_json::Value::Null);
+
+    // TOML leaves out the elapsed time of the kits that were not built.
+    let toml = output::serialize(OutputFormat::Toml, "summary", &without_nulls(json), |_| {
+        String::new()
+    })
+    .unwrap();
+    assert!(toml.contains("[[kits]]\nelapsed-seconds = 60\nkit = \"core-kit\""));
+    assert!(toml.contains("[[kits]]\nkit = \"extra-1-kit\""));
+}
@@ -0,0 +1,120 @@
+use crate::cmd::{DeprecatedProjectPath, GlobalArgs};
+use crate::common::fs;
+use crate::project::{shared_cache_from_env, TWOLITER_SHARED_CACHE};
+use anyhow::{ensure, Context, Result};
+use async_walkdir::WalkDir;
+use clap::Parser;
+use futures::StreamExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Debug, Parser)]
+pub(crate) enum CacheCommand {
+    Prune(PruneCache),
+}
+
+impl CacheCommand {
+    pub(crate) async fn run(self, global: &GlobalArgs) -> Result<()> {
+        match self {
+            CacheCommand::Prune(command) => command.run(global).await,
+        }
+    }
+}
+
+/// Remove the least recently used entries from the shared cache until it fits in the given size.
+#[derive(Debug, Parser)]
+pub(crate) struct PruneCache {
+    #[clap(flatten)]
+    project_path: DeprecatedProjectPath,
+
+    /// The size in GB that the cache may take up once pruned.
+    #[clap(long = "max-size")]
+    max_size: f64,
+}
+
+impl PruneCache {
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        ensure!(
+            self.max_size >= 0.0,
+            "The maximum size must not be negative"
+        );
+        let dir = match shared_cache_from_env() {
+            Some(dir) => dir,
+            None => self
+                .project_path
+                .apply(global)
+                .load_project()
+                .await?
+                .shared_cache()
+                .context(format!(
+                    "No shared cache is configured, set {} or shared-cache in the [build] section \
+                    of Twoliter.toml",
+                    TWOLITER_SHARED_CACHE
+                ))?,
+        };
+        if !dir.is_dir() {
+            println!("The shared cache '{}' is empty", dir.display());
+            return Ok(());
+        }
+        let max_bytes = (self.max_size * 1_000_000_000.0) as u64;
+        let removed = prune(&dir, max_bytes).await?;
+        println!(
+            "Removed {} entries from the shared cache '{}'",
+            removed.len(),
+            dir.display()
+        );
+        Ok(())
+    }
+}
+
+/// Removes the least recently used files below `dir` until the rest take up no more than
+/// `max_bytes`, and returns the removed files. Files whose names start with `.` are writes in
+/// progress or locks and are left alone.
+async fn prune(dir: &Path, max_bytes: u64) -> Result<Vec<PathBuf>> {
+    let mut entries = Vec::new();
+    let mut walk = WalkDir::new(dir);
+    while let Some(entry) = walk.next().await {
+        let entry = entry.context(format!("Unable to list the files in '{}'", dir.display()))?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let metadata = fs::symlink_metadata(entry.path()).await?;
+        if metadata.is_file() {
+            let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            entries.push((used, metadata.len(), entry.path()));
+        }
+    }
+    entries.sort();
+
+    let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+    let mut removed = Vec::new();
+    for (_, size, path) in entries {
+        if total <= max_bytes {
+            break;
+        }
+        fs::remove_file(&path).await?;
+        total -= size;
+        removed.push(path);
+    }
+    Ok(removed)
+}
+
+#[tokio::test]
+async fn test_prune() {
+    use filetime::{set_file_mtime, FileTime};
+
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let dir = temp_dir.path().join("sources");
+    fs::create_dir_all(&dir).await.unwrap();
+    for (name, age) in [("a", 30), ("b", 10), ("c", 20), (".d.lock", 40)] {
+        let path = dir.join(name);
+        fs::write(&path, [0u8; 10]).await.unwrap();
+        set_file_mtime(&path, FileTime::from_unix_time(1_000_000 - age, 0)).unwrap();
+    }
+
+    let removed = prune(temp_dir.path(), 15).await.unwrap();
+    assert_eq!(removed, [dir.join("a"), dir.join("c")]);
+    assert!(dir.join("b").is_file());
+    assert!(dir.join(".d.lock").is_file());
+    assert!(prune(temp_dir.path(), 15).await.unwrap().is_empty());
+}
@@ -0,0 +1,152 @@
+use crate::cmd::{DeprecatedProjectPath, GlobalArgs};
+use crate::docker::docker;
+use crate::infra;
+use crate::kit::{self, KitManifest};
+use crate::lock::{Lock, LockedImage, TWOLITER_LOCK};
+use crate::project::Project;
+use crate::style::{self, SUCCESS, WARNING};
+use anyhow::{bail, Result};
+use clap::Parser;
+use semver::Version;
+use std::path::PathBuf;
+
+/// Check the project for problems that would otherwise only show up later, e.g. when publishing.
+/// All problems are reported at once.
+#[derive(Debug, Parser)]
+pub(crate) struct Check {
+    #[clap(flatten)]
+    project_path: DeprecatedProjectPath,
+
+    /// Only check the given Infra.toml, the way that `build variant --infra-toml` does before
+    /// building. This does not need a project, but relative key paths are resolved against the
+    /// project directory if there is one, and otherwise against the current directory.
+    #[clap(long = "infra")]
+    infra: Option<PathBuf>,
+
+    /// Also check that the host container images of each variant exist in their registries.
+    #[clap(long = "online")]
+    online: bool,
+}
+
+impl Check {
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let problems = match &self.infra {
+            Some(infra_toml) => {
+                // pubsys resolves key paths against the project, or here if there is none.
+                let project_dir = match self
+                    .project_path
+                    .apply(global)
+                    .load_project_unchecked()
+                    .await
+                {
+                    Ok(project) => project.project_dir(),
+                    Err(_) => std::env::current_dir()?,
+                };
+                infra::problems(infra_toml, &project_dir).await?
+            }
+            None => {
+                let project = self.project_path.apply(global).load_project().await?;
+                let mut problems = check_kit_versions(&project).await?;
+                if self.online {
+                    problems.extend(check_host_containers(&project).await?);
+                }
+                problems
+            }
+        };
+        let styler = style::stderr();
+        for problem in &problems {
+            eprintln!("{}", styler.paint(WARNING, problem));
+        }
+        if !problems.is_empty() {
+            bail!("Found {} problem(s)", problems.len());
+        }
+        println!("{}", style::stdout().paint(SUCCESS, "No problems found"));
+        Ok(())
+    }
+}
+
+/// Flags local kits whose version is lower than the version of the same kit in Twoliter.lock.
+async fn check_kit_versions(project: &Project) -> Result<Vec<String>> {
+    if !project.project_dir().join(TWOLITER_LOCK).exists() {
+        return Ok(Vec::new());
+    }
+    let lock = match Lock::load_existing(project).await {
+        Ok(lock) => lock,
+        Err(e) => return Ok(vec![format!("{:#}", e)]),
+    };
+    let mut problems = Vec::new();
+    let mut kits = Vec::new();
+    for name in kit::local_kits(project).await? {
+        match KitManifest::load(project, &name)
+            .await
+            .and_then(|manifest| manifest.version())
+        {
+            Ok(version) => kits.push((name, version)),
+            Err(e) => problems.push(format!("Kit '{}': {:#}", name, e)),
+        }
+    }
+    problems.extend(kit_version_problems(&kits, &lock.kit));
+    Ok(problems)
+}
+
+/// Flags host container images that cannot be found in their registries.
+async fn check_host_containers(project: &Project) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    for (variant, config) in project.variants() {
+        for (name, uri) in config.host_containers()? {
+            let uri = uri.to_string();
+            if let Err(e) = docker(
+                ["manifest", "inspect", uri.as_str()],
+                format!("Unable to find '{}'", uri),
+            )
+            .await
+            {
+                problems.push(format!(
+                    "Host container '{}' of variant '{}': {:#}",
+                    name, variant, e
+                ));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+fn kit_version_problems(kits: &[(String, Version)], locked: &[LockedImage]) -> Vec<String> {
+    kits.iter()
+        .filter_map(|(name, version)| {
+            let locked = locked.iter().find(|locked| &locked.name == name)?;
+            (version < &locked.version).then(|| {
+                format!(
+                    "Kit '{}' is at version {}, which is lower than version {} in {}, bump it \
+                    with 'twoliter kit bump {}' before publishing",
+                    name, version, locked.version, TWOLITER_LOCK, name
+                )
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_kit_version_problems() {
+    let locked = |name: &str, version: Version| LockedImage {
+        name: name.to_string(),
+        version,
+        vendor: "bottlerocket".to_string(),
+        source: format!("example.com/{}", name),
+        digest: String::new(),
+        manifest: Vec::new(),
+    };
+    let kits = [
+        ("core-kit".to_string(), Version::new(1, 0, 0)),
+        ("extra-kit".to_string(), Version::new(1, 0, 0)),
+        ("new-kit".to_string(), Version::new(0, 1, 0)),
+    ];
+    let locked = [
+        locked("core-kit", Version::new(1, 0, 0)),
+        locked("extra-kit", Version::new(1, 2, 0)),
+    ];
+    let problems = kit_version_problems(&kits, &locked);
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].contains("'extra-kit' is at version 1.0.0"));
+    assert!(problems[0].contains("version 1.2.0"));
+}
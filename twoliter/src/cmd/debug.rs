@@ -1,6 +1,15 @@
-use crate::tools::install_tools;
-use anyhow::Result;
+use crate::cargo_make::Explanation;
+use crate::checksums::{verify_checksums, SHA256SUMS};
+use crate::cmd::build::{BuildKit, BuildVariant};
+use crate::cmd::debug_sdk::DebugSdk;
+use crate::cmd::{ArchArg, DeprecatedProjectPath, GlobalArgs};
+use crate::common::fs;
+use crate::lock::Lock;
+use crate::project::TWOLITER_TMPDIR;
+use crate::tools::{install_tools, verify_tools};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
+use std::collections::BTreeMap;
 use std::env;
 use std::path::PathBuf;
 use uuid::Uuid;
@@ -14,12 +23,18 @@ pub(crate) struct Debug {
 #[derive(Debug, Clone, Parser)]
 pub(crate) enum DebugAction {
     CheckTools(CheckToolArgs),
+    Env(EnvArgs),
+    Sdk(DebugSdk),
+    VerifyArtifacts(VerifyArtifactsArgs),
 }
 
 impl DebugAction {
-    pub(crate) async fn run(&self) -> Result<()> {
+    pub(crate) async fn run(&self, global: &GlobalArgs) -> Result<()> {
         match self {
             DebugAction::CheckTools(c) => c.run().await,
+            DebugAction::Env(c) => c.run(global).await,
+            DebugAction::Sdk(c) => c.run(global).await,
+            DebugAction::VerifyArtifacts(c) => c.run().await,
         }
     }
 }
@@ -30,10 +45,16 @@ impl DebugAction {
 #[derive(Debug, Default, Clone, Parser)]
 pub(crate) struct CheckToolArgs {
     /// The directory where the tools will be installed (and left behind for your further
-    /// inspection). If not specified, a directory in the tempdir will be used. The directory will
-    /// be created if it does not exist. Outputs the name of the directory to stdout.
+    /// inspection). If not specified, a directory in TWOLITER_TMPDIR or the system tempdir will be
+    /// used. The directory will be created if it does not exist. Outputs the name of the directory
+    /// to stdout.
     #[clap(long)]
     install_dir: Option<PathBuf>,
+
+    /// Instead of installing, check every file of the tools already installed in `--install-dir`
+    /// and report any that are missing or have changed.
+    #[clap(long, requires = "install_dir")]
+    verify: bool,
 }
 
 fn unique_name() -> String {
@@ -44,12 +65,249 @@ fn unique_name() -> String {
 
 impl CheckToolArgs {
     pub(crate) async fn run(&self) -> Result<()> {
-        let dir = self
-            .install_dir
-            .clone()
-            .unwrap_or_else(|| env::temp_dir().join(unique_name()));
+        if let (true, Some(dir)) = (self.verify, &self.install_dir) {
+            let problems = verify_tools(dir, true).await?;
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if !problems.is_empty() {
+                bail!(
+                    "{} file(s) in '{}' did not match the install marker",
+                    problems.len(),
+                    dir.display()
+                );
+            }
+            println!("All tools in '{}' are intact", dir.display());
+            return Ok(());
+        }
+        let dir = self.install_dir.clone().unwrap_or_else(|| {
+            env::var_os(TWOLITER_TMPDIR)
+                .map(PathBuf::from)
+                .unwrap_or_else(env::temp_dir)
+                .join(unique_name())
+        });
         install_tools(&dir).await?;
         println!("{}", dir.display());
         Ok(())
     }
 }
+
+/// Prints the environment and command line that a build would pass to `cargo make`, sorted by key
+/// and with secret-looking values redacted. Nothing is installed and docker is not used, but
+/// `Twoliter.lock` must already exist.
+#[derive(Debug, Clone, Parser)]
+pub(crate) struct EnvArgs {
+    #[clap(flatten)]
+    project_path: DeprecatedProjectPath,
+
+    #[clap(flatten)]
+    arch: ArchArg,
+
+    /// Show the environment for building this variant.
+    #[clap(long, conflicts_with = "kit", required_unless_present = "kit")]
+    variant: Option<String>,
+
+    /// Show the environment for building this kit.
+    #[clap(long)]
+    kit: Option<String>,
+
+    /// A file containing previously saved output of this command. Instead of printing the
+    /// environment, print how it differs from the one in the file.
+    #[clap(long)]
+    diff: Option<PathBuf>,
+}
+
+impl EnvArgs {
+    pub(crate) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let project = self.project_path.apply(global).load_project().await?;
+        let lock = Lock::load_existing(&project).await?;
+        let explanation = match (&self.variant, &self.kit) {
+            (Some(variant), _) => BuildVariant::with_defaults(self.arch.get(), variant)
+                .cargo_make(&project, &lock, global.frozen())
+                .await?
+                .explain("build")?,
+            (None, Some(kit)) => BuildKit::with_defaults(self.arch.get(), kit)
+                .cargo_make(&project, &lock, global.frozen())
+                .await?
+                .explain("build-kit")?,
+            (None, None) => unreachable!("clap requires either --variant or --kit"),
+        }
+        .redacted();
+
+        match &self.diff {
+            None => print!("{}", render(&explanation)),
+            Some(path) => {
+                let saved = fs::read_to_string(path)
+                    .await
+                    .context("Unable to read the saved environment to compare against")?;
+                print!("{}", diff(&parse(&saved), &explanation.env));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks the files in a directory against the SHA256SUMS file written there by a build. Each
+/// missing or changed file is reported, and the command fails if there are any.
+#[derive(Debug, Clone, Parser)]
+pub(crate) struct VerifyArtifactsArgs {
+    /// The directory containing SHA256SUMS, e.g. build/kits/<kit>/<arch>.
+    dir: PathBuf,
+}
+
+impl VerifyArtifactsArgs {
+    pub(crate) async fn run(&self) -> Result<()> {
+        let mismatches = verify_checksums(&self.dir).await?;
+        for mismatch in &mismatches {
+            println!("{}", mismatch);
+        }
+        if !mismatches.is_empty() {
+            bail!(
+                "{} file(s) in '{}' did not match {}",
+                mismatches.len(),
+                self.dir.display(),
+                SHA256SUMS
+            );
+        }
+        println!("All files in '{}' match {}", self.dir.display(), SHA256SUMS);
+        Ok(())
+    }
+}
+
+/// Renders the explanation as `KEY=VALUE` lines followed by the command line as a comment, so that
+/// the output can be saved and given back to `--diff`.
+fn render(explanation: &Explanation) -> String {
+    let mut out = String::new();
+    for (key, value) in &explanation.env {
+        out.push_str(&format!("{}={}\n", key, value));
+    }
+    out.push_str(&format!("# cargo {}\n", explanation.args.join(" ")));
+    out
+}
+
+/// Parses the `KEY=VALUE` lines of previously rendered output, ignoring comments and blank lines.
+fn parse(saved: &str) -> BTreeMap<String, String> {
+    saved
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Describes how the `current` environment differs from the `saved` one.
+fn diff(saved: &BTreeMap<String, String>, current: &BTreeMap<String, String>) -> String {
+    let mut out = String::new();
+    for (key, old) in saved {
+        match current.get(key) {
+            None => out.push_str(&format!("- {}={}\n", key, old)),
+            Some(new) if new != old => {
+                out.push_str(&format!("~ {}: '{}' -> '{}'\n", key, old, new))
+            }
+            Some(_) => {}
+        }
+    }
+    for (key, new) in current {
+        if !saved.contains_key(key) {
+            out.push_str(&format!("+ {}={}\n", key, new));
+        }
+    }
+    if out.is_empty() {
+        out.push_str("No differences\n");
+    }
+    out
+}
+
+#[test]
+fn test_render_parse_round_trip() {
+    let explanation = Explanation {
+        env: BTreeMap::from([
+            ("BUILDSYS_ARCH".to_string(), "x86_64".to_string()),
+            ("GO_MODULES".to_string(), "a b=c".to_string()),
+        ]),
+        args: vec!["make".to_string(), "build".to_string()],
+    };
+    let rendered = render(&explanation);
+    assert!(rendered.ends_with("# cargo make build\n"));
+    assert_eq!(parse(&rendered), explanation.env);
+}
+
+#[test]
+fn test_diff() {
+    let saved = BTreeMap::from([
+        ("A".to_string(), "1".to_string()),
+        ("B".to_string(), "2".to_string()),
+        ("C".to_string(), "3".to_string()),
+    ]);
+    let current = BTreeMap::from([
+        ("A".to_string(), "1".to_string()),
+        ("B".to_string(), "two".to_string()),
+        ("D".to_string(), "4".to_string()),
+    ]);
+    assert_eq!(diff(&saved, &current), "~ B: '2' -> 'two'\n- C=3\n+ D=4\n");
+    assert_eq!(diff(&saved, &saved), "No differences\n");
+}
+
+/// The environment for a build is explained from a project whose path has spaces and non-ASCII
+/// characters in it, and each path reaches cargo make whole.
+#[tokio::test]
+async fn test_env_in_project_path_with_spaces() {
+    use crate::lock::LockedImage;
+    use crate::project::Project;
+    use crate::schema_version::SchemaVersion;
+
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let dir = temp_dir
+        .path()
+        .join("Shared Drive")
+        .join("prøjects ünïcode");
+    crate::test::copy_project("local-kit", &dir).await;
+    let project = Project::load(dir.join("Twoliter.toml")).await.unwrap();
+    let project_dir = project.project_dir().display().to_string();
+    assert!(project_dir.contains("Shared Drive/prøjects ünïcode"));
+    let lock = Lock {
+        schema_version: SchemaVersion,
+        release_version: "1.0.0".to_string(),
+        sdk: LockedImage {
+            name: "bottlerocket-sdk".to_string(),
+            version: semver::Version::new(1, 2, 3),
+            vendor: "bottlerocket".to_string(),
+            source: "public.ecr.aws/bottlerocket/bottlerocket-sdk:v1.2.3".to_string(),
+            digest: "abc=".to_string(),
+            manifest: Vec::new(),
+        },
+        kit: Vec::new(),
+        digest: "def=".to_string(),
+        go_modules: None,
+        host_containers: Default::default(),
+    };
+
+    let kit = BuildKit::with_defaults("x86_64", "core-kit")
+        .cargo_make(&project, &lock, false)
+        .await
+        .unwrap()
+        .explain("build-kit")
+        .unwrap();
+    let variant = BuildVariant::with_defaults("aarch64", "hello-ootb")
+        .cargo_make(&project, &lock, false)
+        .await
+        .unwrap()
+        .explain("build")
+        .unwrap();
+    for explanation in [kit, variant] {
+        assert!(explanation
+            .args
+            .windows(2)
+            .any(|w| w == ["--cwd", project_dir.as_str()]));
+        assert!(explanation.args.contains(&format!(
+            "-e=TWOLITER_TOOLS_DIR={}/build/tools",
+            project_dir
+        )));
+        assert_eq!(
+            explanation.env["TWOLITER_TOOLS_DIR"],
+            format!("{}/build/tools", project_dir)
+        );
+        // What `debug env` prints can be read back.
+        assert_eq!(parse(&render(&explanation)), explanation.env);
+    }
+}
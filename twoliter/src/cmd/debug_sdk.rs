@@ -0,0 +1,288 @@
+use super::output::{self, OutputFormat};
+use crate::cmd::{ArchArg, GlobalArgs};
+use crate::docker::docker;
+use crate::lock::{local_digest, LockedImage};
+use anyhow::{Context, Result};
+use clap::Parser;
+use futures::future::join_all;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+/// The file in the SDK image that describes the release of the SDK.
+const SDK_VERSION_FILE: &str = "/etc/os-release";
+
+/// Inspect the SDK that the project builds with: its digest, labels and size, the release that it
+/// describes itself as, and the versions of the toolchains inside it. The SDK is pulled first if it
+/// is not present locally. A toolchain that cannot be run is reported rather than stopping the rest.
+#[derive(Debug, Clone, Parser)]
+pub(crate) struct DebugSdk {
+    #[clap(flatten)]
+    arch: ArchArg,
+
+    /// Print the report as JSON, the same as the global `--format json`.
+    #[clap(long)]
+    json: bool,
+}
+
+impl DebugSdk {
+    pub(crate) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let project = global.load_project().await?;
+        let lock = global.load_lock(&project).await?;
+        lock.ensure_sdk(self.arch.get(), global.frozen(), global.images())
+            .await?;
+        let image = global
+            .images()
+            .inspect(&lock.sdk.source)
+            .await?
+            .context(format!(
+                "The SDK image {} is not present locally",
+                lock.sdk.source
+            ))?;
+
+        let version_file = Probe::run(
+            &lock.sdk.source,
+            "version file",
+            vec!["cat".to_string(), SDK_VERSION_FILE.to_string()],
+        );
+        let toolchains = join_all(
+            toolchain_commands(self.arch.get())
+                .into_iter()
+                .map(|(name, command)| Probe::run(&lock.sdk.source, name, command)),
+        );
+        let (version_file, toolchains) = futures::join!(version_file, toolchains);
+        let digest = local_digest(&lock.sdk.source, global.images()).await?;
+        let report = SdkReport::new(&lock.sdk, &digest, &image, version_file, toolchains);
+
+        let format = if self.json {
+            OutputFormat::Json
+        } else {
+            global.format()
+        };
+        output::print(format, "sdk", &report, ToString::to_string)
+    }
+}
+
+/// The commands that print the version of each toolchain in the SDK for `arch`, by name.
+fn toolchain_commands(arch: &str) -> Vec<(&'static str, Vec<String>)> {
+    [
+        (
+            "gcc",
+            vec![
+                format!("{}-bottlerocket-linux-gnu-gcc", arch),
+                "--version".to_string(),
+            ],
+        ),
+        ("rustc", vec!["rustc".to_string(), "--version".to_string()]),
+        ("cargo", vec!["cargo".to_string(), "--version".to_string()]),
+        ("go", vec!["go".to_string(), "version".to_string()]),
+    ]
+    .into()
+}
+
+/// What was found in the SDK image.
+#[derive(Debug, Clone, Serialize)]
+struct SdkReport {
+    /// The image as it is named in Twoliter.lock.
+    image: String,
+    version: String,
+    /// The repo digest of the image that is present locally, or its ID if it was never pulled.
+    digest: String,
+    /// The ID of the image that is present locally.
+    id: String,
+    /// The size of the image in bytes.
+    size: u64,
+    labels: BTreeMap<String, String>,
+    /// The contents of the file that describes the release of the SDK.
+    version_file: Probe,
+    toolchains: Vec<Probe>,
+}
+
+impl SdkReport {
+    /// The report for the `sdk` with `digest` that `docker image inspect` described as `image`.
+    fn new(
+        sdk: &LockedImage,
+        digest: &str,
+        image: &Value,
+        version_file: Probe,
+        toolchains: Vec<Probe>,
+    ) -> Self {
+        let labels = image["Config"]["Labels"]
+            .as_object()
+            .map(|labels| {
+                labels
+                    .iter()
+                    .map(|(key, value)| {
+                        let text = value.as_str().map(str::to_string);
+                        (key.clone(), text.unwrap_or_else(|| value.to_string()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            image: sdk.source.clone(),
+            version: sdk.version.to_string(),
+            digest: digest.to_string(),
+            id: image["Id"].as_str().unwrap_or_default().to_string(),
+            size: image["Size"].as_u64().unwrap_or_default(),
+            labels,
+            version_file,
+            toolchains,
+        }
+    }
+}
+
+impl Display for SdkReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "SDK:       {}", self.image)?;
+        writeln!(f, "Version:   {}", self.version)?;
+        writeln!(f, "Digest:    {}", self.digest)?;
+        writeln!(f, "Image ID:  {}", self.id)?;
+        writeln!(f, "Size:      {:.2} GB", self.size as f64 / 1_000_000_000.0)?;
+        writeln!(f, "Labels:")?;
+        if self.labels.is_empty() {
+            writeln!(f, "  (none)")?;
+        }
+        for (key, value) in &self.labels {
+            writeln!(f, "  {}={}", key, value)?;
+        }
+        writeln!(f, "Version file {}:", SDK_VERSION_FILE)?;
+        match (&self.version_file.output, &self.version_file.error) {
+            (Some(output), _) => {
+                for line in output.lines() {
+                    writeln!(f, "  {}", line)?;
+                }
+            }
+            (None, error) => {
+                writeln!(f, "  unavailable: {}", error.as_deref().unwrap_or_default())?
+            }
+        }
+        writeln!(f, "Toolchains:")?;
+        let width = self
+            .toolchains
+            .iter()
+            .map(|probe| probe.name.len())
+            .max()
+            .unwrap_or(0);
+        for probe in &self.toolchains {
+            let found = match (&probe.output, &probe.error) {
+                (Some(output), _) => output.lines().next().unwrap_or_default().to_string(),
+                (None, error) => format!("unavailable: {}", error.as_deref().unwrap_or_default()),
+            };
+            writeln!(f, "  {:width$}  {}", probe.name, found)?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of running a command in the SDK.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+struct Probe {
+    name: String,
+    command: Vec<String>,
+    /// What the command printed, if it succeeded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    /// Why the command failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Probe {
+    /// Runs `command` in a throwaway container of `sdk`.
+    async fn run(sdk: &str, name: &str, command: Vec<String>) -> Self {
+        let mut args = vec![
+            "run".to_string(),
+            "--rm".to_string(),
+            "--network=none".to_string(),
+            format!("--entrypoint={}", command[0]),
+            sdk.to_string(),
+        ];
+        args.extend(command[1..].iter().cloned());
+        let result = docker(
+            args,
+            format!("Unable to run '{}' in the SDK", command.join(" ")),
+        )
+        .await;
+        Self::new(name, command, result)
+    }
+
+    fn new(name: &str, command: Vec<String>, result: Result<Vec<u8>>) -> Self {
+        let (output, error) = match result {
+            Ok(stdout) => (
+                Some(String::from_utf8_lossy(&stdout).trim().to_string()),
+                None,
+            ),
+            Err(e) => (None, Some(format!("{:#}", e))),
+        };
+        Self {
+            name: name.to_string(),
+            command,
+            output,
+            error,
+        }
+    }
+}
+
+#[test]
+fn test_sdk_report() {
+    let sdk = LockedImage {
+        name: "bottlerocket-sdk".to_string(),
+        version: semver::Version::new(0, 50, 0),
+        vendor: "bottlerocket".to_string(),
+        source: "public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0".to_string(),
+        digest: "abc=".to_string(),
+        manifest: Vec::new(),
+    };
+    let image = serde_json::json!({
+        "Id": "sha256:1234",
+        "Size": 2_500_000_000u64,
+        "Config": { "Labels": { "org.opencontainers.image.version": "v0.50.0" } },
+    });
+    let toolchains: Vec<Probe> = toolchain_commands("aarch64")
+        .into_iter()
+        .map(|(name, command)| {
+            let result = match name {
+                "go" => Err(anyhow::anyhow!("executable file not found")),
+                _ => Ok(format!("{} 1.2.3\nCopyright\n", command[0]).into_bytes()),
+            };
+            Probe::new(name, command, result)
+        })
+        .collect();
+    assert_eq!(
+        toolchains[0].command[0],
+        "aarch64-bottlerocket-linux-gnu-gcc"
+    );
+    let version_file = Probe::new(
+        "version file",
+        vec!["cat".to_string(), SDK_VERSION_FILE.to_string()],
+        Ok(b"NAME=Bottlerocket SDK\nVERSION_ID=0.50.0\n".to_vec()),
+    );
+    let report = SdkReport::new(&sdk, "sha256:abcd", &image, version_file, toolchains);
+
+    let text = report.to_string();
+    for expected in [
+        "SDK:       public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0",
+        "Digest:    sha256:abcd\n",
+        "Size:      2.50 GB",
+        "  org.opencontainers.image.version=v0.50.0",
+        "  VERSION_ID=0.50.0",
+        "  gcc    aarch64-bottlerocket-linux-gnu-gcc 1.2.3\n",
+        "  go     unavailable: executable file not found",
+    ] {
+        assert!(text.contains(expected), "'{}' not in:\n{}", expected, text);
+    }
+
+    let json: Value = serde_json::from_str(
+        &output::serialize(OutputFormat::Json, "sdk", &report, ToString::to_string).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(json["digest"], "sha256:abcd");
+    assert_eq!(json["id"], "sha256:1234");
+    assert_eq!(json["size"], 2_500_000_000u64);
+    assert_eq!(json["toolchains"][1]["output"], "rustc 1.2.3\nCopyright");
+    assert_eq!(json["toolchains"][3]["error"], "executable file not found");
+    assert!(json["toolchains"][3].get("output").is_none());
+    assert_eq!(json["version_file"]["command"][1], SDK_VERSION_FILE);
+}
@@ -0,0 +1,257 @@
+use crate::cmd::{DeprecatedProjectPath, GlobalArgs};
+use crate::common::exec;
+use crate::docker::{docker, ImageInspector};
+use crate::filesystem;
+use crate::lock::{Lock, TWOLITER_LOCK};
+use crate::project::Project;
+use crate::tools::install_tools;
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use semver::Version;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// Docker enables BuildKit by default starting with this version.
+const BUILDKIT_DEFAULT_DOCKER_VERSION: Version = Version::new(23, 0, 0);
+
+/// Builds are likely to run out of space with less free disk than this.
+const RECOMMENDED_FREE_DISK_GIB: u64 = 50;
+
+/// Check that this machine is ready to build the project and print a report. Exits with an error
+/// if any required check fails.
+#[derive(Debug, Parser)]
+pub(crate) struct Doctor {
+    #[clap(flatten)]
+    project_path: DeprecatedProjectPath,
+}
+
+impl Doctor {
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let mut report = Report::default();
+        report.add("docker", check_docker().await);
+        let project = self.project_path.apply(global).load_project().await;
+        let dir = match &project {
+            Ok(project) => project.project_dir(),
+            Err(_) => PathBuf::from("."),
+        };
+        report.add("free disk", check_free_disk(&dir).await);
+        report.add("filesystem", Ok(check_filesystem(&dir).await));
+        match &project {
+            Ok(project) => {
+                report.add(
+                    "Twoliter.toml",
+                    Ok(Finding::pass(project.filepath().display())),
+                );
+                report.add("SDK", check_sdk(project, global.images()).await);
+                report.add("tools", check_tools(project).await);
+            }
+            Err(e) => report.add("Twoliter.toml", Ok(Finding::fail(format!("{:#}", e)))),
+        }
+        print!("{}", report);
+        let failed = report.failed();
+        if failed > 0 {
+            bail!("{} required check(s) failed", failed);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Status {
+    Pass,
+    /// Something that may cause trouble but does not stop a build.
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Finding {
+    status: Status,
+    detail: String,
+}
+
+impl Finding {
+    fn pass(detail: impl Display) -> Self {
+        Self {
+            status: Status::Pass,
+            detail: detail.to_string(),
+        }
+    }
+
+    fn warn(detail: impl Display) -> Self {
+        Self {
+            status: Status::Warn,
+            detail: detail.to_string(),
+        }
+    }
+
+    fn fail(detail: impl Display) -> Self {
+        Self {
+            status: Status::Fail,
+            detail: detail.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    findings: Vec<(&'static str, Finding)>,
+}
+
+impl Report {
+    /// Adds the result of a check. A check that returns an error has failed.
+    fn add(&mut self, name: &'static str, result: Result<Finding>) {
+        let finding = result.unwrap_or_else(|e| Finding::fail(format!("{:#}", e)));
+        self.findings.push((name, finding));
+    }
+
+    fn failed(&self) -> usize {
+        self.findings
+            .iter()
+            .filter(|(_, finding)| finding.status == Status::Fail)
+            .count()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (name, finding) in &self.findings {
+            let status = match finding.status {
+                Status::Pass => "PASS",
+                Status::Warn => "WARN",
+                Status::Fail => "FAIL",
+            };
+            writeln!(f, "[{}] {}: {}", status, name, finding.detail.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// Docker must be installed, the daemon must be running, and BuildKit must be available.
+async fn check_docker() -> Result<Finding> {
+    let version = docker(
+        ["version", "--format", "{{.Server.Version}}"],
+        "Unable to reach the docker daemon",
+    )
+    .await?;
+    let version = String::from_utf8_lossy(&version).trim().to_string();
+    if has_default_buildkit(&version) {
+        return Ok(Finding::pass(format!("version {}", version)));
+    }
+    Ok(
+        match docker(["buildx", "version"], "Unable to run docker buildx").await {
+            Ok(_) => Finding::pass(format!("version {} with buildx", version)),
+            Err(_) => Finding::fail(format!(
+                "version {} does not use BuildKit by default and buildx is not installed, please \
+                upgrade to docker {} or later",
+                version, BUILDKIT_DEFAULT_DOCKER_VERSION
+            )),
+        },
+    )
+}
+
+/// Whether a docker server `version` such as `24.0.5` builds with BuildKit by default.
+fn has_default_buildkit(version: &str) -> bool {
+    // Some distributions append their own suffix, e.g. `20.10.25+dfsg1`.
+    let version = version.split(['+', '-']).next().unwrap_or_default();
+    Version::parse(version).is_ok_and(|v| v >= BUILDKIT_DEFAULT_DOCKER_VERSION)
+}
+
+/// A filesystem that is known to break builds is only a warning, as it is for `twoliter build`
+/// without `--strict-preflight`.
+async fn check_filesystem(dir: &Path) -> Finding {
+    let problems = filesystem::problems(&[dir.to_path_buf()]).await;
+    match problems.first() {
+        Some(problem) => Finding::warn(problem),
+        None => Finding::pass("no known problems"),
+    }
+}
+
+async fn check_free_disk(dir: &Path) -> Result<Finding> {
+    let output = exec(Command::new("df").arg("-Pk").arg(dir), true)
+        .await?
+        .unwrap_or_default();
+    let gib = available_kib(&output)? / (1024 * 1024);
+    Ok(if gib < RECOMMENDED_FREE_DISK_GIB {
+        Finding::warn(format!(
+            "{} GiB available in '{}', builds may need {} GiB or more",
+            gib,
+            dir.display(),
+            RECOMMENDED_FREE_DISK_GIB
+        ))
+    } else {
+        Finding::pass(format!("{} GiB available", gib))
+    })
+}
+
+/// Parses the available space in KiB from the output of `df -Pk`.
+fn available_kib(df: &str) -> Result<u64> {
+    let line = df.lines().nth(1).context("Unexpected output from 'df'")?;
+    line.split_whitespace()
+        .nth(3)
+        .and_then(|available| available.parse().ok())
+        .context(format!("Unable to parse the output of 'df': {}", line))
+}
+
+/// The SDK from `Twoliter.lock` must be present locally or reachable in its registry.
+async fn check_sdk(project: &Project, images: &ImageInspector) -> Result<Finding> {
+    if !project.project_dir().join(TWOLITER_LOCK).exists() {
+        return Ok(Finding::warn(format!(
+            "{} not found, run 'twoliter update' to resolve the SDK",
+            TWOLITER_LOCK
+        )));
+    }
+    let sdk = Lock::load_existing(project).await?.sdk.source;
+    if images.exists(&sdk).await.unwrap_or(false) {
+        return Ok(Finding::pass(format!("{} is present locally", sdk)));
+    }
+    docker(
+        ["manifest", "inspect", &sdk],
+        format!("Unable to reach the SDK '{}'", sdk),
+    )
+    .await?;
+    Ok(Finding::pass(format!("{} is reachable", sdk)))
+}
+
+/// The embedded tools must be installable into the project's temporary directory.
+async fn check_tools(project: &Project) -> Result<Finding> {
+    let temp_dir = project.temp_dir().await?;
+    install_tools(temp_dir.path()).await?;
+    Ok(Finding::pass(format!(
+        "installed to a temporary directory in '{}'",
+        project.temp_dir_base().display()
+    )))
+}
+
+#[test]
+fn test_has_default_buildkit() {
+    assert!(has_default_buildkit("24.0.5"));
+    assert!(has_default_buildkit("23.0.0"));
+    assert!(!has_default_buildkit("20.10.25+dfsg1"));
+    assert!(!has_default_buildkit("20.10.7-ce"));
+    assert!(!has_default_buildkit("unknown"));
+}
+
+#[test]
+fn test_available_kib() {
+    let df = "Filesystem     1024-blocks      Used Available Capacity Mounted on\n\
+              /dev/nvme0n1p1   104845292  52345678  52499614      50% /\n";
+    assert_eq!(available_kib(df).unwrap(), 52499614);
+    assert!(available_kib("Filesystem\n").is_err());
+}
+
+#[test]
+fn test_report() {
+    let mut report = Report::default();
+    report.add("docker", Ok(Finding::pass("version 24.0.5")));
+    report.add("free disk", Ok(Finding::warn("10 GiB available")));
+    report.add("SDK", Err(anyhow::anyhow!("unreachable")));
+    assert_eq!(report.failed(), 1);
+    assert_eq!(
+        report.to_string(),
+        "[PASS] docker: version 24.0.5\n\
+         [WARN] free disk: 10 GiB available\n\
+         [FAIL] SDK: unreachable\n"
+    );
+}
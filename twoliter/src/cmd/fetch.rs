@@ -1,24 +1,38 @@
+use crate::cmd::{ArchArg, DeprecatedProjectPath, GlobalArgs};
 use crate::lock::Lock;
-use crate::project;
 use anyhow::Result;
 use clap::Parser;
-use std::path::PathBuf;
 
+/// Pull the SDK and fetch the external kits that a build for `arch` needs, so that the build can
+/// then run offline. Prints each image with the digest it resolved to.
 #[derive(Debug, Parser)]
 pub(crate) struct Fetch {
-    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
-    #[clap(long = "project-path")]
-    pub(crate) project_path: Option<PathBuf>,
+    #[clap(flatten)]
+    pub(crate) project_path: DeprecatedProjectPath,
 
-    #[clap(long = "arch", default_value = "x86_64")]
-    pub(crate) arch: String,
+    #[clap(flatten)]
+    pub(crate) arch: ArchArg,
+
+    /// Fetch the SDK and the kits from this registry instead of their vendors', e.g. to try staged
+    /// or mirrored images. Neither Twoliter.toml nor Twoliter.lock is changed.
+    #[clap(long = "registry")]
+    pub(crate) registry: Option<String>,
 }
 
 impl Fetch {
-    pub(super) async fn run(&self) -> Result<()> {
-        let project = project::load_or_find_project(self.project_path.clone()).await?;
-        let lock_file = Lock::load(&project).await?;
-        lock_file.fetch(&project, self.arch.as_str()).await?;
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        global.ensure_not_frozen("fetch images")?;
+        let project = self.project_path.apply(global).load_project().await?;
+        let mut lock_file = Lock::load(&project).await?;
+        if let Some(registry) = &self.registry {
+            lock_file = lock_file.with_registry(registry);
+        }
+        for image in lock_file
+            .fetch(&project, self.arch.get(), global.images())
+            .await?
+        {
+            println!("{}", image);
+        }
         Ok(())
     }
 }
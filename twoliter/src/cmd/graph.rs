@@ -0,0 +1,38 @@
+use super::output::OutputFormat;
+use crate::cmd::GlobalArgs;
+use crate::graph::{DependencyGraph, GraphFormat};
+use anyhow::Result;
+use clap::Parser;
+
+/// Show which variants, kits and packages depend on which, including the external kits in
+/// Twoliter.lock. Dependencies that cannot be found and cycles are marked rather than being errors.
+/// This only reads the project and does not need docker or network access.
+#[derive(Debug, Parser)]
+pub(crate) struct Graph {
+    /// The format to write the graph in. Defaults to `dot`, or to `json` or `toml` when the global
+    /// `--format` asks for one of those.
+    #[clap(long = "format", value_enum)]
+    format: Option<GraphFormat>,
+
+    /// Only show this variant, kit or package, what it depends on and what depends on it. External
+    /// kits are named `<vendor>/<name>`.
+    #[clap(long = "focus")]
+    focus: Option<String>,
+}
+
+impl Graph {
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let project = global.load_project().await?;
+        let mut graph = DependencyGraph::load(&project).await?;
+        if let Some(focus) = &self.focus {
+            graph = graph.focus(focus)?;
+        }
+        let format = self.format.unwrap_or(match global.format() {
+            OutputFormat::Text => GraphFormat::Dot,
+            OutputFormat::Json => GraphFormat::Json,
+            OutputFormat::Toml => GraphFormat::Toml,
+        });
+        print!("{}", graph.render(format)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use crate::cmd::GlobalArgs;
+use crate::common::fs;
+use crate::tools::{export_tools, install_tools, tool_names};
+use anyhow::Result;
+use clap::Parser;
+use log::info;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Parser)]
+pub(crate) enum InstallCommand {
+    Tools(InstallTools),
+}
+
+impl InstallCommand {
+    pub(crate) async fn run(self, global: &GlobalArgs) -> Result<()> {
+        match self {
+            InstallCommand::Tools(command) => command.run(global).await,
+        }
+    }
+}
+
+/// Install the tools that are embedded in Twoliter, such as buildsys, pubsys and tuftool, so that
+/// scripts can run the same versions that Twoliter uses. By default they go where a build would put
+/// them, the project's `build/tools` directory.
+#[derive(Debug, Parser)]
+pub(crate) struct InstallTools {
+    /// Install the tools into this directory instead, which is created if needed. Nothing else in
+    /// it is removed. Prints a line that adds the directory to the `PATH`.
+    #[clap(long)]
+    dest: Option<PathBuf>,
+
+    /// Only install this tool, e.g. `buildsys`. Can be given more than once.
+    #[clap(long, requires = "dest")]
+    only: Vec<String>,
+
+    /// Replace tools in `--dest` that were installed by a different version of Twoliter.
+    #[clap(long, requires = "dest")]
+    force: bool,
+
+    /// Print the names of the embedded tools and exit.
+    #[clap(long, conflicts_with_all = ["dest", "only", "force"])]
+    list: bool,
+}
+
+impl InstallTools {
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        if self.list {
+            for name in tool_names()? {
+                println!("{}", name);
+            }
+            return Ok(());
+        }
+        let Some(dest) = &self.dest else {
+            let project = global.load_project().await?;
+            let toolsdir = project.project_dir().join("build/tools");
+            let _project_lock = global.lock_project(&project).await?;
+            install_tools(&toolsdir).await?;
+            println!("{}", toolsdir.display());
+            return Ok(());
+        };
+        let installed = export_tools(dest, &self.only, self.force).await?;
+        let dest = fs::canonicalize(dest).await?;
+        info!(
+            "Installed {} tool(s) into '{}'",
+            installed.len(),
+            dest.display()
+        );
+        println!("{}", path_export(&dest));
+        Ok(())
+    }
+}
+
+/// A shell line that puts `dir` first in the `PATH`.
+fn path_export(dir: &Path) -> String {
+    let dir = dir.display().to_string().replace('\'', r"'\''");
+    format!("export PATH='{}':\"$PATH\"", dir)
+}
+
+#[test]
+fn test_path_export() {
+    assert_eq!(
+        path_export(Path::new("/opt/twoliter tools")),
+        "export PATH='/opt/twoliter tools':\"$PATH\""
+    );
+    assert_eq!(
+        path_export(Path::new("/tmp/it's")),
+        "export PATH='/tmp/it'\\''s':\"$PATH\""
+    );
+}
@@ -0,0 +1,446 @@
+use super::output::{self, OutputFormat};
+use crate::cmd::{ArchArg, DeprecatedProjectPath, GlobalArgs};
+use crate::cosign::{self, Verifier};
+use crate::kit::{self, KitDiff, KitManifest, KitPackages, VersionBump};
+use crate::lock::{Lock, LockedImage, TWOLITER_LOCK};
+use crate::project::Project;
+use anyhow::{bail, ensure, Context, Result};
+use clap::{ArgGroup, Parser};
+use log::{debug, info, warn};
+use semver::Version;
+use std::fmt::Write;
+use std::path::Path;
+use tokio::process::Command;
+
+#[derive(Debug, Parser)]
+pub(crate) enum KitCommand {
+    Bump(BumpKit),
+    Diff(DiffKit),
+    List(ListKits),
+    Validate(ValidateKit),
+    Verify(VerifyKit),
+}
+
+impl KitCommand {
+    pub(crate) async fn run(self, global: &GlobalArgs) -> Result<()> {
+        match self {
+            KitCommand::Bump(command) => command.run(global).await,
+            KitCommand::Diff(command) => command.run(global).await,
+            KitCommand::List(command) => command.run(global).await,
+            KitCommand::Validate(command) => command.run(global).await,
+            KitCommand::Verify(command) => command.run().await,
+        }
+    }
+}
+
+/// List the kits in the project's `kits` directory with their version, vendor and the number of
+/// packages that each depends on.
+#[derive(Debug, Parser)]
+pub(crate) struct ListKits {
+    /// Print the kits as `text`, `json` or `toml`. Overrides the global `--format`.
+    #[clap(long = "format", value_enum)]
+    format: Option<OutputFormat>,
+}
+
+impl ListKits {
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let project = global.load_project().await?;
+        let kits = kit::list(&project).await?;
+        let format = self.format.unwrap_or(global.format());
+        output::print(format, "kit", &kits, |kits| {
+            let width = kits.iter().map(|kit| kit.name.len()).max().unwrap_or(0);
+            let mut text = String::new();
+            for kit in kits {
+                let _ = writeln!(
+                    text,
+                    "{:width$}  {:10}  {:16}  {} package(s)",
+                    kit.name,
+                    kit.version,
+                    kit.vendor.as_deref().unwrap_or("-"),
+                    kit.packages,
+                );
+            }
+            text
+        })
+    }
+}
+
+/// Check the metadata of a kit, and of the packages and kits it depends on, without building it.
+/// All problems are reported at once.
+#[derive(Debug, Parser)]
+pub(crate) struct ValidateKit {
+    #[clap(flatten)]
+    project_path: DeprecatedProjectPath,
+
+    /// The name of the kit to validate.
+    kit: String,
+}
+
+impl ValidateKit {
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let project = self.project_path.apply(global).load_project().await?;
+        let problems = kit::validate(&project, &self.kit).await?;
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        if !problems.is_empty() {
+            bail!(
+                "Found {} problem(s) in kit '{}' and its dependencies",
+                problems.len(),
+                self.kit
+            );
+        }
+        println!("Kit '{}' is valid", self.kit);
+        Ok(())
+    }
+}
+
+/// Compare the packages of a local kit with those of a published version of it.
+#[derive(Debug, Parser)]
+pub(crate) struct DiffKit {
+    /// The name of the kit to compare.
+    kit: String,
+
+    /// The published kit to compare against, either a version such as `1.2.0` or an image
+    /// reference such as `public.ecr.aws/bottlerocket/core-kit:v1.2.0`. Defaults to the version in
+    /// Twoliter.lock.
+    #[clap(long)]
+    against: Option<String>,
+
+    /// The vendor to pull a version given to `--against` from. Defaults to the vendor of the kit in
+    /// Twoliter.lock, or to the only vendor in Twoliter.toml.
+    #[clap(long)]
+    vendor: Option<String>,
+
+    #[clap(flatten)]
+    arch: ArchArg,
+
+    /// Print the differences as JSON, the same as the global `--format json`.
+    #[clap(long)]
+    json: bool,
+}
+
+impl DiffKit {
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let project = global.load_project().await?;
+        let lock = if project.project_dir().join(TWOLITER_LOCK).exists() {
+            Some(Lock::load_existing(&project).await?)
+        } else {
+            None
+        };
+        let locked = lock
+            .iter()
+            .flat_map(|lock| lock.kit.iter())
+            .find(|locked| locked.name == self.kit);
+
+        let old = match self.remote_image(&project, locked).await? {
+            Some(image) => match self.remote_packages(&project, &image).await {
+                Ok(packages) => packages,
+                Err(e) => {
+                    let locked = locked.context(format!(
+                        "Unable to get the packages of {}, and {} has no entry for kit '{}': {:?}",
+                        image, TWOLITER_LOCK, self.kit, e
+                    ))?;
+                    warn!(
+                        "Unable to get the packages of {}, comparing against {} as recorded in \
+                        {} instead: {:?}",
+                        image, locked, TWOLITER_LOCK, e
+                    );
+                    self.locked_packages(&project, locked).await?
+                }
+            },
+            None if lock.is_none() => bail!(
+                "Unable to compare kit '{}' without {}, pass --against or run twoliter update",
+                self.kit,
+                TWOLITER_LOCK
+            ),
+            None => bail!(
+                "Kit '{}' is not in {}, pass --against to choose a published version to compare \
+                against",
+                self.kit,
+                TWOLITER_LOCK
+            ),
+        };
+        let new = self.local_packages(&project).await?;
+
+        let diff = KitDiff::new(&old, &new);
+        let format = if self.json {
+            OutputFormat::Json
+        } else {
+            global.format()
+        };
+        output::print(format, "diff", &diff, |diff| {
+            if diff.is_empty() {
+                format!("Kit '{}' has the same packages\n", self.kit)
+            } else {
+                diff.to_string()
+            }
+        })
+    }
+
+    /// Returns the published kit to compare against, which is resolved if it is given with
+    /// `--against`.
+    async fn remote_image(
+        &self,
+        project: &Project,
+        locked: Option<&LockedImage>,
+    ) -> Result<Option<LockedImage>> {
+        let Some(against) = &self.against else {
+            return Ok(locked.cloned());
+        };
+        let vendor = match (&self.vendor, locked) {
+            (Some(vendor), _) => vendor.clone(),
+            (None, Some(locked)) => locked.vendor.clone(),
+            (None, None) => match project.vendor().keys().collect::<Vec<_>>().as_slice() {
+                [vendor] => vendor.0.clone(),
+                _ => bail!(
+                    "Unable to choose a vendor for kit '{}', pass --vendor",
+                    self.kit
+                ),
+            },
+        };
+        let source = if let Ok(version) = Version::parse(against.trim_start_matches('v')) {
+            let registry = project
+                .vendor()
+                .iter()
+                .find(|(name, _)| name.0 == vendor)
+                .map(|(_, vendor)| vendor.registry.clone())
+                .context(format!("Vendor '{}' is not in Twoliter.toml", vendor))?;
+            format!("{}/{}:v{}", registry, self.kit, version)
+        } else {
+            against.clone()
+        };
+        // Pulling a kit looks it up by the `v<version>` tag of its source.
+        let version = source
+            .rsplit_once(":v")
+            .and_then(|(_, tag)| Version::parse(tag).ok())
+            .context(format!(
+                "Expected '{}' to be a version or an image reference with a tag like ':v1.0.0'",
+                against
+            ))?;
+        LockedImage::resolve(self.kit.clone(), version, vendor, source)
+            .await
+            .map(Some)
+    }
+
+    /// Checks the kit metadata label of `image`, then pulls it for the architecture into the build
+    /// directory and lists its RPMs.
+    async fn remote_packages(&self, project: &Project, image: &LockedImage) -> Result<KitPackages> {
+        // Twoliter.lock does not keep the manifest list that the label is found through.
+        let image = if image.manifest.is_empty() {
+            let resolved = LockedImage::resolve(
+                image.name.clone(),
+                image.version.clone(),
+                image.vendor.clone(),
+                image.source.clone(),
+            )
+            .await?;
+            image.verify_manifest(&resolved.manifest)?;
+            resolved
+        } else {
+            image.clone()
+        };
+        let metadata = image.kit_metadata().await?;
+        ensure!(
+            metadata.name == self.kit,
+            "{} is kit '{}', not '{}'",
+            image.source,
+            metadata.name,
+            self.kit
+        );
+        info!(
+            "Comparing with kit '{}' version {} from {}",
+            metadata.name, metadata.version, image.source
+        );
+        let dir = project.project_dir().join("build/kit-diff");
+        Lock::extract_kit(&dir, &image, self.arch.get()).await?;
+        kit::rpm_packages(
+            &dir.join(&image.vendor)
+                .join(&image.name)
+                .join(self.arch.get()),
+        )
+        .await
+    }
+
+    /// Lists the RPMs of the kit in Twoliter.lock, as last fetched into the build directory.
+    async fn locked_packages(
+        &self,
+        project: &Project,
+        locked: &LockedImage,
+    ) -> Result<KitPackages> {
+        let dir = project
+            .external_kits_dir()
+            .join(&locked.vendor)
+            .join(&locked.name)
+            .join(self.arch.get());
+        ensure!(
+            dir.is_dir(),
+            "Kit '{}' has not been fetched to '{}', run twoliter fetch",
+            locked,
+            dir.display()
+        );
+        kit::rpm_packages(&dir).await
+    }
+
+    /// Lists the RPMs of the local kit if it is built, or else the packages it depends on.
+    async fn local_packages(&self, project: &Project) -> Result<KitPackages> {
+        let dir = project
+            .project_dir()
+            .join("build/kits")
+            .join(&self.kit)
+            .join(self.arch.get());
+        if dir.is_dir() {
+            return kit::rpm_packages(&dir).await;
+        }
+        warn!(
+            "Kit '{}' has not been built for {}, so the versions of its packages are unknown",
+            self.kit,
+            self.arch.get()
+        );
+        kit::graph_packages(project, &self.kit).await
+    }
+}
+
+/// Verify the cosign signature of a published kit image, given the public key it was signed with or,
+/// for a keyless signature, the identity that signed it.
+#[derive(Debug, Parser)]
+#[clap(group(ArgGroup::new("verifier").required(true).args(["key", "certificate_identity"])))]
+pub(crate) struct VerifyKit {
+    /// The kit image, e.g. `public.ecr.aws/acme/core-kit:v1.2.3`.
+    image: String,
+
+    /// The public key: a path to a cosign public key, an AWS KMS ARN, or a cosign key URI.
+    #[clap(long = "key")]
+    key: Option<String>,
+
+    /// For a keyless signature, the identity in the signing certificate, e.g. the URL of the
+    /// workflow that published the kit.
+    #[clap(long = "certificate-identity", requires = "certificate_oidc_issuer")]
+    certificate_identity: Option<String>,
+
+    /// For a keyless signature, the issuer of the OIDC token that the certificate was issued for,
+    /// e.g. `https://token.actions.githubusercontent.com`.
+    #[clap(long = "certificate-oidc-issuer", requires = "certificate_identity")]
+    certificate_oidc_issuer: Option<String>,
+}
+
+impl VerifyKit {
+    pub(super) async fn run(&self) -> Result<()> {
+        let verifier = match (
+            &self.key,
+            &self.certificate_identity,
+            &self.certificate_oidc_issuer,
+        ) {
+            (Some(key), _, _) => Verifier::Key(key.clone()),
+            (None, Some(identity), Some(issuer)) => Verifier::Keyless {
+                identity: identity.clone(),
+                issuer: issuer.clone(),
+            },
+            _ => bail!("Give --key, or --certificate-identity and --certificate-oidc-issuer"),
+        };
+        cosign::verify(&self.image, &verifier)
+            .await
+            .context(format!("Unable to verify the signature of {}", self.image))
+    }
+}
+
+/// Change the version of a kit in its Cargo.toml, preserving the rest of the file.
+#[derive(Debug, Parser)]
+#[clap(group(ArgGroup::new("bump").required(true).args(["major", "minor", "patch", "set"])))]
+pub(crate) struct BumpKit {
+    #[clap(flatten)]
+    project_path: DeprecatedProjectPath,
+
+    /// The name of the kit to bump.
+    kit: String,
+
+    /// Increment the major version.
+    #[clap(long)]
+    major: bool,
+
+    /// Increment the minor version.
+    #[clap(long)]
+    minor: bool,
+
+    /// Increment the patch version.
+    #[clap(long)]
+    patch: bool,
+
+    /// Set the version to this value.
+    #[clap(long)]
+    set: Option<Version>,
+
+    /// Edit the kit's Cargo.toml even if it has uncommitted changes.
+    #[clap(long)]
+    allow_dirty: bool,
+}
+
+impl BumpKit {
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let project = self.project_path.apply(global).load_project().await?;
+        let path = KitManifest::path_for(&project, &self.kit);
+        if !self.allow_dirty && has_uncommitted_changes(&path).await? {
+            bail!(
+                "'{}' has uncommitted changes, commit them first or pass --allow-dirty",
+                path.display()
+            );
+        }
+
+        let (old, new) = kit::bump(&project, &self.kit, &self.version_bump()).await?;
+        println!("{}: {} -> {}", self.kit, old, new);
+
+        // The lock records published kits by digest, so it can only be changed by resolving the
+        // new version with `twoliter update`.
+        if project.project_dir().join(TWOLITER_LOCK).exists() {
+            let lock = Lock::load_existing(&project).await?;
+            if let Some(locked) = lock.kit.iter().find(|locked| locked.name == self.kit) {
+                println!(
+                    "{} refers to {} version {}, update Twoliter.toml and run twoliter update to \
+                    use the new version",
+                    TWOLITER_LOCK, self.kit, locked.version
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn version_bump(&self) -> VersionBump {
+        match &self.set {
+            Some(version) => VersionBump::Set(version.clone()),
+            None if self.major => VersionBump::Major,
+            None if self.minor => VersionBump::Minor,
+            None => VersionBump::Patch,
+        }
+    }
+}
+
+/// Returns `true` if git reports changes to `path`. Paths that are not in a git repository, or
+/// systems without git, are treated as clean.
+async fn has_uncommitted_changes(path: &Path) -> Result<bool> {
+    let dir = path
+        .parent()
+        .context(format!("Expected '{}' to have a parent", path.display()))?;
+    let output = Command::new("git")
+        .arg("status")
+        .arg("--porcelain")
+        .arg("--")
+        .arg(path)
+        .current_dir(dir)
+        .output()
+        .await;
+    match output {
+        Ok(output) if output.status.success() => Ok(!output.stdout.is_empty()),
+        Ok(output) => {
+            debug!(
+                "Unable to check '{}' for uncommitted changes: {}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            Ok(false)
+        }
+        Err(e) => {
+            debug!("Unable to run git: {}", e);
+            Ok(false)
+        }
+    }
+}
@@ -0,0 +1,306 @@
+/*!
+
+The order in which `twoliter build kits` builds kits when it builds several at the same time. A kit
+waits for the local kits that it depends on, and two kits only build at the same time if they have
+no package or kit in common that is still to be built. Cargo builds a kit's package and kit
+dependencies along with it, so kits that share one would otherwise write the same packages to
+`build/rpms` at the same time. What a kit that has finished built is up to date, so the kits that
+depend on it can build together. The build scripts, libraries and lock files that every kit shares
+do not keep kits apart, they are only read.
+
+!*/
+
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Where a kit is in a [`KitSchedule`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum KitStatus {
+    Pending,
+    Running,
+    /// The kit was built, taking the given time.
+    Built(Duration),
+    /// The kit's inputs had not changed since it was last built.
+    UpToDate,
+    /// The build of the kit failed after the given time.
+    Failed(Duration),
+    /// The kit was not built because a kit that it depends on failed.
+    Skipped,
+}
+
+impl KitStatus {
+    fn succeeded(&self) -> bool {
+        matches!(self, KitStatus::Built(_) | KitStatus::UpToDate)
+    }
+
+    fn failed(&self) -> bool {
+        matches!(self, KitStatus::Failed(_) | KitStatus::Skipped)
+    }
+}
+
+impl Display for KitStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KitStatus::Pending => write!(f, "pending"),
+            KitStatus::Running => write!(f, "running"),
+            KitStatus::Built(_) => write!(f, "built"),
+            KitStatus::UpToDate => write!(f, "up to date"),
+            KitStatus::Failed(_) => write!(f, "failed"),
+            KitStatus::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+/// Decides which kits can be built next.
+#[derive(Debug, Clone)]
+pub(crate) struct KitSchedule {
+    /// The package and kit directories of each kit, see [`crate::kit::crate_dirs`].
+    crates: BTreeMap<String, BTreeSet<PathBuf>>,
+    /// The kits being built that each kit depends on.
+    dependencies: BTreeMap<String, BTreeSet<String>>,
+    status: BTreeMap<String, KitStatus>,
+}
+
+impl KitSchedule {
+    /// Schedules the kits that `crates` has the package and kit directories of. A kit depends on
+    /// another if the other's directory in `kits_dir` is one of them. `kits_dir` must be canonical,
+    /// like the directories.
+    pub(crate) fn new(crates: BTreeMap<String, BTreeSet<PathBuf>>, kits_dir: &Path) -> Self {
+        let dependencies = crates
+            .iter()
+            .map(|(kit, kit_crates)| {
+                let dependencies = crates
+                    .keys()
+                    .filter(|other| *other != kit && kit_crates.contains(&kits_dir.join(other)))
+                    .cloned()
+                    .collect();
+                (kit.clone(), dependencies)
+            })
+            .collect();
+        let status = crates
+            .keys()
+            .map(|kit| (kit.clone(), KitStatus::Pending))
+            .collect();
+        Self {
+            crates,
+            dependencies,
+            status,
+        }
+    }
+
+    /// Marks and returns the kits that can start now without more than `jobs` kits building at
+    /// once. It is an error if nothing is building and nothing can start while kits are pending,
+    /// which means that the kits depend on each other in a cycle.
+    pub(crate) fn start(&mut self, jobs: usize) -> Result<Vec<String>> {
+        let mut running: Vec<String> = self.with_status(KitStatus::Running);
+        let mut started = Vec::new();
+        for kit in self.with_status(KitStatus::Pending) {
+            if running.len() >= jobs {
+                break;
+            }
+            let ready = self.dependencies[&kit]
+                .iter()
+                .all(|dependency| self.status[dependency].succeeded());
+            let to_build = self.to_build(&kit);
+            let shares_crates = running
+                .iter()
+                .any(|other| !to_build.is_disjoint(&self.to_build(other)));
+            if ready && !shares_crates {
+                self.status.insert(kit.clone(), KitStatus::Running);
+                running.push(kit.clone());
+                started.push(kit);
+            }
+        }
+        if running.is_empty() && !self.is_done() {
+            bail!(
+                "Unable to schedule the kits {}, they depend on each other",
+                self.with_status(KitStatus::Pending).join(", ")
+            );
+        }
+        Ok(started)
+    }
+
+    /// Records how the build of `kit` ended. If it failed, the kits that depend on it are skipped.
+    pub(crate) fn finish(&mut self, kit: &str, status: KitStatus) {
+        self.status.insert(kit.to_string(), status);
+        // Skipping a kit can skip the kits that depend on it in turn.
+        loop {
+            let skipped: Vec<String> = self
+                .with_status(KitStatus::Pending)
+                .into_iter()
+                .filter(|kit| {
+                    self.dependencies[kit]
+                        .iter()
+                        .any(|dependency| self.status[dependency].failed())
+                })
+                .collect();
+            if skipped.is_empty() {
+                break;
+            }
+            for kit in skipped {
+                self.status.insert(kit, KitStatus::Skipped);
+            }
+        }
+    }
+
+    /// The package and kit directories of `kit` that no kit which has been built, or was up to date,
+    /// has among its own.
+    fn to_build(&self, kit: &str) -> BTreeSet<&PathBuf> {
+        let built: BTreeSet<&PathBuf> = self
+            .status
+            .iter()
+            .filter(|(_, status)| status.succeeded())
+            .flat_map(|(built, _)| &self.crates[built])
+            .collect();
+        self.crates[kit]
+            .iter()
+            .filter(|dir| !built.contains(dir))
+            .collect()
+    }
+
+    /// Whether every kit has finished or been skipped.
+    pub(crate) fn is_done(&self) -> bool {
+        self.status
+            .values()
+            .all(|status| !matches!(status, KitStatus::Pending | KitStatus::Running))
+    }
+
+    /// Each kit, in name order, with its status.
+    pub(crate) fn status(&self) -> &BTreeMap<String, KitStatus> {
+        &self.status
+    }
+
+    fn with_status(&self, status: KitStatus) -> Vec<String> {
+        self.status
+            .iter()
+            .filter(|(_, kit_status)| **kit_status == status)
+            .map(|(kit, _)| kit.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+fn test_schedule() -> KitSchedule {
+    let kits_dir = Path::new("/project/kits");
+    let crates = |paths: &[&str]| -> BTreeSet<PathBuf> {
+        paths
+            .iter()
+            .map(|path| Path::new("/project").join(path))
+            .collect()
+    };
+    KitSchedule::new(
+        BTreeMap::from([
+            (
+                "core-kit".to_string(),
+                crates(&["kits/core-kit", "packages/a"]),
+            ),
+            (
+                "extra-1-kit".to_string(),
+                crates(&[
+                    "kits/extra-1-kit",
+                    "kits/core-kit",
+                    "packages/a",
+                    "packages/b",
+                ]),
+            ),
+            (
+                "extra-2-kit".to_string(),
+                crates(&["kits/extra-2-kit", "packages/c"]),
+            ),
+            (
+                "extra-3-kit".to_string(),
+                crates(&["kits/extra-3-kit", "kits/extra-1-kit", "packages/b"]),
+            ),
+        ]),
+        kits_dir,
+    )
+}
+
+#[test]
+fn test_kit_schedule() {
+    let mut schedule = test_schedule();
+    // extra-1-kit waits for core-kit, and extra-3-kit waits for extra-1-kit.
+    assert_eq!(schedule.start(4).unwrap(), ["core-kit", "extra-2-kit"]);
+    assert!(schedule.start(4).unwrap().is_empty());
+    schedule.finish("core-kit", KitStatus::Built(Duration::from_secs(1)));
+    assert_eq!(schedule.start(4).unwrap(), ["extra-1-kit"]);
+    schedule.finish("extra-1-kit", KitStatus::UpToDate);
+    schedule.finish("extra-2-kit", KitStatus::Built(Duration::from_secs(1)));
+    assert_eq!(schedule.start(4).unwrap(), ["extra-3-kit"]);
+    assert!(!schedule.is_done());
+    schedule.finish("extra-3-kit", KitStatus::Built(Duration::from_secs(1)));
+    assert!(schedule.is_done());
+
+    // One job at a time builds the kits in name order.
+    let mut schedule = test_schedule();
+    assert_eq!(schedule.start(1).unwrap(), ["core-kit"]);
+    schedule.finish("core-kit", KitStatus::UpToDate);
+    assert_eq!(schedule.start(1).unwrap(), ["extra-1-kit"]);
+}
+
+#[test]
+fn test_kit_schedule_failure() {
+    let mut schedule = test_schedule();
+    assert_eq!(schedule.start(2).unwrap(), ["core-kit", "extra-2-kit"]);
+    schedule.finish("core-kit", KitStatus::Failed(Duration::from_secs(1)));
+    // The kits that depend on core-kit, directly or not, are skipped while extra-2-kit finishes.
+    assert_eq!(schedule.status()["extra-1-kit"], KitStatus::Skipped);
+    assert_eq!(schedule.status()["extra-3-kit"], KitStatus::Skipped);
+    assert_eq!(schedule.status()["extra-2-kit"], KitStatus::Running);
+    assert!(schedule.start(2).unwrap().is_empty());
+    schedule.finish("extra-2-kit", KitStatus::Built(Duration::from_secs(1)));
+    assert!(schedule.is_done());
+}
+
+#[test]
+fn test_kit_schedule_cycle() {
+    let kits_dir = Path::new("/project/kits");
+    let mut schedule = KitSchedule::new(
+        BTreeMap::from([
+            ("a".to_string(), BTreeSet::from([kits_dir.join("b")])),
+            ("b".to_string(), BTreeSet::from([kits_dir.join("a")])),
+        ]),
+        kits_dir,
+    );
+    assert!(schedule.start(2).is_err());
+}
+
+/// Kits in a real project all share the project's lock file and the build scripts of packages, but
+/// that does not keep them from building at the same time.
+#[tokio::test]
+async fn test_kit_schedule_local_kit() {
+    use crate::kit;
+    use crate::project::Project;
+    use crate::test::copy_project_to_temp_dir;
+
+    let temp_dir = copy_project_to_temp_dir("local-kit").await;
+    let project = Project::load(temp_dir.path().join("Twoliter.toml"))
+        .await
+        .unwrap();
+    let kits = kit::local_kits(&project).await.unwrap();
+    let mut crates = BTreeMap::new();
+    for name in &kits {
+        crates.insert(name.clone(), kit::crate_dirs(&project, name).await.unwrap());
+    }
+    let extra_1 = kit::inputs(&project, "extra-1-kit").await.unwrap();
+    let extra_2 = kit::inputs(&project, "extra-2-kit").await.unwrap();
+    assert!(!extra_1.is_disjoint(&extra_2));
+    for name in &kits {
+        assert!(crates[name].is_subset(&kit::inputs(&project, name).await.unwrap()));
+    }
+
+    let kits_dir = crate::common::fs::canonicalize(temp_dir.path().join("kits"))
+        .await
+        .unwrap();
+    let mut schedule = KitSchedule::new(crates, &kits_dir);
+    assert_eq!(schedule.start(4).unwrap(), ["core-kit"]);
+    schedule.finish("core-kit", KitStatus::Built(Duration::from_secs(1)));
+    // Both build packages on top of core-kit, which they do not build again.
+    assert_eq!(schedule.start(4).unwrap(), ["extra-1-kit", "extra-2-kit"]);
+    schedule.finish("extra-1-kit", KitStatus::Built(Duration::from_secs(1)));
+    schedule.finish("extra-2-kit", KitStatus::Built(Duration::from_secs(1)));
+    assert_eq!(schedule.start(4).unwrap(), ["extra-3-kit"]);
+}
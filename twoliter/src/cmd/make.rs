@@ -1,30 +1,79 @@
-use crate::cargo_make::CargoMake;
-use crate::lock::Lock;
-use crate::project::{self};
+use crate::cargo_make::{path_var, read_env_file, CargoMake};
+use crate::cmd::{ArchArg, DeprecatedProjectPath, GlobalArgs};
+use crate::common::fs;
+use crate::project::VariantConfig;
 use crate::tools::install_tools;
-use anyhow::Result;
+use crate::variant::ImageLayout;
+use anyhow::{Context, Result};
 use clap::Parser;
-use std::path::PathBuf;
+use log::debug;
+use std::env;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+const MAKE_EXAMPLES: &str = "\
+Examples:
+  # Build a variant for aarch64, with BUILDSYS_VARIANT and BUILDSYS_LOOKASIDE_CACHE set in build.env
+  twoliter make --arch aarch64 --env-file build.env build-variant
+
+  # Run the unit tests, writing the output of cargo make to a file
+  twoliter make --arch x86_64 --capture unit-tests.log unit-tests";
+
+/// `twoliter make` falls back to the build system's own variable and the host rather than `x86_64`.
+const MAKE_ARCH_HELP: &str =
+    "The architecture to build for, which is also passed to cargo make as \
+    `BUILDSYS_ARCH`. When `--arch` is not given, it is read from `TWOLITER_ARCH`, then from \
+    `BUILDSYS_ARCH`, and then the architecture of this host is used";
 
 /// Run a cargo make command in Twoliter's build environment. Known Makefile.toml environment
-/// variables will be passed-through to the cargo make invocation.
+/// variables will be passed-through to the cargo make invocation. The `[variant.<name>.image-layout]`
+/// of the `BUILDSYS_VARIANT` in Twoliter.toml is passed as `BUILDSYS_IMAGE_LAYOUT`.
 #[derive(Debug, Parser)]
-#[clap(trailing_var_arg = true)]
+#[clap(
+    trailing_var_arg = true,
+    after_help = MAKE_EXAMPLES,
+    mut_arg("arch", |arg| arg.help(MAKE_ARCH_HELP))
+)]
 pub(crate) struct Make {
-    /// Path to the project file. Will search for Twoliter.toml when absent.
-    #[clap(long)]
-    project_path: Option<PathBuf>,
+    #[clap(flatten)]
+    project_path: DeprecatedProjectPath,
 
     /// Twoliter does not read this from the CARGO_HOME environment variable to avoid any possible
     /// confusion between a CARGO_HOME set on the system, and the path intended for the Bottlerocket
-    /// build.
+    /// build. Defaults to `cargo-home` in the `[build]` section of Twoliter.toml.
     #[clap(long)]
-    cargo_home: PathBuf,
+    cargo_home: Option<PathBuf>,
+
+    #[clap(flatten)]
+    arch: ArchArg,
 
-    /// This can be passed by environment variable. We require it as part of the command arguments
-    /// because we need it to pull the right SDK target architecture.
-    #[clap(long, env = "BUILDSYS_ARCH")]
-    arch: String,
+    /// Write the output of cargo make to this file instead of the console. Only the name of each
+    /// task is printed as it starts, and the end of the output is shown if the command fails. With
+    /// `--log-level trace` the output is printed as well as captured.
+    #[clap(long)]
+    capture: Option<PathBuf>,
+
+    /// Read build system variables, such as `BUILDSYS_*` and `PUBLISH_*`, from `KEY=VALUE` lines in
+    /// this file. Blank lines and lines starting with `#` are skipped.
+    #[clap(long = "env-file")]
+    env_file: Option<PathBuf>,
+
+    /// Print the environment variables passed to cargo make, and where each value came from,
+    /// before running it. Secret-looking values are hidden.
+    #[clap(long = "print-env")]
+    print_env: bool,
+
+    /// Attach cargo make to the terminal so that tasks can prompt for input, instead of capturing
+    /// or reformatting its output. This is the default when the output is a terminal and
+    /// `--capture` is not given. Use `--interactive=false` to turn it off.
+    #[clap(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        conflicts_with = "capture"
+    )]
+    interactive: Option<bool>,
 
     /// Cargo make task. E.g. the word "build" if we want to execute `cargo make build`.
     makefile_task: String,
@@ -35,21 +84,112 @@ pub(crate) struct Make {
 }
 
 impl Make {
-    pub(super) async fn run(&self) -> Result<()> {
-        let project = project::load_or_find_project(self.project_path.clone()).await?;
-        let lock = Lock::load(&project).await?;
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let project = self.project_path.apply(global).load_project().await?;
+        let cargo_home = self
+            .cargo_home
+            .clone()
+            .or_else(|| project.cargo_home())
+            .context(
+            "No cargo home was given, use --cargo-home or set 'cargo-home' in the [build] section \
+            of Twoliter.toml",
+        )?;
+        prepare_cargo_home(&cargo_home).await?;
+        let arch = self.arch(env::var("BUILDSYS_ARCH").ok());
+        let lock = global.load_lock(&project).await?;
+        if global.frozen() {
+            lock.ensure_local(&project, &arch, global.images()).await?;
+        }
         let toolsdir = project.project_dir().join("build/tools");
+        // Keeps `build clean` out until the run is done.
+        let _build_lock = global.lock_build(&project).await?;
+        let project_lock = global.lock_project(&project).await?;
         install_tools(&toolsdir).await?;
+        drop(project_lock);
         let makefile_path = toolsdir.join("Makefile.toml");
+        let env_file = read_env_file(self.env_file.as_deref()).await?;
+        let image_layout = match given_var(&env_file, "BUILDSYS_VARIANT") {
+            Some(variant) => image_layout(
+                project.variant(&variant),
+                given_var(&env_file, "BUILDSYS_IMAGE_LAYOUT").as_deref(),
+            )?,
+            None => None,
+        };
         CargoMake::new(&lock.sdk.source)?
-            .env("CARGO_HOME", self.cargo_home.display().to_string())
-            .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
-            .env("BUILDSYS_VERSION_IMAGE", project.release_version())
+            .env_file_vars(env_file)?
+            .override_env("CARGO_HOME", path_var(&cargo_home)?)
+            .env("TWOLITER_TOOLS_DIR", path_var(&toolsdir)?)
+            .env("BUILDSYS_ARCH", &arch)
+            .env("BUILDSYS_VERSION_IMAGE", project.image_version(None)?)
+            .optional_envs(image_layout.map(|layout| ("BUILDSYS_IMAGE_LAYOUT", layout.to_string())))
             .makefile(makefile_path)
             .project_dir(project.project_dir())
+            .capture(self.capture.as_ref())
+            .bootstrap_tools(global.bootstrap_tools())
+            .print_env(self.print_env)
+            .interactive(self.is_interactive(std::io::stdout().is_terminal()))
             .exec_with_args(&self.makefile_task, self.additional_args.clone())
             .await
     }
+
+    /// The architecture to build for: `--arch`, then `BUILDSYS_ARCH` from the environment, then
+    /// the architecture of this host.
+    fn arch(&self, from_env: Option<String>) -> String {
+        let (arch, source) = match (self.arch.given(), from_env) {
+            (Some(arch), _) => (arch.to_string(), "--arch or TWOLITER_ARCH"),
+            (None, Some(arch)) if !arch.is_empty() => (arch, "BUILDSYS_ARCH"),
+            _ => (env::consts::ARCH.to_string(), "the host architecture"),
+        };
+        debug!("Building for {}, from {}", arch, source);
+        arch
+    }
+
+    fn is_interactive(&self, stdout_is_terminal: bool) -> bool {
+        self.interactive
+            .unwrap_or(stdout_is_terminal && self.capture.is_none())
+    }
+}
+
+/// The value of the build system variable `key` that cargo make will see, from the env file or else
+/// from the environment.
+fn given_var(env_file: &[(String, String)], key: &str) -> Option<String> {
+    env_file
+        .iter()
+        .rev()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.clone())
+        .or_else(|| env::var(key).ok())
+        .filter(|value| !value.is_empty())
+}
+
+/// The image layout to build the variant with `config` with: `[variant.<name>.image-layout]` in
+/// Twoliter.toml with any `BUILDSYS_IMAGE_LAYOUT` that was `given` on top, the same as
+/// `--image-layout` on top of Twoliter.toml in `build variant`. `None` if there is nothing to
+/// override.
+fn image_layout(config: VariantConfig, given: Option<&str>) -> Result<Option<ImageLayout>> {
+    let mut layout = config.image_layout;
+    if let Some(given) = given {
+        let overrides: ImageLayout = given
+            .parse()
+            .context("Unable to parse BUILDSYS_IMAGE_LAYOUT")?;
+        layout = layout.overlay(&overrides);
+    }
+    Ok((!layout.is_empty()).then_some(layout))
+}
+
+/// Creates `cargo_home` if it is missing and makes sure that it can be written to, which cargo
+/// would otherwise only find out deep into the build.
+async fn prepare_cargo_home(cargo_home: &Path) -> Result<()> {
+    fs::create_dir_all(cargo_home).await.context(format!(
+        "Unable to create cargo home '{}'",
+        cargo_home.display()
+    ))?;
+    tempfile::tempfile_in(cargo_home).context(format!(
+        "The cargo home '{}' is not writable, give a directory that you can write to with \
+        --cargo-home",
+        cargo_home.display()
+    ))?;
+    Ok(())
 }
 
 #[test]
@@ -146,3 +286,131 @@ fn test_trailing_args_3() {
     assert_eq!(args.additional_args[7], "something-else=baz");
     assert_eq!(args.additional_args[8], "--");
 }
+
+#[test]
+fn test_interactive() {
+    let parse = |args: &[&str]| {
+        let mut argv = vec!["make", "--cargo-home", "/tmp/foo", "--arch", "x86_64"];
+        argv.extend(args);
+        argv.push("testsys");
+        Make::try_parse_from(argv).unwrap()
+    };
+    // By default, only when the output is a terminal and not captured.
+    assert!(parse(&[]).is_interactive(true));
+    assert!(!parse(&[]).is_interactive(false));
+    assert!(!parse(&["--capture", "/tmp/log"]).is_interactive(true));
+    assert!(parse(&["--interactive"]).is_interactive(false));
+    assert!(!parse(&["--interactive=false"]).is_interactive(true));
+    assert!(Make::try_parse_from([
+        "make",
+        "--cargo-home",
+        "/tmp/foo",
+        "--arch",
+        "x86_64",
+        "--interactive",
+        "--capture",
+        "/tmp/log",
+        "testsys",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_arch() {
+    let make = |args: &[&str]| {
+        let mut argv = vec!["make", "--cargo-home", "/tmp/foo"];
+        argv.extend(args);
+        argv.push("build");
+        Make::try_parse_from(argv).unwrap()
+    };
+    // The flag wins over the environment, which wins over the host.
+    assert_eq!(
+        make(&["--arch", "aarch64"]).arch(Some("x86_64".to_string())),
+        "aarch64"
+    );
+    assert_eq!(make(&[]).arch(Some("aarch64".to_string())), "aarch64");
+    assert_eq!(make(&[]).arch(None), env::consts::ARCH);
+    assert_eq!(make(&[]).arch(Some(String::new())), env::consts::ARCH);
+
+    // TWOLITER_ARCH stands in for the flag, for make and for the commands that default to x86_64.
+    // This is the only test that sets it, so that the others see it unset.
+    use crate::cmd::ARCH_ENV;
+    let arch = |args: &[&str]| {
+        let mut argv = vec!["twoliter"];
+        argv.extend(args);
+        ArchArg::try_parse_from(argv).unwrap()
+    };
+    assert_eq!(arch(&[]).get(), "x86_64");
+    assert_eq!(arch(&[]).given(), None);
+    env::set_var(ARCH_ENV, "arm64");
+    let from_env = arch(&[]);
+    let flag = arch(&["--arch", "amd64"]);
+    let make_from_env = make(&[]);
+    let make_flag = make(&["--arch", "x86_64"]);
+    env::remove_var(ARCH_ENV);
+    assert_eq!(from_env.get(), "aarch64");
+    assert_eq!(flag.get(), "x86_64");
+    assert_eq!(make_from_env.arch(Some("x86_64".to_string())), "aarch64");
+    assert_eq!(make_flag.arch(Some("aarch64".to_string())), "x86_64");
+}
+
+#[test]
+fn test_image_layout() {
+    let config: VariantConfig = toml::from_str(
+        r#"
+        [image-layout]
+        os-image-size-gib = 4
+        partition-plan = "unified"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        image_layout(config.clone(), None)
+            .unwrap()
+            .unwrap()
+            .to_string(),
+        "os-image-size-gib=4,partition-plan=unified"
+    );
+    // A layout from the environment is applied on top.
+    assert_eq!(
+        image_layout(
+            config.clone(),
+            Some("os-image-size-gib=8,data-image-size-gib=20")
+        )
+        .unwrap()
+        .unwrap()
+        .to_string(),
+        "os-image-size-gib=8,data-image-size-gib=20,partition-plan=unified"
+    );
+    assert!(image_layout(config, Some("os-image-size-gib=0")).is_err());
+    assert_eq!(image_layout(VariantConfig::default(), None).unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_prepare_cargo_home() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let cargo_home = temp_dir.path().join("missing/.cargo");
+    prepare_cargo_home(&cargo_home).await.unwrap();
+    assert!(cargo_home.is_dir());
+
+    // A cargo home below a file cannot be created.
+    let file = temp_dir.path().join("file");
+    fs::write(&file, "").await.unwrap();
+    assert!(prepare_cargo_home(&file.join(".cargo")).await.is_err());
+
+    let read_only = temp_dir.path().join("read-only");
+    fs::create_dir_all(&read_only).await.unwrap();
+    std::fs::set_permissions(&read_only, std::fs::Permissions::from_mode(0o555)).unwrap();
+    // Root can write to a read-only directory, so there is nothing more to check.
+    if std::fs::write(read_only.join("probe"), "").is_ok() {
+        return;
+    }
+    let err = prepare_cargo_home(&read_only).await.unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("is not writable"),
+        "{:#}",
+        err
+    );
+}
@@ -0,0 +1,464 @@
+use crate::common::fs;
+use crate::kit::{shared_files, source_groups, CrateWalk};
+use crate::project::TWOLITER_TOML;
+use crate::variant::VARIANTS_DIRECTORY;
+use anyhow::{bail, ensure, Context, Result};
+use clap::Parser;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::path::{Component, Path, PathBuf};
+use toml::{Table, Value};
+
+/// The registry of the Bottlerocket SDK when a checkout does not name one.
+const DEFAULT_SDK_REGISTRY: &str = "public.ecr.aws/bottlerocket";
+
+/// Copy a variant, the packages it depends on, and their sources from a Bottlerocket checkout into
+/// a Twoliter project. The checkout is never modified.
+#[derive(Debug, Parser)]
+pub(crate) struct Migrate {
+    /// Path to the Bottlerocket checkout to copy from.
+    #[clap(long)]
+    from: PathBuf,
+
+    /// The name of the variant to copy, e.g. `aws-dev`.
+    #[clap(long)]
+    variant: String,
+
+    /// The directory of the project to copy into. Defaults to the current directory.
+    #[clap(long = "project-dir", default_value = ".")]
+    project_dir: PathBuf,
+
+    /// Overwrite files and directories that already exist in the project.
+    #[clap(long)]
+    force: bool,
+}
+
+impl Migrate {
+    pub(super) async fn run(&self) -> Result<()> {
+        let checkout = fs::canonicalize(&self.from).await?;
+        fs::create_dir_all(&self.project_dir).await?;
+        let project_dir = fs::canonicalize(&self.project_dir).await?;
+        ensure!(
+            !project_dir.starts_with(&checkout),
+            "The project directory '{}' is inside the checkout '{}', which must not be modified",
+            project_dir.display(),
+            checkout.display()
+        );
+        let migration = Migration::plan(&checkout, &self.variant).await?;
+        migration.apply(&project_dir, self.force).await?;
+        print!("{}", migration);
+        Ok(())
+    }
+}
+
+/// What it takes to move a variant out of a Bottlerocket checkout. Paths in the project are
+/// relative to the project directory.
+#[derive(Debug, Default)]
+struct Migration {
+    /// The files and directories to copy, keyed by their path in the project.
+    copies: BTreeMap<PathBuf, PathBuf>,
+    /// The path dependencies that point somewhere else once copied, keyed by the path of the
+    /// manifest in the project. Each is the dependency table, the dependency, and its new path.
+    rewrites: BTreeMap<PathBuf, Vec<(&'static str, String, String)>>,
+    /// The copied crates, which need to be added to the project's Cargo workspace.
+    members: Vec<PathBuf>,
+    /// The SDK version found in the checkout, if any.
+    sdk_version: Option<String>,
+    /// A starter `Twoliter.toml`, if the SDK version could be found.
+    twoliter_toml: Option<String>,
+    /// Anything that could not be mapped into the project automatically.
+    unmapped: Vec<String>,
+}
+
+impl Migration {
+    /// Works out what to copy for `variant` by following the path dependencies of its manifest
+    /// through the checkout's `packages` directory. Nothing is written.
+    async fn plan(checkout: &Path, variant: &str) -> Result<Self> {
+        let variant_dir = [VARIANTS_DIRECTORY, "sources/variants"]
+            .iter()
+            .map(|dir| checkout.join(dir).join(variant))
+            .find(|dir| dir.join("Cargo.toml").is_file())
+            .context(format!(
+                "Unable to find the variant '{}' in '{}'",
+                variant,
+                checkout.display()
+            ))?;
+        let variant_dir = fs::canonicalize(&variant_dir).await?;
+        let packages_dir = fs::canonicalize(checkout.join("packages"))
+            .await
+            .unwrap_or_else(|_| checkout.join("packages"));
+
+        let mut migration = Self::default();
+        let mut walk = CrateWalk::new([variant_dir.join("Cargo.toml")]);
+        while let Some(krate) = walk.next().await {
+            let krate = krate?;
+            let src = krate.dir().to_path_buf();
+            // Only the variant and its packages are copied, any other crate has already been
+            // reported by the crate that depends on it.
+            let dst = if src == variant_dir {
+                Path::new(VARIANTS_DIRECTORY).join(variant)
+            } else if let Some(dir_name) = package_dir_name(&src, &packages_dir) {
+                Path::new("packages").join(dir_name)
+            } else {
+                continue;
+            };
+            let manifest = &krate.toml;
+            let name = manifest
+                .get("package")
+                .and_then(|package| package.get("name"))
+                .and_then(Value::as_str)
+                .unwrap_or(variant)
+                .to_string();
+            migration.copies.insert(dst.clone(), src.clone());
+            migration.members.push(dst.clone());
+
+            // Files shared between crates, such as `../build.rs`, keep their place relative to the
+            // crate.
+            for file in shared_files(manifest) {
+                let from = normalize(&src.join(file));
+                if from.starts_with(&src) {
+                    continue;
+                }
+                if from.starts_with(checkout) && from.is_file() {
+                    migration.copies.insert(normalize(&dst.join(file)), from);
+                } else {
+                    migration.unmapped.push(format!(
+                        "'{}' uses '{}', which is not a file in the checkout",
+                        name, file
+                    ));
+                }
+            }
+
+            for group in source_groups(manifest) {
+                let from = checkout.join("sources").join(group);
+                if from.is_dir() {
+                    migration
+                        .copies
+                        .insert(Path::new("sources").join(group), from);
+                } else {
+                    migration.unmapped.push(format!(
+                        "'{}' uses the source group '{}', which was not found at '{}'",
+                        name,
+                        group,
+                        from.display()
+                    ));
+                }
+            }
+
+            for dependency in &krate.dependencies {
+                let Some(path) = &dependency.path else {
+                    migration.unmapped.push(format!(
+                        "'{}' depends on '{}', which is not a path dependency",
+                        name, dependency.name
+                    ));
+                    continue;
+                };
+                let dependency_dir = dependency.manifest.as_deref().and_then(Path::parent);
+                let Some(dir_name) =
+                    dependency_dir.and_then(|dir| package_dir_name(dir, &packages_dir))
+                else {
+                    migration.unmapped.push(format!(
+                        "'{}' depends on '{}' at '{}', which is not a package in '{}'",
+                        name,
+                        dependency.name,
+                        path,
+                        packages_dir.display()
+                    ));
+                    continue;
+                };
+                let dependency_dst = Path::new("packages").join(dir_name);
+                let new_path = relative_path(&dst, &dependency_dst);
+                if new_path != *path {
+                    migration
+                        .rewrites
+                        .entry(dst.join("Cargo.toml"))
+                        .or_default()
+                        .push((dependency.table, dependency.name.clone(), new_path));
+                }
+            }
+        }
+
+        let sdk = detect_sdk(checkout).await?;
+        match sdk {
+            Some((version, registry)) => {
+                let release_version = detect_release_version(checkout)
+                    .await?
+                    .unwrap_or_else(|| "0.1.0".to_string());
+                migration.twoliter_toml =
+                    Some(starter_twoliter_toml(&release_version, &version, &registry));
+                migration.sdk_version = Some(version);
+            }
+            None => migration.unmapped.push(format!(
+                "The SDK version was not found in '{}', so {} was not written",
+                checkout.display(),
+                TWOLITER_TOML
+            )),
+        }
+        Ok(migration)
+    }
+
+    /// The paths in the project that the migration writes.
+    fn destinations(&self) -> impl Iterator<Item = &Path> {
+        self.copies.keys().map(PathBuf::as_path).chain(
+            self.twoliter_toml
+                .as_ref()
+                .map(|_| Path::new(TWOLITER_TOML)),
+        )
+    }
+
+    /// Copies everything into `project_dir`. Unless `force` is set, nothing is written if any of the
+    /// destinations already exist.
+    async fn apply(&self, project_dir: &Path, force: bool) -> Result<()> {
+        let existing: Vec<_> = self
+            .destinations()
+            .filter(|path| project_dir.join(path).exists())
+            .map(|path| path.display().to_string())
+            .collect();
+        if !force && !existing.is_empty() {
+            bail!(
+                "These already exist in '{}', use --force to overwrite them: {}",
+                project_dir.display(),
+                existing.join(", ")
+            );
+        }
+
+        for (dst, src) in &self.copies {
+            let dst = project_dir.join(dst);
+            if src.is_dir() {
+                fs::copy_dir_all(src, &dst).await?;
+            } else {
+                if let Some(parent) = dst.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+                fs::copy(src, &dst).await?;
+            }
+        }
+
+        for (manifest, dependencies) in &self.rewrites {
+            let path = project_dir.join(manifest);
+            let data = fs::read_to_string(&path).await?;
+            let mut document: toml_edit::DocumentMut = data
+                .parse()
+                .context(format!("The manifest '{}' is malformed", path.display()))?;
+            for (table, dependency, new_path) in dependencies {
+                document[table][dependency.as_str()]["path"] = toml_edit::value(new_path);
+            }
+            fs::write_atomic(&path, document.to_string()).await?;
+        }
+
+        if let Some(twoliter_toml) = &self.twoliter_toml {
+            fs::write(project_dir.join(TWOLITER_TOML), twoliter_toml).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Migration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Copied:")?;
+        for path in self.copies.keys() {
+            writeln!(f, "  {}", path.display())?;
+        }
+        if let Some(sdk_version) = &self.sdk_version {
+            writeln!(
+                f,
+                "Wrote {} with SDK version {}",
+                TWOLITER_TOML, sdk_version
+            )?;
+        }
+        writeln!(f, "Add these to the workspace members in Cargo.toml:")?;
+        for member in &self.members {
+            writeln!(f, "  \"{}\",", member.display())?;
+        }
+        if !self.unmapped.is_empty() {
+            writeln!(f, "Could not be mapped automatically:")?;
+            for unmapped in &self.unmapped {
+                writeln!(f, "  {}", unmapped)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Finds the SDK version and registry in the checkout's `Twoliter.toml`, or in the `Makefile.toml`
+/// of a checkout from before Twoliter.
+async fn detect_sdk(checkout: &Path) -> Result<Option<(String, String)>> {
+    let twoliter_toml = checkout.join(TWOLITER_TOML);
+    if twoliter_toml.is_file() {
+        let toml = read_toml(&twoliter_toml).await?;
+        let sdk = toml.get("sdk");
+        let version = sdk
+            .and_then(|sdk| sdk.get("version"))
+            .and_then(Value::as_str);
+        let registry = sdk
+            .and_then(|sdk| sdk.get("vendor"))
+            .and_then(Value::as_str)
+            .and_then(|vendor| toml.get("vendor")?.get(vendor)?.get("registry"))
+            .and_then(Value::as_str)
+            .unwrap_or(DEFAULT_SDK_REGISTRY);
+        return Ok(version.map(|version| (version.to_string(), registry.to_string())));
+    }
+
+    let makefile_toml = checkout.join("Makefile.toml");
+    if makefile_toml.is_file() {
+        let toml = read_toml(&makefile_toml).await?;
+        let env = toml.get("env");
+        let version = env
+            .and_then(|env| env.get("BUILDSYS_SDK_VERSION"))
+            .and_then(Value::as_str);
+        let registry = env
+            .and_then(|env| env.get("BUILDSYS_REGISTRY"))
+            .and_then(Value::as_str)
+            .unwrap_or(DEFAULT_SDK_REGISTRY);
+        return Ok(version.map(|version| {
+            let version = version.strip_prefix('v').unwrap_or(version);
+            (version.to_string(), registry.to_string())
+        }));
+    }
+    Ok(None)
+}
+
+/// The `version` from the checkout's `Release.toml`.
+async fn detect_release_version(checkout: &Path) -> Result<Option<String>> {
+    let release_toml = checkout.join("Release.toml");
+    if !release_toml.is_file() {
+        return Ok(None);
+    }
+    Ok(read_toml(&release_toml)
+        .await?
+        .get("version")
+        .and_then(Value::as_str)
+        .map(str::to_string))
+}
+
+async fn read_toml(path: &Path) -> Result<Table> {
+    let data = fs::read_to_string(path).await?;
+    toml::from_str(&data).context(format!("Unable to parse '{}'", path.display()))
+}
+
+fn starter_twoliter_toml(release_version: &str, sdk_version: &str, registry: &str) -> String {
+    format!(
+        r#"schema-version = 1
+release-version = "{release_version}"
+
+[sdk]
+name = "bottlerocket-sdk"
+vendor = "bottlerocket"
+version = "{sdk_version}"
+
+[vendor.bottlerocket]
+registry = "{registry}"
+"#
+    )
+}
+
+/// The name of the directory of the crate in `dir` if it is a package in `packages_dir`.
+fn package_dir_name<'a>(dir: &'a Path, packages_dir: &Path) -> Option<&'a OsStr> {
+    dir.file_name()
+        .filter(|_| dir.parent() == Some(packages_dir))
+}
+
+/// Resolves `.` and `..` in `path` without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normal.pop();
+            }
+            component => normal.push(component),
+        }
+    }
+    normal
+}
+
+/// The path of `to` relative to the directory `from`, where both are relative to the same root.
+fn relative_path(from: &Path, to: &Path) -> String {
+    let common = from
+        .components()
+        .zip(to.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in common..from.components().count() {
+        relative.push("..");
+    }
+    relative.extend(to.components().skip(common));
+    relative.display().to_string()
+}
+
+#[test]
+fn test_relative_path() {
+    assert_eq!(
+        relative_path(Path::new("variants/aws-dev"), Path::new("packages/hello")),
+        "../../packages/hello"
+    );
+    assert_eq!(
+        relative_path(Path::new("packages/release"), Path::new("packages/hello")),
+        "../hello"
+    );
+    assert_eq!(
+        normalize(Path::new(
+            "/a/sources/variants/aws-dev/../../../packages/./hello"
+        )),
+        Path::new("/a/packages/hello")
+    );
+}
+
+#[tokio::test]
+async fn test_migrate() {
+    use crate::project::Project;
+
+    let checkout = crate::test::data_dir().join("bottlerocket-checkout");
+    let migration = Migration::plan(&checkout, "aws-dev").await.unwrap();
+    let copies: Vec<_> = migration
+        .copies
+        .iter()
+        .map(|(dst, src)| {
+            (
+                dst.to_str().unwrap(),
+                src.strip_prefix(&checkout).unwrap().to_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        copies,
+        [
+            ("packages/build.rs", "packages/build.rs"),
+            ("packages/hello", "packages/hello"),
+            ("packages/packages.rs", "packages/packages.rs"),
+            ("packages/release", "packages/release"),
+            ("sources/hello", "sources/hello"),
+            ("variants/aws-dev", "sources/variants/aws-dev"),
+            ("variants/build.rs", "sources/variants/build.rs"),
+            ("variants/variants.rs", "sources/variants/variants.rs"),
+        ]
+    );
+    assert_eq!(migration.sdk_version.as_deref(), Some("0.50.1"));
+    assert_eq!(migration.unmapped.len(), 2, "{:?}", migration.unmapped);
+    assert!(migration.unmapped[0].contains("'apiclient' at '../../api/apiclient'"));
+    assert!(migration.unmapped[1].contains("source group 'release-files'"));
+
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let project_dir = temp_dir.path();
+    migration.apply(project_dir, false).await.unwrap();
+    let manifest = fs::read_to_string(project_dir.join("variants/aws-dev/Cargo.toml"))
+        .await
+        .unwrap();
+    assert!(manifest.contains("# Packages are built before the variant."));
+    assert!(manifest.contains(r#"hello = { path = "../../packages/hello" }"#));
+    assert!(manifest.contains(r#"apiclient = { path = "../../api/apiclient" }"#));
+    assert!(project_dir.join("packages/hello/hello.spec").is_file());
+    assert!(!project_dir.join("packages/unused").exists());
+    let project = Project::load(project_dir.join(TWOLITER_TOML))
+        .await
+        .unwrap();
+    assert_eq!(project.release_version(), "1.22.0");
+
+    // A second run refuses to overwrite what the first one wrote.
+    let err = migration.apply(project_dir, false).await.unwrap_err();
+    assert!(err.to_string().contains("use --force"), "{}", err);
+    migration.apply(project_dir, true).await.unwrap();
+}
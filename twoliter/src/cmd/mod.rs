@@ -1,27 +1,62 @@
 mod build;
 mod build_clean;
+mod build_summary;
+mod cache;
+mod check;
 mod debug;
+mod debug_sdk;
+mod doctor;
 mod fetch;
+mod graph;
+mod install;
+mod kit;
+mod kit_schedule;
 mod make;
+mod migrate;
+mod output;
 mod publish_kit;
+mod shell;
 mod update;
+mod variant;
+mod version;
 
 use self::build::BuildCommand;
+use crate::cmd::cache::CacheCommand;
+use crate::cmd::check::Check;
 use crate::cmd::debug::DebugAction;
+use crate::cmd::doctor::Doctor;
 use crate::cmd::fetch::Fetch;
+use crate::cmd::graph::Graph;
+use crate::cmd::install::InstallCommand;
+use crate::cmd::kit::KitCommand;
 use crate::cmd::make::Make;
+use crate::cmd::migrate::Migrate;
+use crate::cmd::output::OutputFormat;
 use crate::cmd::publish_kit::PublishCommand;
+use crate::cmd::shell::Shell;
 use crate::cmd::update::Update;
-use anyhow::Result;
+use crate::cmd::variant::VariantCommand;
+use crate::cmd::version::{ShowVersion, VersionInfo};
+use crate::docker::ImageInspector;
+use crate::lock::Lock;
+use crate::logging::{json_record, set_log_format, LogFormat, LOG_FORMAT_ENV};
+use crate::project::{self, Project};
+use crate::project_lock::ProjectLock;
+use crate::style::{set_color, ColorChoice};
+use anyhow::{bail, ensure, Result};
 use clap::Parser;
 use env_logger::Builder;
-use log::LevelFilter;
+use log::{warn, LevelFilter};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 const DEFAULT_LEVEL_FILTER: LevelFilter = LevelFilter::Info;
 
 /// A tool for building custom variants of Bottlerocket.
 #[derive(Debug, Parser)]
-#[clap(about, long_about = None, version)]
+#[clap(about, long_about = None, version, long_version = long_version())]
 pub(crate) struct Args {
     /// Set the logging level. One of [off|error|warn|info|debug|trace]. Defaults to warn. You can
     /// also leave this unset and use the RUST_LOG env variable. See
@@ -29,23 +64,125 @@ pub(crate) struct Args {
     #[clap(long = "log-level")]
     pub(crate) log_level: Option<LevelFilter>,
 
+    /// Write log records, and the output of the commands that Twoliter runs, as `text` or as one
+    /// JSON object per line.
+    #[clap(long = "log-format", env = LOG_FORMAT_ENV, value_enum, default_value_t = LogFormat::Text)]
+    pub(crate) log_format: LogFormat,
+
+    /// Whether to color the summaries, findings and errors that Twoliter prints, and the level of
+    /// log records. `auto` uses color when writing to a terminal and `NO_COLOR` is not set. The
+    /// output of the commands that Twoliter runs is never changed.
+    #[clap(long = "color", value_enum, default_value_t = ColorChoice::Auto)]
+    pub(crate) color: ColorChoice,
+
+    /// Do not use color, the same as `--color never`.
+    #[clap(long = "no-color", conflicts_with = "color")]
+    pub(crate) no_color: bool,
+
+    /// Path to Twoliter.toml. Will search for Twoliter.toml, starting in the current directory,
+    /// when absent.
+    #[clap(long = "project-path", env = "TWOLITER_PROJECT")]
+    pub(crate) project_path: Option<PathBuf>,
+
+    /// Install cargo-make into the project's `build/cargo-home` if it is not installed, rather than
+    /// failing.
+    #[clap(long = "bootstrap-tools")]
+    pub(crate) bootstrap_tools: bool,
+
+    /// Run even if this version of Twoliter does not satisfy `required-twoliter-version` in
+    /// Twoliter.toml.
+    #[clap(long = "ignore-version-requirement")]
+    pub(crate) ignore_version_requirement: bool,
+
+    /// Run even if the project looks like a checkout of the Bottlerocket monorepo, which is built
+    /// in-tree rather than with Twoliter.
+    #[clap(long = "allow-monorepo")]
+    pub(crate) allow_monorepo: bool,
+
+    /// Use Twoliter.lock and the images that are present locally without resolving anything. It is
+    /// an error if Twoliter.lock is missing or out of date, or if the SDK or a kit has not been
+    /// fetched. Nothing is pulled or looked up in a registry, so no dependency can float.
+    #[clap(long = "frozen")]
+    pub(crate) frozen: bool,
+
+    /// How long to wait for another Twoliter command in the same project to finish a step that
+    /// writes to the `build` directory, such as installing the tools, e.g. `90s` or `10m`. Waits for
+    /// as long as it takes when absent.
+    #[clap(long = "lock-timeout", value_parser = humantime::parse_duration)]
+    pub(crate) lock_timeout: Option<Duration>,
+
+    /// Fail instead of warning when the project, build or temporary directory is on a filesystem
+    /// that is known to break builds, such as NTFS, exFAT or a network share, or when a build for
+    /// another architecture cannot run its binaries under emulation.
+    #[clap(long = "strict-preflight")]
+    pub(crate) strict_preflight: bool,
+
+    /// When building for an architecture that the host can only run under emulation and no
+    /// binfmt_misc handler is registered for it, offer to register qemu-user-static's handlers by
+    /// running its privileged container.
+    #[clap(long = "setup-binfmt")]
+    pub(crate) setup_binfmt: bool,
+
+    /// How commands that describe the project, such as `kit list`, print what they found: `text`
+    /// for people, or `json` or `toml` for scripts. Give it before the subcommand, e.g.
+    /// `twoliter --format json kit list`.
+    #[clap(long = "format", value_enum, default_value_t = OutputFormat::Text)]
+    pub(crate) format: OutputFormat,
+
     #[clap(subcommand)]
     pub(crate) subcommand: Subcommand,
 }
 
+impl Args {
+    /// The color choice given with `--color` or `--no-color`.
+    pub(crate) fn color(&self) -> ColorChoice {
+        if self.no_color {
+            ColorChoice::Never
+        } else {
+            self.color
+        }
+    }
+}
+
 #[derive(Debug, Parser)]
 pub(crate) enum Subcommand {
     /// Build something, such as a Bottlerocket image or a kit of packages.
     #[clap(subcommand)]
     Build(BuildCommand),
 
+    /// Work with the cache of downloaded sources that projects on this machine can share.
+    #[clap(subcommand)]
+    Cache(CacheCommand),
+
+    Check(Check),
+
+    Doctor(Doctor),
+
     Fetch(Fetch),
 
+    Graph(Graph),
+
+    /// Install something that is embedded in Twoliter, such as the build tools.
+    #[clap(subcommand)]
+    Install(InstallCommand),
+
+    /// Work with the kits in this project, such as checking their metadata.
+    #[clap(subcommand)]
+    Kit(KitCommand),
+
     Make(Make),
 
+    Migrate(Migrate),
+
+    Shell(Shell),
+
     /// Update Twoliter.lock
     Update(Update),
 
+    /// Work with the variants in this project, such as listing them.
+    #[clap(subcommand)]
+    Variant(VariantCommand),
+
     /// Publish something, such as a Kit
     #[clap(subcommand)]
     Publish(PublishCommand),
@@ -53,39 +190,372 @@ pub(crate) enum Subcommand {
     /// Commands that are used for checking and troubleshooting Twoliter's internals.
     #[clap(subcommand)]
     Debug(DebugAction),
+
+    Version(ShowVersion),
+}
+
+/// The environment variable that gives the architecture to build for when `--arch` is not given,
+/// e.g. once for all of the steps of a CI job.
+pub(crate) const ARCH_ENV: &str = "TWOLITER_ARCH";
+
+/// Parses an architecture given with `--arch` or `TWOLITER_ARCH`. The names that Docker and Go use,
+/// such as `arm64`, are accepted for the ones that Bottlerocket uses.
+pub(crate) fn parse_arch(arch: &str) -> Result<String> {
+    match arch.trim().to_ascii_lowercase().as_str() {
+        "x86_64" | "amd64" => Ok("x86_64".to_string()),
+        "aarch64" | "arm64" => Ok("aarch64".to_string()),
+        _ => bail!("Unsupported architecture '{}', use x86_64 or aarch64", arch),
+    }
+}
+
+/// The `--arch` option of the subcommands that work on one architecture, which is read from
+/// `TWOLITER_ARCH` when it is not given.
+#[derive(Debug, Clone, Default, Parser)]
+pub(crate) struct ArchArg {
+    /// The architecture to use, `x86_64` or `aarch64`. When `--arch` is not given, it is read from
+    /// `TWOLITER_ARCH`, and otherwise `x86_64` is used.
+    #[clap(long = "arch", env = ARCH_ENV, value_parser = parse_arch)]
+    arch: Option<String>,
+}
+
+impl ArchArg {
+    /// The architecture given with `--arch`, e.g. by a command that runs another.
+    pub(crate) fn new(arch: impl Into<String>) -> Self {
+        Self {
+            arch: Some(arch.into()),
+        }
+    }
+
+    /// The architecture given with `--arch` or `TWOLITER_ARCH`, or else `x86_64`.
+    pub(crate) fn get(&self) -> &str {
+        self.given().unwrap_or("x86_64")
+    }
+
+    /// The architecture given with `--arch` or `TWOLITER_ARCH`, if either was.
+    pub(crate) fn given(&self) -> Option<&str> {
+        self.arch.as_deref()
+    }
+}
+
+/// The output of `twoliter --version`, which is the same as the text of `twoliter version`.
+fn long_version() -> &'static str {
+    static LONG_VERSION: OnceLock<String> = OnceLock::new();
+    LONG_VERSION.get_or_init(|| VersionInfo::new().to_string())
 }
 
 /// Entrypoint for the `twoliter` command line program.
 pub(super) async fn run(args: Args) -> Result<()> {
+    let global = GlobalArgs::new(&args);
     match args.subcommand {
-        Subcommand::Build(build_command) => build_command.run().await,
-        Subcommand::Fetch(fetch_args) => fetch_args.run().await,
-        Subcommand::Make(make_args) => make_args.run().await,
-        Subcommand::Update(update_args) => update_args.run().await,
-        Subcommand::Publish(publish_command) => publish_command.run().await,
-        Subcommand::Debug(debug_action) => debug_action.run().await,
+        Subcommand::Build(build_command) => build_command.run(&global).await,
+        Subcommand::Cache(cache_command) => cache_command.run(&global).await,
+        Subcommand::Check(check_args) => check_args.run(&global).await,
+        Subcommand::Doctor(doctor_args) => doctor_args.run(&global).await,
+        Subcommand::Fetch(fetch_args) => fetch_args.run(&global).await,
+        Subcommand::Graph(graph_args) => graph_args.run(&global).await,
+        Subcommand::Install(install_command) => install_command.run(&global).await,
+        Subcommand::Kit(kit_command) => kit_command.run(&global).await,
+        Subcommand::Make(make_args) => make_args.run(&global).await,
+        Subcommand::Migrate(migrate_args) => migrate_args.run().await,
+        Subcommand::Shell(shell_args) => shell_args.run(&global).await,
+        Subcommand::Update(update_args) => update_args.run(&global).await,
+        Subcommand::Variant(variant_command) => variant_command.run(&global).await,
+        Subcommand::Publish(publish_command) => publish_command.run(&global).await,
+        Subcommand::Debug(debug_action) => debug_action.run(&global).await,
+        Subcommand::Version(version_args) => version_args.run(&global).await,
+    }
+}
+
+/// The options given before the subcommand, which apply to every subcommand.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GlobalArgs {
+    project_path: Option<PathBuf>,
+    bootstrap_tools: bool,
+    ignore_version_requirement: bool,
+    allow_monorepo: bool,
+    frozen: bool,
+    lock_timeout: Option<Duration>,
+    strict_preflight: bool,
+    setup_binfmt: bool,
+    format: OutputFormat,
+    /// Shared by everything that the subcommand does, so that each image is only inspected once.
+    images: Arc<ImageInspector>,
+}
+
+impl GlobalArgs {
+    pub(crate) fn new(args: &Args) -> Self {
+        Self {
+            project_path: args.project_path.clone(),
+            bootstrap_tools: args.bootstrap_tools,
+            ignore_version_requirement: args.ignore_version_requirement,
+            allow_monorepo: args.allow_monorepo,
+            frozen: args.frozen,
+            lock_timeout: args.lock_timeout,
+            strict_preflight: args.strict_preflight,
+            setup_binfmt: args.setup_binfmt,
+            format: args.format,
+            images: Arc::default(),
+        }
+    }
+
+    /// The same options, but for the project at `project_path`.
+    pub(crate) fn with_project_path(&self, project_path: PathBuf) -> Self {
+        Self {
+            project_path: Some(project_path),
+            ..self.clone()
+        }
+    }
+
+    /// Whether cargo-make may be installed into the project when it is missing.
+    pub(crate) fn bootstrap_tools(&self) -> bool {
+        self.bootstrap_tools
+    }
+
+    /// What is known about local docker images during this run of Twoliter.
+    pub(crate) fn images(&self) -> &ImageInspector {
+        &self.images
+    }
+
+    /// Whether everything must come from Twoliter.lock and locally present images.
+    pub(crate) fn frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Takes the lock that keeps other Twoliter commands from writing to the project's `build`
+    /// directory at the same time, see [`crate::project_lock`].
+    pub(crate) async fn lock_project(&self, project: &Project) -> Result<ProjectLock> {
+        ProjectLock::acquire(&project.project_dir().join("build"), self.lock_timeout).await
+    }
+
+    /// Takes a share of the lock that keeps `build clean` from removing files under a build that is
+    /// running. Take it before [`GlobalArgs::lock_project`], see [`crate::project_lock`].
+    pub(crate) async fn lock_build(&self, project: &Project) -> Result<ProjectLock> {
+        ProjectLock::acquire_build(&project.project_dir().join("build"), self.lock_timeout).await
+    }
+
+    /// Takes the lock that running builds share for this run alone, once they have finished. Take
+    /// it before [`GlobalArgs::lock_project`], see [`crate::project_lock`].
+    pub(crate) async fn lock_clean(&self, project: &Project) -> Result<ProjectLock> {
+        ProjectLock::acquire_clean(&project.project_dir().join("build"), self.lock_timeout).await
+    }
+
+    /// Whether the problems that a build checks for before it starts are errors rather than
+    /// warnings, see [`crate::filesystem::preflight`] and [`crate::binfmt::preflight`].
+    pub(crate) fn strict_preflight(&self) -> bool {
+        self.strict_preflight
+    }
+
+    /// Whether a missing binfmt_misc handler may be registered, see [`crate::binfmt::preflight`].
+    pub(crate) fn setup_binfmt(&self) -> bool {
+        self.setup_binfmt
+    }
+
+    /// How to print what a command found about the project, see [`output::print`].
+    pub(crate) fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    /// Loads Twoliter.lock, resolving and writing it if it does not exist. With `--frozen` it must
+    /// already exist.
+    pub(crate) async fn load_lock(&self, project: &Project) -> Result<Lock> {
+        if self.frozen {
+            Lock::load_existing(project).await
+        } else {
+            Lock::load(project).await
+        }
+    }
+
+    /// Fails if `--frozen` was given, for subcommands that `action`, such as resolving or pulling
+    /// images, which a frozen run must not do.
+    pub(crate) fn ensure_not_frozen(&self, action: &str) -> Result<()> {
+        ensure!(!self.frozen, "Cannot {} with --frozen", action);
+        Ok(())
+    }
+
+    /// Loads the project for a subcommand and checks that this version of Twoliter satisfies its
+    /// `required-twoliter-version`, and that the project is not the Bottlerocket monorepo.
+    pub(crate) async fn load_project(&self) -> Result<Project> {
+        let project = self.load_project_unchecked().await?;
+        project.check_twoliter_version(self.ignore_version_requirement)?;
+        project.check_monorepo(self.allow_monorepo)?;
+        Ok(project)
+    }
+
+    /// Loads the project for a subcommand without checking its `required-twoliter-version`.
+    pub(crate) async fn load_project_unchecked(&self) -> Result<Project> {
+        project::load_or_find_project(self.project_path.clone()).await
+    }
+}
+
+/// The `--project-path` that the subcommands which existed before the global option took. It is
+/// still accepted for now, with a warning, and wins over the global option. Newer subcommands only
+/// have the global option.
+#[derive(Debug, Clone, Default, Parser)]
+pub(crate) struct DeprecatedProjectPath {
+    /// Deprecated, give `--project-path` before the subcommand instead, e.g.
+    /// `twoliter --project-path <PATH> build kit`.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+}
+
+impl DeprecatedProjectPath {
+    /// The global options, with the project path replaced by this one if it was given.
+    pub(crate) fn apply(&self, global: &GlobalArgs) -> GlobalArgs {
+        match &self.project_path {
+            Some(path) => {
+                warn!(
+                    "Giving --project-path after the subcommand is deprecated and will be removed \
+                    in a future release, use 'twoliter --project-path {} <SUBCOMMAND>' instead",
+                    path.display()
+                );
+                global.with_project_path(path.clone())
+            }
+            None => global.clone(),
+        }
     }
 }
 
 /// use `level` if present, or else use `RUST_LOG` if present, or else use a default.
-pub(super) fn init_logger(level: Option<LevelFilter>) {
-    match (std::env::var(env_logger::DEFAULT_FILTER_ENV).ok(), level) {
+pub(super) fn init_logger(level: Option<LevelFilter>, format: LogFormat, color: ColorChoice) {
+    let mut builder = match (std::env::var(env_logger::DEFAULT_FILTER_ENV).ok(), level) {
         (Some(_), None) => {
             // RUST_LOG exists and level does not; use the environment variable.
-            Builder::from_default_env().init();
+            Builder::from_default_env()
         }
         _ => {
             // use provided log level or default for this crate only.
-            Builder::new()
-                .filter(
-                    Some(env!("CARGO_CRATE_NAME")),
-                    level.unwrap_or(DEFAULT_LEVEL_FILTER),
+            let mut builder = Builder::new();
+            builder.filter(
+                Some(env!("CARGO_CRATE_NAME")),
+                level.unwrap_or(DEFAULT_LEVEL_FILTER),
+            );
+            builder
+        }
+    };
+    set_log_format(format);
+    set_color(color);
+    if let Some(write_style) = color.write_style() {
+        builder.write_style(write_style);
+    }
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let timestamp = buf.timestamp().to_string();
+            let message = record.args().to_string();
+            writeln!(
+                buf,
+                "{}",
+                json_record(
+                    &timestamp,
+                    record.level().as_str(),
+                    record.target(),
+                    &message
                 )
-                .init();
+            )
+        });
+    }
+    builder.init();
+}
+
+#[test]
+fn test_global_project_path() {
+    let args = Args::try_parse_from([
+        "twoliter",
+        "--project-path",
+        "/tmp/global/Twoliter.toml",
+        "fetch",
+    ])
+    .unwrap();
+    let global = GlobalArgs::new(&args);
+    let deprecated = DeprecatedProjectPath::default();
+    assert_eq!(
+        deprecated.apply(&global).project_path,
+        Some(PathBuf::from("/tmp/global/Twoliter.toml"))
+    );
+    let deprecated = DeprecatedProjectPath {
+        project_path: Some(PathBuf::from("/tmp/sub/Twoliter.toml")),
+    };
+    assert_eq!(
+        deprecated.apply(&global).project_path,
+        Some(PathBuf::from("/tmp/sub/Twoliter.toml"))
+    );
+    assert_eq!(
+        deprecated.apply(&GlobalArgs::default()).project_path,
+        deprecated.project_path
+    );
+
+    // Subcommands added since the global option do not take it after the subcommand.
+    assert!(
+        Args::try_parse_from(["twoliter", "graph", "--project-path", "Twoliter.toml"]).is_err()
+    );
+}
+
+#[test]
+fn test_parse_arch() {
+    assert_eq!(parse_arch("x86_64").unwrap(), "x86_64");
+    assert_eq!(parse_arch("amd64").unwrap(), "x86_64");
+    assert_eq!(parse_arch("ARM64").unwrap(), "aarch64");
+    assert_eq!(parse_arch("aarch64").unwrap(), "aarch64");
+    assert!(parse_arch("riscv64").is_err());
+    assert!(parse_arch("").is_err());
+
+    let args = Args::try_parse_from(["twoliter", "fetch", "--arch", "arm64"]).unwrap();
+    let Subcommand::Fetch(fetch) = args.subcommand else {
+        panic!("Expected the fetch subcommand");
+    };
+    assert_eq!(fetch.arch.get(), "aarch64");
+    assert!(Args::try_parse_from(["twoliter", "fetch", "--arch", "i686"]).is_err());
+}
+
+/// The examples in the help of each command are valid invocations.
+#[test]
+fn test_help_examples() {
+    use clap::CommandFactory;
+
+    fn examples(command: &clap::Command) -> Vec<String> {
+        let mut found: Vec<String> = command
+            .get_after_help()
+            .map(|help| help.to_string())
+            .unwrap_or_default()
+            .lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with("twoliter "))
+            .map(str::to_string)
+            .collect();
+        for subcommand in command.get_subcommands() {
+            found.extend(examples(subcommand));
+        }
+        found
+    }
+
+    let examples = examples(&Args::command());
+    assert!(examples.len() >= 8);
+    for example in examples {
+        if let Err(e) = Args::try_parse_from(example.split_whitespace()) {
+            panic!("'{}' is not valid: {}", example, e);
         }
     }
 }
 
+#[tokio::test]
+async fn test_frozen() {
+    let args = Args::try_parse_from(["twoliter", "--frozen", "fetch"]).unwrap();
+    let global = GlobalArgs::new(&args);
+    assert!(global.frozen());
+    assert!(global.ensure_not_frozen("fetch images").is_err());
+    assert!(GlobalArgs::default()
+        .ensure_not_frozen("fetch images")
+        .is_ok());
+
+    // A frozen run does not resolve a missing lock file.
+    let temp_dir = crate::test::copy_project_to_temp_dir("local-kit").await;
+    let project = Project::load(temp_dir.path().join("Twoliter.toml"))
+        .await
+        .unwrap();
+    let err = global.load_lock(&project).await.unwrap_err();
+    assert!(err.to_string().contains("twoliter update"));
+    assert!(!temp_dir.path().join(crate::lock::TWOLITER_LOCK).exists());
+}
+
 #[cfg(feature = "integ-tests")]
 #[cfg(test)]
 mod test {
@@ -131,38 +601,65 @@ mod test {
 
     async fn twoliter_update(project_path: &Path) {
         let command = Update {
-            project_path: Some(project_path.to_path_buf()),
+            bump_required_version: false,
+            project_path: DeprecatedProjectPath::default(),
         };
-        command.run().await.unwrap();
+        command
+            .run(&GlobalArgs::default().with_project_path(project_path.to_path_buf()))
+            .await
+            .unwrap();
     }
 
     async fn twoliter_fetch(project_path: &Path, arch: &str) {
         let command = Fetch {
-            project_path: Some(project_path.to_path_buf()),
-            arch: arch.into(),
+            project_path: DeprecatedProjectPath::default(),
+            arch: ArchArg::new(arch),
+            registry: None,
         };
-        command.run().await.unwrap()
+        command
+            .run(&GlobalArgs::default().with_project_path(project_path.to_path_buf()))
+            .await
+            .unwrap()
     }
 
     #[tokio::test]
     async fn build_core_kit() {
         let kit_name = "core-kit";
         let arch = "aarch64";
-        let temp_dir = crate::test::copy_project_to_temp_dir(PROJECT);
+        let temp_dir = crate::test::copy_project_to_temp_dir(PROJECT).await;
         let project_dir = temp_dir.path();
         let project_path = project_dir.join("Twoliter.toml");
         twoliter_update(&project_path).await;
         twoliter_fetch(&project_path, arch).await;
 
         let command = BuildKit {
-            project_path: Some(project_path),
-            arch: arch.to_string(),
+            project_path: DeprecatedProjectPath::default(),
+            arch: ArchArg::new(arch),
             kit: kit_name.to_string(),
-            lookaside_cache: None,
+            lookaside_cache: Vec::new(),
+            deprecated_lookaside_cache: None,
             upstream_source_fallback: false,
+            no_checksums: false,
+            offline: false,
+            network: None,
+            force: false,
+            watch: false,
+            manifest_path: None,
+            refresh_go_modules: false,
+            env_file: None,
+            tag_suffix: None,
+            build_args: Vec::new(),
+            registry: None,
+            pull: None,
+            sdk: None,
+            format: None,
+            sbom: None,
         };
 
-        command.run().await.unwrap();
+        command
+            .run(&GlobalArgs::default().with_project_path(project_path.to_path_buf()))
+            .await
+            .unwrap();
         expect_kit(&project_dir, "core-kit", arch, &["pkg-a"]).await;
     }
 
@@ -170,21 +667,40 @@ mod test {
     async fn build_extra_1_kit() {
         let kit_name = "extra-1-kit";
         let arch = "x86_64";
-        let temp_dir = crate::test::copy_project_to_temp_dir(PROJECT);
+        let temp_dir = crate::test::copy_project_to_temp_dir(PROJECT).await;
         let project_dir = temp_dir.path();
         let project_path = project_dir.join("Twoliter.toml");
         twoliter_update(&project_path).await;
         twoliter_fetch(&project_path, arch).await;
 
         let command = BuildKit {
-            project_path: Some(project_path),
-            arch: arch.to_string(),
+            project_path: DeprecatedProjectPath::default(),
+            arch: ArchArg::new(arch),
             kit: kit_name.to_string(),
-            lookaside_cache: None,
+            lookaside_cache: Vec::new(),
+            deprecated_lookaside_cache: None,
             upstream_source_fallback: false,
+            no_checksums: false,
+            offline: false,
+            network: None,
+            force: false,
+            watch: false,
+            manifest_path: None,
+            refresh_go_modules: false,
+            env_file: None,
+            tag_suffix: None,
+            build_args: Vec::new(),
+            registry: None,
+            pull: None,
+            sdk: None,
+            format: None,
+            sbom: None,
         };
 
-        command.run().await.unwrap();
+        command
+            .run(&GlobalArgs::default().with_project_path(project_path.to_path_buf()))
+            .await
+            .unwrap();
         expect_kit(&project_dir, "core-kit", arch, &["pkg-a"]).await;
         expect_kit(&project_dir, "extra-1-kit", arch, &["pkg-b", "pkg-d"]).await;
     }
@@ -193,21 +709,40 @@ mod test {
     async fn build_extra_2_kit() {
         let kit_name = "extra-2-kit";
         let arch = "aarch64";
-        let temp_dir = crate::test::copy_project_to_temp_dir(PROJECT);
+        let temp_dir = crate::test::copy_project_to_temp_dir(PROJECT).await;
         let project_dir = temp_dir.path();
         let project_path = project_dir.join("Twoliter.toml");
         twoliter_update(&project_path).await;
         twoliter_fetch(&project_path, arch).await;
 
         let command = BuildKit {
-            project_path: Some(project_path),
-            arch: arch.to_string(),
+            project_path: DeprecatedProjectPath::default(),
+            arch: ArchArg::new(arch),
             kit: kit_name.to_string(),
-            lookaside_cache: None,
+            lookaside_cache: Vec::new(),
+            deprecated_lookaside_cache: None,
             upstream_source_fallback: false,
+            no_checksums: false,
+            offline: false,
+            network: None,
+            force: false,
+            watch: false,
+            manifest_path: None,
+            refresh_go_modules: false,
+            env_file: None,
+            tag_suffix: None,
+            build_args: Vec::new(),
+            registry: None,
+            pull: None,
+            sdk: None,
+            format: None,
+            sbom: None,
         };
 
-        command.run().await.unwrap();
+        command
+            .run(&GlobalArgs::default().with_project_path(project_path.to_path_buf()))
+            .await
+            .unwrap();
         expect_kit(&project_dir, "core-kit", arch, &["pkg-a"]).await;
         expect_kit(&project_dir, "extra-2-kit", arch, &["pkg-c"]).await;
     }
@@ -216,21 +751,40 @@ mod test {
     async fn build_extra_3_kit() {
         let kit_name = "extra-3-kit";
         let arch = "x86_64";
-        let temp_dir = crate::test::copy_project_to_temp_dir(PROJECT);
+        let temp_dir = crate::test::copy_project_to_temp_dir(PROJECT).await;
         let project_dir = temp_dir.path();
         let project_path = project_dir.join("Twoliter.toml");
         twoliter_update(&project_path).await;
         twoliter_fetch(&project_path, arch).await;
 
         let command = BuildKit {
-            project_path: Some(project_path),
-            arch: arch.to_string(),
+            project_path: DeprecatedProjectPath::default(),
+            arch: ArchArg::new(arch),
             kit: kit_name.to_string(),
-            lookaside_cache: None,
+            lookaside_cache: Vec::new(),
+            deprecated_lookaside_cache: None,
             upstream_source_fallback: false,
+            no_checksums: false,
+            offline: false,
+            network: None,
+            force: false,
+            watch: false,
+            manifest_path: None,
+            refresh_go_modules: false,
+            env_file: None,
+            tag_suffix: None,
+            build_args: Vec::new(),
+            registry: None,
+            pull: None,
+            sdk: None,
+            format: None,
+            sbom: None,
         };
 
-        command.run().await.unwrap();
+        command
+            .run(&GlobalArgs::default().with_project_path(project_path.to_path_buf()))
+            .await
+            .unwrap();
         expect_kit(&project_dir, "core-kit", arch, &["pkg-a"]).await;
         expect_kit(&project_dir, "extra-1-kit", arch, &["pkg-b", "pkg-d"]).await;
         expect_kit(&project_dir, "extra-2-kit", arch, &["pkg-c"]).await;
@@ -0,0 +1,98 @@
+/*!
+
+How commands that describe the project, such as `kit list`, print what they found. The global
+`--format` option chooses between text for people and JSON or TOML for scripts, and every such
+command prints through [`print`] so that the structured formats look the same everywhere.
+
+!*/
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// Text meant to be read by people, which may change between releases.
+    #[default]
+    Text,
+    /// Pretty-printed JSON.
+    Json,
+    /// TOML. A list is written as an array of tables named after what it lists, e.g. `[[kit]]`.
+    Toml,
+}
+
+/// Prints `value` in `format`. `name` says what `value` is, e.g. `kit` for a list of kits, and is
+/// used for error messages and to name a list in TOML, which cannot have a list at the top level.
+/// `text` renders the text format.
+pub(crate) fn print<T: Serialize>(
+    format: OutputFormat,
+    name: &str,
+    value: &T,
+    text: impl FnOnce(&T) -> String,
+) -> Result<()> {
+    print!("{}", serialize(format, name, value, text)?);
+    Ok(())
+}
+
+/// Renders `value` the way [`print`] prints it.
+pub(crate) fn serialize<T: Serialize>(
+    format: OutputFormat,
+    name: &str,
+    value: &T,
+    text: impl FnOnce(&T) -> String,
+) -> Result<String> {
+    match format {
+        OutputFormat::Text => Ok(text(value)),
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(value)
+            .context(format!("Unable to serialize the {} as JSON", name))?
+            + "\n"),
+        OutputFormat::Toml => {
+            let value = toml::Value::try_from(value)
+                .context(format!("Unable to serialize the {} as TOML", name))?;
+            let table = match value {
+                toml::Value::Table(table) => table,
+                other => toml::Table::from_iter([(name.to_string(), other)]),
+            };
+            toml::to_string_pretty(&table)
+                .context(format!("Unable to serialize the {} as TOML", name))
+        }
+    }
+}
+
+#[test]
+fn test_serialize() {
+    #[derive(Serialize)]
+    struct Kit {
+        name: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        vendor: Option<&'static str>,
+    }
+    let kits = vec![
+        Kit {
+            name: "core-kit",
+            vendor: Some("bottlerocket"),
+        },
+        Kit {
+            name: "extra-kit",
+            vendor: None,
+        },
+    ];
+    let text = |kits: &Vec<Kit>| format!("{} kit(s)\n", kits.len());
+
+    assert_eq!(
+        serialize(OutputFormat::Text, "kit", &kits, text).unwrap(),
+        "2 kit(s)\n"
+    );
+    let json: serde_json::Value =
+        serde_json::from_str(&serialize(OutputFormat::Json, "kit", &kits, text).unwrap()).unwrap();
+    assert_eq!(json[1]["name"], "extra-kit");
+
+    let toml = serialize(OutputFormat::Toml, "kit", &kits, text).unwrap();
+    assert!(toml.starts_with("[[kit]]\n"), "{}", toml);
+    let table: toml::Table = toml::from_str(&toml).unwrap();
+    assert_eq!(table["kit"][0]["vendor"].as_str(), Some("bottlerocket"));
+
+    // A table is written as it is.
+    let toml = serialize(OutputFormat::Toml, "kit", &kits[0], |_| String::new()).unwrap();
+    assert!(toml.starts_with("name = \"core-kit\"\n"), "{}", toml);
+}
@@ -1,10 +1,17 @@
-use crate::cargo_make::CargoMake;
-use crate::lock::Lock;
-use crate::project;
+use crate::cargo_make::{path_var, CargoMake};
+use crate::cmd::{DeprecatedProjectPath, GlobalArgs};
+use crate::common::fs;
+use crate::cosign::{self, Signed};
+use crate::infra;
+use crate::project::{Project, ValidIdentifier};
 use crate::tools::install_tools;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
-use std::path::PathBuf;
+use log::info;
+use std::path::{Path, PathBuf};
+
+/// Written into the kit's build directory after the published kit is signed.
+const SIGNATURE_FILE: &str = "signature.json";
 
 /// Group all publish commands
 #[derive(Debug, Parser)]
@@ -13,9 +20,9 @@ pub(crate) enum PublishCommand {
 }
 
 impl PublishCommand {
-    pub(crate) async fn run(self) -> Result<()> {
+    pub(crate) async fn run(self, global: &GlobalArgs) -> Result<()> {
         match self {
-            PublishCommand::Kit(command) => command.run().await,
+            PublishCommand::Kit(command) => command.run(global).await,
         }
     }
 }
@@ -23,33 +30,132 @@ impl PublishCommand {
 /// Publish a local kit to a container registry
 #[derive(Debug, Parser)]
 pub(crate) struct PublishKit {
-    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
-    #[clap(long = "project-path")]
-    project_path: Option<PathBuf>,
+    #[clap(flatten)]
+    project_path: DeprecatedProjectPath,
 
     /// Kit name to build
     kit_name: String,
 
     /// Vendor to publish to
     vendor: String,
+
+    /// Appended to the release version in the kit's tag, e.g. `pr-123`. Overrides `tag-suffix` in
+    /// the `[build]` section of Twoliter.toml, and should match the suffix the kit was built with.
+    #[clap(long = "tag-suffix")]
+    tag_suffix: Option<String>,
+
+    /// Sign the published kit with cosign, which must be on the PATH.
+    #[clap(long = "sign")]
+    sign: bool,
+
+    /// The key to sign with: a path to a cosign private key, an AWS KMS ARN, or a cosign key URI.
+    /// Overrides `key` in the `[vendor.<VENDOR>.signing]` section of Twoliter.toml.
+    #[clap(long = "signing-key", requires = "sign")]
+    signing_key: Option<String>,
 }
 
 impl PublishKit {
-    pub(super) async fn run(&self) -> Result<()> {
-        let project = project::load_or_find_project(self.project_path.clone()).await?;
-        let lock = Lock::load(&project).await?;
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let project = self.project_path.apply(global).load_project().await?;
+        let lock = global.load_lock(&project).await?;
+        // Find out whether the kit can be signed before it is pushed.
+        let signing_key = if self.sign {
+            cosign::find_cosign()?;
+            Some(self.signing_key(&project)?)
+        } else {
+            None
+        };
         let toolsdir = project.project_dir().join("build/tools");
+        // Keeps `build clean` out until the run is done.
+        let _build_lock = global.lock_build(&project).await?;
+        let project_lock = global.lock_project(&project).await?;
         install_tools(&toolsdir).await?;
+        drop(project_lock);
         let makefile_path = toolsdir.join("Makefile.toml");
+        let version = project.image_version(self.tag_suffix.as_deref())?;
 
         CargoMake::new(&lock.sdk.source)?
-            .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
+            .env("TWOLITER_TOOLS_DIR", path_var(&toolsdir)?)
             .env("BUILDSYS_KIT", &self.kit_name)
-            .env("BUILDSYS_VERSION_IMAGE", project.release_version())
+            .env("BUILDSYS_VERSION_IMAGE", &version)
             .env("PUBLISH_VENDOR", &self.vendor)
             .makefile(makefile_path)
             .project_dir(project.project_dir())
+            .bootstrap_tools(global.bootstrap_tools())
             .exec("publish-kit")
+            .await?;
+
+        let Some(signing_key) = signing_key else {
+            return Ok(());
+        };
+        let reference = format!(
+            "{}/{}:v{}",
+            self.registry(&project).await?,
+            self.kit_name,
+            version
+        );
+        let signed = cosign::sign(&reference, &signing_key)
             .await
+            .context(format!(
+                "The kit was published to {} but could not be signed, so it is unsigned",
+                reference
+            ))?;
+        let kit_dir = project
+            .project_dir()
+            .join("build/kits")
+            .join(&self.kit_name);
+        write_signature(&kit_dir, &signed).await?;
+        info!(
+            "Signed {}, the signature is {}",
+            signed.image, signed.signature
+        );
+        println!("{}", signed.signature);
+        Ok(())
     }
+
+    /// The signing key from the command line, or else from the vendor in Twoliter.toml.
+    fn signing_key(&self, project: &Project) -> Result<String> {
+        if let Some(key) = &self.signing_key {
+            return Ok(key.clone());
+        }
+        project
+            .vendor()
+            .get(&ValidIdentifier(self.vendor.clone()))
+            .and_then(|vendor| vendor.signing.as_ref())
+            .map(|signing| signing.key.clone())
+            .context(format!(
+                "Signing needs a key, give --signing-key or set 'key' in the \
+                [vendor.{}.signing] section of Twoliter.toml",
+                self.vendor
+            ))
+    }
+
+    /// The registry that pubsys published to, which is the vendor's in Infra.toml, or else in
+    /// Twoliter.toml.
+    async fn registry(&self, project: &Project) -> Result<String> {
+        let infra_toml = std::env::var_os("PUBLISH_INFRA_CONFIG_PATH")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| project.project_dir().join("Infra.toml"));
+        if infra_toml.is_file() {
+            if let Some(registry) = infra::vendor_registry(&infra_toml, &self.vendor).await? {
+                return Ok(registry);
+            }
+        }
+        project
+            .vendor()
+            .get(&ValidIdentifier(self.vendor.clone()))
+            .map(|vendor| vendor.registry.clone())
+            .context(format!(
+                "Unable to find the registry of vendor '{}' to sign the kit in",
+                self.vendor
+            ))
+    }
+}
+
+/// Records where the signature of a published kit is, next to the kit that was built.
+async fn write_signature(kit_dir: &Path, signed: &Signed) -> Result<()> {
+    let data =
+        serde_json::to_vec_pretty(signed).context("Unable to serialize the kit signature")?;
+    fs::create_dir_all(kit_dir).await?;
+    fs::write(kit_dir.join(SIGNATURE_FILE), data).await
 }
@@ -0,0 +1,233 @@
+use crate::cargo_make::path_var;
+use crate::cmd::build::{BuildKit, BuildVariant};
+use crate::cmd::{ArchArg, GlobalArgs};
+use crate::lock::Lock;
+use crate::ownership::{current_user, is_rootless, Owner};
+use crate::project::Project;
+use crate::tools::install_tools;
+use anyhow::{ensure, Context, Result};
+use clap::Parser;
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+use std::path::Path;
+use tokio::process::Command;
+use uuid::Uuid;
+
+const SHELL_EXAMPLES: &str = "\
+Examples:
+  # Open a shell with the environment for building a variant for aarch64
+  twoliter shell --arch aarch64 --variant aws-dev
+
+  # Open a shell with the environment for building a kit
+  twoliter shell --arch x86_64 --kit core-kit";
+
+/// Open a shell in the SDK container that builds run in, for debugging a package build by hand.
+/// The project is mounted at the same path as on the host, and the container gets the environment
+/// that `twoliter build` passes to `cargo make`, along with the directories that the Makefile
+/// derives from it. The container is removed when the shell exits.
+#[derive(Debug, Parser)]
+#[clap(after_help = SHELL_EXAMPLES)]
+pub(crate) struct Shell {
+    #[clap(flatten)]
+    arch: ArchArg,
+
+    /// Use the environment for building this variant.
+    #[clap(long, conflicts_with = "kit")]
+    variant: Option<String>,
+
+    /// Use the environment for building this kit.
+    #[clap(long)]
+    kit: Option<String>,
+
+    /// Run this command with `bash -c` and exit instead of starting an interactive shell. This is
+    /// the only way to use the command without a terminal, e.g. from a script.
+    #[clap(long = "command")]
+    command: Option<String>,
+}
+
+impl Shell {
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        ensure!(
+            self.command.is_some() || std::io::stdout().is_terminal(),
+            "An interactive shell needs a terminal, use --command to run a command without one"
+        );
+        let project = global.load_project().await?;
+        let lock = global.load_lock(&project).await?;
+        lock.ensure_sdk(self.arch.get(), global.frozen(), global.images())
+            .await?;
+        let toolsdir = project.project_dir().join("build/tools");
+        // Keeps `build clean` out until the run is done.
+        let _build_lock = global.lock_build(&project).await?;
+        let project_lock = global.lock_project(&project).await?;
+        install_tools(&toolsdir).await?;
+        drop(project_lock);
+
+        let env = self.env(&project, &lock, global.frozen()).await?;
+        // With rootless docker, root in the container is already the user on the host.
+        let user = if is_rootless().await? {
+            None
+        } else {
+            Some(current_user().await?)
+        };
+        let args = docker_args(
+            &project,
+            &lock.sdk.source,
+            &env,
+            user,
+            self.command.as_deref(),
+            std::io::stdin().is_terminal(),
+        );
+        // The values are passed through the environment of `docker` rather than on its command
+        // line, so that secrets do not show up in the process list.
+        let status = Command::new("docker")
+            .args(&args)
+            .envs(&env)
+            .status()
+            .await
+            .context("Unable to run docker")?;
+        ensure!(
+            status.success(),
+            "The shell in {} exited with {}",
+            lock.sdk.source,
+            status
+        );
+        Ok(())
+    }
+
+    /// The environment that a build of the variant or kit would pass to `cargo make`, or only the
+    /// basics if neither was given, plus the directories that the Makefile derives from them.
+    async fn env(
+        &self,
+        project: &Project,
+        lock: &Lock,
+        frozen: bool,
+    ) -> Result<BTreeMap<String, String>> {
+        let mut env = match (&self.variant, &self.kit) {
+            (Some(variant), _) => {
+                BuildVariant::with_defaults(self.arch.get(), variant)
+                    .cargo_make(project, lock, frozen)
+                    .await?
+                    .explain("build")?
+                    .env
+            }
+            (None, Some(kit)) => {
+                BuildKit::with_defaults(self.arch.get(), kit)
+                    .cargo_make(project, lock, frozen)
+                    .await?
+                    .explain("build-kit")?
+                    .env
+            }
+            (None, None) => BTreeMap::from([
+                ("BUILDSYS_ARCH".to_string(), self.arch.get().to_string()),
+                (
+                    "TWOLITER_TOOLS_DIR".to_string(),
+                    path_var(project.project_dir().join("build/tools"))?,
+                ),
+            ]),
+        };
+        let root = project.project_dir();
+        for (key, path) in [
+            ("BUILDSYS_ROOT_DIR", root.clone()),
+            ("BUILDSYS_BUILD_DIR", root.join("build")),
+            ("BUILDSYS_PACKAGES_DIR", root.join("build/rpms")),
+            ("BUILDSYS_SOURCES_DIR", root.join("sources")),
+            ("CARGO_HOME", root.join(".cargo")),
+        ] {
+            if !env.contains_key(key) {
+                env.insert(key.to_string(), path_var(&path)?);
+            }
+        }
+        Ok(env)
+    }
+}
+
+/// The arguments to `docker` that start the shell in `sdk`. The values of `env` are not included,
+/// `docker` reads them from its own environment.
+fn docker_args(
+    project: &Project,
+    sdk: &str,
+    env: &BTreeMap<String, String>,
+    user: Option<Owner>,
+    command: Option<&str>,
+    stdin_is_terminal: bool,
+) -> Vec<String> {
+    let project_dir = project.project_dir();
+    let mut args = vec![
+        "run".to_string(),
+        "--rm".to_string(),
+        format!("--name=twoliter-shell-{}", &Uuid::new_v4().to_string()[..8]),
+        "--security-opt=label=disable".to_string(),
+        format!("--volume={0}:{0}", project_dir.display()),
+        format!("--workdir={}", project_dir.display()),
+        "--env=HOME=/tmp".to_string(),
+        "--entrypoint=bash".to_string(),
+    ];
+    // The tools and the shared cache can be outside of the project.
+    let mut mounts: Vec<&Path> = Vec::new();
+    for key in ["TWOLITER_TOOLS_DIR", "BUILDSYS_SHARED_CACHE"] {
+        if let Some(dir) = env.get(key).map(Path::new) {
+            if !dir.starts_with(&project_dir) && !mounts.contains(&dir) {
+                mounts.push(dir);
+            }
+        }
+    }
+    for dir in mounts {
+        args.push(format!("--volume={0}:{0}", dir.display()));
+    }
+    if let Some(network) = project.docker_network() {
+        args.push(format!("--network={}", network));
+    }
+    if let Some(user) = user {
+        args.push(format!("--user={}:{}", user.uid, user.gid));
+    }
+    if command.is_none() || stdin_is_terminal {
+        args.push("--interactive".to_string());
+    }
+    if command.is_none() {
+        args.push("--tty".to_string());
+    }
+    args.extend(env.keys().map(|key| format!("--env={}", key)));
+    args.push(sdk.to_string());
+    if let Some(command) = command {
+        args.push("-c".to_string());
+        args.push(command.to_string());
+    }
+    args
+}
+
+#[tokio::test]
+async fn test_docker_args() {
+    let temp_dir = crate::test::copy_project_to_temp_dir("local-kit").await;
+    let project = Project::load(temp_dir.path().join("Twoliter.toml"))
+        .await
+        .unwrap();
+    let project_dir = project.project_dir().display().to_string();
+    let env = BTreeMap::from([
+        ("BUILDSYS_ARCH".to_string(), "x86_64".to_string()),
+        (
+            "TWOLITER_TOOLS_DIR".to_string(),
+            format!("{}/build/tools", project_dir),
+        ),
+        ("BUILDSYS_SHARED_CACHE".to_string(), "/cache".to_string()),
+    ]);
+    let user = Owner {
+        uid: 1000,
+        gid: 100,
+    };
+
+    let args = docker_args(&project, "sdk:v1", &env, Some(user), None, true);
+    assert!(args.contains(&format!("--volume={0}:{0}", project_dir)));
+    assert!(args.contains(&"--volume=/cache:/cache".to_string()));
+    assert!(!args.iter().any(|arg| arg.contains("build/tools:")));
+    assert!(args.contains(&"--user=1000:100".to_string()));
+    assert!(args.contains(&"--tty".to_string()));
+    assert!(args.contains(&"--env=BUILDSYS_ARCH".to_string()));
+    assert!(!args.iter().any(|arg| arg.contains("x86_64")));
+    assert_eq!(args.last().unwrap(), "sdk:v1");
+
+    let args = docker_args(&project, "sdk:v1", &env, None, Some("make -v"), false);
+    assert!(!args.iter().any(|arg| arg.starts_with("--user")));
+    assert!(!args.contains(&"--tty".to_string()));
+    assert!(!args.contains(&"--interactive".to_string()));
+    assert_eq!(args[args.len() - 3..], ["sdk:v1", "-c", "make -v"]);
+}
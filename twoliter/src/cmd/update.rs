@@ -1,19 +1,40 @@
+use crate::cmd::{DeprecatedProjectPath, GlobalArgs};
 use crate::lock::Lock;
-use crate::project;
 use anyhow::Result;
 use clap::Parser;
-use std::path::PathBuf;
+use log::info;
 
 #[derive(Debug, Parser)]
 pub(crate) struct Update {
-    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
-    #[clap(long = "project-path")]
-    pub(crate) project_path: Option<PathBuf>,
+    #[clap(flatten)]
+    pub(crate) project_path: DeprecatedProjectPath,
+
+    /// Change `required-twoliter-version` in Twoliter.toml to allow this version of Twoliter if it
+    /// does not already.
+    #[clap(long = "bump-required-version")]
+    pub(crate) bump_required_version: bool,
 }
 
 impl Update {
-    pub(super) async fn run(&self) -> Result<()> {
-        let project = project::load_or_find_project(self.project_path.clone()).await?;
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        global.ensure_not_frozen("update Twoliter.lock")?;
+        let project = if self.bump_required_version {
+            let project = self
+                .project_path
+                .apply(global)
+                .load_project_unchecked()
+                .await?;
+            if let Some(required) = project.bump_required_twoliter_version().await? {
+                info!(
+                    "Changed required-twoliter-version in '{}' to '{}'",
+                    project.filepath().display(),
+                    required
+                );
+            }
+            project
+        } else {
+            self.project_path.apply(global).load_project().await?
+        };
         Lock::create(&project).await?;
         Ok(())
     }
@@ -0,0 +1,51 @@
+use super::output::{self, OutputFormat};
+use crate::cmd::GlobalArgs;
+use crate::variant;
+use anyhow::Result;
+use clap::Parser;
+use std::fmt::Write;
+
+#[derive(Debug, Parser)]
+pub(crate) enum VariantCommand {
+    List(ListVariants),
+}
+
+impl VariantCommand {
+    pub(crate) async fn run(self, global: &GlobalArgs) -> Result<()> {
+        match self {
+            VariantCommand::List(command) => command.run(global).await,
+        }
+    }
+}
+
+/// List the variants in the project's `variants` directory with the platform, runtime, family and
+/// flavor that a build would use, and the image features that each turns on.
+#[derive(Debug, Parser)]
+pub(crate) struct ListVariants {
+    /// Print the variants as `text`, `json` or `toml`. Overrides the global `--format`.
+    #[clap(long = "format", value_enum)]
+    format: Option<OutputFormat>,
+}
+
+impl ListVariants {
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let project = global.load_project().await?;
+        let variants = variant::list(&project).await?;
+        let format = self.format.unwrap_or(global.format());
+        output::print(format, "variant", &variants, |variants| {
+            let width = variants.iter().map(|v| v.name.len()).max().unwrap_or(0);
+            let mut text = String::new();
+            for variant in variants {
+                let _ = writeln!(
+                    text,
+                    "{:width$}  family={} flavor={} features={}",
+                    variant.name,
+                    variant.parts.family,
+                    variant.parts.flavor.as_deref().unwrap_or("-"),
+                    variant.image_features.join(","),
+                );
+            }
+            text
+        })
+    }
+}
@@ -0,0 +1,154 @@
+use super::output::{self, OutputFormat};
+use crate::cargo_make::CARGO_MAKE_VERSION;
+use crate::cmd::GlobalArgs;
+use crate::tools::EMBEDDED_VERSIONS;
+use anyhow::Result;
+use clap::Parser;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+/// Print the version of Twoliter, the commit it was built from and the versions of the tools that
+/// are embedded in it. With `--json` this is an object that scripts and inventories can collect.
+#[derive(Debug, Parser)]
+pub(crate) struct ShowVersion {
+    /// Print the versions as JSON, the same as the global `--format json`.
+    #[clap(long)]
+    json: bool,
+}
+
+impl ShowVersion {
+    pub(super) async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let format = if self.json {
+            OutputFormat::Json
+        } else {
+            global.format()
+        };
+        output::print(format, "version", &VersionInfo::new(), |info| {
+            format!("twoliter {}\n", info)
+        })
+    }
+}
+
+/// The commit that Twoliter was built from, or `unknown` if it could not be found at build time.
+pub(crate) const GIT_SHA: &str = env!("TWOLITER_GIT_SHA");
+
+/// Whether tracked files had changed since [`GIT_SHA`] when Twoliter was built: `true`, `false` or
+/// `unknown`.
+pub(crate) const GIT_DIRTY: &str = env!("TWOLITER_GIT_DIRTY");
+
+/// What [`GIT_SHA`] is when the commit is not known.
+const UNKNOWN: &str = "unknown";
+
+/// What this build of Twoliter is made of.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub(crate) struct VersionInfo {
+    /// The version of Twoliter.
+    version: &'static str,
+    /// The full commit that Twoliter was built from, or `unknown`.
+    git_sha: &'static str,
+    /// Whether there were uncommitted changes to the commit, if that is known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    git_dirty: Option<bool>,
+    /// The version of cargo-make that Twoliter installs and is tested with.
+    cargo_make: &'static str,
+    /// The version of each package whose binaries are embedded, by name.
+    tools: BTreeMap<&'static str, &'static str>,
+}
+
+impl VersionInfo {
+    pub(crate) fn new() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: GIT_SHA,
+            git_dirty: GIT_DIRTY.parse().ok(),
+            cargo_make: CARGO_MAKE_VERSION,
+            tools: EMBEDDED_VERSIONS
+                .iter()
+                .filter_map(|(name, version)| Some((*name, (*version)?)))
+                .collect(),
+        }
+    }
+}
+
+/// The versions without the name of Twoliter, which clap puts before `--version`.
+impl Display for VersionInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.version)?;
+        if self.git_sha == UNKNOWN {
+            write!(f, " (commit unknown)")?;
+        } else {
+            // Twelve characters are plenty to find the commit, the JSON has all of it.
+            let short = &self.git_sha[..self.git_sha.len().min(12)];
+            match self.git_dirty {
+                Some(true) => write!(f, " ({}, dirty)", short)?,
+                _ => write!(f, " ({})", short)?,
+            }
+        }
+        write!(f, "\ncargo-make {}", self.cargo_make)?;
+        for (name, version) in &self.tools {
+            write!(f, "\n{} {}", name, version)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_version_info() {
+    let info = VersionInfo {
+        version: "0.4.1",
+        git_sha: "0123456789abcdef0123456789abcdef01234567",
+        git_dirty: Some(false),
+        cargo_make: "0.37.9",
+        tools: BTreeMap::from([("buildsys", "0.1.0"), ("tuftool", "0.10.3")]),
+    };
+    assert_eq!(
+        info.to_string(),
+        "0.4.1 (0123456789ab)\ncargo-make 0.37.9\nbuildsys 0.1.0\ntuftool 0.10.3"
+    );
+    let json: serde_json::Value = serde_json::from_str(
+        &output::serialize(OutputFormat::Json, "version", &info, |_| String::new()).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "version": "0.4.1",
+            "git_sha": "0123456789abcdef0123456789abcdef01234567",
+            "git_dirty": false,
+            "cargo_make": "0.37.9",
+            "tools": {"buildsys": "0.1.0", "tuftool": "0.10.3"},
+        })
+    );
+
+    let info = VersionInfo {
+        git_dirty: Some(true),
+        ..info
+    };
+    assert!(info
+        .to_string()
+        .starts_with("0.4.1 (0123456789ab, dirty)\n"));
+
+    // Without a commit, as when built without git.
+    let info = VersionInfo {
+        git_sha: UNKNOWN,
+        git_dirty: None,
+        tools: BTreeMap::new(),
+        ..info
+    };
+    assert_eq!(
+        info.to_string(),
+        "0.4.1 (commit unknown)\ncargo-make 0.37.9"
+    );
+    let json = output::serialize(OutputFormat::Json, "version", &info, |_| String::new()).unwrap();
+    assert!(json.contains(r#""git_sha": "unknown""#));
+    assert!(!json.contains("git_dirty"));
+
+    let info = VersionInfo::new();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert!(info.git_sha == UNKNOWN || info.git_sha.len() >= 40);
+    assert_eq!(info.git_dirty.is_none(), GIT_DIRTY == UNKNOWN);
+    assert!(info.tools.keys().all(|name| EMBEDDED_VERSIONS
+        .iter()
+        .any(|(embedded, _)| embedded == name)));
+}
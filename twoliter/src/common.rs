@@ -1,6 +1,20 @@
+use crate::logging::{log_format, output_line, LogFormat};
 use anyhow::{ensure, Context, Result};
-use log::{self, debug, LevelFilter};
-use tokio::process::Command;
+use futures::stream::{self, Stream, StreamExt};
+use log::{self, debug, warn, LevelFilter};
+use nix::sys::signal::{kill, killpg, Signal};
+use nix::unistd::{setpgid, Pid};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, Once};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::signal::unix::{signal, SignalKind};
 
 /// This is passed as an environment variable to Buildsys. Buildsys tells Cargo to watch this
 /// environment variable for changes. So if we have a breaking change to the way Buildsys and/or
@@ -8,26 +22,303 @@ use tokio::process::Command;
 /// Twoliter.
 pub(crate) const BUILDSYS_OUTPUT_GENERATION_ID: u32 = 1;
 
+/// Environment variables whose names contain any of these words are assumed to hold secrets.
+const SECRET_KEY_WORDS: [&str; 4] = ["TOKEN", "SECRET", "PASSWORD", "KEY"];
+
+/// The text shown in place of a secret value.
+pub(crate) const REDACTED: &str = "<redacted>";
+
+/// Returns `true` if the environment variable named `key` looks like it holds a secret.
+pub(crate) fn is_secret_key(key: &str) -> bool {
+    let key = key.to_uppercase();
+    SECRET_KEY_WORDS.iter().any(|word| key.contains(word))
+}
+
+/// The environment variables that hold `KEY=VALUE` lines, each of which may hold a secret of its
+/// own, such as the `--build-arg` values of a build.
+const ASSIGNMENT_LIST_KEYS: [&str; 1] = ["BUILDSYS_BUILD_ARGS"];
+
+/// Returns `value`, or a placeholder if the environment variable named `key` looks like it holds a
+/// secret. In a variable that holds `KEY=VALUE` lines, each line is redacted by its own key.
+pub(crate) fn redact<'a>(key: &str, value: &'a str) -> Cow<'a, str> {
+    if is_secret_key(key) {
+        Cow::Borrowed(REDACTED)
+    } else if ASSIGNMENT_LIST_KEYS.contains(&key) {
+        let lines: Vec<String> = value
+            .split('\n')
+            .map(|line| match line.split_once('=') {
+                Some((key, value)) => format!("{}={}", key, redact(key, value)),
+                None => line.to_string(),
+            })
+            .collect();
+        Cow::Owned(lines.join("\n"))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Returns `args` with secret values hidden, for logging. Secrets are recognized in `KEY=VALUE`
+/// arguments, such as `--build-arg`, `-e` and `--env` values, and in the value that follows, or is
+/// attached with `=` to, a flag like `--password`.
+pub(crate) fn redact_args<S: AsRef<str>>(args: &[S]) -> Vec<String> {
+    let mut redacted = Vec::with_capacity(args.len());
+    let mut secret_flag = false;
+    for arg in args.iter().map(AsRef::as_ref) {
+        if secret_flag {
+            redacted.push(REDACTED.to_string());
+            secret_flag = false;
+            continue;
+        }
+        if let Some(flag) = arg.strip_prefix('-').filter(|flag| !flag.contains('=')) {
+            secret_flag = is_secret_key(flag);
+            redacted.push(arg.to_string());
+            continue;
+        }
+        // `-e=KEY=VALUE` and `--build-arg=KEY=VALUE` hold a `KEY=VALUE` after the flag.
+        let (prefix, assignment) = match arg.split_once('=') {
+            Some((flag, rest)) if flag.starts_with('-') && rest.contains('=') => {
+                (format!("{}=", flag), rest)
+            }
+            _ => (String::new(), arg),
+        };
+        match assignment.split_once('=') {
+            Some((key, value)) => {
+                redacted.push(format!("{}{}={}", prefix, key, redact(key, value)))
+            }
+            None => redacted.push(arg.to_string()),
+        }
+    }
+    redacted
+}
+
+/// Renders `cmd` for logging, with the environment variables that it sets and its arguments, and
+/// with secret values hidden, see [`redact_args`].
+pub(crate) fn display_command(cmd: &Command) -> String {
+    let cmd = cmd.as_std();
+    let mut words: Vec<String> = cmd
+        .get_envs()
+        .filter_map(|(key, value)| Some((key.to_string_lossy(), value?.to_string_lossy())))
+        .map(|(key, value)| format!("{}={}", key, redact(&key, &value)))
+        .collect();
+    words.push(cmd.get_program().to_string_lossy().to_string());
+    let args: Vec<_> = cmd
+        .get_args()
+        .map(|arg| arg.to_string_lossy().to_string())
+        .collect();
+    words.extend(redact_args(&args));
+    words.join(" ")
+}
+
+/// How long commands are given to exit after Twoliter passes on a termination signal to them, before
+/// they are killed.
+const TERMINATION_GRACE: Duration = Duration::from_secs(10);
+
+/// The commands that are running, by process ID, and whether each leads a process group of its own.
+static CHILDREN: Mutex<BTreeMap<i32, bool>> = Mutex::new(BTreeMap::new());
+
+/// The number of interactive commands that are running. They share Twoliter's terminal and handle
+/// Ctrl-C themselves.
+static INTERACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+fn children() -> MutexGuard<'static, BTreeMap<i32, bool>> {
+    // The map is always left consistent, so it is still usable if a holder panicked.
+    CHILDREN
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Keeps a running command registered, so that a termination signal that Twoliter receives is
+/// passed on to it.
+struct Registered(i32);
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        children().remove(&self.0);
+    }
+}
+
+/// Spawns `cmd` so that it is stopped along with Twoliter. Without a terminal, e.g. in CI, the
+/// command gets a process group of its own, so that everything it starts can be signalled at once.
+/// With a terminal it stays in Twoliter's process group, since a background process group cannot
+/// use the terminal, and Ctrl-C reaches it directly.
+fn spawn(cmd: &mut Command) -> std::io::Result<(Child, Option<Registered>)> {
+    spawn_in(cmd, !std::io::stdin().is_terminal())
+}
+
+/// Spawns and registers `cmd`, in a process group of its own if `own_group`.
+fn spawn_in(cmd: &mut Command, own_group: bool) -> std::io::Result<(Child, Option<Registered>)> {
+    handle_termination();
+    if own_group {
+        // SAFETY: `setpgid` is async-signal-safe, and nothing else happens between fork and exec.
+        unsafe {
+            cmd.pre_exec(|| {
+                setpgid(Pid::from_raw(0), Pid::from_raw(0)).map_err(std::io::Error::from)
+            });
+        }
+    }
+    let child = cmd.spawn()?;
+    let registered = child.id().map(|pid| {
+        let pid = pid as i32;
+        children().insert(pid, own_group);
+        Registered(pid)
+    });
+    Ok((child, registered))
+}
+
+/// Installs, once, the handler for `SIGTERM` and `SIGINT` that stops the running commands and then
+/// exits.
+fn handle_termination() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        tokio::spawn(async {
+            if let Err(e) = wait_for_termination().await {
+                warn!("Unable to pass on termination signals to commands: {:#}", e);
+            }
+        });
+    });
+}
+
+async fn wait_for_termination() -> Result<()> {
+    let mut terminate =
+        signal(SignalKind::terminate()).context("Unable to handle SIGTERM".to_string())?;
+    let mut interrupt =
+        signal(SignalKind::interrupt()).context("Unable to handle SIGINT".to_string())?;
+    loop {
+        let received = tokio::select! {
+            _ = terminate.recv() => Signal::SIGTERM,
+            _ = interrupt.recv() => Signal::SIGINT,
+        };
+        if received == Signal::SIGINT && INTERACTIVE.load(Ordering::SeqCst) > 0 {
+            continue;
+        }
+        stop_children(received).await;
+        std::process::exit(128 + received as i32);
+    }
+}
+
+/// Passes on `received` to the running commands, and kills those that are still running after
+/// [`TERMINATION_GRACE`].
+async fn stop_children(received: Signal) {
+    let running = children().clone();
+    if running.is_empty() {
+        return;
+    }
+    warn!(
+        "Received {}, stopping {} running command(s)",
+        received,
+        running.len()
+    );
+    stop(&running, received).await;
+}
+
+/// Stops the `running` commands, which are process IDs and whether each leads a process group of
+/// its own.
+async fn stop(running: &BTreeMap<i32, bool>, received: Signal) {
+    for (pid, own_group) in running {
+        let pid = Pid::from_raw(*pid);
+        // Ctrl-C in a terminal already reached the commands in Twoliter's process group.
+        let _ = match (own_group, received) {
+            (true, _) => killpg(pid, received),
+            (false, Signal::SIGINT) => Ok(()),
+            (false, _) => kill(pid, received),
+        };
+    }
+    let still_running = || -> Vec<(i32, bool)> {
+        let children = children();
+        running
+            .iter()
+            .filter(|(pid, _)| children.contains_key(pid))
+            .map(|(pid, own_group)| (*pid, *own_group))
+            .collect()
+    };
+    let deadline = Instant::now() + TERMINATION_GRACE;
+    while !still_running().is_empty() && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    for (pid, own_group) in still_running() {
+        warn!("Killing command {} that did not stop in time", pid);
+        let pid = Pid::from_raw(pid);
+        let _ = if own_group {
+            killpg(pid, Signal::SIGKILL)
+        } else {
+            kill(pid, Signal::SIGKILL)
+        };
+    }
+}
+
 /// Run a `tokio::process::Command` and return a `Result` letting us know whether or not it worked.
 /// Pipes stdout/stderr when logging `LevelFilter` is more verbose than `Warn`.
 pub(crate) async fn exec_log(cmd: &mut Command) -> Result<()> {
-    let quiet = matches!(
-        log::max_level(),
-        LevelFilter::Off | LevelFilter::Error | LevelFilter::Warn
+    exec(cmd, is_quiet()).await?;
+    Ok(())
+}
+
+/// Run a `tokio::process::Command` attached to Twoliter's own terminal, so that it can prompt for
+/// input. Its output is never captured or reformatted, whatever the log level or format.
+pub(crate) async fn exec_interactive(cmd: &mut Command) -> Result<()> {
+    debug!("Running: {}", display_command(cmd));
+    INTERACTIVE.fetch_add(1, Ordering::SeqCst);
+    let status = cmd
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .await;
+    INTERACTIVE.fetch_sub(1, Ordering::SeqCst);
+    let status = status.context("Unable to start command".to_string())?;
+    ensure!(
+        status.success(),
+        "Command was unsuccessful, exit code {}",
+        status.code().unwrap_or(1),
     );
-    exec(cmd, quiet).await?;
     Ok(())
 }
 
+/// Like [`exec_log`], but each line of output starts with `[prefix] ` so that the output of commands
+/// that run at the same time can be told apart.
+pub(crate) async fn exec_prefixed(cmd: &mut Command, prefix: &str) -> Result<()> {
+    if is_quiet() {
+        exec(cmd, true).await?;
+        return Ok(());
+    }
+    debug!("Running: {}", display_command(cmd));
+    let format = log_format();
+    let status = stream_lines(cmd, |stream, line| {
+        output_line(format, stream, &format!("[{}] {}", prefix, line))
+    })
+    .await
+    .context("Unable to start command".to_string())?;
+    ensure!(
+        status.success(),
+        "Command was unsuccessful, exit code {}",
+        status.code().unwrap_or(1),
+    );
+    Ok(())
+}
+
+/// Whether the log level is too quiet for the output of commands to be shown.
+fn is_quiet() -> bool {
+    matches!(
+        log::max_level(),
+        LevelFilter::Off | LevelFilter::Error | LevelFilter::Warn
+    )
+}
+
 /// Run a `tokio::process::Command` and return a `Result` letting us know whether or not it worked.
 /// `quiet` determines whether or not the command output will be piped to `stdout/stderr`. When
 /// `quiet=true`, no output will be shown and will be returned instead.
 pub(crate) async fn exec(cmd: &mut Command, quiet: bool) -> Result<Option<String>> {
-    debug!("Running: {:?}", cmd);
+    debug!("Running: {}", display_command(cmd));
     Ok(if quiet {
         // For quiet levels of logging we capture stdout and stderr
-        let output = cmd
-            .output()
+        let (child, _registered) = spawn(
+            cmd.stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )
+        .context("Unable to start command".to_string())?;
+        let output = child
+            .wait_with_output()
             .await
             .context("Unable to start command".to_string())?;
         ensure!(
@@ -44,10 +335,19 @@ pub(crate) async fn exec(cmd: &mut Command, quiet: bool) -> Result<Option<String
         )
     } else {
         // For less quiet log levels we stream to stdout and stderr.
-        let status = cmd
-            .status()
-            .await
-            .context("Unable to start command".to_string())?;
+        let status = match log_format() {
+            LogFormat::Text => match spawn(cmd) {
+                Ok((mut child, _registered)) => child.wait().await,
+                Err(e) => Err(e),
+            },
+            LogFormat::Json => {
+                stream_lines(cmd, |stream, line| {
+                    output_line(LogFormat::Json, stream, line)
+                })
+                .await
+            }
+        }
+        .context("Unable to start command".to_string())?;
 
         ensure!(
             status.success(),
@@ -59,6 +359,109 @@ pub(crate) async fn exec(cmd: &mut Command, quiet: bool) -> Result<Option<String
     })
 }
 
+/// Runs `cmd`, writing each line of its output to the stream that it came from after passing it
+/// through `render` along with the name of the stream, see [`output_line`].
+async fn stream_lines<F>(cmd: &mut Command, render: F) -> std::io::Result<ExitStatus>
+where
+    F: Fn(&str, &str) -> String,
+{
+    let (mut child, _registered) = spawn(cmd.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+    let (Some(stdout), Some(stderr)) = (child.stdout.take(), child.stderr.take()) else {
+        return child.wait().await;
+    };
+    let mut lines = stream::select(
+        lines(stdout).map(|line| ("stdout", line)).boxed(),
+        lines(stderr).map(|line| ("stderr", line)).boxed(),
+    );
+    while let Some((stream, line)) = lines.next().await {
+        let line = render(stream, &line?);
+        match stream {
+            "stdout" => println!("{}", line),
+            _ => eprintln!("{}", line),
+        }
+    }
+    child.wait().await
+}
+
+/// The number of lines from the end of a captured log that are shown when the command fails.
+const CAPTURE_TAIL_LINES: usize = 100;
+
+/// Run a `tokio::process::Command`, writing its combined stdout and stderr to the file at `capture`
+/// as the output arrives. Only the lines for which `is_progress` returns `true` are printed, unless
+/// the log level is `Trace`, in which case everything is printed as well. If the command fails, the
+/// error includes the last lines of output and the path to the full log.
+pub(crate) async fn exec_capture<F>(cmd: &mut Command, capture: &Path, is_progress: F) -> Result<()>
+where
+    F: Fn(&str) -> bool,
+{
+    // Open the file first so that a bad path is reported before the command runs.
+    let mut file = std::fs::File::create(capture).context(format!(
+        "Unable to create capture file '{}'",
+        capture.display()
+    ))?;
+    let stream_all = log::max_level() == LevelFilter::Trace;
+    debug!("Running: {}", display_command(cmd));
+    let (mut child, _registered) = spawn(cmd.stdout(Stdio::piped()).stderr(Stdio::piped()))
+        .context("Unable to start command".to_string())?;
+    let stdout = child
+        .stdout
+        .take()
+        .context("Unable to read command stdout")?;
+    let stderr = child
+        .stderr
+        .take()
+        .context("Unable to read command stderr")?;
+
+    let mut lines = stream::select(
+        lines(stdout).map(|line| ("stdout", line)).boxed(),
+        lines(stderr).map(|line| ("stderr", line)).boxed(),
+    );
+    let mut tail = VecDeque::with_capacity(CAPTURE_TAIL_LINES);
+    while let Some((stream, line)) = lines.next().await {
+        let line = line.context("Unable to read command output")?;
+        // Each line is written straight through to the file so that a killed run still leaves a
+        // useful log behind.
+        writeln!(file, "{}", line)
+            .context(format!("Unable to write to '{}'", capture.display()))?;
+        if stream_all || is_progress(&line) {
+            println!("{}", output_line(log_format(), stream, &line));
+        }
+        if tail.len() == CAPTURE_TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line);
+    }
+
+    let status = child
+        .wait()
+        .await
+        .context("Unable to wait for command".to_string())?;
+    ensure!(
+        status.success(),
+        "Command was unsuccessful, exit code {}. The last {} lines of output were:\n{}\n\
+        The full output is in '{}'",
+        status.code().unwrap_or(1),
+        tail.len(),
+        Vec::from(tail).join("\n"),
+        capture.display()
+    );
+    Ok(())
+}
+
+/// Turns a child process's output pipe into a stream of lines.
+fn lines<R>(reader: R) -> impl Stream<Item = std::io::Result<String>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    stream::unfold(BufReader::new(reader).lines(), |mut lines| async move {
+        lines
+            .next_line()
+            .await
+            .transpose()
+            .map(|line| (line, lines))
+    })
+}
+
 /// These are thin wrappers for `tokio::fs` functions which provide more useful error messages. For
 /// example, tokio will provide an unhelpful `std` error message such as `Error: No such file or
 /// directory (os error 2)` and we want to augment this with the filepath that was not found.
@@ -69,10 +472,16 @@ pub(crate) async fn exec(cmd: &mut Command, quiet: bool) -> Result<Option<String
 #[allow(dead_code)]
 pub(crate) mod fs {
     use anyhow::{Context, Result};
+    use filetime::{set_file_mtime, FileTime};
+    use futures::stream::{self, TryStreamExt};
     use std::fs::Metadata;
     use std::io::ErrorKind;
     use std::path::{Path, PathBuf};
     use tokio::fs;
+    use tokio::task::spawn_blocking;
+
+    /// The maximum number of files that [`copy_dir_all`] copies at the same time.
+    const COPY_CONCURRENCY: usize = 16;
 
     pub(crate) async fn canonicalize(path: impl AsRef<Path>) -> Result<PathBuf> {
         fs::canonicalize(path.as_ref()).await.context(format!(
@@ -95,6 +504,212 @@ pub(crate) mod fs {
         ))
     }
 
+    /// Recursively copies the directory `from` to `to`, creating `to` if it does not exist. File and
+    /// directory permissions are preserved, and symlinks are recreated rather than followed.
+    pub(crate) async fn copy_dir_all(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+        copy_dir_all_filtered(from, to, |_| true).await
+    }
+
+    /// Like [`copy_dir_all`], but only copies the entries for which `filter` returns `true`. The
+    /// filter is given the source path of each entry. Directories that are filtered out are not
+    /// descended into, so a filter that selects files by name, e.g. `*.rpm`, should let all
+    /// directories through.
+    pub(crate) async fn copy_dir_all_filtered<F>(
+        from: impl AsRef<Path>,
+        to: impl AsRef<Path>,
+        filter: F,
+    ) -> Result<()>
+    where
+        F: Fn(&Path) -> bool + Send + 'static,
+    {
+        let from = from.as_ref().to_path_buf();
+        let to = to.as_ref().to_path_buf();
+        let root = from.clone();
+        let entries = spawn_blocking(move || walk(&root, &filter))
+            .await
+            .context(format!(
+                "Unable to list the contents of '{}'",
+                from.display()
+            ))??;
+
+        // Directories are created up front so that files can be copied in any order.
+        create_dir_all(&to).await?;
+        let mut dirs = vec![(from.clone(), to.clone())];
+        let mut copies = Vec::new();
+        for (src, is_dir) in entries {
+            let dst = to.join(src.strip_prefix(&from).context(format!(
+                "Expected '{}' to be inside '{}'",
+                src.display(),
+                from.display()
+            ))?);
+            if is_dir {
+                create_dir_all(&dst).await?;
+                dirs.push((src, dst));
+            } else {
+                copies.push((src, dst));
+            }
+        }
+
+        stream::iter(copies.into_iter().map(Ok))
+            .try_for_each_concurrent(COPY_CONCURRENCY, |(src, dst)| async move {
+                spawn_blocking(move || copy_file_or_symlink(&src, &dst))
+                    .await
+                    .context("Unable to join a file copy task")?
+            })
+            .await?;
+
+        // Permissions are applied last, deepest first, so that a read-only directory does not
+        // prevent its contents from being copied.
+        for (src, dst) in dirs.into_iter().rev() {
+            let permissions = metadata(&src).await?.permissions();
+            fs::set_permissions(&dst, permissions)
+                .await
+                .context(format!("Unable to set permissions on '{}'", dst.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Like [`copy_dir_all_filtered`], but like `cp --update` a file is only copied if `to` does
+    /// not have it or has an older copy. The copies keep the modification time of the original, so
+    /// that tools like cargo that compare modification times treat them the same. Each file is
+    /// written under a temporary name and renamed into place, so a reader never sees part of one.
+    /// Symlinks are skipped.
+    pub(crate) async fn update_dir_filtered<F>(
+        from: impl AsRef<Path>,
+        to: impl AsRef<Path>,
+        filter: F,
+    ) -> Result<()>
+    where
+        F: Fn(&Path) -> bool + Send + 'static,
+    {
+        let from = from.as_ref().to_path_buf();
+        let to = to.as_ref().to_path_buf();
+        let root = from.clone();
+        let entries = spawn_blocking(move || walk(&root, &filter))
+            .await
+            .context(format!(
+                "Unable to list the contents of '{}'",
+                from.display()
+            ))??;
+
+        create_dir_all(&to).await?;
+        let mut updates = Vec::new();
+        for (src, is_dir) in entries {
+            let dst = to.join(src.strip_prefix(&from).context(format!(
+                "Expected '{}' to be inside '{}'",
+                src.display(),
+                from.display()
+            ))?);
+            if is_dir {
+                create_dir_all(&dst).await?;
+            } else {
+                updates.push((src, dst));
+            }
+        }
+
+        stream::iter(updates.into_iter().map(Ok))
+            .try_for_each_concurrent(COPY_CONCURRENCY, |(src, dst)| async move {
+                spawn_blocking(move || update_file(&src, &dst))
+                    .await
+                    .context("Unable to join a file copy task")?
+            })
+            .await
+    }
+
+    /// Lists everything below `dir` that passes `filter`, parents before children. Each entry is
+    /// paired with `true` if it is a directory. Symlinks are not followed.
+    fn walk<F>(dir: &Path, filter: &F) -> Result<Vec<(PathBuf, bool)>>
+    where
+        F: Fn(&Path) -> bool,
+    {
+        let mut entries = Vec::new();
+        let read_dir = std::fs::read_dir(dir)
+            .context(format!("Unable to read directory '{}'", dir.display()))?;
+        for entry in read_dir {
+            let path = entry
+                .context(format!("Unable to read an entry in '{}'", dir.display()))?
+                .path();
+            if !filter(&path) {
+                continue;
+            }
+            let file_type = std::fs::symlink_metadata(&path)
+                .context(format!("Unable to read metadata for '{}'", path.display()))?
+                .file_type();
+            entries.push((path.clone(), file_type.is_dir()));
+            if file_type.is_dir() {
+                entries.extend(walk(&path, filter)?);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn copy_file_or_symlink(src: &Path, dst: &Path) -> Result<()> {
+        let file_type = std::fs::symlink_metadata(src)
+            .context(format!("Unable to read metadata for '{}'", src.display()))?
+            .file_type();
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(src)
+                .context(format!("Unable to read symlink '{}'", src.display()))?;
+            std::os::unix::fs::symlink(&target, dst).context(format!(
+                "Unable to create symlink '{}' pointing to '{}'",
+                dst.display(),
+                target.display()
+            ))
+        } else {
+            // `std::fs::copy` also copies the permission bits.
+            std::fs::copy(src, dst).map(|_| ()).context(format!(
+                "Unable to copy '{}' to '{}'",
+                src.display(),
+                dst.display()
+            ))
+        }
+    }
+
+    fn update_file(src: &Path, dst: &Path) -> Result<()> {
+        let metadata = std::fs::symlink_metadata(src)
+            .context(format!("Unable to read metadata for '{}'", src.display()))?;
+        if !metadata.is_file() {
+            return Ok(());
+        }
+        let mtime = FileTime::from_last_modification_time(&metadata);
+        if let Ok(existing) = std::fs::symlink_metadata(dst) {
+            if FileTime::from_last_modification_time(&existing) >= mtime {
+                return Ok(());
+            }
+        }
+        let file_name = dst
+            .file_name()
+            .context(format!("Expected '{}' to be a file path", dst.display()))?;
+        let temp_path = dst.with_file_name(format!(
+            ".{}.{}",
+            file_name.to_string_lossy(),
+            uuid::Uuid::new_v4().simple()
+        ));
+        let result = std::fs::copy(src, &temp_path)
+            .context(format!(
+                "Unable to copy '{}' to '{}'",
+                src.display(),
+                temp_path.display()
+            ))
+            .and_then(|_| {
+                set_file_mtime(&temp_path, mtime).context(format!(
+                    "Unable to set the modification time of '{}'",
+                    temp_path.display()
+                ))
+            })
+            .and_then(|_| {
+                std::fs::rename(&temp_path, dst).context(format!(
+                    "Unable to rename '{}' to '{}'",
+                    temp_path.display(),
+                    dst.display()
+                ))
+            });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        result
+    }
+
     pub(crate) async fn create_dir(path: impl AsRef<Path>) -> Result<()> {
         fs::create_dir(path.as_ref()).await.context(format!(
             "Unable to create directory '{}'",
@@ -169,6 +784,13 @@ pub(crate) mod fs {
         ))
     }
 
+    pub(crate) async fn symlink_metadata(path: impl AsRef<Path>) -> Result<Metadata> {
+        fs::symlink_metadata(path.as_ref()).await.context(format!(
+            "Unable to read metadata for '{}'",
+            path.as_ref().display()
+        ))
+    }
+
     pub(crate) async fn write<P, C>(path: P, contents: C) -> Result<()>
     where
         P: AsRef<Path>,
@@ -178,6 +800,95 @@ pub(crate) mod fs {
             .await
             .context(format!("Unable to write to '{}'", path.as_ref().display()))
     }
+
+    /// Writes `contents` to a temporary file next to `path` and then renames it into place, so that
+    /// readers see either the old or the new contents but never a partial write. An existing file
+    /// keeps its permissions, e.g. a script stays executable.
+    pub(crate) async fn write_atomic<P, C>(path: P, contents: C) -> Result<()>
+    where
+        P: AsRef<Path>,
+        C: AsRef<[u8]>,
+    {
+        let path = path.as_ref();
+        let file_name = path
+            .file_name()
+            .context(format!("Expected '{}' to be a file path", path.display()))?;
+        let temp_path = path.with_file_name(format!(
+            ".{}.{}",
+            file_name.to_string_lossy(),
+            uuid::Uuid::new_v4().simple()
+        ));
+        let permissions = match fs::metadata(path).await {
+            Ok(metadata) => Some(metadata.permissions()),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(e).context(format!("Unable to read metadata for '{}'", path.display()))
+            }
+        };
+        write(&temp_path, contents).await?;
+        let result = match permissions {
+            Some(permissions) => {
+                fs::set_permissions(&temp_path, permissions)
+                    .await
+                    .context(format!(
+                        "Unable to set permissions of '{}'",
+                        temp_path.display()
+                    ))
+            }
+            None => Ok(()),
+        };
+        if let Err(e) = result.and(rename(&temp_path, path).await) {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_redact() {
+    assert_eq!(redact("BUILDSYS_ARCH", "x86_64"), "x86_64");
+    assert_eq!(redact("AWS_SECRET_ACCESS_KEY", "hunter2"), REDACTED);
+    assert_eq!(redact("GITHUB_TOKEN", "hunter2"), REDACTED);
+    assert_eq!(redact("registry_password", "hunter2"), REDACTED);
+    assert_eq!(
+        redact(
+            "BUILDSYS_BUILD_ARGS",
+            "NPM_TOKEN=hunter2\nMIRROR=example.com"
+        ),
+        format!("NPM_TOKEN={}\nMIRROR=example.com", REDACTED)
+    );
+    assert_eq!(
+        redact_args(&["-e=BUILDSYS_BUILD_ARGS=A=1\nGITHUB_TOKEN=hunter2"]),
+        [format!(
+            "-e=BUILDSYS_BUILD_ARGS=A=1\nGITHUB_TOKEN={}",
+            REDACTED
+        )]
+    );
+}
+
+#[test]
+fn test_display_command_redacts_secrets() {
+    let mut cmd = Command::new("docker");
+    cmd.env("REGISTRY_PASSWORD", "hunter2")
+        .env("BUILDSYS_ARCH", "x86_64")
+        .args(["build", "--build-arg", "NPM_TOKEN=hunter2"])
+        .args(["--build-arg=GIT_SECRET=hunter2", "--password", "hunter2"])
+        .args([
+            "-e=PUBLISH_KEY=hunter2",
+            "--password=hunter2",
+            "--tag",
+            "a=b",
+            ".",
+        ]);
+    let rendered = display_command(&cmd);
+    assert!(!rendered.contains("hunter2"), "{}", rendered);
+    assert!(rendered.contains("BUILDSYS_ARCH=x86_64"));
+    assert!(rendered.contains(&format!("--build-arg NPM_TOKEN={}", REDACTED)));
+    assert!(rendered.contains(&format!("--build-arg=GIT_SECRET={}", REDACTED)));
+    assert!(rendered.contains(&format!("--password {}", REDACTED)));
+    assert!(rendered.contains(&format!("-e=PUBLISH_KEY={}", REDACTED)));
+    assert!(rendered.ends_with("--tag a=b ."));
 }
 
 #[tokio::test]
@@ -214,3 +925,225 @@ async fn test_create_and_remove_dir() {
         path.display()
     )
 }
+
+#[tokio::test]
+async fn test_write_atomic() {
+    use crate::common::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    let tempdir = TempDir::new().unwrap();
+    let path = tempdir.path().join("Cargo.toml");
+    fs::write(&path, "old").await.unwrap();
+    fs::write_atomic(&path, "new").await.unwrap();
+    assert_eq!(fs::read_to_string(&path).await.unwrap(), "new");
+    // The temporary file is gone.
+    assert_eq!(std::fs::read_dir(tempdir.path()).unwrap().count(), 1);
+
+    // The mode of the file that is replaced is kept.
+    for mode in [0o755, 0o600] {
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        fs::write_atomic(&path, "newer").await.unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, mode);
+    }
+    // There is nothing to take the mode from for a new file.
+    let new = tempdir.path().join("new");
+    fs::write_atomic(&new, "new").await.unwrap();
+    assert_eq!(fs::read_to_string(&new).await.unwrap(), "new");
+}
+
+#[tokio::test]
+async fn test_copy_dir_all() {
+    use crate::common::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use tempfile::TempDir;
+
+    let tempdir = TempDir::new().unwrap();
+    let src = tempdir.path().join("src");
+    fs::create_dir_all(src.join("a/b")).await.unwrap();
+    fs::write(src.join("a/b/run.sh"), "#!/bin/sh")
+        .await
+        .unwrap();
+    std::fs::set_permissions(
+        src.join("a/b/run.sh"),
+        std::fs::Permissions::from_mode(0o751),
+    )
+    .unwrap();
+    symlink("b/run.sh", src.join("a/link")).unwrap();
+
+    let dst = tempdir.path().join("dst");
+    fs::copy_dir_all(&src, &dst).await.unwrap();
+
+    assert_eq!(
+        fs::read_to_string(dst.join("a/b/run.sh")).await.unwrap(),
+        "#!/bin/sh"
+    );
+    let mode = fs::metadata(dst.join("a/b/run.sh"))
+        .await
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o751);
+    assert_eq!(
+        std::fs::read_link(dst.join("a/link")).unwrap(),
+        std::path::PathBuf::from("b/run.sh")
+    );
+}
+
+#[tokio::test]
+async fn test_copy_dir_all_filtered() {
+    use crate::common::fs;
+    use tempfile::TempDir;
+
+    let tempdir = TempDir::new().unwrap();
+    let src = tempdir.path().join("src");
+    fs::create_dir_all(src.join("x86_64")).await.unwrap();
+    fs::create_dir_all(src.join("target")).await.unwrap();
+    fs::write(src.join("x86_64/pkg.rpm"), "").await.unwrap();
+    fs::write(src.join("x86_64/pkg.spec"), "").await.unwrap();
+    fs::write(src.join("target/other.rpm"), "").await.unwrap();
+
+    let dst = tempdir.path().join("dst");
+    fs::copy_dir_all_filtered(&src, &dst, |path| {
+        if path.is_dir() {
+            path.file_name().unwrap() != "target"
+        } else {
+            path.extension().is_some_and(|ext| ext == "rpm")
+        }
+    })
+    .await
+    .unwrap();
+
+    assert!(dst.join("x86_64/pkg.rpm").is_file());
+    assert!(!dst.join("x86_64/pkg.spec").exists());
+    assert!(!dst.join("target").exists());
+}
+
+#[tokio::test]
+async fn test_update_dir_filtered() {
+    use crate::common::fs;
+    use filetime::{set_file_mtime, FileTime};
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    let tempdir = TempDir::new().unwrap();
+    let src = tempdir.path().join("src");
+    let dst = tempdir.path().join("dst");
+    fs::create_dir_all(src.join("debug/build")).await.unwrap();
+    fs::create_dir_all(src.join("kits")).await.unwrap();
+    fs::create_dir_all(&dst).await.unwrap();
+    let old = FileTime::from_unix_time(1_000_000, 0);
+    let new = FileTime::from_unix_time(2_000_000, 0);
+    for (file, contents, mtime) in [
+        ("debug/build/script", "new script", new),
+        ("debug/fingerprint", "old fingerprint", old),
+        ("kits/skipped", "", new),
+    ] {
+        fs::write(src.join(file), contents).await.unwrap();
+        set_file_mtime(src.join(file), mtime).unwrap();
+    }
+    std::fs::set_permissions(
+        src.join("debug/build/script"),
+        std::fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+    // The destination already has a newer fingerprint, which is kept.
+    fs::create_dir_all(dst.join("debug")).await.unwrap();
+    fs::write(dst.join("debug/fingerprint"), "new fingerprint")
+        .await
+        .unwrap();
+    set_file_mtime(dst.join("debug/fingerprint"), new).unwrap();
+
+    let skip = src.join("kits");
+    fs::update_dir_filtered(&src, &dst, move |path| path != skip)
+        .await
+        .unwrap();
+
+    let script = dst.join("debug/build/script");
+    assert_eq!(fs::read_to_string(&script).await.unwrap(), "new script");
+    let metadata = fs::metadata(&script).await.unwrap();
+    assert_eq!(FileTime::from_last_modification_time(&metadata), new);
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+    assert_eq!(
+        fs::read_to_string(dst.join("debug/fingerprint"))
+            .await
+            .unwrap(),
+        "new fingerprint"
+    );
+    assert!(!dst.join("kits").exists());
+}
+
+#[tokio::test]
+async fn test_copy_dir_all_missing_source() {
+    use crate::common::fs;
+    use tempfile::TempDir;
+
+    let tempdir = TempDir::new().unwrap();
+    let err = fs::copy_dir_all(tempdir.path().join("nope"), tempdir.path().join("dst"))
+        .await
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("nope"));
+}
+
+#[tokio::test]
+async fn test_exec_capture() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let capture = temp_dir.path().join("make.log");
+    let script = "for i in $(seq 1 150); do echo line $i; done; echo oops >&2; exit 3";
+    let err = exec_capture(
+        Command::new("bash").args(["-c", script]),
+        &capture,
+        |line| line == "line 1",
+    )
+    .await
+    .unwrap_err()
+    .to_string();
+    let log = std::fs::read_to_string(&capture).unwrap();
+    assert_eq!(log.lines().count(), 151);
+    assert!(err.contains("exit code 3"), "{}", err);
+    assert!(err.contains("line 150\n"), "{}", err);
+    assert!(!err.contains("line 50\n"), "{}", err);
+    assert!(err.contains(&capture.display().to_string()), "{}", err);
+
+    let missing = temp_dir.path().join("missing/make.log");
+    let err = exec_capture(&mut Command::new("true"), &missing, |_| true)
+        .await
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("Unable to create capture file"));
+}
+
+#[tokio::test]
+async fn test_stop() {
+    use nix::unistd::getpgid;
+
+    // The shell starts a command of its own, which is stopped along with it.
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "sleep 30 & wait"]);
+    let (mut child, registered) = spawn_in(&mut cmd, true).unwrap();
+    let pid = child.id().unwrap() as i32;
+    assert_eq!(getpgid(Some(Pid::from_raw(pid))).unwrap().as_raw(), pid);
+    assert!(children().contains_key(&pid));
+
+    let running = BTreeMap::from([(pid, true)]);
+    let start = Instant::now();
+    let wait = async {
+        let status = child.wait().await.unwrap();
+        drop(registered);
+        status
+    };
+    let (status, ()) = tokio::join!(wait, stop(&running, Signal::SIGTERM));
+    assert!(!status.success());
+    assert!(start.elapsed() < TERMINATION_GRACE);
+    assert!(!children().contains_key(&pid));
+    // Nothing is left in the process group once the orphaned `sleep` has been reaped, which init
+    // does in its own time.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while killpg(Pid::from_raw(pid), None).is_ok() {
+        assert!(
+            Instant::now() < deadline,
+            "The process group is still running"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
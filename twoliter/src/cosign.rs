@@ -0,0 +1,252 @@
+/*!
+
+Published kits can be signed with [cosign](https://github.com/sigstore/cosign), so that the projects
+that consume them can check where they came from. cosign pushes the signature to the kit's own
+repository, under a tag that it derives from the digest of the kit, and `twoliter kit verify` checks
+it with a public key or, for keyless signatures, with the identity that signed.
+
+cosign is not embedded in Twoliter. Signing needs access to keys, KMS or an OIDC identity, which is
+set up for the `cosign` on the `PATH`, and that is the one Twoliter runs.
+
+!*/
+
+use crate::common::display_command;
+use crate::docker::{docker, ImageUri};
+use anyhow::{ensure, Context, Result};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::process::Command;
+
+const INSTALL_HINT: &str =
+    "see https://docs.sigstore.dev/cosign/system_config/installation/ to install it";
+
+/// What a signature is verified against.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Verifier {
+    /// A public key, as a path, a KMS ARN or a cosign key URI.
+    Key(String),
+    /// A keyless signature, by the identity in its certificate and the issuer of the OIDC token that
+    /// the certificate was issued for.
+    Keyless { identity: String, issuer: String },
+}
+
+/// An image that was signed, and where its signature is.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Signed {
+    /// The image, by digest.
+    pub(crate) image: String,
+    /// The tag that cosign pushed the signature to.
+    pub(crate) signature: String,
+}
+
+/// The `cosign` on the `PATH`.
+pub(crate) fn find_cosign() -> Result<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path)
+        .map(|dir| dir.join("cosign"))
+        .find(|cosign| cosign.is_file())
+        .context(format!(
+            "cosign was not found on the PATH, {}",
+            INSTALL_HINT
+        ))
+}
+
+/// The cosign key reference for `key`. cosign takes AWS KMS keys as `awskms:///<ARN>`, so a bare ARN
+/// is turned into that, and anything else is passed on as it is.
+pub(crate) fn key_ref(key: &str) -> String {
+    if key.starts_with("arn:") && key.contains(":kms:") {
+        format!("awskms:///{}", key)
+    } else {
+        key.to_string()
+    }
+}
+
+/// The tag that cosign stores the signature of the image `digest` in `repository` under.
+fn signature_ref(repository: &str, digest: &str) -> String {
+    format!("{}:{}.sig", repository, digest.replace(':', "-"))
+}
+
+/// The repository of `reference`, without its tag or digest.
+fn repository(reference: &str) -> Result<String> {
+    let uri = ImageUri::parse(reference)?;
+    Ok(ImageUri {
+        tag: String::new(),
+        digest: None,
+        ..uri
+    }
+    .uri())
+}
+
+fn sign_args(key: &str, image: &str) -> Vec<String> {
+    ["sign", "--yes", "--key", &key_ref(key), image]
+        .map(String::from)
+        .to_vec()
+}
+
+fn verify_args(verifier: &Verifier, image: &str) -> Vec<String> {
+    let mut args = vec!["verify".to_string()];
+    match verifier {
+        Verifier::Key(key) => args.extend(["--key".to_string(), key_ref(key)]),
+        Verifier::Keyless { identity, issuer } => args.extend([
+            "--certificate-identity".to_string(),
+            identity.clone(),
+            "--certificate-oidc-issuer".to_string(),
+            issuer.clone(),
+        ]),
+    }
+    args.push(image.to_string());
+    args
+}
+
+/// The digest that `reference` resolves to in its registry. For a multi-architecture image this is
+/// the digest of the image index.
+pub(crate) async fn remote_digest(reference: &str) -> Result<String> {
+    let stdout = docker(
+        [
+            "buildx",
+            "imagetools",
+            "inspect",
+            reference,
+            "--format",
+            "{{json .Manifest}}",
+        ],
+        format!("Unable to find the digest of {}", reference),
+    )
+    .await?;
+    parse_manifest_digest(&stdout).context(format!("Unable to find the digest of {}", reference))
+}
+
+fn parse_manifest_digest(json: &[u8]) -> Result<String> {
+    let manifest: serde_json::Value =
+        serde_json::from_slice(json).context("Unable to parse the image manifest")?;
+    manifest["digest"]
+        .as_str()
+        .map(str::to_string)
+        .context("The image manifest has no digest")
+}
+
+async fn cosign(args: &[String]) -> Result<()> {
+    let cosign = find_cosign()?;
+    let mut command = Command::new(&cosign);
+    command.args(args);
+    // Key references, such as `env://COSIGN_PRIVATE_KEY` or a KMS ARN, are hidden from the log.
+    debug!("Running: {}", display_command(&command));
+    let status = command
+        .status()
+        .await
+        .context(format!("Unable to run '{}'", cosign.display()))?;
+    ensure!(
+        status.success(),
+        "cosign {} exited with {}",
+        args[0],
+        status
+    );
+    Ok(())
+}
+
+/// Signs the image that `reference` resolves to in its registry with `key`.
+pub(crate) async fn sign(reference: &str, key: &str) -> Result<Signed> {
+    let repository = repository(reference)?;
+    let digest = remote_digest(reference).await?;
+    let image = format!("{}@{}", repository, digest);
+    info!("Signing {}", image);
+    cosign(&sign_args(key, &image)).await?;
+    Ok(Signed {
+        image,
+        signature: signature_ref(&repository, &digest),
+    })
+}
+
+/// Verifies the signature of the image at `reference` with `verifier`.
+pub(crate) async fn verify(reference: &str, verifier: &Verifier) -> Result<()> {
+    cosign(&verify_args(verifier, reference)).await
+}
+
+#[test]
+fn test_key_ref() {
+    assert_eq!(
+        key_ref("arn:aws:kms:us-west-2:123456789012:key/abcd"),
+        "awskms:///arn:aws:kms:us-west-2:123456789012:key/abcd"
+    );
+    assert_eq!(
+        key_ref("awskms:///arn:aws:kms:us-west-2:123456789012:alias/kits"),
+        "awskms:///arn:aws:kms:us-west-2:123456789012:alias/kits"
+    );
+    assert_eq!(key_ref("cosign.key"), "cosign.key");
+    assert_eq!(
+        key_ref("arn:aws:iam::123456789012:role/publish"),
+        "arn:aws:iam::123456789012:role/publish"
+    );
+}
+
+#[test]
+fn test_cosign_args() {
+    let digest = format!("sha256:{}", "ab".repeat(32));
+    let repository = repository("public.ecr.aws/acme/core-kit:v1.2.3").unwrap();
+    assert_eq!(repository, "public.ecr.aws/acme/core-kit");
+    assert_eq!(
+        signature_ref(&repository, &digest),
+        format!(
+            "public.ecr.aws/acme/core-kit:sha256-{}.sig",
+            "ab".repeat(32)
+        )
+    );
+    assert_eq!(
+        sign_args("cosign.key", "example.com/kit@sha256:1"),
+        [
+            "sign",
+            "--yes",
+            "--key",
+            "cosign.key",
+            "example.com/kit@sha256:1"
+        ]
+    );
+    assert_eq!(
+        verify_args(
+            &Verifier::Keyless {
+                identity:
+                    "https://github.com/acme/kits/.github/workflows/publish.yml@refs/heads/main"
+                        .to_string(),
+                issuer: "https://token.actions.githubusercontent.com".to_string(),
+            },
+            "example.com/kit:v1"
+        ),
+        [
+            "verify",
+            "--certificate-identity",
+            "https://github.com/acme/kits/.github/workflows/publish.yml@refs/heads/main",
+            "--certificate-oidc-issuer",
+            "https://token.actions.githubusercontent.com",
+            "example.com/kit:v1"
+        ]
+    );
+    assert_eq!(
+        verify_args(
+            &Verifier::Key("cosign.pub".to_string()),
+            "example.com/kit:v1"
+        ),
+        ["verify", "--key", "cosign.pub", "example.com/kit:v1"]
+    );
+    assert_eq!(
+        parse_manifest_digest(br#"{"mediaType":"application/vnd.oci.image.index.v1+json","digest":"sha256:1","size":2}"#).unwrap(),
+        "sha256:1"
+    );
+    assert!(parse_manifest_digest(b"{}").is_err());
+}
+
+#[test]
+fn test_cosign_command_is_redacted() {
+    let mut command = Command::new("cosign");
+    command.args(sign_args(
+        "arn:aws:kms:us-west-2:123456789012:key/abcd",
+        "example.com/kit@sha256:1",
+    ));
+    assert_eq!(
+        display_command(&command),
+        format!(
+            "cosign sign --yes --key {} example.com/kit@sha256:1",
+            crate::common::REDACTED
+        )
+    );
+}
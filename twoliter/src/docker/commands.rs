@@ -1 +1,199 @@
+use crate::common::redact_args;
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use std::process::{Output, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::Command;
+use tokio::time::sleep;
 
+/// The number of times a docker operation will be attempted before giving up.
+const DOCKER_ATTEMPTS: u32 = 3;
+
+/// How long to wait before the first retry. The wait is doubled for each subsequent retry.
+const DOCKER_INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Fragments of docker error messages that indicate a failure which will not go away by trying
+/// again. These are matched case-insensitively against the stderr of the failed command.
+const PERMANENT_FAILURES: [&str; 8] = [
+    "no space left on device",
+    "manifest unknown",
+    "not found",
+    "unauthorized",
+    "denied",
+    "invalid reference format",
+    "no such image",
+    "is not a docker command",
+];
+
+/// Run `docker` with the given `args`, capturing its output, and return `stdout` if successful.
+/// Transient failures are retried with backoff.
+pub(crate) async fn docker<I, S>(args: I, error_msg: impl AsRef<str>) -> Result<Vec<u8>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let args = collect_args(args);
+    let output = retry(&args, error_msg.as_ref(), Console::Capture).await?;
+    Ok(output.stdout)
+}
+
+/// Run `docker` with the given `args`, letting its progress output stream to the console. Stderr
+/// is captured so that we can decide whether a failure is worth retrying, and is printed after the
+/// command exits.
+pub(crate) async fn docker_noisy<I, S>(args: I, error_msg: impl AsRef<str>) -> Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let args = collect_args(args);
+    retry(&args, error_msg.as_ref(), Console::Inherit).await?;
+    Ok(())
+}
+
+/// Run `docker` with the given `args`, printing its output line by line as it arrives, and return
+/// `stdout` if successful. This is for commands such as `docker pull` that report progress on
+/// `stdout` and also print something there that is needed afterwards.
+pub(crate) async fn docker_streamed<I, S>(args: I, error_msg: impl AsRef<str>) -> Result<Vec<u8>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let args = collect_args(args);
+    let output = retry(&args, error_msg.as_ref(), Console::Stream).await?;
+    Ok(output.stdout)
+}
+
+/// What happens to the output of a docker command.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Console {
+    /// `stdout` and `stderr` are captured.
+    Capture,
+    /// `stdout` goes to the console, `stderr` is captured and printed when the command exits.
+    Inherit,
+    /// `stdout` is printed as it arrives and captured, `stderr` is as with `Inherit`.
+    Stream,
+}
+
+fn collect_args<I, S>(args: I) -> Vec<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    args.into_iter().map(|s| s.as_ref().to_string()).collect()
+}
+
+async fn retry(args: &[String], error_msg: &str, console: Console) -> Result<Output> {
+    let mut backoff = DOCKER_INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let output = run(args, console).await.context(error_msg.to_string())?;
+        if output.status.success() {
+            return Ok(output);
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !is_transient(&stderr) {
+            bail!("{}: docker failed to run operation: {}", error_msg, stderr)
+        }
+        if attempt >= DOCKER_ATTEMPTS {
+            bail!(
+                "{}: docker failed to run operation after {} attempts: {}",
+                error_msg,
+                attempt,
+                stderr
+            )
+        }
+        warn!(
+            "docker {} failed (attempt {} of {}), retrying in {}s: {}",
+            args.first().map(String::as_str).unwrap_or_default(),
+            attempt,
+            DOCKER_ATTEMPTS,
+            backoff.as_secs(),
+            stderr.trim()
+        );
+        sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+async fn run(args: &[String], console: Console) -> Result<Output> {
+    debug!("Running: docker {}", redact_args(args).join(" "));
+    let mut command = Command::new("docker");
+    command.args(args);
+    if console == Console::Capture {
+        return command
+            .output()
+            .await
+            .context("Unable to start docker command");
+    }
+    let stdout = match console {
+        Console::Stream => Stdio::piped(),
+        _ => Stdio::inherit(),
+    };
+    let mut child = command
+        .stdout(stdout)
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Unable to start docker command")?;
+    let mut stderr = child
+        .stderr
+        .take()
+        .context("Unable to read docker stderr")?;
+    // Both pipes are drained at once, so that docker never blocks on a full one.
+    let read_stderr = async {
+        let mut buf = Vec::new();
+        stderr.read_to_end(&mut buf).await.map(|_| buf)
+    };
+    let read_stdout = async {
+        let mut buf = Vec::new();
+        if let Some(stdout) = child.stdout.take() {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Some(line) = lines.next_line().await? {
+                println!("{}", line);
+                buf.extend_from_slice(line.as_bytes());
+                buf.push(b'\n');
+            }
+        }
+        Ok::<_, std::io::Error>(buf)
+    };
+    let (stdout, stderr) =
+        tokio::try_join!(read_stdout, read_stderr).context("docker failed to run operation")?;
+    let status = child
+        .wait()
+        .await
+        .context("docker failed to run operation")?;
+    eprint!("{}", String::from_utf8_lossy(&stderr));
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+/// Returns `true` if the docker error message does not match any known permanent failure and is
+/// therefore worth retrying.
+fn is_transient(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    !PERMANENT_FAILURES
+        .iter()
+        .any(|fragment| stderr.contains(fragment))
+}
+
+#[test]
+fn test_is_transient() {
+    assert!(is_transient(
+        "Error response from daemon: Get \"https://public.ecr.aws/v2/\": net/http: TLS handshake \
+         timeout"
+    ));
+    assert!(is_transient(
+        "Cannot connect to the Docker daemon at unix:///var/run/docker.sock."
+    ));
+    assert!(!is_transient(
+        "write /var/lib/docker/tmp/docker-export-1234: no space left on device"
+    ));
+    assert!(!is_transient(
+        "Error response from daemon: manifest unknown: Requested image not found"
+    ));
+    assert!(!is_transient("Error: No such image: foo:v1.2.3"));
+}
@@ -1,3 +1,4 @@
+use anyhow::{ensure, Result};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 
@@ -10,8 +11,11 @@ pub(crate) struct ImageUri {
     pub(crate) registry: Option<String>,
     /// e.g. my-repo
     pub(crate) repo: String,
-    /// e.g. v0.31.0
+    /// e.g. v0.31.0, empty if the image is only referred to by `digest`.
     pub(crate) tag: String,
+    /// e.g. sha256:0123..., which pins the image regardless of its tag.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) digest: Option<String>,
 }
 
 impl ImageUri {
@@ -26,15 +30,65 @@ impl ImageUri {
             registry,
             repo: repo.as_ref().into(),
             tag: tag.as_ref().into(),
+            digest: None,
         }
     }
 
+    /// Parses an image reference such as `public.ecr.aws/bottlerocket/repo:v0.1.0`, optionally
+    /// followed by `@sha256:<digest>`. A tag or a digest is required.
+    pub(crate) fn parse(reference: &str) -> Result<Self> {
+        let (name, digest) = match reference.split_once('@') {
+            Some((name, digest)) => {
+                let hex = digest.strip_prefix("sha256:").unwrap_or_default();
+                ensure!(
+                    hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+                    "The digest of image '{}' must be 'sha256:' followed by 64 hex digits",
+                    reference
+                );
+                (name, Some(digest.to_string()))
+            }
+            None => (reference, None),
+        };
+        let (path, tag) = match name.rsplit_once(':') {
+            // A colon before the last slash belongs to a registry port, e.g. `localhost:5000/repo`.
+            Some((path, tag)) if !tag.contains('/') => (path, tag),
+            _ => (name, ""),
+        };
+        ensure!(
+            !tag.is_empty() || digest.is_some(),
+            "Image '{}' must have a tag or a digest",
+            reference
+        );
+        let (registry, repo) = match path.rsplit_once('/') {
+            Some((registry, repo)) => (Some(registry.to_string()), repo),
+            None => (None, path),
+        };
+        ensure!(
+            !repo.is_empty() && registry.as_deref() != Some(""),
+            "Unable to parse the image reference '{}'",
+            reference
+        );
+        Ok(Self {
+            registry,
+            repo: repo.to_string(),
+            tag: tag.to_string(),
+            digest,
+        })
+    }
+
     /// Returns the `ImageUri` for use with docker, e.g. `public.ecr.aws/myregistry/myrepo:v0.1.0`
     pub(crate) fn uri(&self) -> String {
-        match &self.registry {
-            None => format!("{}:{}", self.repo, self.tag),
-            Some(registry) => format!("{}/{}:{}", registry, self.repo, self.tag),
+        let mut uri = match &self.registry {
+            None => self.repo.clone(),
+            Some(registry) => format!("{}/{}", registry, self.repo),
+        };
+        if !self.tag.is_empty() {
+            uri.push_str(&format!(":{}", self.tag));
+        }
+        if let Some(digest) = &self.digest {
+            uri.push_str(&format!("@{}", digest));
         }
+        uri
     }
 }
 
@@ -65,3 +119,50 @@ fn image_uri_with_registry() {
     let expected = "example.com/a/b/c/foo:v1.2.3";
     assert_eq!(expected, formatted);
 }
+
+#[test]
+fn image_uri_parse() {
+    let digest = format!("sha256:{}", "ab".repeat(32));
+    for (reference, registry, repo, tag, has_digest) in [
+        ("foo:v1", None, "foo", "v1", false),
+        (
+            "public.ecr.aws/bottlerocket/bottlerocket-control:v0.7.10",
+            Some("public.ecr.aws/bottlerocket"),
+            "bottlerocket-control",
+            "v0.7.10",
+            false,
+        ),
+        (
+            "localhost:5000/admin:v1",
+            Some("localhost:5000"),
+            "admin",
+            "v1",
+            false,
+        ),
+        (
+            &format!("example.com/admin@{}", digest),
+            Some("example.com"),
+            "admin",
+            "",
+            true,
+        ),
+        (
+            &format!("example.com/admin:v2@{}", digest),
+            Some("example.com"),
+            "admin",
+            "v2",
+            true,
+        ),
+    ] {
+        let uri = ImageUri::parse(reference).unwrap();
+        assert_eq!(uri.registry.as_deref(), registry);
+        assert_eq!(uri.repo, repo);
+        assert_eq!(uri.tag, tag);
+        assert_eq!(uri.digest.is_some(), has_digest);
+        assert_eq!(uri.uri(), reference);
+    }
+    assert!(ImageUri::parse("example.com/admin").is_err());
+    assert!(ImageUri::parse("localhost:5000/admin").is_err());
+    assert!(ImageUri::parse("example.com/admin@sha256:abc").is_err());
+    assert!(ImageUri::parse("/admin:v1").is_err());
+}
@@ -0,0 +1,110 @@
+use super::commands::docker;
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Memoizes `docker image inspect` for one run of Twoliter, keyed by the exact image reference.
+/// References that are not present locally are remembered too. Pulling or building an image
+/// changes what its reference resolves to, so whatever does that must call
+/// [`ImageInspector::invalidate`] afterwards.
+#[derive(Debug, Default)]
+pub(crate) struct ImageInspector {
+    results: Mutex<HashMap<String, Option<Value>>>,
+    /// Results that tests give in place of running docker.
+    fake: Option<HashMap<String, Option<Value>>>,
+    /// How many times docker, or the fake results, have been consulted.
+    lookups: AtomicUsize,
+}
+
+impl ImageInspector {
+    /// An inspector that answers from `results` instead of running docker. A reference that is not
+    /// in `results` is not present.
+    #[cfg(test)]
+    pub(crate) fn with_results(results: HashMap<String, Option<Value>>) -> Self {
+        Self {
+            fake: Some(results),
+            ..Default::default()
+        }
+    }
+
+    /// The details of the image that `reference` names, as `docker image inspect` gives them, or
+    /// `None` if there is no such image locally.
+    pub(crate) async fn inspect(&self, reference: &str) -> Result<Option<Value>> {
+        if let Some(result) = self.results().get(reference) {
+            return Ok(result.clone());
+        }
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        let result = match &self.fake {
+            Some(fake) => fake.get(reference).cloned().flatten(),
+            None => inspect(reference).await?,
+        };
+        self.results().insert(reference.to_string(), result.clone());
+        Ok(result)
+    }
+
+    /// Whether the image that `reference` names is present locally.
+    pub(crate) async fn exists(&self, reference: &str) -> Result<bool> {
+        Ok(self.inspect(reference).await?.is_some())
+    }
+
+    /// Forgets what is known about `reference`, after it has been pulled or built.
+    pub(crate) fn invalidate(&self, reference: &str) {
+        self.results().remove(reference);
+    }
+
+    /// How many times the inspector had to look beyond what it remembered.
+    #[cfg(test)]
+    pub(crate) fn lookups(&self) -> usize {
+        self.lookups.load(Ordering::Relaxed)
+    }
+
+    fn results(&self) -> std::sync::MutexGuard<'_, HashMap<String, Option<Value>>> {
+        // The map is always left consistent, so it is still usable if a holder panicked.
+        self.results
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Runs `docker image inspect` for `reference`.
+async fn inspect(reference: &str) -> Result<Option<Value>> {
+    let stdout = match docker(
+        ["image", "inspect", reference],
+        format!("Unable to inspect the image {}", reference),
+    )
+    .await
+    {
+        Ok(stdout) => stdout,
+        Err(e) if format!("{:#}", e).to_lowercase().contains("no such image") => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let images: Vec<Value> = serde_json::from_slice(&stdout).context(format!(
+        "Unable to parse the output of docker image inspect for {}",
+        reference
+    ))?;
+    Ok(images.into_iter().next())
+}
+
+#[tokio::test]
+async fn test_image_inspector() {
+    let inspector = ImageInspector::with_results(HashMap::from([(
+        "example.com/sdk:v1".to_string(),
+        Some(serde_json::json!({ "Id": "sha256:1234" })),
+    )]));
+    for _ in 0..3 {
+        let image = inspector.inspect("example.com/sdk:v1").await.unwrap();
+        assert_eq!(image.unwrap()["Id"], "sha256:1234");
+    }
+    assert_eq!(inspector.lookups(), 1);
+
+    // An image that is not present is remembered as well.
+    assert!(!inspector.exists("example.com/missing:v1").await.unwrap());
+    assert!(!inspector.exists("example.com/missing:v1").await.unwrap());
+    assert_eq!(inspector.lookups(), 2);
+
+    inspector.invalidate("example.com/sdk:v1");
+    assert!(inspector.exists("example.com/sdk:v1").await.unwrap());
+    assert_eq!(inspector.lookups(), 3);
+}
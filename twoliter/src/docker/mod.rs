@@ -1,4 +1,11 @@
 mod commands;
 mod image;
+mod inspect;
+mod network;
+mod pull;
 
+pub(crate) use self::commands::{docker, docker_noisy};
 pub(crate) use self::image::ImageUri;
+pub(crate) use self::inspect::ImageInspector;
+pub(crate) use self::network::DockerNetwork;
+pub(crate) use self::pull::{pull, PullPolicy};
@@ -0,0 +1,70 @@
+use anyhow::{ensure, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// The network that docker uses for build steps: `none`, `host`, `default` to leave the choice to
+/// docker, or the name of a user-defined docker network.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct DockerNetwork(String);
+
+impl DockerNetwork {
+    /// Returns `true` if build steps have no network access.
+    pub(crate) fn is_none(&self) -> bool {
+        self.0 == "none"
+    }
+}
+
+impl FromStr for DockerNetwork {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // These are the characters that docker allows in network names.
+        let mut chars = s.chars();
+        ensure!(
+            chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+                && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-')),
+            "'{}' is not a valid docker network, expected 'none', 'host', 'default' or the name of \
+            a docker network",
+            s
+        );
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for DockerNetwork {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<DockerNetwork> for String {
+    fn from(value: DockerNetwork) -> Self {
+        value.0
+    }
+}
+
+impl Display for DockerNetwork {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[test]
+fn test_docker_network() {
+    assert!("none".parse::<DockerNetwork>().unwrap().is_none());
+    assert!(!"host".parse::<DockerNetwork>().unwrap().is_none());
+    assert_eq!(
+        "build-proxy_1.net"
+            .parse::<DockerNetwork>()
+            .unwrap()
+            .to_string(),
+        "build-proxy_1.net"
+    );
+    assert!("".parse::<DockerNetwork>().is_err());
+    assert!("-net".parse::<DockerNetwork>().is_err());
+    assert!("my net".parse::<DockerNetwork>().is_err());
+}
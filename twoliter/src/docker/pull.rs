@@ -0,0 +1,105 @@
+use super::commands::{docker, docker_streamed};
+use super::ImageUri;
+use anyhow::{ensure, Context, Result};
+use clap::ValueEnum;
+use log::{debug, Level};
+use serde::{Deserialize, Serialize};
+
+/// When the SDK image that a build uses is pulled.
+#[derive(
+    Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Deserialize, Serialize, ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum PullPolicy {
+    /// Pull before every build, so that a tag that was moved upstream is picked up.
+    Always,
+    /// Pull only when the image is not present locally.
+    #[default]
+    Missing,
+    /// Never pull, and fail if the image is not present locally.
+    Never,
+}
+
+impl PullPolicy {
+    /// Whether an image should be pulled, given whether it is `present` locally.
+    pub(crate) fn should_pull(self, present: bool) -> bool {
+        match self {
+            PullPolicy::Always => true,
+            PullPolicy::Missing => !present,
+            PullPolicy::Never => false,
+        }
+    }
+}
+
+/// Pulls `image` and returns the digest that it resolved to, e.g. `sha256:0123...`. Progress is
+/// printed when info messages are, and transient failures are retried. When `image` is pinned to a
+/// digest, the pulled image is verified to have it.
+pub(crate) async fn pull(image: &ImageUri) -> Result<String> {
+    let uri = image.uri();
+    let args = ["pull", uri.as_str()];
+    let error_msg = format!("Unable to pull the image {}", uri);
+    let stdout = if log::log_enabled!(Level::Info) {
+        docker_streamed(args, error_msg).await?
+    } else {
+        docker(args, error_msg).await?
+    };
+    let digest = parse_digest(&String::from_utf8_lossy(&stdout)).context(format!(
+        "Unable to find the digest of {} in the output of docker pull",
+        uri
+    ))?;
+    if let Some(expected) = &image.digest {
+        ensure!(
+            &digest == expected,
+            "Pulled {} but docker reported the digest {}",
+            uri,
+            digest
+        );
+    }
+    debug!("Pulled {} with digest {}", uri, digest);
+    Ok(digest)
+}
+
+/// Finds the digest in the output of `docker pull`, which reports it on a line such as
+/// `Digest: sha256:0123...`.
+fn parse_digest(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let digest = line.trim().strip_prefix("Digest:")?.trim();
+        let hex = digest.strip_prefix("sha256:")?;
+        (hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit())).then(|| digest.to_string())
+    })
+}
+
+#[test]
+fn test_should_pull() {
+    assert!(PullPolicy::Always.should_pull(true));
+    assert!(PullPolicy::Always.should_pull(false));
+    assert!(!PullPolicy::Missing.should_pull(true));
+    assert!(PullPolicy::Missing.should_pull(false));
+    assert!(!PullPolicy::Never.should_pull(false));
+    assert_eq!(PullPolicy::default(), PullPolicy::Missing);
+}
+
+#[test]
+fn test_parse_digest() {
+    let digest = format!("sha256:{}", "0123456789abcdef".repeat(4));
+    let output = format!(
+        "v0.50.0: Pulling from bottlerocket/bottlerocket-sdk\n\
+         4a1e2f3b5c6d: Pull complete\n\
+         Digest: {}\n\
+         Status: Downloaded newer image for public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0\n\
+         public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0\n",
+        digest
+    );
+    assert_eq!(parse_digest(&output), Some(digest.clone()));
+
+    // Already present locally.
+    let output = format!(
+        "v0.50.0: Pulling from bottlerocket/bottlerocket-sdk\nDigest: {}\nStatus: Image is up to \
+         date for public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0\n",
+        digest
+    );
+    assert_eq!(parse_digest(&output), Some(digest));
+
+    assert_eq!(parse_digest("public.ecr.aws/bottlerocket/sdk:v1\n"), None);
+    assert_eq!(parse_digest("Digest: sha256:abc\n"), None);
+}
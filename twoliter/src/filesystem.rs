@@ -0,0 +1,294 @@
+/*!
+
+Some filesystems cannot hold a build. RPM and the build system rely on hardlinks, symlinks, Unix
+permissions and case-sensitive file names, and a project on an NTFS or exFAT drive, a Windows
+directory shared into WSL or a VM, or a network share tends to fail late and in confusing ways. The
+build commands look at the filesystems of the directories they write to before they start, and warn
+about the ones that are known to cause trouble, or fail with `--strict-preflight`.
+
+The filesystem of a directory is found from `/proc/mounts`, falling back to the magic number that
+`statfs` reports. This is best-effort: a filesystem that cannot be identified, or that is not known
+to be a problem, is assumed to be fine.
+
+!*/
+
+use crate::common::fs;
+use anyhow::{bail, Result};
+use log::warn;
+use std::path::{Path, PathBuf};
+
+const PROC_MOUNTS: &str = "/proc/mounts";
+
+/// An entry in `/proc/mounts`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Mount {
+    pub(crate) mount_point: PathBuf,
+    pub(crate) fstype: String,
+}
+
+/// A directory on a filesystem that is known to break builds.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Problem {
+    pub(crate) dir: PathBuf,
+    pub(crate) fstype: String,
+    pub(crate) limitation: &'static str,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "'{}' is on a {} filesystem, which {}",
+            self.dir.display(),
+            self.fstype,
+            self.limitation
+        )
+    }
+}
+
+/// Parses the contents of `/proc/mounts`. Lines that do not look like a mount are skipped.
+pub(crate) fn parse_mounts(contents: &str) -> Vec<Mount> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let _device = fields.next()?;
+            let mount_point = fields.next()?;
+            let fstype = fields.next()?;
+            Some(Mount {
+                mount_point: PathBuf::from(unescape(mount_point)),
+                fstype: fstype.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The kernel writes spaces, tabs, newlines and backslashes in mount points as octal escapes, e.g.
+/// `\040` for a space.
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(i) = rest.find('\\') {
+        unescaped.push_str(&rest[..i]);
+        let escape = rest.get(i + 1..i + 4).unwrap_or_default();
+        match u8::from_str_radix(escape, 8) {
+            Ok(byte) if escape.len() == 3 => {
+                unescaped.push(char::from(byte));
+                rest = &rest[i + 4..];
+            }
+            _ => {
+                unescaped.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// The filesystem type of the mount that `path` is on, which is the last mounted of the longest
+/// mount points that contain it. `path` should be canonical.
+pub(crate) fn mount_fstype<'a>(mounts: &'a [Mount], path: &Path) -> Option<&'a str> {
+    mounts
+        .iter()
+        .filter(|mount| path.starts_with(&mount.mount_point))
+        .fold(None, |best: Option<&Mount>, mount| match best {
+            Some(best)
+                if best.mount_point.components().count()
+                    > mount.mount_point.components().count() =>
+            {
+                Some(best)
+            }
+            _ => Some(mount),
+        })
+        .map(|mount| mount.fstype.as_str())
+}
+
+/// What goes wrong when building on a filesystem of type `fstype`, as named in `/proc/mounts`, or
+/// `None` if it is not known to be a problem.
+pub(crate) fn limitation(fstype: &str) -> Option<&'static str> {
+    Some(match fstype {
+        "vfat" | "msdos" | "exfat" => {
+            "does not support hardlinks, symlinks or Unix permissions, and treats file names that \
+            differ only in case as the same file"
+        }
+        // NTFS mounted with ntfs-3g shows up as `fuseblk`.
+        "ntfs" | "ntfs3" | "fuseblk" => {
+            "only emulates Unix permissions and ownership, and is usually mounted so that it treats \
+            file names that differ only in case as the same file"
+        }
+        // Windows directories in WSL.
+        "9p" | "drvfs" => {
+            "is shared from Windows, where file names that differ only in case are the same file \
+            and Unix permissions are only emulated"
+        }
+        "vboxsf" | "prl_fs" => {
+            "is shared from the host of a virtual machine and does not support hardlinks or Unix \
+            permissions"
+        }
+        "cifs" | "smb3" | "smbfs" => {
+            "is a network share that usually does not support hardlinks and treats file names that \
+            differ only in case as the same file"
+        }
+        "nfs" | "nfs4" => {
+            "is a network filesystem, where root squashing breaks file ownership and file locks \
+            are unreliable"
+        }
+        "fuse.sshfs" => "is a network filesystem that does not support hardlinks",
+        _ => return None,
+    })
+}
+
+/// The name of a filesystem from its `statfs` magic number, for the filesystems that [`limitation`]
+/// knows about.
+fn magic_fstype(magic: u64) -> Option<&'static str> {
+    Some(match magic {
+        0x4d44 => "vfat",
+        0x2011_bab0 => "exfat",
+        0x5346_544e => "ntfs",
+        0x0102_1997 => "9p",
+        0x786f_4256 => "vboxsf",
+        0xff53_4d42 => "cifs",
+        0xfe53_4d42 => "smb3",
+        0x517b => "smbfs",
+        0x6969 => "nfs",
+        _ => return None,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn statfs_fstype(path: &Path) -> Option<&'static str> {
+    let magic = nix::sys::statfs::statfs(path).ok()?.filesystem_type().0;
+    // The type is signed on some platforms, but magic numbers are 32 bits.
+    magic_fstype(magic as u64 & 0xffff_ffff)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn statfs_fstype(_path: &Path) -> Option<&'static str> {
+    None
+}
+
+/// `path`, or its closest ancestor that exists, with symlinks resolved. A directory that a build
+/// will create ends up on the filesystem of its parent.
+async fn existing_ancestor(path: &Path) -> Option<PathBuf> {
+    for ancestor in path.ancestors() {
+        if let Ok(path) = fs::canonicalize(ancestor).await {
+            return Some(path);
+        }
+    }
+    None
+}
+
+/// The `dirs` that are on filesystems known to break builds.
+pub(crate) async fn problems(dirs: &[PathBuf]) -> Vec<Problem> {
+    let mounts = fs::read_to_string(PROC_MOUNTS)
+        .await
+        .map(|contents| parse_mounts(&contents))
+        .unwrap_or_default();
+    let mut problems: Vec<Problem> = Vec::new();
+    for dir in dirs {
+        let Some(path) = existing_ancestor(dir).await else {
+            continue;
+        };
+        let fstype = match mount_fstype(&mounts, &path) {
+            Some(fstype) => fstype.to_string(),
+            None => match statfs_fstype(&path) {
+                Some(fstype) => fstype.to_string(),
+                None => continue,
+            },
+        };
+        if let Some(limitation) = limitation(&fstype) {
+            // A build directory inside of a project that was already reported adds nothing.
+            if !problems
+                .iter()
+                .any(|problem| dir.starts_with(&problem.dir) && problem.fstype == fstype)
+            {
+                problems.push(Problem {
+                    dir: dir.clone(),
+                    fstype,
+                    limitation,
+                });
+            }
+        }
+    }
+    problems
+}
+
+/// Warns about the `dirs` that are on filesystems known to break builds, or fails if `strict`.
+pub(crate) async fn preflight(dirs: &[PathBuf], strict: bool) -> Result<()> {
+    let problems = problems(dirs).await;
+    if problems.is_empty() {
+        return Ok(());
+    }
+    if strict {
+        let problems: Vec<String> = problems.iter().map(ToString::to_string).collect();
+        bail!(
+            "Refusing to build with --strict-preflight: {}",
+            problems.join("; ")
+        );
+    }
+    for problem in problems {
+        warn!("{}, the build may fail", problem);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MOUNTS: &str = "\
+/dev/nvme0n1p2 / ext4 rw,relatime 0 0
+proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+/dev/sdb1 /mnt/usb exfat rw,relatime,fmask=0022 0 0
+/dev/sdc1 /mnt/my\\040disk fuseblk rw,relatime,user_id=0 0 0
+server:/export /home/builder/share nfs4 rw,relatime,vers=4.2 0 0
+tmpfs /home/builder/share/tmp tmpfs rw 0 0
+not-a-mount
+";
+
+    #[test]
+    fn test_parse_mounts() {
+        let mounts = parse_mounts(MOUNTS);
+        assert_eq!(mounts.len(), 6);
+        assert_eq!(
+            mounts[3],
+            Mount {
+                mount_point: PathBuf::from("/mnt/my disk"),
+                fstype: "fuseblk".to_string(),
+            }
+        );
+        assert_eq!(unescape(r"a\134b\tc\0"), r"a\b\tc\0");
+
+        let fstype = |path: &str| mount_fstype(&mounts, Path::new(path));
+        assert_eq!(fstype("/home/builder/project"), Some("ext4"));
+        assert_eq!(fstype("/mnt/usb/project"), Some("exfat"));
+        assert_eq!(fstype("/mnt/usbstick"), Some("ext4"));
+        assert_eq!(fstype("/mnt/my disk/project"), Some("fuseblk"));
+        assert_eq!(fstype("/home/builder/share/project"), Some("nfs4"));
+        assert_eq!(fstype("/home/builder/share/tmp/build"), Some("tmpfs"));
+        assert_eq!(mount_fstype(&[], Path::new("/")), None);
+    }
+
+    #[test]
+    fn test_limitation() {
+        for fstype in ["vfat", "exfat", "ntfs3", "fuseblk", "9p", "cifs", "nfs4"] {
+            assert!(limitation(fstype).is_some(), "{}", fstype);
+        }
+        for fstype in [
+            "ext4",
+            "xfs",
+            "btrfs",
+            "tmpfs",
+            "overlay",
+            "zfs",
+            "unknownfs",
+        ] {
+            assert!(limitation(fstype).is_none(), "{}", fstype);
+        }
+        assert!(limitation("exfat").unwrap().contains("hardlinks"));
+        assert!(limitation("cifs").unwrap().contains("case"));
+        assert_eq!(magic_fstype(0x2011_bab0), Some("exfat"));
+        assert_eq!(magic_fstype(0xef53), None);
+    }
+}
@@ -0,0 +1,506 @@
+/*!
+
+The dependency graph of a project's variants, kits and packages, as `twoliter graph` shows it. The
+graph is read from the `path` dependencies in each `Cargo.toml` with the same
+[`crate::kit::CrateWalk`] that finds a kit's inputs, so it needs neither cargo nor docker. External
+kits come from Twoliter.lock, or from Twoliter.toml if there is no usable lock, and every variant
+depends on all of them.
+
+A dependency whose `Cargo.toml` cannot be found becomes a node of its own that is marked as missing,
+and the edges of a cycle are marked as such, so that a broken project can still be drawn.
+
+!*/
+
+use crate::kit::{build_metadata, local_kits, read_cargo_toml, CrateWalk, KitManifest};
+use crate::lock::Lock;
+use crate::project::Project;
+use crate::variant::{local_variants, VariantManifest};
+use anyhow::{ensure, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write;
+use std::path::Path;
+use toml::{Table, Value};
+
+/// What a node of a [`DependencyGraph`] is.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum NodeKind {
+    Variant,
+    Kit,
+    Package,
+    /// A kit that the project gets from a vendor rather than building.
+    ExternalKit,
+    /// A dependency whose `Cargo.toml` does not exist.
+    Missing,
+}
+
+/// A variant, kit or package in a [`DependencyGraph`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub(crate) struct Node {
+    pub(crate) kind: NodeKind,
+    /// The version of an external kit, from Twoliter.lock or Twoliter.toml.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) version: Option<String>,
+}
+
+/// The formats that a [`DependencyGraph`] can be written in.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, ValueEnum)]
+pub(crate) enum GraphFormat {
+    /// Graphviz, e.g. `twoliter graph | dot -Tsvg > graph.svg`.
+    #[default]
+    Dot,
+    /// A Mermaid flowchart, which GitHub renders in Markdown.
+    Mermaid,
+    Json,
+    Toml,
+}
+
+/// Which variants, kits and packages depend on which. Nodes are named after their crate, or
+/// `<vendor>/<name>` for an external kit.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(crate) struct DependencyGraph {
+    nodes: BTreeMap<String, Node>,
+    /// The dependencies of each node.
+    edges: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl DependencyGraph {
+    /// Reads the graph of `project`. A Twoliter.lock that is out of date is not an error, the
+    /// external kits are then taken from Twoliter.toml instead.
+    pub(crate) async fn load(project: &Project) -> Result<Self> {
+        let mut graph = Self::default();
+        for (name, version) in external_kits(project).await {
+            graph.add_node(name, NodeKind::ExternalKit, Some(version));
+        }
+        let externals: Vec<String> = graph.nodes.keys().cloned().collect();
+
+        let mut roots = Vec::new();
+        for variant in local_variants(project).await? {
+            roots.push(VariantManifest::path_for(project, &variant));
+        }
+        for kit in local_kits(project).await? {
+            roots.push(KitManifest::path_for(project, &kit));
+        }
+        let mut walk = CrateWalk::new(roots);
+        while let Some(krate) = walk.next().await {
+            let krate = krate?;
+            let name = crate_name(&krate.toml, &krate.manifest);
+            let kind = crate_kind(&krate.toml);
+            graph.add_node(name.clone(), kind, None);
+            if kind == NodeKind::Variant {
+                for external in &externals {
+                    graph.add_edge(&name, external);
+                }
+            }
+            for dependency in krate.dependencies.iter().filter(|d| d.path.is_some()) {
+                let Some(manifest) = &dependency.manifest else {
+                    graph.add_node(dependency.name.clone(), NodeKind::Missing, None);
+                    graph.add_edge(&name, &dependency.name);
+                    continue;
+                };
+                let dependency = crate_name(&read_cargo_toml(manifest).await?, manifest);
+                graph.add_edge(&name, &dependency);
+            }
+        }
+        Ok(graph)
+    }
+
+    fn add_node(&mut self, name: String, kind: NodeKind, version: Option<String>) {
+        self.edges.entry(name.clone()).or_default();
+        let node = self.nodes.entry(name).or_insert(Node { kind, version });
+        // A crate that was first seen as someone's dependency is only missing until it is found.
+        if node.kind == NodeKind::Missing {
+            node.kind = kind;
+        }
+    }
+
+    fn add_edge(&mut self, from: &str, to: &str) {
+        self.edges
+            .entry(from.to_string())
+            .or_default()
+            .insert(to.to_string());
+    }
+
+    /// The nodes by name.
+    #[cfg(test)]
+    pub(crate) fn nodes(&self) -> &BTreeMap<String, Node> {
+        &self.nodes
+    }
+
+    /// The dependencies of the node `name`.
+    pub(crate) fn dependencies(&self, name: &str) -> impl Iterator<Item = &String> {
+        self.edges.get(name).into_iter().flatten()
+    }
+
+    /// Only the node `name`, everything that it depends on and everything that depends on it,
+    /// directly or not.
+    pub(crate) fn focus(&self, name: &str) -> Result<Self> {
+        ensure!(
+            self.nodes.contains_key(name),
+            "There is no variant, kit or package named '{}' in the project",
+            name
+        );
+        let mut keep = self.reachable(name, |node| self.dependencies(node).cloned().collect());
+        keep.extend(self.reachable(name, |node| self.dependents(node)));
+        let nodes = self
+            .nodes
+            .iter()
+            .filter(|(name, _)| keep.contains(*name))
+            .map(|(name, node)| (name.clone(), node.clone()))
+            .collect();
+        let edges = self
+            .edges
+            .iter()
+            .filter(|(name, _)| keep.contains(*name))
+            .map(|(name, dependencies)| {
+                let dependencies = dependencies.intersection(&keep).cloned().collect();
+                (name.clone(), dependencies)
+            })
+            .collect();
+        Ok(Self { nodes, edges })
+    }
+
+    /// The edges that are part of a cycle, as `(from, to)`.
+    pub(crate) fn cycle_edges(&self) -> BTreeSet<(String, String)> {
+        self.all_edges()
+            .filter(|(from, to)| {
+                self.reachable(to, |node| self.dependencies(node).cloned().collect())
+                    .contains(*from)
+            })
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .collect()
+    }
+
+    /// Every edge, as `(from, to)`, sorted.
+    fn all_edges(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.edges.iter().flat_map(|(from, dependencies)| {
+            dependencies
+                .iter()
+                .map(move |to| (from.as_str(), to.as_str()))
+        })
+    }
+
+    /// Every node that depends on the node `name`, directly or not.
+    pub(crate) fn all_dependents(&self, name: &str) -> BTreeSet<String> {
+        let mut dependents = self.reachable(name, |node| self.dependents(node));
+        dependents.remove(name);
+        dependents
+    }
+
+    fn dependents(&self, name: &str) -> Vec<String> {
+        self.all_edges()
+            .filter(|(_, to)| *to == name)
+            .map(|(from, _)| from.to_string())
+            .collect()
+    }
+
+    /// The nodes that can be reached from `start` by following `next`, including `start`.
+    fn reachable(&self, start: &str, next: impl Fn(&str) -> Vec<String>) -> BTreeSet<String> {
+        let mut reached = BTreeSet::new();
+        let mut queue = VecDeque::from([start.to_string()]);
+        while let Some(node) = queue.pop_front() {
+            if reached.insert(node.clone()) {
+                queue.extend(next(&node));
+            }
+        }
+        reached
+    }
+
+    /// Writes the graph in `format`.
+    pub(crate) fn render(&self, format: GraphFormat) -> Result<String> {
+        match format {
+            GraphFormat::Dot => Ok(self.dot()),
+            GraphFormat::Mermaid => Ok(self.mermaid()),
+            GraphFormat::Json => Ok(serde_json::to_string_pretty(self)? + "\n"),
+            GraphFormat::Toml => Ok(toml::to_string_pretty(self)?),
+        }
+    }
+
+    fn dot(&self) -> String {
+        let cycles = self.cycle_edges();
+        let mut out = String::from("digraph dependencies {\n    rankdir=LR;\n");
+        for (name, node) in &self.nodes {
+            let (shape, style) = match node.kind {
+                NodeKind::Variant => ("box", "filled,bold"),
+                NodeKind::Kit => ("box3d", "filled"),
+                NodeKind::Package => ("ellipse", "filled"),
+                NodeKind::ExternalKit => ("box3d", "filled,dashed"),
+                NodeKind::Missing => ("octagon", "filled,bold"),
+            };
+            let color = if node.kind == NodeKind::Missing {
+                ", color=red, fillcolor=mistyrose"
+            } else {
+                ", fillcolor=white"
+            };
+            let _ = writeln!(
+                out,
+                "    \"{}\" [label=\"{}\", shape={}, style=\"{}\"{}];",
+                name,
+                label(name, node),
+                shape,
+                style,
+                color
+            );
+        }
+        for (from, to) in self.all_edges() {
+            let cycle = cycles.contains(&(from.to_string(), to.to_string()));
+            let attributes = if cycle {
+                " [color=red, penwidth=2, label=\"cycle\"]"
+            } else {
+                ""
+            };
+            let _ = writeln!(out, "    \"{}\" -> \"{}\"{};", from, to, attributes);
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    fn mermaid(&self) -> String {
+        let cycles = self.cycle_edges();
+        // Mermaid ids cannot contain most punctuation, so nodes are numbered in name order.
+        let ids: BTreeMap<&str, String> = self
+            .nodes
+            .keys()
+            .enumerate()
+            .map(|(i, name)| (name.as_str(), format!("n{}", i)))
+            .collect();
+        let mut out = String::from("flowchart LR\n");
+        for (name, node) in &self.nodes {
+            let label = label(name, node).replace("\\n", "<br/>");
+            let id = &ids[name.as_str()];
+            let _ = match node.kind {
+                NodeKind::Variant => writeln!(out, "    {}[[\"{}\"]]", id, label),
+                NodeKind::Kit | NodeKind::ExternalKit => writeln!(out, "    {}[\"{}\"]", id, label),
+                NodeKind::Package => writeln!(out, "    {}([\"{}\"])", id, label),
+                NodeKind::Missing => writeln!(out, "    {}{{{{\"{}\"}}}}", id, label),
+            };
+        }
+        let mut cycle_links = Vec::new();
+        for (i, (from, to)) in self.all_edges().enumerate() {
+            let cycle = cycles.contains(&(from.to_string(), to.to_string()));
+            if cycle {
+                cycle_links.push(i.to_string());
+                let _ = writeln!(out, "    {} -->|cycle| {}", ids[from], ids[to]);
+            } else {
+                let _ = writeln!(out, "    {} --> {}", ids[from], ids[to]);
+            }
+        }
+        out.push_str("    classDef missing stroke:#d00,stroke-width:2px,fill:#fee\n");
+        out.push_str("    classDef external stroke-dasharray:5 5\n");
+        for (kind, class) in [
+            (NodeKind::Missing, "missing"),
+            (NodeKind::ExternalKit, "external"),
+        ] {
+            let members: Vec<&str> = self
+                .nodes
+                .iter()
+                .filter(|(_, node)| node.kind == kind)
+                .map(|(name, _)| ids[name.as_str()].as_str())
+                .collect();
+            if !members.is_empty() {
+                let _ = writeln!(out, "    class {} {}", members.join(","), class);
+            }
+        }
+        if !cycle_links.is_empty() {
+            let _ = writeln!(
+                out,
+                "    linkStyle {} stroke:#d00,stroke-width:2px",
+                cycle_links.join(",")
+            );
+        }
+        out
+    }
+}
+
+/// The graph as JSON or TOML: each node with its dependencies, and the edges that are in a cycle.
+impl Serialize for DependencyGraph {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct SerializedNode<'a> {
+            name: &'a str,
+            #[serde(flatten)]
+            node: &'a Node,
+            dependencies: Vec<&'a str>,
+        }
+        #[derive(Serialize)]
+        struct SerializedGraph<'a> {
+            nodes: Vec<SerializedNode<'a>>,
+            /// The edges that are part of a cycle, as `[from, to]`.
+            cycles: Vec<(String, String)>,
+        }
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|(name, node)| SerializedNode {
+                name,
+                node,
+                dependencies: self.dependencies(name).map(String::as_str).collect(),
+            })
+            .collect();
+        SerializedGraph {
+            nodes,
+            cycles: self.cycle_edges().into_iter().collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// The label of a node in a drawing of the graph, with `\n` separating its lines.
+fn label(name: &str, node: &Node) -> String {
+    match (&node.kind, &node.version) {
+        (NodeKind::Missing, _) => format!("{}\\n(missing)", name),
+        (_, Some(version)) => format!("{}\\n{}", name, version),
+        (_, None) => name.to_string(),
+    }
+}
+
+/// The external kits of `project` as `<vendor>/<name>`, along with their version.
+async fn external_kits(project: &Project) -> Vec<(String, String)> {
+    match Lock::load_existing(project).await {
+        Ok(lock) => lock
+            .kit
+            .iter()
+            .map(|kit| {
+                (
+                    format!("{}/{}", kit.vendor, kit.name),
+                    kit.version.to_string(),
+                )
+            })
+            .collect(),
+        Err(e) => {
+            log::debug!("Using the kits in Twoliter.toml: {:#}", e);
+            project
+                .kits()
+                .iter()
+                .map(|kit| {
+                    (
+                        format!("{}/{}", kit.vendor, kit.name),
+                        kit.version.to_string(),
+                    )
+                })
+                .collect()
+        }
+    }
+}
+
+/// The `package.name` of a crate, or the name of its directory if it has none.
+fn crate_name(toml: &Table, path: &Path) -> String {
+    toml.get("package")
+        .and_then(|package| package.get("name"))
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .unwrap_or_else(|| {
+            path.parent()
+                .and_then(Path::file_name)
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string()
+        })
+}
+
+fn crate_kind(toml: &Table) -> NodeKind {
+    if build_metadata(toml, "build-variant").is_some() {
+        NodeKind::Variant
+    } else if build_metadata(toml, "build-kit").is_some() {
+        NodeKind::Kit
+    } else {
+        NodeKind::Package
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::fs;
+    use crate::test::copy_project_to_temp_dir;
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn test_dependency_graph() {
+        let temp_dir = copy_project_to_temp_dir("local-kit").await;
+        let project_dir = temp_dir.path();
+        let project = Project::load(project_dir.join("Twoliter.toml"))
+            .await
+            .unwrap();
+        let graph = DependencyGraph::load(&project).await.unwrap();
+        assert_eq!(graph.nodes()["hello-ootb"].kind, NodeKind::Variant);
+        assert_eq!(graph.nodes()["extra-3-kit"].kind, NodeKind::Kit);
+        assert_eq!(graph.nodes()["pkg-a-1_27"].kind, NodeKind::Package);
+        assert!(graph.dependencies("pkg-g").any(|d| d == "pkg-f"));
+        assert!(graph.cycle_edges().is_empty());
+
+        // pkg-c is neither a dependency nor a dependent of extra-1-kit.
+        let focused = graph.focus("extra-1-kit").unwrap();
+        assert!(focused.nodes().contains_key("hello-ootb"));
+        assert!(focused.nodes().contains_key("pkg-a-1_27"));
+        assert!(!focused.nodes().contains_key("pkg-c"));
+        assert!(graph.focus("no-such-kit").is_err());
+        let dependents = graph.all_dependents("core-kit");
+        assert!(dependents.contains("extra-3-kit"));
+        assert!(dependents.contains("hello-ootb"));
+        assert!(!dependents.contains("core-kit"));
+
+        // Break the project and expect it to still be drawn, with the problems marked.
+        let edit = |path: PathBuf, from: &'static str, to: &'static str| async move {
+            let data = fs::read_to_string(&path).await.unwrap();
+            assert!(data.contains(from));
+            fs::write(&path, data.replace(from, to)).await.unwrap();
+        };
+        edit(
+            project_dir.join("kits/extra-3-kit/Cargo.toml"),
+            "pkg-g = { path = \"../../packages/pkg-g\" }",
+            "pkg-z = { path = \"../../packages/pkg-z\" }",
+        )
+        .await;
+        edit(
+            project_dir.join("kits/core-kit/Cargo.toml"),
+            "[build-dependencies]",
+            "[build-dependencies]\npkg-c = { path = \"../../packages/pkg-c\" }",
+        )
+        .await;
+        let graph = DependencyGraph::load(&project).await.unwrap();
+        assert_eq!(graph.nodes()["pkg-z"].kind, NodeKind::Missing);
+        let cycles = graph.cycle_edges();
+        assert!(cycles.contains(&("core-kit".to_string(), "pkg-c".to_string())));
+        assert!(cycles.contains(&("pkg-c".to_string(), "core-kit".to_string())));
+        assert!(!cycles.contains(&("extra-2-kit".to_string(), "core-kit".to_string())));
+
+        let dot = graph.render(GraphFormat::Dot).unwrap();
+        assert!(
+            dot.contains("\"pkg-c\" -> \"core-kit\" [color=red"),
+            "{}",
+            dot
+        );
+        assert!(dot.contains("pkg-z\\n(missing)"), "{}", dot);
+        let mermaid = graph.render(GraphFormat::Mermaid).unwrap();
+        assert!(mermaid.contains("-->|cycle|"), "{}", mermaid);
+        assert!(mermaid.contains("class "), "{}", mermaid);
+        let json: serde_json::Value =
+            serde_json::from_str(&graph.render(GraphFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["cycles"].as_array().unwrap().len(), 2);
+        assert!(json["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|node| node["name"] == "pkg-z" && node["kind"] == "missing"));
+        let toml: toml::Table = toml::from_str(&graph.render(GraphFormat::Toml).unwrap()).unwrap();
+        assert_eq!(toml["cycles"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_external_kits() {
+        let temp_dir = copy_project_to_temp_dir("external-kit").await;
+        let project = Project::load(temp_dir.path().join("Twoliter.toml"))
+            .await
+            .unwrap();
+        let graph = DependencyGraph::load(&project).await.unwrap();
+        let core_kit = &graph.nodes()["custom-vendor/core-kit"];
+        assert_eq!(core_kit.kind, NodeKind::ExternalKit);
+        assert_eq!(core_kit.version.as_deref(), Some("0.1.0"));
+        assert!(graph
+            .dependencies("hello-ootb")
+            .any(|d| d == "custom-vendor/core-kit"));
+    }
+}
@@ -0,0 +1,235 @@
+/*!
+
+`Infra.toml` is read by pubsys, which only runs once an image has been built. A mistake in the file
+would otherwise only be found at the end of a long build, so Twoliter checks the parts that it knows
+about up front. The model here is deliberately permissive: sections and fields that it does not know
+about are ignored, so that newer pubsys features do not break older versions of Twoliter.
+
+!*/
+
+use crate::common::fs;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// The parts of `Infra.toml` that Twoliter checks.
+#[derive(Debug, Default, Deserialize)]
+struct InfraToml {
+    #[serde(default)]
+    repo: BTreeMap<String, Repo>,
+    aws: Option<Aws>,
+    #[serde(default)]
+    vendor: BTreeMap<String, Vendor>,
+}
+
+/// A container registry that pubsys publishes kits to.
+#[derive(Debug, Default, Deserialize)]
+struct Vendor {
+    registry: Option<String>,
+}
+
+/// A TUF repository and the keys used to sign it. The URLs are only parsed so that malformed ones
+/// are reported.
+#[derive(Debug, Default, Deserialize)]
+#[allow(dead_code)]
+struct Repo {
+    root_role_url: Option<Url>,
+    metadata_base_url: Option<Url>,
+    targets_url: Option<Url>,
+    signing_keys: Option<SigningKeys>,
+    root_keys: Option<SigningKeys>,
+}
+
+/// Where a signing key is kept. Only keys in local files can be checked ahead of time.
+#[derive(Debug, Default, Deserialize)]
+struct SigningKeys {
+    file: Option<KeyFile>,
+    kms: Option<Kms>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyFile {
+    path: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Kms {
+    #[serde(default)]
+    regions: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Aws {
+    #[serde(default)]
+    regions: Vec<String>,
+    #[serde(default)]
+    region: BTreeMap<String, toml::Value>,
+    #[serde(default)]
+    s3: BTreeMap<String, S3>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct S3 {
+    region: Option<String>,
+}
+
+/// Checks the `Infra.toml` at `path` and returns an error describing every problem found. Relative
+/// key file paths are resolved against `project_dir`, where pubsys runs.
+pub(crate) async fn validate(path: &Path, project_dir: &Path) -> Result<()> {
+    let problems = problems(path, project_dir).await?;
+    if !problems.is_empty() {
+        bail!(
+            "Found {} problem(s) in '{}':\n{}",
+            problems.len(),
+            path.display(),
+            problems.join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// Returns a description of each problem in the `Infra.toml` at `path`, resolving relative key file
+/// paths against `project_dir`. A file that cannot be parsed is an error.
+pub(crate) async fn problems(path: &Path, project_dir: &Path) -> Result<Vec<String>> {
+    let data = fs::read_to_string(path).await?;
+    let infra: InfraToml =
+        toml::from_str(&data).context(format!("Unable to parse '{}'", path.display()))?;
+
+    let mut problems = Vec::new();
+    for (name, repo) in &infra.repo {
+        for (field, keys) in [
+            ("signing_keys", &repo.signing_keys),
+            ("root_keys", &repo.root_keys),
+        ] {
+            let Some(keys) = keys else {
+                continue;
+            };
+            if let Some(file) = &keys.file {
+                let key = project_dir.join(&file.path);
+                if !key.is_file() {
+                    problems.push(format!(
+                        "repo.{}.{}: the key file '{}' does not exist",
+                        name,
+                        field,
+                        key.display()
+                    ));
+                }
+            }
+            for region in keys.kms.iter().flat_map(|kms| &kms.regions) {
+                check_region(&mut problems, &format!("repo.{}.{}", name, field), region);
+            }
+        }
+    }
+
+    if let Some(aws) = &infra.aws {
+        for region in aws.regions.iter().chain(aws.region.keys()) {
+            check_region(&mut problems, "aws", region);
+        }
+        for (name, s3) in &aws.s3 {
+            if let Some(region) = &s3.region {
+                check_region(&mut problems, &format!("aws.s3.{}", name), region);
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// The registry that pubsys publishes the kits of `vendor` to, according to the `Infra.toml` at
+/// `path`, or `None` if the file has no such vendor.
+pub(crate) async fn vendor_registry(path: &Path, vendor: &str) -> Result<Option<String>> {
+    let data = fs::read_to_string(path).await?;
+    let infra: InfraToml =
+        toml::from_str(&data).context(format!("Unable to parse '{}'", path.display()))?;
+    Ok(infra
+        .vendor
+        .get(vendor)
+        .and_then(|vendor| vendor.registry.clone()))
+}
+
+fn check_region(problems: &mut Vec<String>, field: &str, region: &str) {
+    if !is_aws_region(region) {
+        problems.push(format!("{}: '{}' is not an AWS region", field, region));
+    }
+}
+
+/// Whether `name` has the shape of an AWS region name, e.g. `us-west-2` or `us-gov-east-1`. New
+/// regions are added all the time, so the names themselves are not checked.
+fn is_aws_region(name: &str) -> bool {
+    let parts: Vec<_> = name.split('-').collect();
+    let (Some(first), Some(last)) = (parts.first(), parts.last()) else {
+        return false;
+    };
+    let is_word = |part: &&str| !part.is_empty() && part.chars().all(|c| c.is_ascii_lowercase());
+    parts.len() >= 3
+        && first.len() == 2
+        && parts[..parts.len() - 1].iter().all(is_word)
+        && !last.is_empty()
+        && last.chars().all(|c| c.is_ascii_digit())
+}
+
+#[test]
+fn test_is_aws_region() {
+    for region in [
+        "us-west-2",
+        "us-gov-east-1",
+        "ap-southeast-4",
+        "us-isob-east-1",
+    ] {
+        assert!(is_aws_region(region), "{}", region);
+    }
+    for region in ["us-west2", "uswest-2", "us-West-2", "us-west-", "global"] {
+        assert!(!is_aws_region(region), "{}", region);
+    }
+}
+
+#[tokio::test]
+async fn test_problems() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("keys.json"), "{}").await.unwrap();
+    // Infra.toml may live outside the project, key paths are relative to the project regardless.
+    fs::create_dir_all(dir.join("infra")).await.unwrap();
+    let path = dir.join("infra/Infra.toml");
+    fs::write(
+        &path,
+        r#"
+        [repo.default]
+        root_role_url = "https://example.com/root.json"
+        signing_keys = { file = { path = "keys.json" } }
+        root_keys = { file = { path = "missing.json" } }
+        some_future_setting = true
+
+        [aws]
+        regions = ["us-west-2", "us-east1"]
+
+        [aws.region.us-gov-west-1]
+        role = "arn:aws:iam::123456789012:role/publish"
+
+        [vendor.acme]
+        registry = "123456789012.dkr.ecr.us-west-2.amazonaws.com/acme"
+
+        [vmware]
+        datacenters = ["north"]
+        "#,
+    )
+    .await
+    .unwrap();
+    let found = problems(&path, dir).await.unwrap();
+    assert_eq!(found.len(), 2, "{:?}", found);
+    assert!(found[0].contains("repo.default.root_keys: the key file"));
+    assert!(found[1].contains("'us-east1' is not an AWS region"));
+    assert!(validate(&path, dir).await.is_err());
+    assert_eq!(
+        vendor_registry(&path, "acme").await.unwrap().as_deref(),
+        Some("123456789012.dkr.ecr.us-west-2.amazonaws.com/acme")
+    );
+    assert_eq!(vendor_registry(&path, "other").await.unwrap(), None);
+
+    fs::write(&path, "[repo.default]\nmetadata_base_url = \"not a url\"\n")
+        .await
+        .unwrap();
+    let err = format!("{:#}", problems(&path, dir).await.unwrap_err());
+    assert!(err.contains("line 2"), "{}", err);
+}
@@ -0,0 +1,1234 @@
+use crate::checksums::hash_file;
+use crate::common::fs;
+use crate::project::Project;
+use anyhow::{bail, ensure, Context, Result};
+use async_walkdir::{Filtering, WalkDir};
+use buildsys_config::IMAGE_FEATURES;
+use futures::StreamExt;
+use semver::Version;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use toml::{Table, Value};
+
+/// The name of the directory, relative to the project directory, that holds local kits.
+pub(crate) const KITS_DIRECTORY: &str = "kits";
+
+/// Written into a kit's output directory after a build to record the hash of its inputs, see
+/// [`inputs_hash`].
+pub(crate) const INPUTS_SHA256: &str = "INPUTS_SHA256";
+
+/// The `Cargo.toml` of a kit in the project's `kits` directory.
+#[derive(Debug, Clone)]
+pub(crate) struct KitManifest {
+    toml: Table,
+}
+
+impl KitManifest {
+    /// Returns the path to the `Cargo.toml` of the local kit named `name`.
+    pub(crate) fn path_for(project: &Project, name: &str) -> PathBuf {
+        project
+            .project_dir()
+            .join(KITS_DIRECTORY)
+            .join(name)
+            .join("Cargo.toml")
+    }
+
+    /// Loads the manifest of the local kit named `name`.
+    pub(crate) async fn load(project: &Project, name: &str) -> Result<Self> {
+        Self::load_path(Self::path_for(project, name)).await
+    }
+
+    /// Loads and parses the kit manifest at `path`. Parse errors are reported with the file and
+    /// the line and column at which they occurred so that a broken kit manifest can be told apart
+    /// from a package that failed to build.
+    pub(crate) async fn load_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        ensure!(
+            fs::metadata(path).await.is_ok(),
+            "Unable to find the kit manifest '{}'",
+            path.display()
+        );
+        let manifest = Self {
+            toml: read_cargo_toml(path).await?,
+        };
+        ensure!(
+            manifest.build_kit().is_some(),
+            "The kit manifest '{}' is missing the [package.metadata.build-kit] section",
+            path.display()
+        );
+        Ok(manifest)
+    }
+
+    /// The `[package.metadata.build-kit]` table.
+    pub(crate) fn build_kit(&self) -> Option<&Table> {
+        build_metadata(&self.toml, "build-kit")
+    }
+
+    /// The `vendor` in the `[package.metadata.build-kit]` table.
+    pub(crate) fn vendor(&self) -> Option<&str> {
+        self.build_kit()?.get("vendor")?.as_str()
+    }
+
+    /// The kit's `package.version`.
+    pub(crate) fn version(&self) -> Result<Version> {
+        let version = self
+            .toml
+            .get("package")
+            .and_then(|package| package.get("version"))
+            .and_then(Value::as_str)
+            .context("The kit manifest does not have a package.version")?;
+        Version::parse(version).context(format!("Unable to parse kit version '{}'", version))
+    }
+}
+
+/// Returns the names of the kits in the project's `kits` directory, i.e. the subdirectories that
+/// contain a `Cargo.toml`, sorted by name.
+pub(crate) async fn local_kits(project: &Project) -> Result<Vec<String>> {
+    let kits_dir = project.project_dir().join(KITS_DIRECTORY);
+    if !kits_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut entries = tokio::fs::read_dir(&kits_dir)
+        .await
+        .context(format!("Unable to read directory '{}'", kits_dir.display()))?;
+    let mut kits = Vec::new();
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context(format!("Unable to read directory '{}'", kits_dir.display()))?
+    {
+        if entry.path().join("Cargo.toml").is_file() {
+            kits.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    kits.sort();
+    Ok(kits)
+}
+
+/// A local kit as `twoliter kit list` shows it.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct KitListing {
+    pub(crate) name: String,
+    pub(crate) version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) vendor: Option<String>,
+    /// The number of packages that the kit depends on, see [`package_dirs`].
+    pub(crate) packages: usize,
+}
+
+/// Describes each of the kits in the project's `kits` directory, sorted by name. Kits whose
+/// manifest cannot be loaded are skipped with a warning.
+pub(crate) async fn list(project: &Project) -> Result<Vec<KitListing>> {
+    let mut listings = Vec::new();
+    for name in local_kits(project).await? {
+        let manifest = match KitManifest::load(project, &name).await {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                log::warn!("Skipping kit '{}': {:#}", name, e);
+                continue;
+            }
+        };
+        listings.push(KitListing {
+            version: manifest.version()?.to_string(),
+            vendor: manifest.vendor().map(str::to_string),
+            packages: package_dirs(project, &name).await?.len(),
+            name,
+        });
+    }
+    Ok(listings)
+}
+
+/// How to change a kit's version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum VersionBump {
+    Major,
+    Minor,
+    Patch,
+    Set(Version),
+}
+
+impl VersionBump {
+    fn apply(&self, version: &Version) -> Version {
+        match self {
+            VersionBump::Major => Version::new(version.major + 1, 0, 0),
+            VersionBump::Minor => Version::new(version.major, version.minor + 1, 0),
+            VersionBump::Patch => Version::new(version.major, version.minor, version.patch + 1),
+            VersionBump::Set(version) => version.clone(),
+        }
+    }
+}
+
+/// Changes the `package.version` of the local kit named `name`, preserving the formatting of the
+/// rest of its `Cargo.toml`. The file is replaced atomically. Returns the old and new versions.
+pub(crate) async fn bump(
+    project: &Project,
+    name: &str,
+    bump: &VersionBump,
+) -> Result<(Version, Version)> {
+    let path = KitManifest::path_for(project, name);
+    let old = KitManifest::load_path(&path).await?.version()?;
+    let new = bump.apply(&old);
+
+    let data = fs::read_to_string(&path).await?;
+    let mut document: toml_edit::DocumentMut = data
+        .parse()
+        .context(format!("The manifest '{}' is malformed", path.display()))?;
+    document["package"]["version"] = toml_edit::value(new.to_string());
+    fs::write_atomic(&path, document.to_string()).await?;
+    Ok((old, new))
+}
+
+/// Checks the local kit named `name`, and every package and kit that it depends on through `path`
+/// dependencies, without building anything. Returns a description of every problem found rather
+/// than stopping at the first one.
+pub(crate) async fn validate(project: &Project, name: &str) -> Result<Vec<String>> {
+    let external_kits: HashSet<String> = project
+        .kits()
+        .into_iter()
+        .map(|kit| kit.name.to_string())
+        .collect();
+    let root = KitManifest::path_for(project, name);
+    ensure!(
+        fs::metadata(&root).await.is_ok(),
+        "Unable to find the kit manifest '{}'",
+        root.display()
+    );
+
+    let root = fs::canonicalize(&root).await?;
+
+    let mut problems = Vec::new();
+    let mut walk = CrateWalk::new([root.clone()]);
+    while let Some(krate) = walk.next().await {
+        let krate = match krate {
+            Ok(krate) => krate,
+            Err(e) => {
+                problems.push(format!("{:#}", e));
+                continue;
+            }
+        };
+        let (path, toml) = (&krate.manifest, &krate.toml);
+        let package = toml
+            .get("package")
+            .and_then(|p| p.get("name"))
+            .and_then(Value::as_str)
+            .unwrap_or("<unnamed>");
+        let problem = |message: String| format!("{}: {}", path.display(), message);
+
+        match build_metadata(toml, "build-kit") {
+            Some(build_kit) => {
+                if build_kit.get("vendor").and_then(Value::as_str).is_none() {
+                    problems.push(problem(format!(
+                        "Package '{}' has no 'vendor' field in build-kit metadata",
+                        package
+                    )));
+                }
+            }
+            None if *path == root => problems.push(problem(
+                "Missing the [package.metadata.build-kit] section".to_string(),
+            )),
+            None => {}
+        }
+
+        let package_features = build_metadata(toml, "build-package")
+            .and_then(|build_package| build_package.get("package-features"))
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str);
+        for feature in package_features {
+            if !IMAGE_FEATURES.contains(&feature) {
+                problems.push(problem(format!(
+                    "Unknown image feature '{}', expected one of: {}",
+                    feature,
+                    IMAGE_FEATURES.join(", ")
+                )));
+            }
+        }
+
+        for dependency in &krate.dependencies {
+            match (&dependency.path, &dependency.manifest) {
+                (Some(_), Some(_)) => {}
+                (Some(dependency_path), None) => problems.push(problem(format!(
+                    "Dependency '{}' not found at '{}'",
+                    dependency.name,
+                    krate
+                        .dir()
+                        .join(dependency_path)
+                        .join("Cargo.toml")
+                        .display()
+                ))),
+                (None, _) if external_kits.contains(&dependency.name) => {}
+                (None, _) => problems.push(problem(format!(
+                    "Dependency '{}' is neither a path dependency nor a kit listed in \
+                    Twoliter.toml",
+                    dependency.name
+                ))),
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// Returns the paths that the local kit named `name` is built from, see [`manifest_inputs`].
+pub(crate) async fn inputs(project: &Project, name: &str) -> Result<BTreeSet<PathBuf>> {
+    manifest_inputs(project, &KitManifest::path_for(project, name)).await
+}
+
+/// Returns the paths that the kit with the manifest at `manifest_path` is built from: the directory
+/// of the kit and of every package and kit that it depends on through `path` dependencies, the
+/// source groups of those packages, the build scripts and libraries that they share, and the
+/// `Cargo.lock` of the project and of its sources. Paths are canonical.
+pub(crate) async fn manifest_inputs(
+    project: &Project,
+    manifest_path: &Path,
+) -> Result<BTreeSet<PathBuf>> {
+    Ok(walk_inputs(project, manifest_path).await?.paths)
+}
+
+/// Returns the directory of the kit `name` and of every package and kit that it depends on through
+/// `path` dependencies. These are the [`inputs`] that building the kit builds packages from, unlike
+/// the build scripts, libraries and lock files that every kit shares. Paths are canonical.
+pub(crate) async fn crate_dirs(project: &Project, name: &str) -> Result<BTreeSet<PathBuf>> {
+    Ok(walk_inputs(project, &KitManifest::path_for(project, name))
+        .await?
+        .crates)
+}
+
+/// Returns the files that buildsys fetches into the directories of the packages that the kit with
+/// the manifest at `manifest_path` is built from, see [`fetched_files`]. Paths are canonical.
+pub(crate) async fn manifest_fetched_files(
+    project: &Project,
+    manifest_path: &Path,
+) -> Result<BTreeSet<PathBuf>> {
+    Ok(walk_inputs(project, manifest_path).await?.fetched)
+}
+
+/// What [`walk_inputs`] found.
+struct Inputs {
+    paths: BTreeSet<PathBuf>,
+    crates: BTreeSet<PathBuf>,
+    fetched: BTreeSet<PathBuf>,
+}
+
+/// Follows the `path` dependencies from the manifest at `manifest_path` to find the
+/// [`manifest_inputs`] and [`manifest_fetched_files`] of a kit.
+async fn walk_inputs(project: &Project, manifest_path: &Path) -> Result<Inputs> {
+    let root = fs::canonicalize(manifest_path).await?;
+    let project_dir = fs::canonicalize(project.project_dir()).await?;
+    let sources_dir = project_dir.join("sources");
+    let mut inputs = Inputs {
+        paths: BTreeSet::new(),
+        crates: BTreeSet::new(),
+        fetched: BTreeSet::new(),
+    };
+    let mut has_sources = false;
+    let mut walk = CrateWalk::new([root]);
+    while let Some(krate) = walk.next().await {
+        let krate = krate?;
+        let (dir, toml) = (krate.dir().to_path_buf(), &krate.toml);
+        inputs.paths.insert(dir.clone());
+        inputs.crates.insert(dir.clone());
+        for file in shared_files(toml) {
+            if let Ok(file) = fs::canonicalize(dir.join(file)).await {
+                inputs.paths.insert(file);
+            }
+        }
+        for group in source_groups(toml) {
+            if let Ok(group) = fs::canonicalize(sources_dir.join(group)).await {
+                inputs.paths.insert(group);
+                has_sources = true;
+            }
+        }
+        inputs
+            .fetched
+            .extend(fetched_files(toml).into_iter().map(|file| dir.join(file)));
+        for dependency in &krate.dependencies {
+            if let (Some(dependency_path), None) = (&dependency.path, &dependency.manifest) {
+                bail!(
+                    "Dependency '{}' of '{}' not found at '{}'",
+                    dependency.name,
+                    krate.manifest.display(),
+                    dir.join(dependency_path).display()
+                );
+            }
+        }
+    }
+    let mut lock_files = vec![project_dir.join("Cargo.lock")];
+    if has_sources {
+        lock_files.push(sources_dir.join("Cargo.lock"));
+    }
+    inputs
+        .paths
+        .extend(lock_files.into_iter().filter(|path| path.is_file()));
+    Ok(inputs)
+}
+
+/// Returns a hash of the contents of every file in the [`manifest_inputs`] of the kit with the
+/// manifest at `manifest_path`, along with `context`, which should name anything else that the
+/// build depends on such as the architecture and the digest of the SDK. `target` directories are
+/// skipped.
+pub(crate) async fn inputs_hash(
+    project: &Project,
+    manifest_path: &Path,
+    context: &[&str],
+) -> Result<String> {
+    let project_dir = fs::canonicalize(project.project_dir()).await?;
+    let mut hasher = Sha256::new();
+    for item in context {
+        hasher.update(format!("{}\n", item));
+    }
+    for file in input_files(project, manifest_path).await? {
+        let relative = file.strip_prefix(&project_dir).unwrap_or(&file);
+        let (sha256, _) = hash_file(file.clone()).await?;
+        hasher.update(format!("{}  {}\n", sha256, relative.display()));
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Returns a hash of the path, size and modification time of every file in the
+/// [`manifest_inputs`] of the kit with the manifest at `manifest_path`. This is much cheaper than
+/// [`inputs_hash`] and changes whenever a file is edited, added or removed, so it can be used to
+/// notice changes that might need a rebuild.
+pub(crate) async fn inputs_fingerprint(project: &Project, manifest_path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for file in input_files(project, manifest_path).await? {
+        let metadata = fs::metadata(&file).await?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        hasher.update(format!(
+            "{} {} {}\n",
+            file.display(),
+            metadata.len(),
+            modified.as_nanos()
+        ));
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Returns the files in the [`manifest_inputs`] of the kit with the manifest at `manifest_path`,
+/// sorted. `target` directories and the [`manifest_fetched_files`] are skipped.
+async fn input_files(project: &Project, manifest_path: &Path) -> Result<Vec<PathBuf>> {
+    let inputs = walk_inputs(project, manifest_path).await?;
+    let mut files = Vec::new();
+    for input in inputs.paths {
+        if input.is_file() {
+            files.push(input);
+            continue;
+        }
+        let mut entries = WalkDir::new(&input).filter(|entry| async move {
+            if entry.file_name() == "target" {
+                Filtering::IgnoreDir
+            } else {
+                Filtering::Continue
+            }
+        });
+        while let Some(entry) = entries.next().await {
+            let entry =
+                entry.context(format!("Unable to list the files in '{}'", input.display()))?;
+            if entry.path().is_file() && !inputs.fetched.contains(&entry.path()) {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Returns the kits, in the order given, with an input that is or contains one of the `changed`
+/// paths. `inputs` are the [`inputs`] of each kit.
+pub(crate) fn affected_kits(
+    inputs: &BTreeMap<String, BTreeSet<PathBuf>>,
+    changed: &[PathBuf],
+) -> Vec<String> {
+    inputs
+        .iter()
+        .filter(|(_, inputs)| {
+            changed
+                .iter()
+                .any(|path| inputs.iter().any(|input| path.starts_with(input)))
+        })
+        .map(|(kit, _)| kit.clone())
+        .collect()
+}
+
+/// Reads and parses a `Cargo.toml`, naming the file and the location of any syntax error.
+pub(crate) async fn read_cargo_toml(path: &Path) -> Result<Table> {
+    let data = fs::read_to_string(path).await?;
+    toml::from_str(&data).with_context(|| format!("The manifest '{}' is malformed", path.display()))
+}
+
+/// A crate found by a [`CrateWalk`].
+pub(crate) struct Crate {
+    /// The canonical path of its `Cargo.toml`.
+    pub(crate) manifest: PathBuf,
+    pub(crate) toml: Table,
+    pub(crate) dependencies: Vec<Dependency>,
+}
+
+impl Crate {
+    /// The directory of the crate.
+    pub(crate) fn dir(&self) -> &Path {
+        self.manifest.parent().unwrap_or(Path::new("."))
+    }
+}
+
+/// An entry in the `[dependencies]` or `[build-dependencies]` of a [`Crate`].
+pub(crate) struct Dependency {
+    /// The table that lists it.
+    pub(crate) table: &'static str,
+    pub(crate) name: String,
+    /// The `path` of a path dependency, as written.
+    pub(crate) path: Option<String>,
+    /// The canonical path of the `Cargo.toml` of a path dependency, or `None` if it was not found.
+    pub(crate) manifest: Option<PathBuf>,
+}
+
+/// Visits each crate that can be reached from a set of manifests through `path` dependencies once,
+/// breadth first. The dependency graph, kit validation, kit inputs and `migrate` all find crates
+/// this way.
+pub(crate) struct CrateWalk {
+    queue: VecDeque<PathBuf>,
+    seen: HashSet<PathBuf>,
+}
+
+impl CrateWalk {
+    /// Starts a walk from the `Cargo.toml` files in `manifests`.
+    pub(crate) fn new(manifests: impl IntoIterator<Item = PathBuf>) -> Self {
+        Self {
+            queue: manifests.into_iter().collect(),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Reads the next crate and queues the path dependencies that it has, or returns `None` once
+    /// every crate has been visited. A manifest that cannot be read is an error, but the walk can
+    /// go on past it.
+    pub(crate) async fn next(&mut self) -> Option<Result<Crate>> {
+        loop {
+            let manifest = match fs::canonicalize(self.queue.pop_front()?).await {
+                Ok(manifest) => manifest,
+                Err(e) => return Some(Err(e)),
+            };
+            if self.seen.insert(manifest.clone()) {
+                return Some(self.read(manifest).await);
+            }
+        }
+    }
+
+    async fn read(&mut self, manifest: PathBuf) -> Result<Crate> {
+        let toml = read_cargo_toml(&manifest).await?;
+        let dir = manifest.parent().unwrap_or(Path::new("."));
+        let mut dependencies = Vec::new();
+        for table in ["dependencies", "build-dependencies"] {
+            let Some(entries) = toml.get(table).and_then(Value::as_table) else {
+                continue;
+            };
+            for (name, spec) in entries {
+                let path = spec.get("path").and_then(Value::as_str);
+                let dependency_manifest = match path {
+                    Some(path) => fs::canonicalize(dir.join(path).join("Cargo.toml"))
+                        .await
+                        .ok(),
+                    None => None,
+                };
+                if let Some(dependency_manifest) = &dependency_manifest {
+                    self.queue.push_back(dependency_manifest.clone());
+                }
+                dependencies.push(Dependency {
+                    table,
+                    name: name.clone(),
+                    path: path.map(str::to_string),
+                    manifest: dependency_manifest,
+                });
+            }
+        }
+        Ok(Crate {
+            manifest,
+            toml,
+            dependencies,
+        })
+    }
+}
+
+/// The `[package.metadata.<name>]` table of a parsed `Cargo.toml`.
+pub(crate) fn build_metadata<'a>(toml: &'a Table, name: &str) -> Option<&'a Table> {
+    toml.get("package")?.get("metadata")?.get(name)?.as_table()
+}
+
+/// The build script and library of a crate, which Bottlerocket crates share through paths such as
+/// `../build.rs`.
+pub(crate) fn shared_files(manifest: &Table) -> Vec<&str> {
+    let build = manifest
+        .get("package")
+        .and_then(|package| package.get("build"))
+        .and_then(Value::as_str);
+    let lib = manifest
+        .get("lib")
+        .and_then(|lib| lib.get("path"))
+        .and_then(Value::as_str);
+    build.into_iter().chain(lib).collect()
+}
+
+/// The directories under `sources` that a package is built from.
+pub(crate) fn source_groups(manifest: &Table) -> Vec<&str> {
+    build_metadata(manifest, "build-package")
+        .and_then(|build_package| build_package.get("source-groups"))
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect()
+}
+
+/// The files that buildsys fetches into a package's directory for its `external-files`: each file,
+/// the hidden file that it is downloaded to first, and the bundle of Go modules made from it. They
+/// are named as in buildsys, by `path` or else the last segment of the `url`.
+pub(crate) fn fetched_files(manifest: &Table) -> Vec<String> {
+    let external_files = build_metadata(manifest, "build-package")
+        .and_then(|build_package| build_package.get("external-files"))
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten();
+    let mut files = Vec::new();
+    for external_file in external_files {
+        let name = external_file
+            .get("path")
+            .and_then(Value::as_str)
+            .or_else(|| {
+                let url = external_file.get("url").and_then(Value::as_str)?;
+                url.split(['?', '#']).next()?.rsplit('/').next()
+            });
+        let Some(name) = name.filter(|name| !name.is_empty()) else {
+            continue;
+        };
+        files.push(name.to_string());
+        files.push(format!(".{}", name));
+        if external_file.get("bundle-modules").is_some() {
+            let bundle = external_file
+                .get("bundle-output-path")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("bundled-{}", name));
+            files.push(bundle);
+        }
+    }
+    files
+}
+
+/// The packages of a kit by name, with the `version-release` of each when it is known.
+pub(crate) type KitPackages = BTreeMap<String, Option<String>>;
+
+/// Returns the packages of the RPMs found below `dir`, such as a kit's RPM repository.
+pub(crate) async fn rpm_packages(dir: &Path) -> Result<KitPackages> {
+    let mut packages = KitPackages::new();
+    let mut entries = WalkDir::new(dir);
+    while let Some(entry) = entries.next().await {
+        let entry = entry.context(format!("Unable to list the files in '{}'", dir.display()))?;
+        let filename = entry.file_name().to_string_lossy().to_string();
+        if let Some((name, version)) = parse_rpm_filename(&filename) {
+            packages.insert(name, Some(version));
+        }
+    }
+    Ok(packages)
+}
+
+/// Returns the directories of the packages that the local kit named `name` depends on through
+/// `path` dependencies, i.e. the directories under `packages` among its [`inputs`].
+async fn package_dirs(project: &Project, name: &str) -> Result<Vec<PathBuf>> {
+    let packages_dir = fs::canonicalize(project.project_dir().join("packages")).await?;
+    Ok(inputs(project, name)
+        .await?
+        .into_iter()
+        // The build script and library that packages share are inputs in the same directory.
+        .filter(|input| input.parent() == Some(packages_dir.as_path()) && input.is_dir())
+        .collect())
+}
+
+/// Returns the names of the RPMs that the packages of the local kit named `name` build, as their
+/// spec files declare them, so that they compare with the RPMs of a built kit. Their versions are
+/// only known once the kit is built.
+pub(crate) async fn graph_packages(project: &Project, name: &str) -> Result<KitPackages> {
+    let mut packages = KitPackages::new();
+    for dir in package_dirs(project, name).await? {
+        let mut specs = Vec::new();
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .context(format!("Unable to read directory '{}'", dir.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path().extension().is_some_and(|ext| ext == "spec") {
+                specs.push(entry.path());
+            }
+        }
+        ensure!(
+            !specs.is_empty(),
+            "Unable to find the spec file of package '{}'",
+            dir.display()
+        );
+        for spec in specs {
+            let spec = fs::read_to_string(&spec).await?;
+            packages.extend(spec_rpm_names(&spec).into_iter().map(|name| (name, None)));
+        }
+    }
+    Ok(packages)
+}
+
+/// Returns the names of the RPMs that a spec file declares with its `Name:` and `%package` lines,
+/// e.g. `bottlerocket-glibc` and `bottlerocket-glibc-devel`. Subpackages that are only built under
+/// some conditions are included.
+fn spec_rpm_names(spec: &str) -> Vec<String> {
+    let mut name = None;
+    let mut names = Vec::new();
+    for line in spec.lines().map(str::trim) {
+        if let Some(value) = line.strip_prefix("Name:") {
+            let value = expand_spec_macros(value.trim(), None);
+            names.push(value.clone());
+            name = Some(value);
+        } else if let Some(args) = line.strip_prefix("%package ") {
+            let args: Vec<_> = args.split_whitespace().collect();
+            let subpackage = match (args.as_slice(), &name) {
+                (["-n", full_name, ..], _) => expand_spec_macros(full_name, name.as_deref()),
+                ([suffix, ..], Some(name)) => {
+                    format!("{}-{}", name, expand_spec_macros(suffix, Some(name)))
+                }
+                _ => continue,
+            };
+            names.push(subpackage);
+        }
+    }
+    names
+}
+
+/// Expands the macros that the names of Bottlerocket packages are built from.
+fn expand_spec_macros(value: &str, name: Option<&str>) -> String {
+    let value = value.replace("%{_cross_os}", "bottlerocket-");
+    match name {
+        Some(name) => value.replace("%{name}", name),
+        None => value,
+    }
+}
+
+/// Splits an RPM filename such as `kernel-6.1-6.1.90-1.x86_64.rpm` into the package name and its
+/// `version-release`, here `kernel-6.1` and `6.1.90-1`.
+fn parse_rpm_filename(filename: &str) -> Option<(String, String)> {
+    let (nvr, _arch) = filename.strip_suffix(".rpm")?.rsplit_once('.')?;
+    let mut parts = nvr.rsplitn(3, '-');
+    let release = parts.next()?;
+    let version = parts.next()?;
+    let name = parts.next().filter(|name| !name.is_empty())?;
+    Some((name.to_string(), format!("{}-{}", version, release)))
+}
+
+/// The packages that differ between two versions of a kit.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct KitDiff {
+    pub(crate) added: Vec<PackageChange>,
+    pub(crate) removed: Vec<PackageChange>,
+    pub(crate) changed: Vec<PackageChange>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PackageChange {
+    pub(crate) name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) old_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) new_version: Option<String>,
+}
+
+impl KitDiff {
+    /// Compares the packages of the `old` kit with those of the `new` one. A package has only
+    /// changed if both of its versions are known and they differ.
+    pub(crate) fn new(old: &KitPackages, new: &KitPackages) -> Self {
+        let mut diff = Self::default();
+        for (name, new_version) in new {
+            let change = |old_version: Option<String>| PackageChange {
+                name: name.clone(),
+                old_version,
+                new_version: new_version.clone(),
+            };
+            match old.get(name) {
+                None => diff.added.push(change(None)),
+                Some(Some(old_version)) => {
+                    if new_version.as_ref().is_some_and(|new| new != old_version) {
+                        diff.changed.push(change(Some(old_version.clone())))
+                    }
+                }
+                Some(None) => {}
+            }
+        }
+        for (name, old_version) in old {
+            if !new.contains_key(name) {
+                diff.removed.push(PackageChange {
+                    name: name.clone(),
+                    old_version: old_version.clone(),
+                    new_version: None,
+                });
+            }
+        }
+        diff
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl Display for KitDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let version = |version: &Option<String>| version.clone().unwrap_or_else(|| "?".into());
+        if !self.added.is_empty() {
+            writeln!(f, "Added:")?;
+            for change in &self.added {
+                writeln!(f, "  {} {}", change.name, version(&change.new_version))?;
+            }
+        }
+        if !self.removed.is_empty() {
+            writeln!(f, "Removed:")?;
+            for change in &self.removed {
+                writeln!(f, "  {} {}", change.name, version(&change.old_version))?;
+            }
+        }
+        if !self.changed.is_empty() {
+            writeln!(f, "Changed:")?;
+            for change in &self.changed {
+                writeln!(
+                    f,
+                    "  {} {} -> {}",
+                    change.name,
+                    version(&change.old_version),
+                    version(&change.new_version)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::copy_project_to_temp_dir;
+
+    #[tokio::test]
+    async fn test_load_malformed_manifest() {
+        let temp_dir = copy_project_to_temp_dir("local-kit").await;
+        let project = Project::load(temp_dir.path().join("Twoliter.toml"))
+            .await
+            .unwrap();
+        let manifest = KitManifest::load(&project, "core-kit").await.unwrap();
+        assert!(manifest.build_kit().is_some());
+
+        let path = KitManifest::path_for(&project, "core-kit");
+        fs::write(&path, "[package]\nname = \"core-kit\"\nversion = 0.1.0\n")
+            .await
+            .unwrap();
+        let err = format!(
+            "{:?}",
+            KitManifest::load(&project, "core-kit").await.unwrap_err()
+        );
+        assert!(err.contains(&path.display().to_string()), "{}", err);
+        assert!(err.contains("line 3"), "{}", err);
+
+        fs::write(&path, "[package]\nname = \"core-kit\"\n")
+            .await
+            .unwrap();
+        let err = KitManifest::load(&project, "core-kit").await.unwrap_err();
+        assert!(err.to_string().contains("[package.metadata.build-kit]"));
+
+        let err = KitManifest::load(&project, "no-such-kit")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Unable to find"));
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let temp_dir = copy_project_to_temp_dir("local-kit").await;
+        let project_dir = temp_dir.path();
+        let project = Project::load(project_dir.join("Twoliter.toml"))
+            .await
+            .unwrap();
+        assert_eq!(
+            validate(&project, "extra-3-kit").await.unwrap(),
+            Vec::<String>::new()
+        );
+
+        // Break the kit in three different places and expect to hear about all of them.
+        let edit = |path: PathBuf, from: &'static str, to: &'static str| async move {
+            let data = fs::read_to_string(&path).await.unwrap();
+            assert!(data.contains(from));
+            fs::write(&path, data.replace(from, to)).await.unwrap();
+        };
+        edit(
+            project_dir.join("kits/extra-1-kit/Cargo.toml"),
+            "vendor = \"bottlerocket\"",
+            "",
+        )
+        .await;
+        edit(
+            project_dir.join("packages/pkg-e/Cargo.toml"),
+            "source-groups = []",
+            "package-features = [\"fips\", \"fipps\"]",
+        )
+        .await;
+        edit(
+            project_dir.join("kits/extra-3-kit/Cargo.toml"),
+            "../../packages/pkg-g",
+            "../../packages/pkg-z",
+        )
+        .await;
+
+        let problems = validate(&project, "extra-3-kit").await.unwrap();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("'extra-1-kit' has no 'vendor'")));
+        assert!(problems.iter().any(|p| p.contains("'fipps'")));
+        assert!(problems.iter().any(|p| p.contains("'pkg-g' not found")));
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        let temp_dir = copy_project_to_temp_dir("local-kit").await;
+        let project = Project::load(temp_dir.path().join("Twoliter.toml"))
+            .await
+            .unwrap();
+        let broken = temp_dir.path().join("kits/broken-kit");
+        fs::create_dir_all(&broken).await.unwrap();
+        fs::write(
+            broken.join("Cargo.toml"),
+            "[package]\nname = \"broken-kit\"\n",
+        )
+        .await
+        .unwrap();
+
+        let kits = list(&project).await.unwrap();
+        let names: Vec<_> = kits.iter().map(|kit| kit.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["core-kit", "extra-1-kit", "extra-2-kit", "extra-3-kit"]
+        );
+        assert_eq!(
+            kits[0],
+            KitListing {
+                name: "core-kit".to_string(),
+                version: "0.1.0".to_string(),
+                vendor: Some("bottlerocket".to_string()),
+                packages: 1,
+            }
+        );
+        assert_eq!(kits[3].packages, 7);
+    }
+
+    #[tokio::test]
+    async fn test_bump() {
+        let temp_dir = copy_project_to_temp_dir("local-kit").await;
+        let project = Project::load(temp_dir.path().join("Twoliter.toml"))
+            .await
+            .unwrap();
+        assert_eq!(
+            local_kits(&project).await.unwrap(),
+            ["core-kit", "extra-1-kit", "extra-2-kit", "extra-3-kit"]
+        );
+
+        let path = KitManifest::path_for(&project, "extra-3-kit");
+        let before = fs::read_to_string(&path).await.unwrap();
+        let (old, new) = bump(&project, "extra-3-kit", &VersionBump::Minor)
+            .await
+            .unwrap();
+        assert_eq!(
+            (old.to_string(), new.to_string()),
+            ("0.1.0".into(), "0.2.0".into())
+        );
+
+        // Only the version changes, comments and layout are preserved.
+        let after = fs::read_to_string(&path).await.unwrap();
+        assert_eq!(
+            after,
+            before.replace("version = \"0.1.0\"", "version = \"0.2.0\"")
+        );
+
+        let (_, new) = bump(&project, "extra-3-kit", &VersionBump::Major)
+            .await
+            .unwrap();
+        assert_eq!(new, Version::new(1, 0, 0));
+        let (_, new) = bump(&project, "extra-3-kit", &VersionBump::Patch)
+            .await
+            .unwrap();
+        assert_eq!(new, Version::new(1, 0, 1));
+        let (_, new) = bump(
+            &project,
+            "extra-3-kit",
+            &VersionBump::Set(Version::new(0, 9, 0)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(new, Version::new(0, 9, 0));
+    }
+
+    #[tokio::test]
+    async fn test_affected_kits() {
+        let temp_dir = copy_project_to_temp_dir("local-kit").await;
+        let project = Project::load(temp_dir.path().join("Twoliter.toml"))
+            .await
+            .unwrap();
+        let mut all_inputs = BTreeMap::new();
+        for kit in local_kits(&project).await.unwrap() {
+            let kit_inputs = inputs(&project, &kit).await.unwrap();
+            all_inputs.insert(kit, kit_inputs);
+        }
+        let project_dir = project.project_dir();
+        let affected = |paths: &[&str]| {
+            let changed: Vec<_> = paths.iter().map(|path| project_dir.join(path)).collect();
+            affected_kits(&all_inputs, &changed)
+        };
+
+        assert_eq!(
+            affected(&["packages/pkg-c/pkg-c.spec"]),
+            ["extra-2-kit", "extra-3-kit"]
+        );
+        // pkg-f is only reached through pkg-g.
+        assert_eq!(affected(&["packages/pkg-f/Cargo.toml"]), ["extra-3-kit"]);
+        assert_eq!(
+            affected(&["packages/pkg-a-1.27", "README.md"]),
+            ["core-kit", "extra-1-kit", "extra-2-kit", "extra-3-kit"]
+        );
+        assert_eq!(affected(&["packages/packages.rs"]).len(), 4);
+        assert!(affected(&["sources/hello-go/main.go", "README.md"]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_inputs_hash() {
+        let temp_dir = copy_project_to_temp_dir("local-kit").await;
+        let project = Project::load(temp_dir.path().join("Twoliter.toml"))
+            .await
+            .unwrap();
+        let hash = |context: &'static [&'static str]| {
+            let project = project.clone();
+            let manifest_path = KitManifest::path_for(&project, "extra-2-kit");
+            async move {
+                inputs_hash(&project, &manifest_path, context)
+                    .await
+                    .unwrap()
+            }
+        };
+        let before = hash(&["x86_64", "sha256:abc"]).await;
+        assert_eq!(before, hash(&["x86_64", "sha256:abc"]).await);
+        assert_ne!(before, hash(&["aarch64", "sha256:abc"]).await);
+
+        // A package that the kit does not use can change without affecting the hash.
+        let project_dir = project.project_dir();
+        fs::write(project_dir.join("packages/pkg-b/pkg-b.spec"), "changed")
+            .await
+            .unwrap();
+        fs::create_dir_all(project_dir.join("packages/pkg-c/target"))
+            .await
+            .unwrap();
+        fs::write(project_dir.join("packages/pkg-c/target/out"), "")
+            .await
+            .unwrap();
+        assert_eq!(before, hash(&["x86_64", "sha256:abc"]).await);
+
+        // Neither do the files that buildsys fetches for a package that the kit uses.
+        let manifest = project_dir.join("packages/pkg-c/Cargo.toml");
+        let data = fs::read_to_string(&manifest).await.unwrap().replace(
+            "source-groups = []",
+            "source-groups = []\n\n[[package.metadata.build-package.external-files]]\n\
+            url = \"https://example.com/pkg-c-1.0.tar.gz?download\"\n\
+            sha512 = \"abc\"\n",
+        );
+        fs::write(&manifest, data).await.unwrap();
+        let before = hash(&["x86_64", "sha256:abc"]).await;
+        fs::write(
+            project_dir.join("packages/pkg-c/pkg-c-1.0.tar.gz"),
+            "fetched",
+        )
+        .await
+        .unwrap();
+        fs::write(
+            project_dir.join("packages/pkg-c/.pkg-c-1.0.tar.gz"),
+            "partial",
+        )
+        .await
+        .unwrap();
+        assert_eq!(before, hash(&["x86_64", "sha256:abc"]).await);
+
+        fs::write(project_dir.join("Cargo.lock"), "changed")
+            .await
+            .unwrap();
+        let after_lock = hash(&["x86_64", "sha256:abc"]).await;
+        assert_ne!(before, after_lock);
+
+        fs::write(project_dir.join("packages/pkg-c/pkg-c.spec"), "changed")
+            .await
+            .unwrap();
+        assert_ne!(after_lock, hash(&["x86_64", "sha256:abc"]).await);
+    }
+
+    #[test]
+    fn test_fetched_files() {
+        let manifest: Table = toml::from_str(
+            r#"
+            [[package.metadata.build-package.external-files]]
+            url = "https://example.com/a/src-1.0.tar.gz"
+            sha512 = "abc"
+
+            [[package.metadata.build-package.external-files]]
+            path = "renamed.tar.xz"
+            url = "https://example.com/b"
+            sha512 = "abc"
+            bundle-modules = ["go"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            fetched_files(&manifest),
+            [
+                "src-1.0.tar.gz",
+                ".src-1.0.tar.gz",
+                "renamed.tar.xz",
+                ".renamed.tar.xz",
+                "bundled-renamed.tar.xz"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inputs_fingerprint() {
+        let temp_dir = copy_project_to_temp_dir("local-kit").await;
+        let project = Project::load(temp_dir.path().join("Twoliter.toml"))
+            .await
+            .unwrap();
+        let manifest_path = KitManifest::path_for(&project, "extra-2-kit");
+        let before = inputs_fingerprint(&project, &manifest_path).await.unwrap();
+        assert_eq!(
+            before,
+            inputs_fingerprint(&project, &manifest_path).await.unwrap()
+        );
+
+        let project_dir = project.project_dir();
+        fs::write(project_dir.join("packages/pkg-b/pkg-b.spec"), "changed")
+            .await
+            .unwrap();
+        assert_eq!(
+            before,
+            inputs_fingerprint(&project, &manifest_path).await.unwrap()
+        );
+
+        fs::write(
+            project_dir.join("packages/pkg-c/pkg-c.spec"),
+            "a change in length",
+        )
+        .await
+        .unwrap();
+        assert_ne!(
+            before,
+            inputs_fingerprint(&project, &manifest_path).await.unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_rpm_filename() {
+        assert_eq!(
+            parse_rpm_filename("bottlerocket-kernel-6.1-6.1.90-1.x86_64.rpm"),
+            Some((
+                "bottlerocket-kernel-6.1".to_string(),
+                "6.1.90-1".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_rpm_filename("bottlerocket-glibc-2.38-1.1715894283.cbd2d3a8.br1.noarch.rpm"),
+            Some((
+                "bottlerocket-glibc".to_string(),
+                "2.38-1.1715894283.cbd2d3a8.br1".to_string()
+            ))
+        );
+        assert_eq!(parse_rpm_filename("repomd.xml"), None);
+        assert_eq!(parse_rpm_filename("1.0-1.x86_64.rpm"), None);
+    }
+
+    #[tokio::test]
+    async fn test_graph_packages() {
+        let temp_dir = copy_project_to_temp_dir("local-kit").await;
+        let project = Project::load(temp_dir.path().join("Twoliter.toml"))
+            .await
+            .unwrap();
+        // The package in `packages/pkg-a-1.27` builds the RPM `bottlerocket-pkg-a`.
+        let packages = graph_packages(&project, "core-kit").await.unwrap();
+        assert_eq!(
+            packages,
+            KitPackages::from([("bottlerocket-pkg-a".to_string(), None)])
+        );
+    }
+
+    #[test]
+    fn test_spec_rpm_names() {
+        let spec = "\
+Name: %{_cross_os}glibc
+Version: 2.38
+
+%package devel
+Summary: Headers
+
+%package -n %{_cross_os}nss-%{name}
+Summary: NSS
+
+%description devel
+";
+        assert_eq!(
+            spec_rpm_names(spec),
+            [
+                "bottlerocket-glibc",
+                "bottlerocket-glibc-devel",
+                "bottlerocket-nss-bottlerocket-glibc",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_kit_diff() {
+        let packages = |list: &[(&str, Option<&str>)]| -> KitPackages {
+            list.iter()
+                .map(|(name, version)| (name.to_string(), version.map(str::to_string)))
+                .collect()
+        };
+        let old = packages(&[
+            ("acpid", Some("2.0.34-1")),
+            ("glibc", Some("2.38-1")),
+            ("kernel", Some("6.1.90-1")),
+        ]);
+        let new = packages(&[
+            ("acpid", Some("2.0.34-1")),
+            ("glibc", Some("2.39-1")),
+            ("iptables", Some("1.8.10-1")),
+        ]);
+        let diff = KitDiff::new(&old, &new);
+        let names = |changes: &[PackageChange]| -> Vec<String> {
+            changes.iter().map(|change| change.name.clone()).collect()
+        };
+        assert_eq!(names(&diff.added), ["iptables"]);
+        assert_eq!(names(&diff.removed), ["kernel"]);
+        assert_eq!(names(&diff.changed), ["glibc"]);
+        assert_eq!(
+            diff.to_string(),
+            "Added:\n  iptables 1.8.10-1\nRemoved:\n  kernel 6.1.90-1\nChanged:\n  glibc 2.38-1 -> 2.39-1\n"
+        );
+
+        // Unbuilt packages have no version, so they can be added or removed but not changed.
+        let unbuilt = packages(&[("acpid", None), ("glibc", None)]);
+        let diff = KitDiff::new(&old, &unbuilt);
+        assert!(diff.added.is_empty() && diff.changed.is_empty());
+        assert_eq!(names(&diff.removed), ["kernel"]);
+        assert!(KitDiff::new(&old, &old).is_empty());
+    }
+}
@@ -1,16 +1,19 @@
 use crate::common::fs::{create_dir_all, read, remove_dir_all, remove_file, write};
+use crate::cosign::remote_digest;
+use crate::docker::{docker, docker_noisy, pull, ImageInspector, ImageUri};
 use crate::project::{Image, Project, ValidIdentifier, Vendor};
 use crate::schema_version::SchemaVersion;
 use anyhow::{ensure, Context, Result};
 use base64::Engine;
 use buildsys_config::DockerArchitecture;
+use log::warn;
 use olpc_cjson::CanonicalFormatter as CanonicalJsonFormatter;
 use semver::Version;
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize};
 use sha2::Digest;
 use std::cmp::PartialEq;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::hash::{Hash, Hasher};
@@ -19,36 +22,20 @@ use std::path::{Path, PathBuf};
 use tar::Archive as TarArchive;
 use tempfile::TempDir;
 use tokio::fs::read_to_string;
-use tokio::process::Command;
 
-const TWOLITER_LOCK: &str = "Twoliter.lock";
+pub(crate) const TWOLITER_LOCK: &str = "Twoliter.lock";
 
-macro_rules! docker {
-    ($arg: expr, $error_msg: expr) => {{
-        let output = Command::new("docker")
-            .args($arg)
-            .output()
-            .await
-            .context($error_msg)?;
-        ensure!(
-            output.status.success(),
-            "docker failed to run operation: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
-        output.stdout
-    }};
-}
+/// Holds the [`GoModulesChecked`] from when the go modules in `Twoliter.lock` were last checked. It
+/// depends on file times, which differ between checkouts, so it is kept out of the lock.
+const GO_MODULES_CHECKED: &str = "build/go-modules.json";
 
-macro_rules! docker_noisy {
-    ($arg: expr, $error_msg: expr) => {{
-        Command::new("docker")
-            .args($arg)
-            .spawn()
-            .context($error_msg)?
-            .wait()
-            .await
-            .context("docker failed to run operation")?;
-    }};
+/// Where the go modules in `Twoliter.lock` were found, and the [`Project::sources_fingerprint`] of
+/// those directories when they were.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct GoModulesChecked {
+    /// The directories of the go modules, relative to the project.
+    dirs: Vec<PathBuf>,
+    fingerprint: String,
 }
 
 /// Represents a locked dependency on an image
@@ -71,24 +58,113 @@ pub(crate) struct LockedImage {
 impl LockedImage {
     pub async fn new(vendor: &Vendor, image: &Image) -> Result<Self> {
         let source = format!("{}/{}:v{}", vendor.registry, image.name, image.version);
-        let manifest_bytes = docker!(
+        Self::resolve(
+            image.name.to_string(),
+            image.version.clone(),
+            image.vendor.to_string(),
+            source,
+        )
+        .await
+    }
+
+    /// Locks the image at `source`, which is tagged `v<version>`, to the manifest list that the
+    /// tag refers to now.
+    pub(crate) async fn resolve(
+        name: String,
+        version: Version,
+        vendor: String,
+        source: String,
+    ) -> Result<Self> {
+        let manifest_bytes = docker(
             ["manifest", "inspect", source.as_str()],
-            format!("failed to inspect manifest of resource at {}", source)
-        );
+            format!("failed to inspect manifest of resource at {}", source),
+        )
+        .await?;
 
         // We calculate a 'digest' of the manifest to use as our unique id
-        let digest = sha2::Sha256::digest(manifest_bytes.as_slice());
-        let digest = base64::engine::general_purpose::STANDARD.encode(digest.as_slice());
+        let digest = manifest_digest(&manifest_bytes);
         Ok(Self {
-            name: image.name.to_string(),
-            version: image.version.clone(),
-            vendor: image.vendor.to_string(),
+            name,
+            version,
+            vendor,
             source,
             digest,
             manifest: manifest_bytes,
         })
     }
 
+    /// The same image from `registry` instead of its vendor's registry, e.g. a mirror or a staging
+    /// registry.
+    pub(crate) fn with_registry(&self, registry: &str) -> Self {
+        Self {
+            source: format!(
+                "{}/{}:v{}",
+                registry.trim_end_matches('/'),
+                self.name,
+                self.version
+            ),
+            ..self.clone()
+        }
+    }
+
+    /// Errors unless `manifest_bytes`, from `docker manifest inspect` of the source, are the
+    /// manifest that this image was locked to. A tag that was moved in the registry since
+    /// Twoliter.lock was written no longer matches.
+    pub(crate) fn verify_manifest(&self, manifest_bytes: &[u8]) -> Result<()> {
+        ensure!(
+            manifest_digest(manifest_bytes) == self.digest,
+            "The manifest of {} does not match the digest in Twoliter.lock, the tag may have been \
+            moved to a different image. Run 'twoliter update' if the new image is intended.",
+            self.source
+        );
+        Ok(())
+    }
+
+    /// Reads the metadata of a kit from the label on the image for its first architecture. It is an
+    /// error if the image is not a kit.
+    pub(crate) async fn kit_metadata(&self) -> Result<ImageMetadata> {
+        let manifest_list: ManifestListView = serde_json::from_slice(self.manifest.as_slice())
+            .context("failed to deserialize manifest list")?;
+        let manifest = manifest_list.manifests.first().context(format!(
+            "kit image at {} does not have an architecture image",
+            self.source
+        ))?;
+        let image_uri = self.digest_uri(&manifest.digest);
+        pull(&ImageUri::parse(&image_uri)?).await.context(format!(
+            "failed to pull image for {} with digest {}",
+            self, manifest.digest
+        ))?;
+        // Now we want to fetch the metadata from the OCI image config
+        let label_bytes = docker(
+            [
+                "image",
+                "inspect",
+                image_uri.as_str(),
+                "--format",
+                "\"{{ json .Config.Labels }}\"",
+            ],
+            format!(
+                "failed to fetch kit metadata for {} with digest {}",
+                self, manifest.digest
+            ),
+        )
+        .await?;
+        let label_str = String::from_utf8_lossy(label_bytes.as_slice()).to_string();
+        let label_str = label_str.trim().trim_matches('"');
+        let labels: HashMap<String, String> = serde_json::from_str(label_str).context(format!(
+            "could not deserialize labels on the image for {}",
+            self
+        ))?;
+        let encoded = labels
+            .get("dev.bottlerocket.kit.v1")
+            .context("no metadata stored on image, this image appears to not be a kit")?;
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded.as_str())
+            .context("malformed kit metadata detected")?;
+
+        serde_json::from_slice(decoded.as_slice()).context("malformed kit metadata json")
+    }
+
     pub fn digest_uri(&self, digest: &str) -> String {
         self.source.replace(
             format!(":v{}", self.version).as_str(),
@@ -106,6 +182,12 @@ impl Display for LockedImage {
     }
 }
 
+/// The digest that [`LockedImage`] records for the manifest list from `docker manifest inspect`.
+fn manifest_digest(manifest_bytes: &[u8]) -> String {
+    let digest = sha2::Sha256::digest(manifest_bytes);
+    base64::engine::general_purpose::STANDARD.encode(digest.as_slice())
+}
+
 /// The hash should not contain the source to allow for collision detection
 impl Hash for LockedImage {
     fn hash<H: Hasher>(&self, state: &mut H) {
@@ -115,13 +197,12 @@ impl Hash for LockedImage {
     }
 }
 
+/// The metadata that a kit image carries in its `dev.bottlerocket.kit.v1` label.
 #[derive(Deserialize, Debug)]
-struct ImageMetadata {
+pub(crate) struct ImageMetadata {
     /// The name of the kit
-    #[allow(dead_code)]
     pub name: String,
     /// The version of the kit
-    #[allow(dead_code)]
     pub version: Version,
     /// The required sdk of the kit,
     pub sdk: Image,
@@ -222,18 +303,18 @@ impl OCIArchive {
         if !oci_archive_path.exists() {
             let oci_archive_str = oci_archive_path.to_string_lossy();
             // First use docker pull to let the daemon cache individual blobs
-            docker_noisy!(
-                ["pull", digest_uri.as_str()],
-                format!("failed to fetch kit from {}", digest_uri)
-            );
+            pull(&ImageUri::parse(&digest_uri)?)
+                .await
+                .context(format!("failed to fetch kit from {}", digest_uri))?;
             // Save the image out to disk
-            docker_noisy!(
+            docker_noisy(
                 ["save", digest_uri.as_str(), "-o", oci_archive_str.as_ref()],
                 format!(
                     "failed to save to disk from {} to {}",
                     digest_uri, oci_archive_str
-                )
-            );
+                ),
+            )
+            .await?;
         }
         Ok(())
     }
@@ -307,6 +388,72 @@ impl OCIArchive {
     }
 }
 
+/// An image that `twoliter fetch` made available locally.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct FetchedImage {
+    /// The image as it is named in the lock file
+    pub(crate) uri: String,
+    /// The digest that the image resolved to, e.g. `sha256:...`
+    pub(crate) digest: String,
+}
+
+impl Display for FetchedImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.uri, self.digest)
+    }
+}
+
+/// Pulls the image `uri` for `arch`.
+pub(crate) async fn pull_for_arch(uri: &str, arch: &str, images: &ImageInspector) -> Result<()> {
+    let platform = format!("linux/{}", DockerArchitecture::try_from(arch)?);
+    docker_noisy(
+        ["pull", "--platform", platform.as_str(), uri],
+        format!("failed to pull {}", uri),
+    )
+    .await?;
+    images.invalidate(uri);
+    Ok(())
+}
+
+/// Finds the digest of `source` in the JSON list of repo digests from `docker image inspect`, e.g.
+/// `["public.ecr.aws/bottlerocket/bottlerocket-sdk@sha256:..."]`.
+fn repo_digest(source: &str, repo_digests: &[u8]) -> Result<String> {
+    let repo_digests: Vec<String> = serde_json::from_slice(repo_digests)
+        .context(format!("failed to parse the repo digests of {}", source))?;
+    // Drop the tag, taking care not to mistake a registry port for one.
+    let repo = match source.rsplit_once(':') {
+        Some((repo, tag)) if !tag.contains('/') => repo,
+        _ => source,
+    };
+    repo_digests
+        .iter()
+        .find_map(|repo_digest| {
+            repo_digest
+                .split_once('@')
+                .filter(|(name, _)| *name == repo)
+                .map(|(_, digest)| digest.to_string())
+        })
+        .context(format!("no digest was found for {}", source))
+}
+
+/// The digest of the local image `source`: the repo digest that identifies it in its registry, or
+/// else the ID of an image that was never pulled, e.g. an SDK that was built locally.
+pub(crate) async fn local_digest(source: &str, images: &ImageInspector) -> Result<String> {
+    let image = images
+        .inspect(source)
+        .await?
+        .context(format!("the image {} is not present locally", source))?;
+    let repo_digests = serde_json::to_vec(&image["RepoDigests"])
+        .context(format!("failed to read the repo digests of {}", source))?;
+    match repo_digest(source, &repo_digests) {
+        Ok(digest) => Ok(digest),
+        Err(_) => image["Id"]
+            .as_str()
+            .map(str::to_string)
+            .context(format!("no digest was found for {}", source)),
+    }
+}
+
 /// Represents the structure of a `Twoliter.lock` lock file.
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
@@ -321,6 +468,13 @@ pub(crate) struct Lock {
     pub kit: Vec<LockedImage>,
     /// sha256 digest of the Project this was generated from
     pub digest: String,
+    /// The go modules found in the project's `sources` directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub go_modules: Option<Vec<String>>,
+    /// The host container images of each variant by name, pinned to the digest that their tag
+    /// referred to when the lock was resolved
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub host_containers: BTreeMap<String, BTreeMap<String, String>>,
 }
 
 #[allow(dead_code)]
@@ -328,18 +482,77 @@ impl Lock {
     pub(crate) async fn load(project: &Project) -> Result<Self> {
         let lock_file_path = project.project_dir().join(TWOLITER_LOCK);
         if lock_file_path.exists() {
-            let lock_str = read_to_string(&lock_file_path)
-                .await
-                .context("failed to read lockfile")?;
-            let lock: Self =
-                toml::from_str(lock_str.as_str()).context("failed to deserialize lockfile")?;
-            // The digests must match, if changes are needed twoliter
-            ensure!(lock.digest == project.digest()?, "changes have occurred to Twoliter.toml that require an update to Twoliter.lock, if intentional please run twoliter update");
-            return Ok(lock);
+            return Self::load_existing(project).await;
         }
         Self::create(project).await
     }
 
+    /// The lock with the SDK and every kit taken from `registry`. This is only for the current run
+    /// and is never written to `Twoliter.lock`, whose digest does not depend on the registries.
+    pub(crate) fn with_registry(self, registry: &str) -> Self {
+        Self {
+            sdk: self.sdk.with_registry(registry),
+            kit: self
+                .kit
+                .iter()
+                .map(|kit| kit.with_registry(registry))
+                .collect(),
+            ..self
+        }
+    }
+
+    /// The lock with the image `source` in place of the SDK, e.g. an SDK that was built locally and
+    /// is in no registry. `digest` identifies the image instead of the digest of its manifest.
+    pub(crate) fn with_sdk(self, source: &str, digest: &str) -> Self {
+        Self {
+            sdk: LockedImage {
+                source: source.to_string(),
+                digest: digest.to_string(),
+                manifest: Vec::new(),
+                ..self.sdk
+            },
+            ..self
+        }
+    }
+
+    /// Loads `Twoliter.lock` without resolving it (i.e. without any docker calls). It is an error
+    /// if the lock file does not exist.
+    pub(crate) async fn load_existing(project: &Project) -> Result<Self> {
+        let lock_file_path = project.project_dir().join(TWOLITER_LOCK);
+        ensure!(
+            lock_file_path.exists(),
+            "{} does not exist, please run twoliter update",
+            lock_file_path.display()
+        );
+        let lock_str = read_to_string(&lock_file_path)
+            .await
+            .context("failed to read lockfile")?;
+        let lock: Self =
+            toml::from_str(lock_str.as_str()).context("failed to deserialize lockfile")?;
+        // The digests must match, if changes are needed twoliter
+        ensure!(lock.digest == project.digest()?, "changes have occurred to Twoliter.toml that require an update to Twoliter.lock, if intentional please run twoliter update");
+        Ok(lock.with_vendor_registries(project))
+    }
+
+    /// The lock with each image taken from the registry that its vendor has in `project` now. A
+    /// registry that refers to environment variables is left out of the digest of the project, so
+    /// the registry recorded when Twoliter.lock was written may not be the one for this environment.
+    /// The images are still pinned by their digests.
+    fn with_vendor_registries(self, project: &Project) -> Self {
+        let vendor_registry = |image: &LockedImage| {
+            let vendor = project.vendor().get(&ValidIdentifier(image.vendor.clone()));
+            match vendor {
+                Some(vendor) => image.with_registry(&vendor.registry),
+                None => image.clone(),
+            }
+        };
+        Self {
+            sdk: vendor_registry(&self.sdk),
+            kit: self.kit.iter().map(vendor_registry).collect(),
+            ..self
+        }
+    }
+
     pub(crate) async fn create(project: &Project) -> Result<Self> {
         let lock_file_path = project.project_dir().join(TWOLITER_LOCK);
         if lock_file_path.exists() {
@@ -353,6 +566,100 @@ impl Lock {
         Ok(lock)
     }
 
+    /// Returns the go modules in the project's `sources` directory. The list in `Twoliter.lock` is
+    /// used unless `sources` or the modules found last time have changed since it was checked, see
+    /// [`Project::sources_fingerprint`], or `refresh` is set, in which case the modules are
+    /// searched for again. Builds never rewrite
+    /// `Twoliter.lock`, so if the modules found differ from the lock they are used with a warning to
+    /// run `twoliter update`, or it is an error when `frozen`.
+    pub(crate) async fn go_modules(
+        &self,
+        project: &Project,
+        refresh: bool,
+        frozen: bool,
+    ) -> Result<Vec<String>> {
+        let checked_path = project.project_dir().join(GO_MODULES_CHECKED);
+        if let Some(go_modules) = self.go_modules.as_ref().filter(|_| !refresh) {
+            let checked: GoModulesChecked = read_to_string(&checked_path)
+                .await
+                .ok()
+                .and_then(|checked| serde_json::from_str(&checked).ok())
+                .unwrap_or_default();
+            if checked.fingerprint == project.sources_fingerprint(&checked.dirs).await? {
+                return Ok(go_modules.clone());
+            }
+        }
+
+        let found = project.find_go_module_dirs().await?;
+        let go_modules: Vec<String> = found.keys().cloned().collect();
+        if self.go_modules.as_ref() != Some(&go_modules) {
+            ensure!(
+                !frozen,
+                "The go modules in {} are out of date, please run twoliter update or build \
+                without --frozen",
+                TWOLITER_LOCK
+            );
+            warn!(
+                "The go modules in {} are out of date, please run twoliter update to record {:?}",
+                TWOLITER_LOCK, go_modules
+            );
+            // Leave the fingerprint alone so that the modules are checked again until the lock is
+            // updated.
+            return Ok(go_modules);
+        }
+        let dirs: Vec<PathBuf> = found.into_values().collect();
+        let checked = GoModulesChecked {
+            fingerprint: project.sources_fingerprint(&dirs).await?,
+            dirs,
+        };
+        if let Some(parent) = checked_path.parent() {
+            create_dir_all(parent).await?;
+        }
+        write(&checked_path, serde_json::to_string(&checked)?).await?;
+        Ok(go_modules)
+    }
+
+    /// The host container images of `variant` by name, with the digests that they are pinned to
+    /// in `Twoliter.lock`. An image that the lock has no digest for, e.g. because the lock was
+    /// written by an older Twoliter, is used as it is in Twoliter.toml.
+    pub(crate) fn host_containers(
+        &self,
+        project: &Project,
+        variant: &str,
+    ) -> Result<BTreeMap<String, String>> {
+        let pinned = self.host_containers.get(variant);
+        Ok(project
+            .variant(variant)
+            .host_containers()?
+            .into_iter()
+            .map(|(name, uri)| {
+                let image = pinned
+                    .and_then(|pinned| pinned.get(&name))
+                    .cloned()
+                    .unwrap_or_else(|| uri.to_string());
+                (name, image)
+            })
+            .collect())
+    }
+
+    /// The value of `BUILDSYS_HOST_CONTAINERS`, space-separated `name=image` pairs, if `variant`
+    /// has any host containers.
+    pub(crate) fn host_containers_env(
+        &self,
+        project: &Project,
+        variant: &str,
+    ) -> Result<Option<String>> {
+        let containers = self.host_containers(project, variant)?;
+        if containers.is_empty() {
+            return Ok(None);
+        }
+        let pairs: Vec<_> = containers
+            .iter()
+            .map(|(name, image)| format!("{}={}", name, image))
+            .collect();
+        Ok(Some(pairs.join(" ")))
+    }
+
     fn external_kit_metadata(&self) -> ExternalKitMetadata {
         ExternalKitMetadata {
             sdk: self.sdk.clone(),
@@ -360,16 +667,27 @@ impl Lock {
         }
     }
 
-    /// Fetches all external kits defined in a Twoliter.lock to the build directory
-    pub(crate) async fn fetch(&self, project: &Project, arch: &str) -> Result<()> {
+    /// Pulls the SDK for `arch` and fetches all external kits defined in a Twoliter.lock to the
+    /// build directory, so that a build can run without network access afterwards. Returns each
+    /// image that was fetched along with its resolved digest.
+    pub(crate) async fn fetch(
+        &self,
+        project: &Project,
+        arch: &str,
+        images: &ImageInspector,
+    ) -> Result<Vec<FetchedImage>> {
+        let mut fetched = vec![self.pull_sdk(arch, images).await?];
         let target_dir = project.external_kits_dir();
         create_dir_all(&target_dir).await.context(format!(
             "failed to create external-kits directory at {}",
             target_dir.display()
         ))?;
         for image in self.kit.iter() {
-            self.extract_kit(&project.external_kits_dir(), image, arch)
-                .await?;
+            let digest = Self::extract_kit(&project.external_kits_dir(), image, arch).await?;
+            fetched.push(FetchedImage {
+                uri: image.source.clone(),
+                digest,
+            });
         }
         let mut kit_list = Vec::new();
         let mut ser =
@@ -386,7 +704,7 @@ impl Lock {
             ))?;
             // If this is the same as what we generated skip the write
             if existing == kit_list {
-                return Ok(());
+                return Ok(fetched);
             }
         }
         write(project.external_kits_metadata(), kit_list.as_slice())
@@ -396,14 +714,126 @@ impl Lock {
                 project.external_kits_metadata().display()
             ))?;
 
+        Ok(fetched)
+    }
+
+    /// Pulls the SDK image for `arch` and returns the digest that it resolved to. The image is
+    /// pulled by its digest in the manifest list that Twoliter.lock pins, and then tagged as the
+    /// SDK's source, so that a tag that was moved in the registry is not used.
+    pub(crate) async fn pull_sdk(
+        &self,
+        arch: &str,
+        images: &ImageInspector,
+    ) -> Result<FetchedImage> {
+        let source = self.sdk.source.as_str();
+        let manifest = Self::get_manifest(&self.sdk, arch).await?;
+        let by_digest = self.sdk.digest_uri(&manifest.digest);
+        pull_for_arch(&by_digest, arch, images).await?;
+        docker(
+            ["tag", by_digest.as_str(), source],
+            format!("failed to tag the SDK {} as {}", by_digest, source),
+        )
+        .await?;
+        images.invalidate(source);
+        let image = images
+            .inspect(source)
+            .await?
+            .context(format!("the SDK {} was not found after pulling it", source))?;
+        let repo_digests = serde_json::to_vec(&image["RepoDigests"])
+            .context(format!("failed to read the repo digests of {}", source))?;
+        let digest = repo_digest(source, &repo_digests)?;
+        Ok(FetchedImage {
+            uri: source.to_string(),
+            digest,
+        })
+    }
+
+    /// Pulls the SDK image for `arch` unless it is already present locally. With `frozen`, a missing
+    /// SDK is an error instead.
+    pub(crate) async fn ensure_sdk(
+        &self,
+        arch: &str,
+        frozen: bool,
+        images: &ImageInspector,
+    ) -> Result<()> {
+        if images.exists(&self.sdk.source).await? {
+            return Ok(());
+        }
+        ensure!(
+            !frozen,
+            "The SDK image {} is not present locally, please run twoliter fetch",
+            self.sdk.source
+        );
+        self.pull_sdk(arch, images).await?;
         Ok(())
     }
 
-    async fn get_manifest(&self, image: &LockedImage, arch: &str) -> Result<ManifestView> {
-        let manifest_bytes = docker!(
-            ["manifest", "inspect", image.source.as_str()],
-            format!("failed to find a kit {}", image.to_string())
+    /// Ensures that the SDK image and the external kits for `arch` are available without network
+    /// access, i.e. that the SDK has been pulled and that `twoliter fetch` has been run. Offline and
+    /// `--frozen` builds need this.
+    pub(crate) async fn ensure_local(
+        &self,
+        project: &Project,
+        arch: &str,
+        images: &ImageInspector,
+    ) -> Result<()> {
+        ensure!(
+            images.exists(&self.sdk.source).await?,
+            "The SDK image {} is not present locally, please run twoliter fetch before building \
+            offline or with --frozen",
+            self.sdk.source
         );
+        for image in self.kit.iter() {
+            let digest_file = project
+                .external_kits_dir()
+                .join(format!("{}/{}/{}/digest", image.vendor, image.name, arch));
+            ensure!(
+                digest_file.exists(),
+                "The kit {} has not been fetched for {}, please run twoliter fetch before \
+                building offline or with --frozen",
+                image,
+                arch
+            );
+        }
+        Ok(())
+    }
+
+    /// The SDK and the external kits that a build for `arch` used, with the digest of each image:
+    /// the SDK's [`local_digest`] and the digest that each kit was fetched at.
+    pub(crate) async fn local_images(
+        &self,
+        project: &Project,
+        arch: &str,
+        images: &ImageInspector,
+    ) -> Result<Vec<FetchedImage>> {
+        let mut local = vec![FetchedImage {
+            uri: self.sdk.source.clone(),
+            digest: local_digest(&self.sdk.source, images).await?,
+        }];
+        for image in self.kit.iter() {
+            let digest_file = project
+                .external_kits_dir()
+                .join(format!("{}/{}/{}/digest", image.vendor, image.name, arch));
+            let digest = read_to_string(&digest_file).await.context(format!(
+                "The kit {} has not been fetched for {}, please run twoliter fetch",
+                image, arch
+            ))?;
+            local.push(FetchedImage {
+                uri: image.source.clone(),
+                digest,
+            });
+        }
+        Ok(local)
+    }
+
+    /// The manifest of `image` for `arch`, from the manifest list that Twoliter.lock pins.
+    async fn get_manifest(image: &LockedImage, arch: &str) -> Result<ManifestView> {
+        let manifest_bytes = docker(
+            ["manifest", "inspect", image.source.as_str()],
+            format!("failed to find the image {}", image),
+        )
+        .await?;
+        image.verify_manifest(&manifest_bytes)?;
         let manifest_list: ManifestListView = serde_json::from_slice(manifest_bytes.as_slice())
             .context("failed to deserialize manifest list")?;
         let docker_arch = DockerArchitecture::try_from(arch)?;
@@ -413,12 +843,14 @@ impl Lock {
             .find(|x| x.platform.as_ref().unwrap().architecture == docker_arch)
             .cloned()
             .context(format!(
-                "could not find kit image for architecture '{}' at {}",
+                "could not find an image for architecture '{}' at {}",
                 docker_arch, image.source
             ))
     }
 
-    async fn extract_kit<P>(&self, path: P, image: &LockedImage, arch: &str) -> Result<()>
+    /// Extracts the kit `image` for `arch` below `path` and returns the digest of the image that was
+    /// used.
+    pub(crate) async fn extract_kit<P>(path: P, image: &LockedImage, arch: &str) -> Result<String>
     where
         P: AsRef<Path>,
     {
@@ -430,7 +862,7 @@ impl Lock {
         create_dir_all(&cache_path).await?;
 
         // First get the manifest for the specific requested architecture
-        let manifest = self.get_manifest(image, arch).await?;
+        let manifest = Self::get_manifest(image, arch).await?;
         let oci_archive = OCIArchive::new(image, manifest.digest.as_str(), &cache_path);
 
         // Checks for the saved image locally, or else pulls and saves it
@@ -440,7 +872,7 @@ impl Lock {
         // otherwise cleans up the path and unpacks the archive
         oci_archive.unpack_layers(&target_path).await?;
 
-        Ok(())
+        Ok(manifest.digest)
     }
 
     async fn resolve(project: &Project) -> Result<Self> {
@@ -476,7 +908,7 @@ impl Lock {
                     image.version.clone(),
                 );
                 let locked_image = LockedImage::new(vendor, image).await?;
-                let kit = Self::find_kit(vendor, &locked_image).await?;
+                let kit = locked_image.kit_metadata().await?;
                 locked.push(locked_image);
                 sdk_set.insert(kit.sdk);
                 for dep in kit.kits {
@@ -507,53 +939,308 @@ impl Lock {
             digest: project.digest()?,
             sdk: LockedImage::new(vendor, sdk).await?,
             kit: locked,
+            go_modules: Some(project.find_go_modules().await?),
+            host_containers: resolve_host_containers(project).await?,
         })
     }
+}
 
-    async fn find_kit(vendor: &Vendor, image: &LockedImage) -> Result<ImageMetadata> {
-        let manifest_list: ManifestListView = serde_json::from_slice(image.manifest.as_slice())
-            .context("failed to deserialize manifest list")?;
-        let manifest = manifest_list.manifests.first().context(format!(
-            "kit image at {} does not have an architecture image",
-            image.source
-        ))?;
-        let image_uri = format!("{}/{}@{}", vendor.registry, image.name, manifest.digest);
-        docker_noisy!(
-            ["pull", image_uri.as_str()],
-            format!(
-                "failed to pull image for {} with digest {}",
-                image.to_string(),
-                manifest.digest
-            )
-        );
-        // Now we want to fetch the metadata from the OCI image config
-        let label_bytes = docker!(
-            [
-                "image",
-                "inspect",
-                image_uri.as_str(),
-                "--format",
-                "\"{{ json .Config.Labels }}\"",
-            ],
-            format!(
-                "failed to fetch kit metadata for {} with digest {}",
-                image.to_string(),
-                manifest.digest
-            )
-        );
-        let label_str = String::from_utf8_lossy(label_bytes.as_slice()).to_string();
-        let label_str = label_str.trim().trim_matches('"');
-        let labels: HashMap<String, String> = serde_json::from_str(label_str).context(format!(
-            "could not deserialize labels on the image for {}",
-            image
-        ))?;
-        let encoded = labels
-            .get("dev.bottlerocket.kit.v1")
-            .context("no metadata stored on image, this image appears to not be a kit")?;
-        let decoded = base64::engine::general_purpose::STANDARD
-            .decode(encoded.as_str())
-            .context("malformed kit metadata detected")?;
-
-        serde_json::from_slice(decoded.as_slice()).context("malformed kit metadata json")
+/// The host container images of each variant in `project`, with those that are not already pinned
+/// to a digest pinned to the one that their tag refers to now.
+async fn resolve_host_containers(
+    project: &Project,
+) -> Result<BTreeMap<String, BTreeMap<String, String>>> {
+    let mut resolved = BTreeMap::new();
+    for (variant, config) in project.variants() {
+        let mut containers = BTreeMap::new();
+        for (name, mut uri) in config.host_containers()? {
+            if uri.digest.is_none() {
+                uri.digest = Some(remote_digest(&uri.uri()).await?);
+            }
+            containers.insert(name, uri.to_string());
+        }
+        if !containers.is_empty() {
+            resolved.insert(variant.clone(), containers);
+        }
     }
+    Ok(resolved)
+}
+
+#[test]
+fn test_verify_manifest() {
+    let manifest = br#"{"manifests":[{"digest":"sha256:aaa"}]}"#;
+    let image = LockedImage {
+        name: "bottlerocket-sdk".to_string(),
+        version: Version::new(0, 50, 0),
+        vendor: "bottlerocket".to_string(),
+        source: "public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0".to_string(),
+        digest: manifest_digest(manifest),
+        manifest: Vec::new(),
+    };
+    image.verify_manifest(manifest).unwrap();
+    let moved = br#"{"manifests":[{"digest":"sha256:bbb"}]}"#;
+    let err = image.verify_manifest(moved).unwrap_err().to_string();
+    assert!(
+        err.contains("does not match the digest in Twoliter.lock"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_repo_digest() {
+    let repo_digests =
+        br#"["example.com:5000/other@sha256:aaa","example.com:5000/sdk@sha256:bbb"]"#;
+    assert_eq!(
+        repo_digest("example.com:5000/sdk:v0.42.0", repo_digests).unwrap(),
+        "sha256:bbb"
+    );
+    assert!(repo_digest("example.com:5000/missing:v1", repo_digests).is_err());
+    assert!(repo_digest("example.com/sdk:v1", b"[]").is_err());
+}
+
+#[test]
+fn test_with_registry() {
+    let image = |name: &str| LockedImage {
+        name: name.to_string(),
+        version: Version::new(1, 2, 3),
+        vendor: "bottlerocket".to_string(),
+        source: format!("public.ecr.aws/bottlerocket/{}:v1.2.3", name),
+        digest: "abc=".to_string(),
+        manifest: Vec::new(),
+    };
+    let lock = Lock {
+        schema_version: SchemaVersion,
+        release_version: "1.0.0".to_string(),
+        sdk: image("bottlerocket-sdk"),
+        kit: vec![image("core-kit")],
+        digest: "def=".to_string(),
+        go_modules: None,
+        host_containers: Default::default(),
+    };
+    let staged = lock.clone().with_registry("public.ecr.aws/mystaging/");
+    assert_eq!(
+        staged.sdk.source,
+        "public.ecr.aws/mystaging/bottlerocket-sdk:v1.2.3"
+    );
+    assert_eq!(
+        staged.kit[0].source,
+        "public.ecr.aws/mystaging/core-kit:v1.2.3"
+    );
+    assert_eq!(
+        staged.kit[0].digest_uri("sha256:aaa"),
+        "public.ecr.aws/mystaging/core-kit@sha256:aaa"
+    );
+    // Only the sources change.
+    assert_eq!(staged.sdk.digest, lock.sdk.digest);
+    assert_eq!(staged.digest, lock.digest);
+}
+
+#[tokio::test]
+async fn test_go_modules_keep_lock() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let p = temp_dir.path();
+    tokio::fs::copy(
+        crate::test::data_dir().join("Twoliter-1.toml"),
+        p.join("Twoliter.toml"),
+    )
+    .await
+    .unwrap();
+    create_dir_all(p.join("sources/hello-go")).await.unwrap();
+    write(
+        p.join("sources/hello-go/go.mod"),
+        "module example.com/hello\n",
+    )
+    .await
+    .unwrap();
+    let project = Project::find_and_load(p).await.unwrap();
+    let image = LockedImage {
+        name: "bottlerocket-sdk".to_string(),
+        version: Version::new(0, 50, 0),
+        vendor: "bottlerocket".to_string(),
+        source: "public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0".to_string(),
+        digest: "abc=".to_string(),
+        manifest: Vec::new(),
+    };
+    let lock = Lock {
+        schema_version: SchemaVersion,
+        release_version: "1.0.0".to_string(),
+        sdk: image,
+        kit: Vec::new(),
+        digest: "def=".to_string(),
+        go_modules: Some(vec!["old-go".to_string()]),
+        host_containers: Default::default(),
+    };
+    let lock_str = "committed";
+    write(p.join(TWOLITER_LOCK), lock_str).await.unwrap();
+
+    // A stale lock is an error when frozen, and otherwise the modules found are used without
+    // touching the lock or recording the fingerprint.
+    assert!(lock.go_modules(&project, false, true).await.is_err());
+    let go_modules = lock.go_modules(&project, false, false).await.unwrap();
+    assert_eq!(go_modules, ["hello-go"]);
+    assert_eq!(
+        read_to_string(p.join(TWOLITER_LOCK)).await.unwrap(),
+        lock_str
+    );
+    assert!(!p.join(GO_MODULES_CHECKED).exists());
+
+    // Once the lock is up to date the fingerprint is recorded.
+    let lock = Lock {
+        go_modules: Some(go_modules),
+        ..lock
+    };
+    lock.go_modules(&project, false, true).await.unwrap();
+    assert!(p.join(GO_MODULES_CHECKED).is_file());
+
+    // A go.mod that is edited in place is checked again.
+    create_dir_all(p.join("sources/other-go")).await.unwrap();
+    write(
+        p.join("sources/other-go/go.mod"),
+        "module example.com/other\n",
+    )
+    .await
+    .unwrap();
+    let lock = Lock {
+        go_modules: Some(vec!["hello-go".to_string(), "other-go".to_string()]),
+        ..lock
+    };
+    lock.go_modules(&project, false, true).await.unwrap();
+    write(
+        p.join("sources/other-go/go.mod"),
+        "module example.com/hello\n",
+    )
+    .await
+    .unwrap();
+    let err = lock.go_modules(&project, false, true).await.unwrap_err();
+    assert!(err.to_string().contains("import path 'example.com/hello'"));
+}
+
+#[tokio::test]
+async fn test_load_existing_vendor_registry() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let p = temp_dir.path();
+    tokio::fs::copy(
+        crate::test::data_dir().join("Twoliter-1.toml"),
+        p.join("Twoliter.toml"),
+    )
+    .await
+    .unwrap();
+    let project = Project::find_and_load(p).await.unwrap();
+    let image = |vendor: &str, source: &str| LockedImage {
+        name: "my-bottlerocket-sdk".to_string(),
+        version: Version::new(1, 2, 3),
+        vendor: vendor.to_string(),
+        source: source.to_string(),
+        digest: "abc=".to_string(),
+        manifest: Vec::new(),
+    };
+    let lock = Lock {
+        schema_version: SchemaVersion,
+        release_version: "1.0.0".to_string(),
+        sdk: image("my-vendor", "locked.example.com/my-bottlerocket-sdk:v1.2.3"),
+        kit: vec![image(
+            "other",
+            "other.example.com/my-bottlerocket-sdk:v1.2.3",
+        )],
+        digest: project.digest().unwrap(),
+        go_modules: None,
+        host_containers: Default::default(),
+    };
+    write(p.join(TWOLITER_LOCK), toml::to_string(&lock).unwrap())
+        .await
+        .unwrap();
+
+    // The SDK comes from the registry of its vendor in Twoliter.toml, not the one that was locked.
+    let loaded = Lock::load_existing(&project).await.unwrap();
+    assert_eq!(loaded.sdk.source, "a.com/b/my-bottlerocket-sdk:v1.2.3");
+    assert_eq!(loaded.sdk.digest, "abc=");
+    assert_eq!(loaded.kit, lock.kit);
+}
+
+#[tokio::test]
+async fn test_host_containers() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let p = temp_dir.path();
+    let twoliter_toml = read_to_string(crate::test::data_dir().join("Twoliter-1.toml"))
+        .await
+        .unwrap();
+    let digest = format!("sha256:{}", "0".repeat(64));
+    write(
+        p.join("Twoliter.toml"),
+        format!(
+            "{}\n[variant.aws-dev.host-containers]\n\
+            admin = \"example.com/admin:v1\"\n\
+            control = \"example.com/control:v2\"\n",
+            twoliter_toml
+        ),
+    )
+    .await
+    .unwrap();
+    let project = Project::find_and_load(p).await.unwrap();
+    let lock = Lock {
+        schema_version: SchemaVersion,
+        release_version: "1.0.0".to_string(),
+        sdk: LockedImage {
+            name: "bottlerocket-sdk".to_string(),
+            version: Version::new(0, 50, 0),
+            vendor: "bottlerocket".to_string(),
+            source: "public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0".to_string(),
+            digest: "abc=".to_string(),
+            manifest: Vec::new(),
+        },
+        kit: Vec::new(),
+        digest: "def=".to_string(),
+        go_modules: None,
+        host_containers: BTreeMap::from([(
+            "aws-dev".to_string(),
+            BTreeMap::from([(
+                "admin".to_string(),
+                format!("example.com/admin:v1@{}", digest),
+            )]),
+        )]),
+    };
+
+    // The digest in the lock is used, and an image without one is used as it is configured.
+    assert_eq!(
+        lock.host_containers_env(&project, "aws-dev")
+            .unwrap()
+            .unwrap(),
+        format!(
+            "admin=example.com/admin:v1@{} control=example.com/control:v2",
+            digest
+        )
+    );
+    assert_eq!(
+        lock.host_containers_env(&project, "metal-dev").unwrap(),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_local_digest() {
+    let images = ImageInspector::with_results(HashMap::from([
+        (
+            "example.com/sdk:v0.42.0".to_string(),
+            Some(serde_json::json!({
+                "Id": "sha256:aaa",
+                "RepoDigests": ["example.com/sdk@sha256:bbb"],
+            })),
+        ),
+        (
+            "sdk:dev".to_string(),
+            Some(serde_json::json!({ "Id": "sha256:ccc", "RepoDigests": [] })),
+        ),
+    ]));
+    assert_eq!(
+        local_digest("example.com/sdk:v0.42.0", &images)
+            .await
+            .unwrap(),
+        "sha256:bbb"
+    );
+    assert_eq!(
+        local_digest("sdk:dev", &images).await.unwrap(),
+        "sha256:ccc"
+    );
+    assert!(local_digest("sdk:missing", &images).await.is_err());
 }
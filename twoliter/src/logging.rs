@@ -0,0 +1,134 @@
+/*!
+
+How Twoliter's log records, and the output of the commands that it runs, are written. The `text`
+format is env_logger's. The `json` format writes one JSON object per line so that logs can be
+collected without parsing text: log records have a `timestamp`, `level`, `target` and `message`
+along with any fields given with [`log_with!`], and each line of a child process's output is a
+`{"stream":"stdout","line":"..."}` record.
+
+!*/
+
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::sync::OnceLock;
+
+/// The environment variable that chooses the log format when `--log-format` is not given.
+pub(crate) const LOG_FORMAT_ENV: &str = "TWOLITER_LOG_FORMAT";
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, ValueEnum)]
+pub(crate) enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+static LOG_FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+thread_local! {
+    /// The fields of the record that [`log_with!`] is logging on this thread.
+    static FIELDS: RefCell<Vec<(&'static str, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Chooses the log format for the rest of the process. Only the first call has an effect.
+pub(crate) fn set_log_format(format: LogFormat) {
+    let _ = LOG_FORMAT.set(format);
+}
+
+/// The log format chosen with [`set_log_format`], `text` if none was.
+pub(crate) fn log_format() -> LogFormat {
+    LOG_FORMAT.get().copied().unwrap_or_default()
+}
+
+/// Logs a record with `key = value` fields, which are included in `json` logs and left out of
+/// `text` logs, so the message should make sense on its own. For example:
+///
+/// ```ignore
+/// log_with!(Level::Warn, { kit = "core", artifact = "RPM repo" }, "No RPM repo was found");
+/// ```
+macro_rules! log_with {
+    ($level:expr, { $($key:ident = $value:expr),+ $(,)? }, $($arg:tt)+) => {
+        $crate::logging::with_fields(
+            vec![$((stringify!($key), $value.to_string())),+],
+            || log::log!($level, $($arg)+),
+        )
+    };
+}
+pub(crate) use log_with;
+
+/// Makes `fields` available to the logger while `log` runs. Used by [`log_with!`].
+pub(crate) fn with_fields(fields: Vec<(&'static str, String)>, log: impl FnOnce()) {
+    FIELDS.with(|current| *current.borrow_mut() = fields);
+    log();
+    FIELDS.with(|current| current.borrow_mut().clear());
+}
+
+/// Renders a log record as a line of JSON, including the fields given with [`log_with!`].
+pub(crate) fn json_record(timestamp: &str, level: &str, target: &str, message: &str) -> String {
+    let mut record = Map::new();
+    record.insert("timestamp".into(), timestamp.into());
+    record.insert("level".into(), level.into());
+    record.insert("target".into(), target.into());
+    record.insert("message".into(), message.into());
+    FIELDS.with(|fields| {
+        for (key, value) in fields.borrow().iter() {
+            // The standard keys win over fields with the same name.
+            record
+                .entry(key.to_string())
+                .or_insert_with(|| value.clone().into());
+        }
+    });
+    Value::Object(record).to_string()
+}
+
+/// Renders a line of a child process's output in `format`. `stream` is `stdout` or `stderr`.
+pub(crate) fn output_line(format: LogFormat, stream: &str, line: &str) -> String {
+    match format {
+        LogFormat::Text => line.to_string(),
+        LogFormat::Json => serde_json::json!({ "stream": stream, "line": line }).to_string(),
+    }
+}
+
+#[test]
+fn test_json_record() {
+    let line = json_record(
+        "2024-05-01T12:00:00Z",
+        "INFO",
+        "twoliter::cmd::build",
+        "Building \"kit\"\nnext line",
+    );
+    let record: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(record["timestamp"], "2024-05-01T12:00:00Z");
+    assert_eq!(record["level"], "INFO");
+    assert_eq!(record["target"], "twoliter::cmd::build");
+    assert_eq!(record["message"], "Building \"kit\"\nnext line");
+    assert!(!line.contains('\n'));
+
+    let mut line = String::new();
+    with_fields(
+        vec![
+            ("arch", "x86_64".to_string()),
+            ("level", "ignored".to_string()),
+        ],
+        || line = json_record("now", "WARN", "twoliter", "message"),
+    );
+    let record: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(record["arch"], "x86_64");
+    assert_eq!(record["level"], "WARN");
+
+    // Fields only apply to the record that they were given for.
+    let record: Value = serde_json::from_str(&json_record("now", "INFO", "t", "m")).unwrap();
+    assert!(record.get("arch").is_none());
+}
+
+#[test]
+fn test_output_line() {
+    assert_eq!(
+        output_line(LogFormat::Text, "stdout", "Compiling"),
+        "Compiling"
+    );
+    let line = output_line(LogFormat::Json, "stderr", "error: \"a\"");
+    let record: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(record["stream"], "stderr");
+    assert_eq!(record["line"], "error: \"a\"");
+}
@@ -1,24 +1,43 @@
 use crate::cmd::{init_logger, Args};
-use anyhow::Result;
 use clap::Parser;
+use std::process::ExitCode;
 
+mod binfmt;
 mod cargo_make;
+mod checksums;
 mod cmd;
 mod common;
+mod cosign;
 mod docker;
+mod filesystem;
+mod graph;
+mod infra;
+mod kit;
 mod lock;
+mod logging;
+mod ownership;
 mod project;
+mod project_lock;
+mod provenance;
+mod sbom;
 mod schema_version;
+mod style;
 /// Test code that should only be compiled when running tests.
 #[cfg(test)]
 mod test;
 mod tools;
+mod variant;
 
-/// `anyhow` prints a nicely formatted error message with `Debug`, so we can return a result from
-/// the `main` function.
+/// Errors are printed with `anyhow`'s `Debug` format, which includes the chain of causes.
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     let args = Args::parse();
-    init_logger(args.log_level);
-    cmd::run(args).await
+    init_logger(args.log_level, args.log_format, args.color());
+    match cmd::run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}: {:?}", style::stderr().paint(style::ERROR, "Error"), e);
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,161 @@
+/*!
+
+Files under `build/` are written by containers. With some docker configurations these end up owned
+by root, after which `twoliter build clean` or `git clean` fail for the user who ran the build. Most
+containers are already run with `--user`, so as a backstop, after a build we look for files under
+`build/` that the invoking user does not own and, if there are any, `chown` them back from inside a
+helper container that mounts only `build/`.
+
+!*/
+
+use crate::common::exec;
+use crate::docker::docker;
+use crate::project::Project;
+use anyhow::{Context, Result};
+use async_walkdir::WalkDir;
+use futures::StreamExt;
+use log::{debug, info};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use tokio::process::Command;
+
+/// A user and group ID pair.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Owner {
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+}
+
+/// Gives the files under the project's `build/` directory back to the invoking user if any of them
+/// are owned by someone else. `sdk` is the image used to run `chown`. This does nothing if
+/// `build.fix-ownership` is `false` in `Twoliter.toml`.
+pub(crate) async fn fix_ownership(project: &Project, sdk: &str) -> Result<()> {
+    if !project.fix_ownership() {
+        return Ok(());
+    }
+    let build_dir = project.project_dir().join("build");
+    if !build_dir.is_dir() {
+        return Ok(());
+    }
+    let user = current_user().await?;
+    // Skip the more expensive checks when an earlier one already rules out a change.
+    let foreign_owned = user.uid != 0 && has_foreign_owned_files(&build_dir, user).await;
+    let rootless = foreign_owned && is_rootless().await?;
+    if !should_fix(user, rootless, foreign_owned) {
+        if rootless {
+            debug!("Docker is running rootless, not changing ownership of build files");
+        }
+        return Ok(());
+    }
+
+    info!(
+        "Some files in '{}' are not owned by the current user, changing their ownership",
+        build_dir.display()
+    );
+    docker(
+        [
+            "run".to_string(),
+            "--rm".to_string(),
+            "--network=none".to_string(),
+            "--user=0:0".to_string(),
+            "--security-opt=label=disable".to_string(),
+            format!("--volume={}:/build", build_dir.display()),
+            "--entrypoint=chown".to_string(),
+            sdk.to_string(),
+            "-R".to_string(),
+            format!("{}:{}", user.uid, user.gid),
+            "/build".to_string(),
+        ],
+        format!("Unable to change ownership of '{}'", build_dir.display()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Whether the ownership of the build files should be changed. There is nothing to do for root,
+/// and with a rootless runtime, files owned by root in the container belong to the user already.
+fn should_fix(user: Owner, rootless: bool, foreign_owned: bool) -> bool {
+    user.uid != 0 && !rootless && foreign_owned
+}
+
+/// The user running Twoliter, from `id`.
+pub(crate) async fn current_user() -> Result<Owner> {
+    let id = |flag: &'static str| async move {
+        let output = exec(Command::new("id").arg(flag), true)
+            .await?
+            .unwrap_or_default();
+        output
+            .trim()
+            .parse::<u32>()
+            .context(format!("Unable to parse the output of 'id {}'", flag))
+    };
+    Ok(Owner {
+        uid: id("-u").await?,
+        gid: id("-g").await?,
+    })
+}
+
+/// Returns `true` as soon as a file or directory below `dir` is found that is not owned by `user`.
+/// A directory that cannot be read is assumed to be owned by someone else.
+async fn has_foreign_owned_files(dir: &Path, user: Owner) -> bool {
+    let mut entries = WalkDir::new(dir);
+    while let Some(entry) = entries.next().await {
+        let foreign = match entry {
+            Ok(entry) => match entry.path().symlink_metadata() {
+                Ok(metadata) => metadata.uid() != user.uid || metadata.gid() != user.gid,
+                Err(_) => true,
+            },
+            Err(_) => true,
+        };
+        if foreign {
+            return true;
+        }
+    }
+    false
+}
+
+/// Returns `true` if the docker daemon is running in rootless mode.
+pub(crate) async fn is_rootless() -> Result<bool> {
+    let output = docker(
+        ["info", "--format", "{{json .SecurityOptions}}"],
+        "Unable to get docker security options",
+    )
+    .await?;
+    Ok(String::from_utf8_lossy(&output).contains("name=rootless"))
+}
+
+#[test]
+fn test_should_fix() {
+    let user = Owner {
+        uid: 1000,
+        gid: 1000,
+    };
+    let root = Owner { uid: 0, gid: 0 };
+    assert!(should_fix(user, false, true));
+    assert!(!should_fix(root, false, true));
+    assert!(!should_fix(user, true, true));
+    assert!(!should_fix(user, false, false));
+}
+
+#[tokio::test]
+async fn test_has_foreign_owned_files() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    crate::common::fs::create_dir_all(dir.join("rpms"))
+        .await
+        .unwrap();
+    crate::common::fs::write(dir.join("rpms/a.rpm"), "")
+        .await
+        .unwrap();
+    let metadata = dir.metadata().unwrap();
+    let owner = Owner {
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+    };
+    assert!(!has_foreign_owned_files(dir, owner).await);
+    let other = Owner {
+        uid: owner.uid + 1,
+        gid: owner.gid,
+    };
+    assert!(has_foreign_owned_files(dir, other).await);
+}
@@ -1,24 +1,28 @@
 use crate::common::fs;
-use crate::docker::ImageUri;
+use crate::docker::{DockerNetwork, ImageUri, PullPolicy};
 use crate::schema_version::SchemaVersion;
-use anyhow::{ensure, Context, Result};
+use crate::variant::ImageLayout;
+use anyhow::{bail, ensure, Context, Result};
 use async_recursion::async_recursion;
-use async_walkdir::WalkDir;
 use base64::Engine;
 use buildsys_config::{EXTERNAL_KIT_DIRECTORY, EXTERNAL_KIT_METADATA};
-use futures::stream::StreamExt;
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use log::{debug, info, trace, warn};
-use semver::Version;
+use semver::{Comparator, Op, Prerelease, Version, VersionReq};
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha2::{Digest, Sha256};
 use std::collections::BTreeMap;
-use std::ffi::OsStr;
 use std::fmt::{Display, Formatter};
 use std::hash::Hash;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use toml::Table;
+use tempfile::TempDir;
+use toml::{Table, Value};
+use url::Url;
+
+/// The name of the project file.
+pub(crate) const TWOLITER_TOML: &str = "Twoliter.toml";
 
 /// Common functionality in commands, if the user gave a path to the `Twoliter.toml` file,
 /// we use it, otherwise we search for the file. Returns the `Project` and the path at which it was
@@ -35,6 +39,13 @@ pub(crate) async fn load_or_find_project(user_path: Option<PathBuf>) -> Result<P
     Ok(project)
 }
 
+/// The shared cache directory given by `TWOLITER_SHARED_CACHE`, if it is set and not empty.
+pub(crate) fn shared_cache_from_env() -> Option<PathBuf> {
+    std::env::var_os(TWOLITER_SHARED_CACHE)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
 /// Represents the structure of a `Twoliter.toml` project file.
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize)]
 #[serde(rename_all = "kebab-case")]
@@ -56,8 +67,191 @@ pub(crate) struct Project {
     /// Set of vendors
     vendor: BTreeMap<ValidIdentifier, Vendor>,
 
+    /// The registry of each vendor as written in `Twoliter.toml`, before environment variables
+    /// were interpolated. The lock file digest uses these so that it does not change with the
+    /// environment.
+    #[serde(skip)]
+    uninterpolated_registries: BTreeMap<String, String>,
+
     /// Set of kit dependencies
     kit: Vec<Image>,
+
+    /// Settings that affect how Twoliter builds but not what it builds.
+    build: BuildConfig,
+
+    /// Settings for individual variants, keyed by the variant's name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    variant: BTreeMap<String, VariantConfig>,
+
+    /// The versions of Twoliter that may build this project, e.g. `">=0.4, <0.6"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    required_twoliter_version: Option<String>,
+}
+
+/// The environment variable that overrides where Twoliter creates temporary directories.
+pub(crate) const TWOLITER_TMPDIR: &str = "TWOLITER_TMPDIR";
+
+/// The environment variable that overrides the shared cache directory.
+pub(crate) const TWOLITER_SHARED_CACHE: &str = "TWOLITER_SHARED_CACHE";
+
+/// The `[build]` section of `Twoliter.toml`. These settings do not contribute to the lock file
+/// digest.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct BuildConfig {
+    /// The directory in which Twoliter creates temporary directories. Relative paths are relative
+    /// to the project directory. `TWOLITER_TMPDIR` takes precedence over this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) temp_dir: Option<PathBuf>,
+
+    /// The docker network for build steps. When `none`, builds must also be able to proceed
+    /// offline. Overridden by `--network`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) network: Option<DockerNetwork>,
+
+    /// Whether files under `build/` that are not owned by the invoking user are given back to
+    /// them after a build. Defaults to `true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) fix_ownership: Option<bool>,
+
+    /// A directory, which may be shared between projects, where downloaded sources are stored by
+    /// their hash. Relative paths are relative to the project directory. `TWOLITER_SHARED_CACHE`
+    /// takes precedence over this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) shared_cache: Option<PathBuf>,
+
+    /// The lookaside caches that sources are fetched from, tried in order before any upstream
+    /// fallback, e.g. a local mirror and then `https://cache.bottlerocket.aws`. Each is a URL or a
+    /// local directory, relative to the project directory. Overridden by `--lookaside-cache`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) lookaside_caches: Vec<String>,
+
+    /// Go modules to leave out of the build, by name. Each is a glob pattern, e.g. `hello-*`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) go_modules_exclude: Vec<String>,
+
+    /// Appended to the release version of built images and kits, e.g. `pr-123` gives `1.2.3-pr-123`.
+    /// Overridden by `--tag-suffix`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tag_suffix: Option<String>,
+
+    /// The `CARGO_HOME` for `twoliter make` when `--cargo-home` is not given, e.g. `.cargo`.
+    /// Relative paths are relative to the project directory. The `CARGO_HOME` environment variable
+    /// is never used, so that the cargo home of the system is not mistaken for the build's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) cargo_home: Option<PathBuf>,
+
+    /// When the SDK image is pulled: `always`, `missing` or `never`. Defaults to `missing`.
+    /// Overridden by `--pull`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pull: Option<PullPolicy>,
+}
+
+/// The number of [`monorepo_markers`] that must be found before a directory is taken to be a
+/// checkout of the Bottlerocket monorepo.
+const MONOREPO_MIN_MARKERS: usize = 2;
+
+/// Variants that only exist in the Bottlerocket monorepo, or are named by prefix there. A project
+/// may have copied one of them into `variants/` with `twoliter migrate`, so they only count under
+/// `sources/variants`, where the monorepo keeps them.
+const UPSTREAM_VARIANT_PREFIXES: [&str; 6] = [
+    "aws-k8s-",
+    "aws-ecs-",
+    "vmware-k8s-",
+    "metal-k8s-",
+    "aws-dev",
+    "metal-dev",
+];
+
+/// Returns descriptions of the signs in `dir` that it is a checkout of the Bottlerocket monorepo
+/// rather than a Twoliter project. Only parts of the in-tree layout count: a Twoliter project has
+/// its own `Release.toml`, `Makefile.toml` and `variants/` as well.
+pub(crate) fn monorepo_markers(dir: &Path) -> Vec<&'static str> {
+    let mut markers = Vec::new();
+    // Twoliter brings its own copies of the build tools.
+    if ["buildsys", "pubsys"]
+        .iter()
+        .any(|tool| dir.join("tools").join(tool).is_dir())
+    {
+        markers.push("it has the build tools in tools/");
+    }
+    // A Twoliter project gets the API and the OS packages from a kit.
+    let has_os_sources =
+        dir.join("sources").join("api").is_dir() || dir.join("packages").join("os").is_dir();
+    if has_os_sources && !dir.join("kits").is_dir() {
+        markers.push("it has the Bottlerocket API and OS sources outside of a kit");
+    }
+    let has_upstream_variant = std::fs::read_dir(dir.join("sources").join("variants"))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .any(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            UPSTREAM_VARIANT_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+        });
+    if has_upstream_variant {
+        markers.push("it has the upstream variants in sources/variants");
+    }
+    markers
+}
+
+/// A `[variant.<name>]` section of `Twoliter.toml`. A variant's platform, runtime, family and flavor
+/// are normally derived from its name, e.g. `aws-k8s-1.28-nvidia`. Any that are given here are used
+/// instead, so that a variant can be named freely. These settings do not contribute to the lock file
+/// digest.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct VariantConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) variant_platform: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) variant_runtime: Option<String>,
+
+    /// Usually `<platform>-<runtime>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) variant_family: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) variant_flavor: Option<String>,
+
+    /// Host container images by name, e.g. `admin = "<registry>/bottlerocket-admin:v0.11.0"`, which
+    /// may be pinned with `@sha256:<digest>`. `twoliter update` pins the others to the digest that
+    /// their tag refers to in Twoliter.lock. The variant's image build reads each one from the
+    /// `%_cross_host_container_<name>` RPM macro, with `-` in the name replaced by `_`. Packages are
+    /// built the same way for every variant, so they do not see them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) host_containers: BTreeMap<String, String>,
+
+    /// Overrides `[package.metadata.build-variant.image-layout]` in the variant's `Cargo.toml`,
+    /// e.g. `os-image-size-gib = 4` or `partition-plan = "unified"`.
+    #[serde(default, skip_serializing_if = "ImageLayout::is_empty")]
+    pub(crate) image_layout: ImageLayout,
+}
+
+impl VariantConfig {
+    /// Parses the images in `host-containers`.
+    pub(crate) fn host_containers(&self) -> Result<BTreeMap<String, ImageUri>> {
+        let mut containers = BTreeMap::new();
+        for (name, image) in &self.host_containers {
+            ensure!(
+                !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+                "The host container name '{}' may only contain letters, digits, '-' and '_'",
+                name
+            );
+            let uri = ImageUri::parse(image).context(format!(
+                "Unable to parse the image of host container '{}'",
+                name
+            ))?;
+            containers.insert(name.clone(), uri);
+        }
+        Ok(containers)
+    }
 }
 
 impl Project {
@@ -67,11 +261,22 @@ impl Project {
         let data = fs::read_to_string(&path)
             .await
             .context(format!("Unable to read project file '{}'", path.display()))?;
-        let unvalidated: UnvalidatedProject = toml::from_str(&data).context(format!(
+        let mut toml: Table = toml::from_str(&data).context(format!(
             "Unable to deserialize project file '{}'",
             path.display()
         ))?;
-        unvalidated.validate(path).await
+        let uninterpolated_registries = vendor_registries(&toml);
+        interpolate_fields(&mut toml, &|name| std::env::var(name).ok()).context(format!(
+            "Unable to interpolate environment variables in project file '{}'",
+            path.display()
+        ))?;
+        let unvalidated: UnvalidatedProject = toml.try_into().context(format!(
+            "Unable to deserialize project file '{}'",
+            path.display()
+        ))?;
+        let mut project = unvalidated.validate(path).await?;
+        project.uninterpolated_registries = uninterpolated_registries;
+        Ok(project)
     }
 
     /// Recursively search for a file named `Twoliter.toml` starting in `dir`. If it is not found,
@@ -88,7 +293,7 @@ impl Project {
         let dir = dir
             .canonicalize()
             .context(format!("Unable to canonicalize '{}'", dir.display()))?;
-        let filepath = dir.join("Twoliter.toml");
+        let filepath = dir.join(TWOLITER_TOML);
         if filepath.is_file() {
             return Self::load(&filepath).await;
         }
@@ -116,6 +321,85 @@ impl Project {
         self.project_dir.join(EXTERNAL_KIT_METADATA)
     }
 
+    /// Checks that this version of Twoliter satisfies `required-twoliter-version`. When `ignore` is
+    /// set, a mismatch is only a warning.
+    pub(crate) fn check_twoliter_version(&self, ignore: bool) -> Result<()> {
+        let Some(required) = &self.required_twoliter_version else {
+            return Ok(());
+        };
+        let required = parse_version_req(required)?;
+        let version = twoliter_version();
+        if required.matches(&version) {
+            return Ok(());
+        }
+        let message = format!(
+            "'{}' requires twoliter {}, but this is twoliter {}. Please {} twoliter, or run \
+            'twoliter update --bump-required-version' to allow this version",
+            self.filepath.display(),
+            required,
+            version,
+            if needs_upgrade(&required, &version) {
+                "upgrade"
+            } else {
+                "downgrade"
+            }
+        );
+        if ignore {
+            warn!("IGNORING THE VERSION REQUIREMENT! {}", message);
+            return Ok(());
+        }
+        bail!(message)
+    }
+
+    /// Fails if the project directory looks like a checkout of the Bottlerocket monorepo, which is
+    /// built with its own Makefile.toml rather than as a Twoliter project. When `allow` is set, this
+    /// is only a warning.
+    pub(crate) fn check_monorepo(&self, allow: bool) -> Result<()> {
+        let markers = monorepo_markers(&self.project_dir);
+        if markers.len() < MONOREPO_MIN_MARKERS {
+            return Ok(());
+        }
+        let message = format!(
+            "'{}' looks like a checkout of the Bottlerocket monorepo ({}). The monorepo builds \
+            variants in-tree with its own Makefile.toml and tools, while Twoliter builds out-of-tree \
+            projects that depend on the SDK and kits through Twoliter.toml, so running Twoliter here \
+            only half works. To build a variant with Twoliter, copy it into a new project with \
+            'twoliter migrate --from {} --variant <VARIANT> --project-dir <DIR>', or pass \
+            --allow-monorepo to continue anyway",
+            self.project_dir.display(),
+            markers.join(", "),
+            self.project_dir.display(),
+        );
+        if allow {
+            warn!("{}", message);
+            return Ok(());
+        }
+        bail!(message)
+    }
+
+    /// Changes `required-twoliter-version` in Twoliter.toml so that it allows this version of
+    /// Twoliter. Returns the new requirement, or `None` if it already allowed this version.
+    pub(crate) async fn bump_required_twoliter_version(&self) -> Result<Option<VersionReq>> {
+        let version = twoliter_version();
+        let required = self
+            .required_twoliter_version
+            .as_deref()
+            .map(parse_version_req)
+            .transpose()?;
+        if required.as_ref().is_some_and(|req| req.matches(&version)) {
+            return Ok(None);
+        }
+        let bumped = bumped_version_req(required.as_ref(), &version);
+        let data = fs::read_to_string(&self.filepath).await?;
+        let mut document: toml_edit::DocumentMut = data.parse().context(format!(
+            "Unable to parse project file '{}'",
+            self.filepath.display()
+        ))?;
+        document["required-twoliter-version"] = toml_edit::value(bumped.to_string());
+        fs::write(&self.filepath, document.to_string()).await?;
+        Ok(Some(bumped))
+    }
+
     pub(crate) fn schema_version(&self) -> SchemaVersion<1> {
         self.schema_version
     }
@@ -124,6 +408,15 @@ impl Project {
         self.release_version.as_str()
     }
 
+    /// The version given to built images and kits, which is the release version with a tag suffix
+    /// from `tag_suffix`, or else from `tag-suffix` in the `[build]` section, added as a pre-release.
+    pub(crate) fn image_version(&self, tag_suffix: Option<&str>) -> Result<String> {
+        match tag_suffix.or(self.build.tag_suffix.as_deref()) {
+            Some(tag_suffix) => with_tag_suffix(&self.release_version, tag_suffix),
+            None => Ok(self.release_version.clone()),
+        }
+    }
+
     pub(crate) fn vendor(&self) -> &BTreeMap<ValidIdentifier, Vendor> {
         &self.vendor
     }
@@ -136,6 +429,116 @@ impl Project {
         self.sdk.clone()
     }
 
+    pub(crate) fn docker_network(&self) -> Option<&DockerNetwork> {
+        self.build.network.as_ref()
+    }
+
+    /// The `[variant.<name>]` settings for the variant `name`, which are empty if there are none.
+    pub(crate) fn variant(&self, name: &str) -> VariantConfig {
+        self.variant.get(name).cloned().unwrap_or_default()
+    }
+
+    /// The `[variant.<name>]` sections of Twoliter.toml.
+    pub(crate) fn variants(&self) -> &BTreeMap<String, VariantConfig> {
+        &self.variant
+    }
+
+    pub(crate) fn fix_ownership(&self) -> bool {
+        self.build.fix_ownership.unwrap_or(true)
+    }
+
+    /// Removes the modules that match a pattern in `go-modules-exclude` from `modules`.
+    pub(crate) fn exclude_go_modules(&self, modules: Vec<String>) -> Result<Vec<String>> {
+        let excluded = &self.build.go_modules_exclude;
+        let globs = go_module_globs(excluded)?;
+        Ok(modules
+            .into_iter()
+            .filter(|module| match globs.matches(module).first() {
+                Some(&index) => {
+                    debug!(
+                        "Excluding go module '{}', which matches '{}'",
+                        module, excluded[index]
+                    );
+                    false
+                }
+                None => true,
+            })
+            .collect())
+    }
+
+    /// The shared cache directory, if any. This is `TWOLITER_SHARED_CACHE` if set, otherwise
+    /// `shared-cache` from the `[build]` section of `Twoliter.toml`.
+    pub(crate) fn shared_cache(&self) -> Option<PathBuf> {
+        self.resolve_shared_cache(shared_cache_from_env())
+    }
+
+    /// `cargo-home` from the `[build]` section of `Twoliter.toml`, relative to the project directory.
+    pub(crate) fn cargo_home(&self) -> Option<PathBuf> {
+        self.build
+            .cargo_home
+            .as_ref()
+            .map(|dir| self.project_dir.join(dir))
+    }
+
+    /// `pull` from the `[build]` section of `Twoliter.toml`.
+    pub(crate) fn pull_policy(&self) -> Option<PullPolicy> {
+        self.build.pull
+    }
+
+    /// The `lookaside-caches` in the `[build]` section of Twoliter.toml, with local directories
+    /// made relative to the project directory.
+    pub(crate) fn lookaside_caches(&self) -> Vec<String> {
+        self.build
+            .lookaside_caches
+            .iter()
+            .map(|cache| match Url::parse(cache) {
+                Ok(_) => cache.clone(),
+                Err(_) => self.project_dir.join(cache).display().to_string(),
+            })
+            .collect()
+    }
+
+    fn resolve_shared_cache(&self, from_env: Option<PathBuf>) -> Option<PathBuf> {
+        from_env.or_else(|| {
+            self.build
+                .shared_cache
+                .as_ref()
+                .map(|dir| self.project_dir.join(dir))
+        })
+    }
+
+    /// The directory in which Twoliter creates temporary directories. This is `TWOLITER_TMPDIR` if
+    /// set, otherwise `temp-dir` from the `[build]` section of `Twoliter.toml`, otherwise the
+    /// project directory. The default keeps temporary files on the same filesystem as the build
+    /// directory so that they can be renamed into place.
+    pub(crate) fn temp_dir_base(&self) -> PathBuf {
+        self.resolve_temp_dir_base(std::env::var_os(TWOLITER_TMPDIR).map(PathBuf::from))
+    }
+
+    fn resolve_temp_dir_base(&self, from_env: Option<PathBuf>) -> PathBuf {
+        from_env
+            .or_else(|| {
+                self.build
+                    .temp_dir
+                    .as_ref()
+                    .map(|dir| self.project_dir.join(dir))
+            })
+            .unwrap_or_else(|| self.project_dir.clone())
+    }
+
+    /// Creates a temporary directory in [`Project::temp_dir_base`], creating the base directory if
+    /// needed. Errors if the base directory is not writable.
+    pub(crate) async fn temp_dir(&self) -> Result<TempDir> {
+        let base = self.temp_dir_base();
+        fs::create_dir_all(&base).await?;
+        TempDir::new_in(&base).context(format!(
+            "Unable to create a temporary directory in '{}', please make sure it is writable or \
+            choose a different location with {} or the build.temp-dir setting in Twoliter.toml",
+            base.display(),
+            TWOLITER_TMPDIR,
+        ))
+    }
+
     #[allow(unused)]
     pub(crate) fn kit(&self, name: &str) -> Result<Option<ImageUri>> {
         if let Some(kit) = self.kit.iter().find(|y| y.name.to_string() == name) {
@@ -154,49 +557,75 @@ impl Project {
     }
 
     /// Returns a list of the names of Go modules by searching the `sources` directory for `go.mod`
-    /// files.
+    /// files. The name of a module is the name of its directory. It is an error for two modules to
+    /// have the same name or the same import path.
     pub(crate) async fn find_go_modules(&self) -> Result<Vec<String>> {
-        let root = self.project_dir.join("sources");
-        let mut entries = WalkDir::new(&root);
+        Ok(self.find_go_module_dirs().await?.into_keys().collect())
+    }
+
+    /// Like [`Project::find_go_modules`], along with the directory of each module relative to the
+    /// project.
+    pub(crate) async fn find_go_module_dirs(&self) -> Result<BTreeMap<String, PathBuf>> {
         let mut modules = Vec::new();
-        loop {
-            match entries.next().await {
-                Some(Ok(entry)) => {
-                    if let Some(filename) = entry.path().file_name() {
-                        if filename == OsStr::new("go.mod") {
-                            let parent_dir = entry
-                                .path()
-                                .parent()
-                                .context(format!(
-                                    "Expected the path '{}' to have a parent when searching for \
-                                 go modules",
-                                    entry.path().display()
-                                ))?
-                                .to_path_buf();
-
-                            let module_name = parent_dir
-                                .file_name()
-                                .context(format!(
-                                    "Expected to find a module name in path '{}'",
-                                    parent_dir.display()
-                                ))?
-                                .to_str()
-                                .context(format!(
-                                    "Found non-UTF-8 character in file path '{}'",
-                                    parent_dir.display(),
-                                ))?
-                                .to_string();
-                            modules.push(module_name)
-                        }
-                    }
+        for dir in self.source_dirs().await? {
+            let go_mod = dir.join("go.mod");
+            if go_mod.is_file() {
+                modules.push(GoModule::load(&go_mod).await?);
+            }
+        }
+        Ok(unique_go_modules(modules)?
+            .into_iter()
+            .map(|(name, dir)| {
+                let relative = dir.strip_prefix(&self.project_dir).unwrap_or(&dir);
+                (name, relative.to_path_buf())
+            })
+            .collect())
+    }
+
+    /// Returns a hash of the modification times of the `sources` directory, of each of the go
+    /// module directories in `module_dirs`, which are relative to the project, and of their
+    /// `go.mod` files. Adding or removing a module at the top of `sources`, or removing or editing
+    /// a known module, changes it, so the go modules only need to be searched for again when it
+    /// changes. Nothing else under `sources` is looked at.
+    pub(crate) async fn sources_fingerprint(&self, module_dirs: &[PathBuf]) -> Result<String> {
+        let mut paths = vec![PathBuf::from("sources")];
+        for dir in module_dirs {
+            paths.push(dir.clone());
+            paths.push(dir.join("go.mod"));
+        }
+        let mut hash = Sha256::default();
+        for path in paths {
+            // A module that was removed is recorded as missing rather than failing.
+            let modified = fs::metadata(self.project_dir.join(&path))
+                .await
+                .ok()
+                .and_then(|metadata| metadata.modified().ok());
+            writeln!(hash, "{} {:?}", path.display(), modified)?;
+        }
+        Ok(hex::encode(hash.finalize()))
+    }
+
+    /// Returns the `sources` directory and every directory below it, in a stable order. Symlinks
+    /// are not followed.
+    async fn source_dirs(&self) -> Result<Vec<PathBuf>> {
+        let mut dirs = Vec::new();
+        let mut remaining = vec![self.project_dir.join("sources")];
+        while let Some(dir) = remaining.pop() {
+            let mut entries = tokio::fs::read_dir(&dir)
+                .await
+                .context(format!("Unable to read directory '{}'", dir.display()))?;
+            let mut subdirs = Vec::new();
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_type().await?.is_dir() {
+                    subdirs.push(entry.path());
                 }
-                Some(Err(e)) => break Err(e).context("Error while searching for go modules"),
-                None => break Ok(()),
             }
-        }?;
-        // Provide a predictable ordering.
-        modules.sort();
-        Ok(modules)
+            // Directory listings are unordered, so sort them for a stable order.
+            subdirs.sort();
+            remaining.extend(subdirs.into_iter().rev());
+            dirs.push(dir);
+        }
+        Ok(dirs)
     }
 
     /// Returns a base64 encoded sha256 hash of the contents of the Project structure.
@@ -208,7 +637,11 @@ impl Project {
         for (key, value) in self.vendor.iter() {
             hash.write(key.to_string().as_bytes())
                 .context("failed to encode vendor name in hash")?;
-            hash.write(value.registry.as_bytes())
+            let registry = self
+                .uninterpolated_registries
+                .get(&key.0)
+                .unwrap_or(&value.registry);
+            hash.write(registry.as_bytes())
                 .context("failed to encode vendor registry in hash")?;
         }
         if let Some(sdk) = self.sdk.as_ref() {
@@ -232,12 +665,132 @@ impl Project {
     }
 }
 
+/// The fields of `Twoliter.toml` whose values may refer to environment variables, as a path of
+/// keys where `*` matches any key. `${NAME}` is replaced with the value of `NAME`, and it is an
+/// error if `NAME` is not set. `${NAME:-default}` is replaced with `default` if `NAME` is unset or
+/// empty. Names of kits and the SDK are not interpolated so that `Twoliter.lock` always describes
+/// the same images.
+const INTERPOLATED_FIELDS: [&[&str]; 6] = [
+    &["vendor", "*", "registry"],
+    &["vendor", "*", "signing", "key"],
+    &["build", "temp-dir"],
+    &["build", "network"],
+    &["build", "shared-cache"],
+    &["build", "tag-suffix"],
+];
+
+/// Returns the registry of each vendor in `toml` by the vendor's name.
+fn vendor_registries(toml: &Table) -> BTreeMap<String, String> {
+    toml.get("vendor")
+        .and_then(Value::as_table)
+        .into_iter()
+        .flatten()
+        .filter_map(|(name, vendor)| {
+            let registry = vendor.get("registry")?.as_str()?;
+            Some((name.clone(), registry.to_string()))
+        })
+        .collect()
+}
+
+/// Replaces references to environment variables in the [`INTERPOLATED_FIELDS`] of `toml`. `lookup`
+/// returns the value of a variable.
+fn interpolate_fields(toml: &mut Table, lookup: &dyn Fn(&str) -> Option<String>) -> Result<()> {
+    for field in INTERPOLATED_FIELDS {
+        interpolate_field(None, toml, field, lookup)?;
+    }
+    Ok(())
+}
+
+/// Interpolates the values in `table` that are found at `field`. `name` is the dotted path of
+/// `table` within `Twoliter.toml`, used in error messages.
+fn interpolate_field(
+    name: Option<&str>,
+    table: &mut Table,
+    field: &[&str],
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<()> {
+    let (first, rest) = match field.split_first() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+    for (key, value) in table.iter_mut() {
+        if *first != "*" && key != first {
+            continue;
+        }
+        let name = match name {
+            Some(name) => format!("{}.{}", name, key),
+            None => key.clone(),
+        };
+        match value {
+            Value::String(s) if rest.is_empty() => {
+                *s = interpolate(s, lookup).context(format!("Unable to interpolate '{}'", name))?;
+            }
+            Value::Table(table) => interpolate_field(Some(&name), table, rest, lookup)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Replaces each `${NAME}` or `${NAME:-default}` in `s`.
+fn interpolate(s: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let mut result = String::new();
+    let mut remaining = s;
+    while let Some(start) = remaining.find("${") {
+        result.push_str(&remaining[..start]);
+        let reference = &remaining[start + 2..];
+        let end = reference
+            .find('}')
+            .context(format!("Unterminated '${{' in '{}'", s))?;
+        let (name, default) = match reference[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&reference[..end], None),
+        };
+        ensure!(
+            !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "Invalid environment variable name '{}' in '{}'",
+            name,
+            s
+        );
+        let value = match (lookup(name), default) {
+            (Some(value), Some(default)) if value.is_empty() => default.to_string(),
+            (Some(value), _) => value,
+            (None, Some(default)) => default.to_string(),
+            (None, None) => bail!(
+                "The environment variable '{}' is not set, use '${{{}:-default}}' to provide a \
+                default",
+                name,
+                name
+            ),
+        };
+        result.push_str(&value);
+        remaining = &reference[end + 1..];
+    }
+    result.push_str(remaining);
+    Ok(result)
+}
+
 /// This represents a container registry vendor that is used in resolving the kits and also
 /// now the bottlerocket sdk
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) struct Vendor {
+    /// May refer to environment variables, see [`INTERPOLATED_FIELDS`].
     pub registry: String,
+    /// How kits published to this vendor are signed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing: Option<Signing>,
+}
+
+/// The `[vendor.<name>.signing]` section of Twoliter.toml.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Signing {
+    /// The key that cosign signs with: a path to a private key, a KMS ARN, or a cosign key URI such
+    /// as `awskms:///<ARN>`. May refer to environment variables, see [`INTERPOLATED_FIELDS`].
+    pub key: String,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -314,6 +867,9 @@ struct UnvalidatedProject {
     sdk: Option<Image>,
     vendor: Option<BTreeMap<ValidIdentifier, Vendor>>,
     kit: Option<Vec<Image>>,
+    build: Option<BuildConfig>,
+    variant: Option<BTreeMap<String, VariantConfig>>,
+    required_twoliter_version: Option<String>,
 }
 
 impl UnvalidatedProject {
@@ -330,6 +886,21 @@ impl UnvalidatedProject {
 
         self.check_vendor_availability().await?;
         self.check_release_toml(&project_dir).await?;
+        if let Some(required) = &self.required_twoliter_version {
+            parse_version_req(required)?;
+        }
+        if let Some(build) = &self.build {
+            go_module_globs(&build.go_modules_exclude)?;
+        }
+        for (name, variant) in self.variant.iter().flatten() {
+            variant
+                .host_containers()
+                .context(format!("Invalid host containers for variant '{}'", name))?;
+            variant
+                .image_layout
+                .validate()
+                .context(format!("Invalid image layout for variant '{}'", name))?;
+        }
 
         Ok(Project {
             filepath,
@@ -338,7 +909,11 @@ impl UnvalidatedProject {
             release_version: self.release_version,
             sdk: self.sdk,
             vendor: self.vendor.unwrap_or_default(),
+            uninterpolated_registries: BTreeMap::new(),
             kit: self.kit.unwrap_or_default(),
+            build: self.build.unwrap_or_default(),
+            variant: self.variant.unwrap_or_default(),
+            required_twoliter_version: self.required_twoliter_version,
         })
     }
 
@@ -409,6 +984,246 @@ impl UnvalidatedProject {
     }
 }
 
+/// The version of this build of Twoliter.
+fn twoliter_version() -> Version {
+    Version::parse(env!("CARGO_PKG_VERSION")).unwrap_or_else(|_| Version::new(0, 0, 0))
+}
+
+fn parse_version_req(required: &str) -> Result<VersionReq> {
+    VersionReq::parse(required).context(format!(
+        "Unable to parse required-twoliter-version '{}', expected a requirement such as \
+        '>=0.4, <0.6'",
+        required
+    ))
+}
+
+/// The lowest version that a comparator allows, or `None` if it only sets an upper bound.
+fn lower_bound(comparator: &Comparator) -> Option<Version> {
+    let version = Version {
+        major: comparator.major,
+        minor: comparator.minor.unwrap_or(0),
+        patch: comparator.patch.unwrap_or(0),
+        pre: comparator.pre.clone(),
+        build: Default::default(),
+    };
+    match comparator.op {
+        Op::Less | Op::LessEq => None,
+        _ => Some(version),
+    }
+}
+
+/// Whether `version` fails `required` because it is too old rather than too new.
+fn needs_upgrade(required: &VersionReq, version: &Version) -> bool {
+    required
+        .comparators
+        .iter()
+        .filter_map(lower_bound)
+        .any(|lower| *version < lower)
+}
+
+/// The lowest version that no longer satisfies a comparator, or `None` if it only sets a lower
+/// bound.
+fn upper_bound(comparator: &Comparator) -> Option<Version> {
+    let (major, minor, patch) = (comparator.major, comparator.minor, comparator.patch);
+    let version = match (comparator.op, minor, patch) {
+        (Op::Greater | Op::GreaterEq, _, _) => return None,
+        (Op::Less, _, _) => {
+            return Some(Version {
+                pre: comparator.pre.clone(),
+                ..Version::new(major, minor.unwrap_or(0), patch.unwrap_or(0))
+            })
+        }
+        (Op::Caret, Some(0), Some(patch)) if major == 0 => Version::new(0, 0, patch + 1),
+        (Op::Caret, Some(minor), _) if major == 0 => Version::new(0, minor + 1, 0),
+        (Op::Caret, _, _) => Version::new(major + 1, 0, 0),
+        (Op::Exact | Op::LessEq, Some(minor), Some(patch)) => Version::new(major, minor, patch + 1),
+        (_, Some(minor), _) => Version::new(major, minor + 1, 0),
+        (_, None, _) => Version::new(major + 1, 0, 0),
+    };
+    Some(version)
+}
+
+/// A requirement that allows `version` along with everything that `required` allowed: from the
+/// lowest version that `required` allows, or `version` if that is lower, up to the next breaking
+/// release after `version`, or the upper bound of `required` if that is higher. A bound that
+/// `required` did not have is not added. A pre-release such as `1.2.3-rc1` only satisfies a
+/// requirement that names `1.2.3`, so it is always the lower bound.
+fn bumped_version_req(required: Option<&VersionReq>, version: &Version) -> VersionReq {
+    let version = Version {
+        build: Default::default(),
+        ..version.clone()
+    };
+    let comparators = required.map_or(&[][..], |req| req.comparators.as_slice());
+    let unbounded = |bound: fn(&Comparator) -> Option<Version>| {
+        required.is_some() && comparators.iter().all(|c| bound(c).is_none())
+    };
+    let lower = if !version.pre.is_empty() {
+        Some(version.clone())
+    } else if unbounded(lower_bound) {
+        None
+    } else {
+        comparators
+            .iter()
+            .filter_map(lower_bound)
+            .chain([version.clone()])
+            .min()
+    };
+    let next_breaking = if version.major == 0 {
+        Version::new(0, version.minor + 1, 0)
+    } else {
+        Version::new(version.major + 1, 0, 0)
+    };
+    let upper = if unbounded(upper_bound) {
+        None
+    } else {
+        comparators
+            .iter()
+            .filter_map(upper_bound)
+            .chain([next_breaking])
+            .max()
+    };
+    let comparator = |op, bound: Version| Comparator {
+        op,
+        major: bound.major,
+        minor: Some(bound.minor),
+        patch: Some(bound.patch),
+        pre: bound.pre,
+    };
+    VersionReq {
+        comparators: [
+            lower.map(|lower| comparator(Op::GreaterEq, lower)),
+            upper.map(|upper| comparator(Op::Less, upper)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect(),
+    }
+}
+
+/// Adds `tag_suffix` to the pre-release of `version`, so that the result can still be parsed as a
+/// semantic version and used in image tags. Characters other than ASCII letters, digits, `.` and `-`
+/// are replaced with `-`, e.g. `feature/thing` becomes `feature-thing`.
+fn with_tag_suffix(version: &str, tag_suffix: &str) -> Result<String> {
+    let sanitized: String = tag_suffix
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    ensure!(!sanitized.is_empty(), "The tag suffix may not be empty");
+    let mut parsed = Version::parse(version)
+        .context(format!("Unable to parse release version '{}'", version))?;
+    let pre = if parsed.pre.is_empty() {
+        sanitized.clone()
+    } else {
+        format!("{}.{}", parsed.pre, sanitized)
+    };
+    parsed.pre = Prerelease::new(&pre).context(format!(
+        "Invalid tag suffix '{}', it must be one or more '.' separated parts made of letters, \
+        digits and '-', and numeric parts may not have leading zeros",
+        tag_suffix
+    ))?;
+    Ok(parsed.to_string())
+}
+
+/// Compiles the `go-modules-exclude` patterns of Twoliter.toml.
+fn go_module_globs(patterns: &[String]) -> Result<GlobSet> {
+    let mut globs = GlobSetBuilder::new();
+    for pattern in patterns {
+        globs.add(Glob::new(pattern).context(format!(
+            "Invalid pattern '{}' in go-modules-exclude",
+            pattern
+        ))?);
+    }
+    globs
+        .build()
+        .context("Unable to compile the patterns in go-modules-exclude")
+}
+
+/// A directory in `sources` with a `go.mod` file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct GoModule {
+    name: String,
+    dir: PathBuf,
+    /// The path given by the `module` directive of `go.mod`.
+    import_path: Option<String>,
+}
+
+impl GoModule {
+    async fn load(go_mod: &Path) -> Result<Self> {
+        let dir = go_mod
+            .parent()
+            .context(format!(
+                "Expected the path '{}' to have a parent when searching for go modules",
+                go_mod.display()
+            ))?
+            .to_path_buf();
+        let name = dir
+            .file_name()
+            .context(format!(
+                "Expected to find a module name in path '{}'",
+                dir.display()
+            ))?
+            .to_str()
+            .context(format!(
+                "Found non-UTF-8 character in file path '{}'",
+                dir.display(),
+            ))?
+            .to_string();
+        let import_path = fs::read_to_string(go_mod)
+            .await?
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("module "))
+            .map(|path| {
+                // The directive may be followed by a comment.
+                let path = path.split("//").next().unwrap_or_default();
+                path.trim().trim_matches('"').to_string()
+            });
+        Ok(Self {
+            name,
+            dir,
+            import_path,
+        })
+    }
+}
+
+/// Returns the directories of `modules` by name, dropping any directory that was found twice. Two
+/// different directories with the same name or import path are an error.
+fn unique_go_modules(mut modules: Vec<GoModule>) -> Result<BTreeMap<String, PathBuf>> {
+    modules.sort_by(|a, b| (&a.name, &a.dir).cmp(&(&b.name, &b.dir)));
+    modules.dedup_by(|a, b| a.dir == b.dir);
+    let mut names: BTreeMap<&str, &Path> = BTreeMap::new();
+    let mut import_paths: BTreeMap<&str, &Path> = BTreeMap::new();
+    for module in &modules {
+        if let Some(other) = names.insert(&module.name, &module.dir) {
+            bail!(
+                "Found two go modules named '{}', in '{}' and '{}'",
+                module.name,
+                other.display(),
+                module.dir.display()
+            );
+        }
+        if let Some(import_path) = &module.import_path {
+            if let Some(other) = import_paths.insert(import_path, &module.dir) {
+                bail!(
+                    "Found two go modules with the import path '{}', in '{}' and '{}'",
+                    import_path,
+                    other.display(),
+                    module.dir.display()
+                );
+            }
+        }
+    }
+    Ok(names
+        .into_iter()
+        .map(|(name, dir)| (name.to_string(), dir.to_path_buf()))
+        .collect())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -477,6 +1292,104 @@ mod test {
         assert_eq!(project.filepath(), twoliter_toml_path);
     }
 
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "REGISTRY" => Some("registry.example.com".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_interpolate() {
+        assert_eq!(
+            interpolate("${REGISTRY}/bottlerocket", &lookup).unwrap(),
+            "registry.example.com/bottlerocket"
+        );
+        assert_eq!(
+            interpolate("${UNSET:-public.ecr.aws}/${REGISTRY}", &lookup).unwrap(),
+            "public.ecr.aws/registry.example.com"
+        );
+        assert_eq!(
+            interpolate("${EMPTY:-default}", &lookup).unwrap(),
+            "default"
+        );
+        assert_eq!(interpolate("${EMPTY}", &lookup).unwrap(), "");
+        assert_eq!(
+            interpolate("no references", &lookup).unwrap(),
+            "no references"
+        );
+
+        let err = interpolate("${UNSET}", &lookup).unwrap_err().to_string();
+        assert!(err.contains("'UNSET' is not set"), "{}", err);
+        assert!(interpolate("${REGISTRY", &lookup).is_err());
+        assert!(interpolate("${}", &lookup).is_err());
+        assert!(interpolate("${1X}", &lookup).is_err());
+    }
+
+    #[test]
+    fn test_interpolate_fields() {
+        let mut toml: Table = toml::from_str(
+            r#"
+            release-version = "${REGISTRY}"
+
+            [vendor.a]
+            registry = "${REGISTRY}/a"
+
+            [vendor.b]
+            registry = "${UNSET:-b.example.com}"
+
+            [build]
+            network = "${UNSET:-none}"
+            "#,
+        )
+        .unwrap();
+        interpolate_fields(&mut toml, &lookup).unwrap();
+        assert_eq!(toml["release-version"].as_str(), Some("${REGISTRY}"));
+        assert_eq!(
+            toml["vendor"]["a"]["registry"].as_str(),
+            Some("registry.example.com/a")
+        );
+        assert_eq!(
+            toml["vendor"]["b"]["registry"].as_str(),
+            Some("b.example.com")
+        );
+        assert_eq!(toml["build"]["network"].as_str(), Some("none"));
+
+        let mut toml: Table = toml::from_str("[vendor.a]\nregistry = \"${UNSET}\"").unwrap();
+        let err = format!("{:#}", interpolate_fields(&mut toml, &lookup).unwrap_err());
+        assert!(err.contains("'vendor.a.registry'"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_digest_uninterpolated() {
+        let tempdir = TempDir::new().unwrap();
+        let path = tempdir.path().join("Twoliter.toml");
+        let data = fs::read_to_string(data_dir().join("Twoliter-1.toml"))
+            .await
+            .unwrap()
+            .replace(
+                "registry = \"a.com/b\"",
+                "registry = \"${TWOLITER_TEST_UNSET_REGISTRY:-a.com}/b\"",
+            );
+        fs::write(&path, data).await.unwrap();
+        let project = Project::load(&path).await.unwrap();
+        let vendor = ValidIdentifier("my-vendor".into());
+        assert_eq!(project.vendor[&vendor].registry, "a.com/b");
+
+        // The digest is the same wherever the registry resolves to.
+        let mut elsewhere = project.clone();
+        elsewhere.vendor.get_mut(&vendor).unwrap().registry = "c.com/b".into();
+        assert_eq!(project.digest().unwrap(), elsewhere.digest().unwrap());
+
+        // But it changes with what is written in Twoliter.toml.
+        let mut edited = project.clone();
+        edited
+            .uninterpolated_registries
+            .insert("my-vendor".into(), "${OTHER:-a.com}/b".into());
+        assert_ne!(project.digest().unwrap(), edited.digest().unwrap());
+    }
+
     #[tokio::test]
     async fn test_release_toml_check_error() {
         let tempdir = TempDir::new().unwrap();
@@ -514,6 +1427,7 @@ mod test {
                 ValidIdentifier("not-bottlerocket".into()),
                 Vendor {
                     registry: "public.ecr.aws/not-bottlerocket".into(),
+                    signing: None,
                 },
             )])),
             kit: Some(vec![Image {
@@ -521,6 +1435,9 @@ mod test {
                 version: Version::new(1, 20, 0),
                 vendor: ValidIdentifier("not-bottlerocket".into()),
             }]),
+            build: None,
+            variant: None,
+            required_twoliter_version: None,
         };
         assert!(project.check_vendor_availability().await.is_err());
     }
@@ -545,6 +1462,94 @@ mod test {
         Project::find_and_load(p).await.unwrap();
     }
 
+    #[tokio::test]
+    async fn temp_dir_base() {
+        let path = data_dir().join("Twoliter-1.toml");
+        let project = Project::load(path).await.unwrap();
+        // The project directory is used by default.
+        assert_eq!(project.resolve_temp_dir_base(None), data_dir());
+
+        // The Twoliter.toml setting is relative to the project directory.
+        let project = Project {
+            build: BuildConfig {
+                temp_dir: Some(PathBuf::from("build/tmp")),
+                ..Default::default()
+            },
+            ..project
+        };
+        assert_eq!(
+            project.resolve_temp_dir_base(None),
+            data_dir().join("build/tmp")
+        );
+
+        // The environment variable wins.
+        assert_eq!(
+            project.resolve_temp_dir_base(Some(PathBuf::from("/elsewhere"))),
+            PathBuf::from("/elsewhere")
+        );
+    }
+
+    #[tokio::test]
+    async fn shared_cache() {
+        let path = data_dir().join("Twoliter-1.toml");
+        let project = Project::load(path).await.unwrap();
+        assert_eq!(project.resolve_shared_cache(None), None);
+
+        let project = Project {
+            build: BuildConfig {
+                shared_cache: Some(PathBuf::from("../cache")),
+                ..Default::default()
+            },
+            ..project
+        };
+        assert_eq!(
+            project.resolve_shared_cache(None),
+            Some(data_dir().join("../cache"))
+        );
+        assert_eq!(
+            project.resolve_shared_cache(Some(PathBuf::from("/shared"))),
+            Some(PathBuf::from("/shared"))
+        );
+    }
+
+    #[tokio::test]
+    async fn cargo_home() {
+        let path = data_dir().join("Twoliter-1.toml");
+        let project = Project::load(path).await.unwrap();
+        assert_eq!(project.cargo_home(), None);
+
+        let project = Project {
+            build: BuildConfig {
+                cargo_home: Some(PathBuf::from(".cargo")),
+                ..Default::default()
+            },
+            ..project
+        };
+        assert_eq!(project.cargo_home(), Some(data_dir().join(".cargo")));
+    }
+
+    #[tokio::test]
+    async fn lookaside_caches() {
+        let path = data_dir().join("Twoliter-1.toml");
+        let project = Project::load(path).await.unwrap();
+        assert!(project.lookaside_caches().is_empty());
+
+        let project = Project {
+            build: toml::from_str(
+                r#"lookaside-caches = ["mirror", "https://cache.bottlerocket.aws"]"#,
+            )
+            .unwrap(),
+            ..project
+        };
+        assert_eq!(
+            project.lookaside_caches(),
+            [
+                data_dir().join("mirror").display().to_string(),
+                "https://cache.bottlerocket.aws".to_string(),
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn find_go_modules() {
         let twoliter_toml_path = projects_dir().join("project1").join("Twoliter.toml");
@@ -553,4 +1558,302 @@ mod test {
         assert_eq!(go_modules.len(), 1, "Expected to find 1 go module");
         assert_eq!(go_modules.first().unwrap(), "hello-go");
     }
+
+    #[test]
+    fn required_twoliter_version() {
+        let required = parse_version_req(">=0.4, <0.6").unwrap();
+        let v = |s: &str| Version::parse(s).unwrap();
+        assert!(!required.matches(&v("0.3.9")));
+        assert!(required.matches(&v("0.4.0")));
+        assert!(required.matches(&v("0.5.99")));
+        assert!(!required.matches(&v("0.6.0")));
+        // Pre-releases only satisfy a requirement that names a pre-release of the same version.
+        assert!(!required.matches(&v("0.6.0-rc1")));
+        assert!(!required.matches(&v("0.5.0-rc1")));
+        assert!(parse_version_req(">=0.5.0-rc1, <0.6")
+            .unwrap()
+            .matches(&v("0.5.0-rc1")));
+
+        let pinned = parse_version_req("=0.5.1").unwrap();
+        assert!(pinned.matches(&v("0.5.1")));
+        assert!(!pinned.matches(&v("0.5.2")));
+
+        assert!(needs_upgrade(&required, &v("0.3.9")));
+        assert!(!needs_upgrade(&required, &v("0.6.0")));
+        assert!(needs_upgrade(&pinned, &v("0.5.0")));
+        assert!(!needs_upgrade(&pinned, &v("0.5.2")));
+
+        assert!(parse_version_req("0.4 to 0.6").is_err());
+    }
+
+    #[test]
+    fn bump_required_twoliter_version() {
+        let v = |s: &str| Version::parse(s).unwrap();
+        let bump = |required: Option<&str>, version: &str| {
+            let required = required.map(|req| parse_version_req(req).unwrap());
+            let bumped = bumped_version_req(required.as_ref(), &v(version));
+            assert!(
+                bumped.matches(&v(version)),
+                "{} does not allow {}",
+                bumped,
+                version
+            );
+            bumped.to_string()
+        };
+        assert_eq!(bump(Some(">=0.4, <0.6"), "0.6.1"), ">=0.4.0, <0.7.0");
+        assert_eq!(bump(Some(">=0.4, <0.6"), "0.3.0"), ">=0.3.0, <0.6.0");
+        assert_eq!(bump(Some("=0.5.1"), "0.5.2"), ">=0.5.1, <0.6.0");
+        assert_eq!(bump(None, "1.2.3-rc1"), ">=1.2.3-rc1, <2.0.0");
+        assert_eq!(bump(Some("^1.3"), "1.2.3-rc1"), ">=1.2.3-rc1, <2.0.0");
+
+        // A downgrade keeps what was allowed before.
+        assert_eq!(bump(Some("=0.5.1"), "0.3.0"), ">=0.3.0, <0.5.2");
+        assert_eq!(bump(Some(">=0.7"), "0.5.0"), ">=0.5.0");
+        assert_eq!(bump(Some("<0.5"), "0.6.0"), "<0.7.0");
+        assert_eq!(bump(Some("~0.7.2"), "0.5.0"), ">=0.5.0, <0.8.0");
+        for (required, version, allowed) in [
+            ("=0.5.1", "0.3.0", "0.5.1"),
+            (">=0.7", "0.5.0", "1.0.0"),
+            ("<0.5", "0.6.0", "0.1.0"),
+            ("~0.7.2", "0.5.0", "0.7.9"),
+            (">=0.4, <0.6", "0.3.0", "0.5.9"),
+        ] {
+            let bumped =
+                bumped_version_req(Some(&parse_version_req(required).unwrap()), &v(version));
+            assert!(
+                bumped.matches(&v(allowed)),
+                "{} dropped {}",
+                bumped,
+                allowed
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn find_duplicate_go_modules() {
+        let tempdir = TempDir::new().unwrap();
+        let p = tempdir.path();
+        fs::copy(data_dir().join("Twoliter-1.toml"), p.join("Twoliter.toml"))
+            .await
+            .unwrap();
+        let project = Project::find_and_load(p).await.unwrap();
+        for (dir, import_path) in [
+            ("sources/hello-go", "example.com/hello"),
+            ("sources/vendored/hello-go", "example.com/vendored/hello"),
+        ] {
+            fs::create_dir_all(p.join(dir)).await.unwrap();
+            fs::write(
+                p.join(dir).join("go.mod"),
+                format!("module {}\n\ngo 1.21\n", import_path),
+            )
+            .await
+            .unwrap();
+        }
+        let err = project.find_go_modules().await.unwrap_err().to_string();
+        assert!(err.contains("two go modules named 'hello-go'"), "{}", err);
+        assert!(err.contains("sources/hello-go'"), "{}", err);
+        assert!(err.contains("sources/vendored/hello-go'"), "{}", err);
+
+        fs::remove_dir_all(p.join("sources/vendored"))
+            .await
+            .unwrap();
+        fs::create_dir_all(p.join("sources/hello-again"))
+            .await
+            .unwrap();
+        fs::write(
+            p.join("sources/hello-again/go.mod"),
+            "module \"example.com/hello\"\n",
+        )
+        .await
+        .unwrap();
+        let err = project.find_go_modules().await.unwrap_err().to_string();
+        assert!(err.contains("import path 'example.com/hello'"), "{}", err);
+    }
+
+    #[test]
+    fn tag_suffix() {
+        assert_eq!(
+            with_tag_suffix("1.22.0", "pr-123").unwrap(),
+            "1.22.0-pr-123"
+        );
+        assert_eq!(
+            with_tag_suffix("1.22.0", "feature/new_thing").unwrap(),
+            "1.22.0-feature-new-thing"
+        );
+        assert_eq!(
+            with_tag_suffix("1.22.0-rc1", "pr-123").unwrap(),
+            "1.22.0-rc1.pr-123"
+        );
+        assert!(with_tag_suffix("1.22.0", "").is_err());
+        assert!(with_tag_suffix("1.22.0", "pr..1").is_err());
+        assert!(with_tag_suffix("1.22.0", "007").is_err());
+
+        // The versions that buildsys and pubsys are given must still parse.
+        for suffix in ["pr-123", "feature/new_thing", "ci.42"] {
+            let version = with_tag_suffix("1.22.0", suffix).unwrap();
+            let parsed = Version::parse(&version).unwrap();
+            assert_eq!((parsed.major, parsed.minor, parsed.patch), (1, 22, 0));
+            assert!(Version::parse(&format!("{}-{}", version, "abcdef0")).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn exclude_go_modules() {
+        assert!(go_module_globs(&["hello-[".to_string()]).is_err());
+
+        let path = data_dir().join("Twoliter-1.toml");
+        let project = Project::load(path).await.unwrap();
+        let project = Project {
+            build: BuildConfig {
+                go_modules_exclude: ["broken-*", "old", "h?llo-{go,rs}"]
+                    .map(String::from)
+                    .to_vec(),
+                ..Default::default()
+            },
+            ..project
+        };
+        let modules = ["broken-agent", "hello-go", "hello-py", "old", "older"].map(String::from);
+        assert_eq!(
+            project.exclude_go_modules(modules.to_vec()).unwrap(),
+            vec!["hello-py".to_string(), "older".to_string()]
+        );
+    }
+
+    #[test]
+    fn unique_go_modules_dedup() {
+        let module = GoModule {
+            name: "hello-go".to_string(),
+            dir: PathBuf::from("/project/sources/hello-go"),
+            import_path: Some("example.com/hello".to_string()),
+        };
+        assert_eq!(
+            unique_go_modules(vec![module.clone(), module]).unwrap(),
+            BTreeMap::from([(
+                "hello-go".to_string(),
+                PathBuf::from("/project/sources/hello-go")
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sources_fingerprint() {
+        let tempdir = TempDir::new().unwrap();
+        let p = tempdir.path();
+        fs::copy(data_dir().join("Twoliter-1.toml"), p.join("Twoliter.toml"))
+            .await
+            .unwrap();
+        fs::create_dir_all(p.join("sources/hello-go"))
+            .await
+            .unwrap();
+        fs::write(
+            p.join("sources/hello-go/go.mod"),
+            "module example.com/hello\n",
+        )
+        .await
+        .unwrap();
+        fs::create_dir_all(p.join("sources/hello-go/cmd"))
+            .await
+            .unwrap();
+        let project = Project::find_and_load(p).await.unwrap();
+        let dirs: Vec<_> = project
+            .find_go_module_dirs()
+            .await
+            .unwrap()
+            .into_values()
+            .collect();
+        assert_eq!(dirs, [PathBuf::from("sources/hello-go")]);
+
+        let fingerprint = project.sources_fingerprint(&dirs).await.unwrap();
+        assert_eq!(
+            fingerprint,
+            project.sources_fingerprint(&dirs).await.unwrap()
+        );
+
+        // Directories below a module are not looked at.
+        fs::create_dir(p.join("sources/hello-go/cmd/hello"))
+            .await
+            .unwrap();
+        assert_eq!(
+            fingerprint,
+            project.sources_fingerprint(&dirs).await.unwrap()
+        );
+
+        // Editing a go.mod in place changes it, so that its import path is checked again.
+        fs::write(
+            p.join("sources/hello-go/go.mod"),
+            "module example.com/other\n",
+        )
+        .await
+        .unwrap();
+        let fingerprint_edited = project.sources_fingerprint(&dirs).await.unwrap();
+        assert_ne!(fingerprint, fingerprint_edited);
+
+        // So does adding a module.
+        fs::create_dir(p.join("sources/other-go")).await.unwrap();
+        assert_ne!(
+            fingerprint_edited,
+            project.sources_fingerprint(&dirs).await.unwrap()
+        );
+    }
+
+    #[test]
+    fn host_containers() {
+        let config: VariantConfig = toml::from_str(
+            r#"
+            [host-containers]
+            admin = "public.ecr.aws/bottlerocket/bottlerocket-admin:v0.11.0"
+            control-plane = "example.com/control@sha256:0000000000000000000000000000000000000000000000000000000000000000"
+            "#,
+        )
+        .unwrap();
+        let containers = config.host_containers().unwrap();
+        assert_eq!(containers["admin"].tag, "v0.11.0");
+        assert!(containers["control-plane"].digest.is_some());
+
+        let config: VariantConfig =
+            toml::from_str("host-containers = { admin = \"admin\" }").unwrap();
+        assert!(config.host_containers().is_err());
+        let config: VariantConfig =
+            toml::from_str("host-containers = { \"a b\" = \"admin:v1\" }").unwrap();
+        assert!(config.host_containers().is_err());
+    }
+
+    #[tokio::test]
+    async fn detect_monorepo() {
+        let checkout = data_dir().join("bottlerocket-checkout");
+        assert!(monorepo_markers(&checkout).len() >= MONOREPO_MIN_MARKERS);
+
+        // An ordinary project with a Release.toml, a Makefile.toml and variants copied from the
+        // monorepo is not mistaken for it.
+        let project_dir = data_dir().join("out-of-tree-project");
+        assert!(monorepo_markers(&project_dir).is_empty());
+        let project = Project::load(project_dir.join(TWOLITER_TOML))
+            .await
+            .unwrap();
+        project.check_monorepo(false).unwrap();
+
+        // A project file in the checkout is refused unless it is allowed.
+        let temp_dir = TempDir::new().unwrap();
+        let copy = temp_dir.path().join("bottlerocket");
+        for dir in ["tools/buildsys", "sources/api"] {
+            fs::create_dir_all(copy.join(dir)).await.unwrap();
+        }
+        fs::copy(checkout.join("Makefile.toml"), copy.join("Makefile.toml"))
+            .await
+            .unwrap();
+        for file in [TWOLITER_TOML, "Release.toml"] {
+            fs::copy(project_dir.join(file), copy.join(file))
+                .await
+                .unwrap();
+        }
+        let project = Project::load(copy.join(TWOLITER_TOML)).await.unwrap();
+        let err = project.check_monorepo(false).unwrap_err().to_string();
+        assert!(err.contains("twoliter migrate"), "{}", err);
+        assert!(err.contains("--allow-monorepo"), "{}", err);
+        project.check_monorepo(true).unwrap();
+
+        // The build tools alone are not enough once the API sources are part of a kit.
+        fs::create_dir_all(copy.join("kits")).await.unwrap();
+        project.check_monorepo(false).unwrap();
+    }
 }
@@ -0,0 +1,264 @@
+/*!
+
+The Twoliter runs in a project share its `build` directory: the tools in `build/tools`, the RPMs in
+`build/rpms` and the tag of the env image. The steps that write to them hold an advisory lock on
+`build/.twoliter.lock`, so that two builds in the same checkout take turns rather than interleave
+their writes. Long steps that are safe to run side by side, such as the cargo build of a kit, run
+without that lock, but hold a share of `build/.twoliter-builds.lock` for as long as the build runs.
+`build clean` takes that lock for itself, so that it waits for the running builds rather than
+removing files from under them. A command that takes both locks takes the builds lock first, so
+that two commands never wait for each other.
+
+The lock is a `flock` of the file, which the kernel releases when the process that holds it exits,
+however that happens. So the lock of a process that died never holds up another run; the PID that
+it wrote stays in the file until the next holder replaces it.
+
+!*/
+
+use crate::common::fs;
+use anyhow::{bail, Context, Result};
+use log::info;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// The name of the lock file in the project's `build` directory.
+pub(crate) const PROJECT_LOCK: &str = ".twoliter.lock";
+
+/// The name of the lock file that running builds share and that `build clean` takes for itself.
+pub(crate) const BUILDS_LOCK: &str = ".twoliter-builds.lock";
+
+/// How often a run that is waiting for the lock tries to take it.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A lock of a project, which is held until it is dropped.
+#[derive(Debug)]
+pub(crate) struct ProjectLock {
+    _file: Flock<std::fs::File>,
+}
+
+impl ProjectLock {
+    /// Takes the lock in `build_dir`, waiting up to `timeout` for the run that holds it, or for as
+    /// long as it takes if `timeout` is `None`.
+    pub(crate) async fn acquire(build_dir: &Path, timeout: Option<Duration>) -> Result<Self> {
+        Self::wait(
+            &build_dir.join(PROJECT_LOCK),
+            FlockArg::LockExclusiveNonblock,
+            timeout,
+        )
+        .await
+    }
+
+    /// Takes a share of the builds lock in `build_dir`, which other builds may hold at the same
+    /// time, waiting up to `timeout` for a clean to finish.
+    pub(crate) async fn acquire_build(build_dir: &Path, timeout: Option<Duration>) -> Result<Self> {
+        Self::wait(
+            &build_dir.join(BUILDS_LOCK),
+            FlockArg::LockSharedNonblock,
+            timeout,
+        )
+        .await
+    }
+
+    /// Takes the builds lock in `build_dir` for this run alone, waiting up to `timeout` for the
+    /// running builds to finish.
+    pub(crate) async fn acquire_clean(build_dir: &Path, timeout: Option<Duration>) -> Result<Self> {
+        Self::wait(
+            &build_dir.join(BUILDS_LOCK),
+            FlockArg::LockExclusiveNonblock,
+            timeout,
+        )
+        .await
+    }
+
+    /// Takes the lock at `path` as `arg` says, waiting up to `timeout` for the runs that hold it.
+    async fn wait(path: &Path, arg: FlockArg, timeout: Option<Duration>) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let start = Instant::now();
+        let mut waiting_for = None;
+        loop {
+            if let Some(lock) = try_lock(path, arg)? {
+                return Ok(lock);
+            }
+            let holder = holder(path);
+            if let Some(timeout) = timeout {
+                if start.elapsed() >= timeout {
+                    bail!(
+                        "Timed out after {} waiting for the lock on '{}' held by {}, another \
+                        Twoliter command is running in this project. Use --lock-timeout to wait \
+                        longer.",
+                        humantime::format_duration(timeout),
+                        path.display(),
+                        holder
+                    );
+                }
+            }
+            if waiting_for.as_ref() != Some(&holder) {
+                info!(
+                    "Waiting for the lock on '{}' held by {}",
+                    path.display(),
+                    holder
+                );
+                waiting_for = Some(holder);
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Takes the lock at `path` as `arg` says if no other run holds it in a way that conflicts. The
+/// holder of an exclusive lock writes the PID of its process into it, and the holders of a shared
+/// lock leave it empty.
+fn try_lock(path: &Path, arg: FlockArg) -> Result<Option<ProjectLock>> {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .context(format!("Unable to open lock file '{}'", path.display()))?;
+    let file = match Flock::lock(file, arg) {
+        Ok(file) => file,
+        Err((_, Errno::EWOULDBLOCK)) => return Ok(None),
+        Err((_, e)) => {
+            return Err(e).context(format!("Unable to lock '{}'", path.display()));
+        }
+    };
+    // The file may have been replaced between opening and locking it, and then this lock would not
+    // keep anyone else out.
+    let locked = file
+        .metadata()
+        .context(format!("Unable to read metadata of '{}'", path.display()))?;
+    match std::fs::metadata(path) {
+        Ok(current) if current.dev() == locked.dev() && current.ino() == locked.ino() => {}
+        _ => return Ok(None),
+    }
+    let written = if arg == FlockArg::LockExclusiveNonblock {
+        file.set_len(0)
+            .and_then(|()| writeln!(&*file, "{}", std::process::id()))
+    } else {
+        file.set_len(0)
+    };
+    written.context(format!("Unable to write to lock file '{}'", path.display()))?;
+    Ok(Some(ProjectLock { _file: file }))
+}
+
+/// Describes the run that holds the lock at `path` by the PID that it wrote.
+fn holder(path: &Path) -> String {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|pid| pid.trim().parse::<u32>().ok())
+        .map(|pid| format!("PID {}", pid))
+        .unwrap_or_else(|| "another process, such as a running build".to_string())
+}
+
+#[tokio::test]
+async fn test_project_lock() {
+    use std::sync::{Arc, Mutex};
+
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let build_dir = temp_dir.path().join("build");
+    let lock = ProjectLock::acquire(&build_dir, None).await.unwrap();
+    let pid = std::process::id().to_string();
+    let path = build_dir.join(PROJECT_LOCK);
+    assert_eq!(std::fs::read_to_string(&path).unwrap().trim(), pid);
+    let err = ProjectLock::acquire(&build_dir, Some(Duration::from_millis(300)))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains(&format!("held by PID {}", pid)));
+    drop(lock);
+
+    // Runs that contend for the lock take turns.
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let tasks: Vec<_> = (0..2)
+        .map(|task| {
+            let build_dir = build_dir.clone();
+            let events = Arc::clone(&events);
+            tokio::spawn(async move {
+                let _lock = ProjectLock::acquire(&build_dir, Some(Duration::from_secs(10)))
+                    .await
+                    .unwrap();
+                events.lock().unwrap().push((task, "start"));
+                sleep(Duration::from_millis(300)).await;
+                events.lock().unwrap().push((task, "end"));
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let events = events.lock().unwrap().clone();
+    assert_eq!(events.len(), 4);
+    for turn in events.chunks(2) {
+        assert_eq!(turn[0].0, turn[1].0);
+        assert_eq!((turn[0].1, turn[1].1), ("start", "end"));
+    }
+    assert_ne!(events[0].0, events[2].0);
+}
+
+#[tokio::test]
+async fn test_builds_lock() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let build_dir = temp_dir.path();
+    let timeout = Some(Duration::from_millis(300));
+
+    // Builds share the lock, and a clean waits for all of them.
+    let first = ProjectLock::acquire_build(build_dir, timeout)
+        .await
+        .unwrap();
+    let second = ProjectLock::acquire_build(build_dir, timeout)
+        .await
+        .unwrap();
+    assert!(ProjectLock::acquire_clean(build_dir, timeout)
+        .await
+        .is_err());
+    drop(first);
+    assert!(ProjectLock::acquire_clean(build_dir, timeout)
+        .await
+        .is_err());
+    drop(second);
+
+    // A build waits for a clean, which is named by its PID.
+    let clean = ProjectLock::acquire_clean(build_dir, timeout)
+        .await
+        .unwrap();
+    let err = ProjectLock::acquire_build(build_dir, timeout)
+        .await
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains(&format!("held by PID {}", std::process::id())));
+    // The builds lock does not keep out the steps that take the project lock.
+    ProjectLock::acquire(build_dir, Some(Duration::ZERO))
+        .await
+        .unwrap();
+    drop(clean);
+    ProjectLock::acquire_build(build_dir, timeout)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_project_lock_stale() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let mut child = std::process::Command::new("true").spawn().unwrap();
+    let dead = child.id();
+    child.wait().unwrap();
+    let path = temp_dir.path().join(PROJECT_LOCK);
+    std::fs::write(&path, format!("{}\n", dead)).unwrap();
+
+    // The lock that the dead process left behind is taken without waiting.
+    let _lock = ProjectLock::acquire(temp_dir.path(), Some(Duration::ZERO))
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap().trim(),
+        std::process::id().to_string()
+    );
+}
@@ -0,0 +1,218 @@
+/*!
+
+Build provenance for variant images. After a build, an [in-toto] statement with a [SLSA provenance]
+predicate is written next to the images. Its subjects are the artifacts from `SHA256SUMS`, and its
+materials are the SDK, the kits and the commit of the project that the images were built from.
+Nothing here needs network access, except signing with a remote key.
+
+[in-toto]: https://github.com/in-toto/attestation
+[SLSA provenance]: https://slsa.dev/provenance/v0.2
+
+!*/
+
+use crate::checksums::Checksums;
+use crate::common::{exec_log, fs};
+use crate::lock::FetchedImage;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::process::Command;
+
+/// The in-toto statement written next to a variant's images.
+pub(crate) const PROVENANCE: &str = "provenance.intoto.json";
+
+/// The signature of [`PROVENANCE`] written by `cosign sign-blob`.
+pub(crate) const PROVENANCE_SIG: &str = "provenance.intoto.json.sig";
+
+const STATEMENT_TYPE: &str = "https://in-toto.io/Statement/v0.1";
+const PREDICATE_TYPE: &str = "https://slsa.dev/provenance/v0.2";
+const BUILD_TYPE: &str = "twoliter build variant";
+
+/// What Twoliter knows about a finished build.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BuildMetadata {
+    /// The options that the build was run with, e.g. `arch = x86_64`.
+    pub(crate) parameters: BTreeMap<String, String>,
+    /// The SDK and the external kits, with the digests of the images that the build used.
+    pub(crate) images: Vec<FetchedImage>,
+    /// Where the project came from, e.g. the URL of its git remote, and the commit that was built.
+    pub(crate) git: Option<(String, String)>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub(crate) struct Statement {
+    #[serde(rename = "_type")]
+    statement_type: String,
+    subject: Vec<Subject>,
+    #[serde(rename = "predicateType")]
+    predicate_type: String,
+    predicate: Predicate,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+struct Subject {
+    name: String,
+    digest: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Predicate {
+    builder: Builder,
+    build_type: String,
+    invocation: Invocation,
+    materials: Vec<Material>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+struct Builder {
+    id: String,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+struct Invocation {
+    parameters: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+struct Material {
+    uri: String,
+    digest: BTreeMap<String, String>,
+}
+
+/// Builds the provenance statement for the artifacts in `checksums`.
+pub(crate) fn statement(checksums: &Checksums, metadata: &BuildMetadata) -> Result<Statement> {
+    let subject = checksums
+        .artifacts
+        .iter()
+        .map(|artifact| Subject {
+            name: artifact.path.clone(),
+            digest: BTreeMap::from([("sha256".to_string(), artifact.sha256.clone())]),
+        })
+        .collect();
+
+    let mut materials = Vec::new();
+    for image in &metadata.images {
+        let (algorithm, digest) = image.digest.split_once(':').context(format!(
+            "Unable to parse the digest '{}' of '{}'",
+            image.digest, image.uri
+        ))?;
+        materials.push(Material {
+            uri: image.uri.clone(),
+            digest: BTreeMap::from([(algorithm.to_string(), digest.to_string())]),
+        });
+    }
+    if let Some((uri, commit)) = &metadata.git {
+        materials.push(Material {
+            uri: format!("git+{}", uri),
+            digest: BTreeMap::from([("sha1".to_string(), commit.clone())]),
+        });
+    }
+
+    Ok(Statement {
+        statement_type: STATEMENT_TYPE.to_string(),
+        subject,
+        predicate_type: PREDICATE_TYPE.to_string(),
+        predicate: Predicate {
+            builder: Builder {
+                id: format!("twoliter v{}", env!("CARGO_PKG_VERSION")),
+            },
+            build_type: BUILD_TYPE.to_string(),
+            invocation: Invocation {
+                parameters: metadata.parameters.clone(),
+            },
+            materials,
+        },
+    })
+}
+
+/// Writes `statement` to [`PROVENANCE`] in `dir`. If `sign_key` is given, `cosign` signs it with that
+/// key, which may be a file or a KMS URI, and the signature is written to [`PROVENANCE_SIG`].
+pub(crate) async fn write_provenance(
+    dir: &Path,
+    statement: &Statement,
+    sign_key: Option<&str>,
+) -> Result<()> {
+    let path = dir.join(PROVENANCE);
+    let json =
+        serde_json::to_string_pretty(statement).context("Unable to serialize the provenance")?;
+    fs::write(&path, json).await?;
+    let Some(key) = sign_key else {
+        return Ok(());
+    };
+    if Command::new("cosign")
+        .arg("version")
+        .output()
+        .await
+        .is_err()
+    {
+        warn!(
+            "Unable to sign '{}' because cosign is not installed",
+            path.display()
+        );
+        return Ok(());
+    }
+    info!("Signing '{}'", path.display());
+    exec_log(
+        Command::new("cosign")
+            .args(["sign-blob", "--yes", "--key", key, "--output-signature"])
+            .arg(dir.join(PROVENANCE_SIG))
+            .arg(&path),
+    )
+    .await
+    .context(format!("Unable to sign '{}'", path.display()))
+}
+
+#[test]
+fn test_statement() {
+    use crate::checksums::Artifact;
+
+    let checksums = Checksums {
+        artifacts: vec![Artifact {
+            path: "bottlerocket-aws-dev-x86_64.img.lz4".to_string(),
+            size: 3,
+            sha256: "abc123".to_string(),
+            content_type: "application/x-lz4".to_string(),
+        }],
+    };
+    let metadata = BuildMetadata {
+        parameters: BTreeMap::from([("arch".to_string(), "x86_64".to_string())]),
+        images: vec![FetchedImage {
+            uri: "public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.42.0".to_string(),
+            digest: "sha256:0102ff".to_string(),
+        }],
+        git: Some((
+            "https://example.com/project.git".to_string(),
+            "0123abcd".to_string(),
+        )),
+    };
+
+    let statement = statement(&checksums, &metadata).unwrap();
+    let json = serde_json::to_value(statement).unwrap();
+    assert_eq!(json["_type"], STATEMENT_TYPE);
+    assert_eq!(json["predicateType"], PREDICATE_TYPE);
+    assert_eq!(
+        json["subject"][0]["name"],
+        "bottlerocket-aws-dev-x86_64.img.lz4"
+    );
+    assert_eq!(json["subject"][0]["digest"]["sha256"], "abc123");
+    assert_eq!(
+        json["predicate"]["builder"]["id"],
+        format!("twoliter v{}", env!("CARGO_PKG_VERSION"))
+    );
+    assert_eq!(
+        json["predicate"]["invocation"]["parameters"]["arch"],
+        "x86_64"
+    );
+    let materials = json["predicate"]["materials"].as_array().unwrap();
+    assert_eq!(materials.len(), 2);
+    assert_eq!(
+        materials[0]["uri"],
+        "public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.42.0"
+    );
+    assert_eq!(materials[0]["digest"]["sha256"], "0102ff");
+    assert_eq!(materials[1]["uri"], "git+https://example.com/project.git");
+    assert_eq!(materials[1]["digest"]["sha1"], "0123abcd");
+}
@@ -0,0 +1,315 @@
+/*!
+
+Software bills of materials for kits. `twoliter build kit --sbom <FORMAT>` writes an [SPDX] or
+[CycloneDX] document next to the kit's RPM repository that lists the packages in the kit with their
+versions. The packages are read from the RPMs that were built, so the document describes what was
+actually shipped rather than what the kit's `Cargo.toml` asked for.
+
+The creation time is taken from `SOURCE_DATE_EPOCH` when it is set, so that a reproducible build
+also gets a reproducible SBOM.
+
+[SPDX]: https://spdx.github.io/spdx-spec/v2.3/
+[CycloneDX]: https://cyclonedx.org/docs/1.5/json/
+
+!*/
+
+use crate::common::fs;
+use crate::kit::{rpm_packages, KitPackages};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// The SPDX document written into a kit's directory.
+pub(crate) const SBOM_SPDX: &str = "sbom.spdx.json";
+
+/// The CycloneDX document written into a kit's directory.
+pub(crate) const SBOM_CYCLONEDX: &str = "sbom.cdx.json";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ValueEnum)]
+pub(crate) enum SbomFormat {
+    /// SPDX 2.3, as JSON.
+    Spdx,
+    /// CycloneDX 1.5, as JSON.
+    Cyclonedx,
+}
+
+impl SbomFormat {
+    fn filename(&self) -> &'static str {
+        match self {
+            SbomFormat::Spdx => SBOM_SPDX,
+            SbomFormat::Cyclonedx => SBOM_CYCLONEDX,
+        }
+    }
+}
+
+/// The kit that an SBOM describes.
+#[derive(Debug, Clone)]
+pub(crate) struct KitSbom {
+    pub(crate) name: String,
+    pub(crate) version: String,
+    pub(crate) vendor: String,
+    pub(crate) arch: String,
+    pub(crate) packages: KitPackages,
+    pub(crate) created: SystemTime,
+}
+
+impl KitSbom {
+    /// Describes the kit built into `kit_dir` from the RPMs found there.
+    pub(crate) async fn load(
+        kit_dir: &Path,
+        name: &str,
+        version: &str,
+        vendor: &str,
+        arch: &str,
+    ) -> Result<Self> {
+        Ok(Self {
+            name: name.to_string(),
+            version: version.to_string(),
+            vendor: vendor.to_string(),
+            arch: arch.to_string(),
+            packages: rpm_packages(kit_dir).await?,
+            created: creation_time()?,
+        })
+    }
+
+    /// Writes the SBOM into `kit_dir` in `format` and returns its path.
+    pub(crate) async fn write(&self, kit_dir: &Path, format: SbomFormat) -> Result<PathBuf> {
+        let document = match format {
+            SbomFormat::Spdx => self.spdx(),
+            SbomFormat::Cyclonedx => self.cyclonedx(),
+        };
+        let path = kit_dir.join(format.filename());
+        let json =
+            serde_json::to_string_pretty(&document).context("Unable to serialize the SBOM")?;
+        fs::write(&path, json).await?;
+        Ok(path)
+    }
+
+    fn spdx(&self) -> Value {
+        let kit_id = format!("SPDXRef-Kit-{}", spdx_id(&self.name));
+        let mut packages = vec![json!({
+            "SPDXID": kit_id,
+            "name": self.name,
+            "versionInfo": self.version,
+            "supplier": format!("Organization: {}", self.vendor),
+            "downloadLocation": "NOASSERTION",
+            "filesAnalyzed": false,
+        })];
+        let mut relationships = vec![json!({
+            "spdxElementId": "SPDXRef-DOCUMENT",
+            "relationshipType": "DESCRIBES",
+            "relatedSpdxElement": kit_id,
+        })];
+        for (name, version) in &self.packages {
+            let package_id = format!("SPDXRef-Package-{}", spdx_id(name));
+            packages.push(json!({
+                "SPDXID": package_id,
+                "name": name,
+                "versionInfo": version.as_deref().unwrap_or("NOASSERTION"),
+                "supplier": format!("Organization: {}", self.vendor),
+                "downloadLocation": "NOASSERTION",
+                "filesAnalyzed": false,
+                "externalRefs": [{
+                    "referenceCategory": "PACKAGE-MANAGER",
+                    "referenceType": "purl",
+                    "referenceLocator": self.purl(name, version.as_deref()),
+                }],
+            }));
+            relationships.push(json!({
+                "spdxElementId": kit_id,
+                "relationshipType": "CONTAINS",
+                "relatedSpdxElement": package_id,
+            }));
+        }
+        json!({
+            "spdxVersion": "SPDX-2.3",
+            "dataLicense": "CC0-1.0",
+            "SPDXID": "SPDXRef-DOCUMENT",
+            "name": format!("{}-{}-{}", self.name, self.version, self.arch),
+            "documentNamespace": format!(
+                "https://spdx.org/spdxdocs/{}-{}-{}-{}",
+                self.name,
+                self.version,
+                self.arch,
+                uuid::Uuid::new_v4()
+            ),
+            "creationInfo": {
+                "created": humantime::format_rfc3339_seconds(self.created).to_string(),
+                "creators": [format!("Tool: twoliter-{}", env!("CARGO_PKG_VERSION"))],
+            },
+            "packages": packages,
+            "relationships": relationships,
+        })
+    }
+
+    fn cyclonedx(&self) -> Value {
+        let kit_ref = format!("kit:{}", self.name);
+        let components: Vec<Value> = self
+            .packages
+            .iter()
+            .map(|(name, version)| {
+                let purl = self.purl(name, version.as_deref());
+                let mut component = json!({
+                    "type": "library",
+                    "bom-ref": purl,
+                    "name": name,
+                    "purl": purl,
+                    "supplier": { "name": self.vendor },
+                });
+                if let Some(version) = version {
+                    component["version"] = json!(version);
+                }
+                component
+            })
+            .collect();
+        let depends_on: Vec<&Value> = components
+            .iter()
+            .map(|component| &component["bom-ref"])
+            .collect();
+        json!({
+            "bomFormat": "CycloneDX",
+            "specVersion": "1.5",
+            "serialNumber": format!("urn:uuid:{}", uuid::Uuid::new_v4()),
+            "version": 1,
+            "metadata": {
+                "timestamp": humantime::format_rfc3339_seconds(self.created).to_string(),
+                "tools": {
+                    "components": [{
+                        "type": "application",
+                        "name": "twoliter",
+                        "version": env!("CARGO_PKG_VERSION"),
+                    }],
+                },
+                "component": {
+                    "type": "library",
+                    "bom-ref": kit_ref,
+                    "name": self.name,
+                    "version": self.version,
+                    "supplier": { "name": self.vendor },
+                },
+            },
+            "components": components,
+            "dependencies": [{ "ref": kit_ref, "dependsOn": depends_on }],
+        })
+    }
+
+    /// The package URL of an RPM in the kit, e.g.
+    /// `pkg:rpm/bottlerocket/kernel-6.1@6.1.90-1?arch=x86_64`.
+    fn purl(&self, name: &str, version: Option<&str>) -> String {
+        match version {
+            Some(version) => format!(
+                "pkg:rpm/{}/{}@{}?arch={}",
+                self.vendor, name, version, self.arch
+            ),
+            None => format!("pkg:rpm/{}/{}?arch={}", self.vendor, name, self.arch),
+        }
+    }
+}
+
+/// SPDX identifiers may only contain letters, numbers, `.` and `-`.
+fn spdx_id(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Now, or `SOURCE_DATE_EPOCH` if it is set.
+fn creation_time() -> Result<SystemTime> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => {
+            let seconds: u64 = epoch
+                .trim()
+                .parse()
+                .context(format!("Unable to parse SOURCE_DATE_EPOCH '{}'", epoch))?;
+            Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+        }
+        Err(_) => Ok(SystemTime::now()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sbom() -> KitSbom {
+        KitSbom {
+            name: "core-kit".to_string(),
+            version: "2.1.0".to_string(),
+            vendor: "bottlerocket".to_string(),
+            arch: "x86_64".to_string(),
+            packages: KitPackages::from([
+                ("kernel-6.1".to_string(), Some("6.1.90-1".to_string())),
+                ("pkg_x".to_string(), None),
+            ]),
+            created: SystemTime::UNIX_EPOCH + Duration::from_secs(1_714_564_800),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_kit_sbom() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let rpms = temp_dir.path().join("Packages");
+        fs::create_dir_all(&rpms).await.unwrap();
+        fs::write(rpms.join("bottlerocket-kernel-6.1-6.1.90-1.x86_64.rpm"), "")
+            .await
+            .unwrap();
+        let loaded = KitSbom::load(
+            temp_dir.path(),
+            "core-kit",
+            "2.1.0",
+            "bottlerocket",
+            "x86_64",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            loaded.packages,
+            KitPackages::from([(
+                "bottlerocket-kernel-6.1".to_string(),
+                Some("6.1.90-1".to_string())
+            )])
+        );
+
+        let path = sbom()
+            .write(temp_dir.path(), SbomFormat::Spdx)
+            .await
+            .unwrap();
+        assert_eq!(path, temp_dir.path().join(SBOM_SPDX));
+        let spdx: Value = serde_json::from_str(&fs::read_to_string(&path).await.unwrap()).unwrap();
+        assert_eq!(spdx["spdxVersion"], "SPDX-2.3");
+        assert_eq!(spdx["creationInfo"]["created"], "2024-05-01T12:00:00Z");
+        let packages = spdx["packages"].as_array().unwrap();
+        assert_eq!(packages.len(), 3);
+        assert_eq!(packages[1]["name"], "kernel-6.1");
+        assert_eq!(packages[1]["versionInfo"], "6.1.90-1");
+        assert_eq!(
+            packages[1]["externalRefs"][0]["referenceLocator"],
+            "pkg:rpm/bottlerocket/kernel-6.1@6.1.90-1?arch=x86_64"
+        );
+        assert_eq!(packages[2]["SPDXID"], "SPDXRef-Package-pkg-x");
+        assert_eq!(packages[2]["versionInfo"], "NOASSERTION");
+        assert_eq!(spdx["relationships"].as_array().unwrap().len(), 3);
+
+        let path = sbom()
+            .write(temp_dir.path(), SbomFormat::Cyclonedx)
+            .await
+            .unwrap();
+        let cdx: Value = serde_json::from_str(&fs::read_to_string(&path).await.unwrap()).unwrap();
+        assert_eq!(cdx["bomFormat"], "CycloneDX");
+        assert_eq!(cdx["metadata"]["component"]["version"], "2.1.0");
+        assert_eq!(cdx["components"][0]["version"], "6.1.90-1");
+        assert!(cdx["components"][1].get("version").is_none());
+        assert_eq!(
+            cdx["dependencies"][0]["dependsOn"][1],
+            "pkg:rpm/bottlerocket/pkg_x?arch=x86_64"
+        );
+    }
+}
@@ -0,0 +1,149 @@
+/*!
+
+Colors for the output that Twoliter writes itself: build progress and summaries, `check` findings,
+and the error that a command fails with. Whether color is used is decided for each stream: with
+`--color auto`, the default, it is used when the stream is a terminal and `NO_COLOR` is not set.
+Color is never used in `json` logs, and the output of the commands that Twoliter runs is passed
+through as it is.
+
+!*/
+
+use crate::logging::{log_format, LogFormat};
+use anstyle::{AnsiColor, Color, Style};
+use clap::ValueEnum;
+use std::fmt::{self, Display, Formatter};
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+
+/// Marks what a summary or message is about, such as the kit that is being built.
+pub(crate) const HEADING: Style = Style::new().bold();
+/// Marks something that succeeded.
+pub(crate) const SUCCESS: Style = Style::new().fg_color(Some(Color::Ansi(AnsiColor::Green)));
+/// Marks something that may need attention but did not fail.
+pub(crate) const WARNING: Style = Style::new().fg_color(Some(Color::Ansi(AnsiColor::Yellow)));
+/// Marks something that failed.
+pub(crate) const ERROR: Style = Style::new()
+    .fg_color(Some(Color::Ansi(AnsiColor::Red)))
+    .bold();
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, ValueEnum)]
+pub(crate) enum ColorChoice {
+    /// Use color when writing to a terminal and `NO_COLOR` is not set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// The equivalent style for env_logger, which colors the level of each log record. `None` for
+    /// `auto`, which leaves env_logger to decide, and to honor `RUST_LOG_STYLE`.
+    pub(crate) fn write_style(self) -> Option<env_logger::WriteStyle> {
+        match self {
+            ColorChoice::Auto => None,
+            ColorChoice::Always => Some(env_logger::WriteStyle::Always),
+            ColorChoice::Never => Some(env_logger::WriteStyle::Never),
+        }
+    }
+}
+
+static COLOR: OnceLock<ColorChoice> = OnceLock::new();
+
+/// Chooses whether to use color for the rest of the process. Only the first call has an effect.
+pub(crate) fn set_color(choice: ColorChoice) {
+    let _ = COLOR.set(choice);
+}
+
+/// Whether to use color on a stream, given whether it `is_terminal` and whether `NO_COLOR` is set
+/// to something other than an empty string.
+fn use_color(choice: ColorChoice, format: LogFormat, is_terminal: bool, no_color: bool) -> bool {
+    match (choice, format) {
+        (_, LogFormat::Json) | (ColorChoice::Never, _) => false,
+        (ColorChoice::Always, _) => true,
+        (ColorChoice::Auto, _) => is_terminal && !no_color,
+    }
+}
+
+fn styler(is_terminal: bool) -> Styler {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let choice = COLOR.get().copied().unwrap_or_default();
+    Styler::new(use_color(choice, log_format(), is_terminal, no_color))
+}
+
+/// Styles text that is written to stdout.
+pub(crate) fn stdout() -> Styler {
+    styler(std::io::stdout().is_terminal())
+}
+
+/// Styles text that is written to stderr, which includes log records.
+pub(crate) fn stderr() -> Styler {
+    styler(std::io::stderr().is_terminal())
+}
+
+/// Applies styles to text, or leaves it as it is when color is not used.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Styler {
+    color: bool,
+}
+
+impl Styler {
+    pub(crate) fn new(color: bool) -> Self {
+        Self { color }
+    }
+
+    pub(crate) fn paint<T: Display>(self, style: Style, value: T) -> Painted<T> {
+        Painted {
+            style: if self.color { style } else { Style::new() },
+            value,
+        }
+    }
+}
+
+/// A value that is displayed with a style.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Painted<T> {
+    style: Style,
+    value: T,
+}
+
+impl<T: Display> Display for Painted<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // A plain style renders as nothing, so uncolored text has no escape sequences.
+        write!(
+            f,
+            "{}{}{}",
+            self.style.render(),
+            self.value,
+            self.style.render_reset()
+        )
+    }
+}
+
+#[test]
+fn test_use_color() {
+    use ColorChoice::{Always, Auto, Never};
+    use LogFormat::{Json, Text};
+
+    assert!(use_color(Auto, Text, true, false));
+    assert!(!use_color(Auto, Text, false, false));
+    assert!(!use_color(Auto, Text, true, true));
+    assert!(use_color(Always, Text, false, true));
+    assert!(!use_color(Never, Text, true, false));
+    assert!(!use_color(Always, Json, true, false));
+}
+
+#[test]
+fn test_paint() {
+    assert_eq!(
+        Styler::new(false).paint(ERROR, "failed").to_string(),
+        "failed"
+    );
+    assert_eq!(
+        Styler::new(true).paint(ERROR, "failed").to_string(),
+        "\x1b[1m\x1b[31mfailed\x1b[0m"
+    );
+    assert_eq!(
+        Styler::new(true).paint(Style::new(), "plain").to_string(),
+        "plain"
+    );
+}
@@ -17,6 +17,8 @@ async fn test_cargo_make() {
         release_version: project.release_version().to_string(),
         digest: project.digest().unwrap(),
         kit: Vec::new(),
+        go_modules: None,
+        host_containers: Default::default(),
         sdk: LockedImage {
             name: "my-bottlerocket-sdk".to_string(),
             version: version,
@@ -0,0 +1 @@
+Name: %{_cross_os}hello
@@ -0,0 +1 @@
+// This is a dummy library.
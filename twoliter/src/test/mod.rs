@@ -10,7 +10,6 @@ be compiled for `cfg(test)`, which is accomplished at its declaration in `main.r
 #[cfg(feature = "integ-tests")]
 mod cargo_make;
 
-use std::fs;
 use std::path::{Path, PathBuf};
 use tempfile::TempDir;
 
@@ -33,32 +32,30 @@ pub(crate) fn projects_dir() -> PathBuf {
 pub(crate) fn project_dir(name: &str) -> PathBuf {
     let path = projects_dir().join(name);
     path.canonicalize()
-        .expect(&format!("Unable to canonicalize '{}'", path.display()))
+        .unwrap_or_else(|e| panic!("Unable to canonicalize '{}': {}", path.display(), e))
 }
 
-pub(crate) fn copy_project_to_temp_dir(project: &str) -> TempDir {
+/// Copy a test project to a temporary directory, skipping some of the larger "ignoreable" dirs
+/// that may exist in the user's checkout.
+pub(crate) async fn copy_project_to_temp_dir(project: &str) -> TempDir {
     let temp_dir = TempDir::new().unwrap();
-    let src = project_dir(project);
-    let dst = temp_dir.path();
-    copy_most_dirs_recursively(&src, dst);
+    copy_project(project, temp_dir.path()).await;
     temp_dir
 }
 
-/// Copy dirs recursively except for some of the larger "ignoreable" dirs that may exist in the
-/// user's checkout.
-fn copy_most_dirs_recursively(src: &Path, dst: &Path) {
-    for entry in fs::read_dir(src).unwrap() {
-        fs::create_dir_all(&dst).unwrap();
-        let entry = entry.unwrap();
-        let file_type = entry.file_type().unwrap();
-        if file_type.is_dir() {
-            let name = entry.file_name().to_str().unwrap().to_string();
-            if matches!(name.as_ref(), "target" | "build" | ".gomodcache" | ".cargo") {
-                continue;
-            }
-            copy_most_dirs_recursively(&entry.path(), &dst.join(entry.file_name()));
-        } else {
-            fs::copy(entry.path(), dst.join(entry.file_name())).unwrap();
-        }
-    }
+/// Copy a test project to `dst`, skipping the same dirs as [`copy_project_to_temp_dir`].
+pub(crate) async fn copy_project(project: &str, dst: &Path) {
+    let src = project_dir(project);
+    crate::common::fs::copy_dir_all_filtered(&src, dst, |path| {
+        let ignored = path.is_dir()
+            && path.file_name().is_some_and(|name| {
+                matches!(
+                    name.to_str(),
+                    Some("target" | "build" | ".gomodcache" | ".cargo")
+                )
+            });
+        !ignored
+    })
+    .await
+    .unwrap();
 }
@@ -1,9 +1,11 @@
+use crate::checksums::hash_file;
 use crate::common::fs;
-use anyhow::{Context, Result};
+use anyhow::{ensure, Context, Result};
 use filetime::{set_file_handle_times, set_file_mtime, FileTime};
 use flate2::read::ZlibDecoder;
-use log::debug;
-use std::path::Path;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use tar::Archive;
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
@@ -18,41 +20,321 @@ const PUBSYS_SETUP: &[u8] = include_bytes!(env!("CARGO_BIN_FILE_PUBSYS_SETUP"));
 const TESTSYS: &[u8] = include_bytes!(env!("CARGO_BIN_FILE_TESTSYS"));
 const TUFTOOL: &[u8] = include_bytes!(env!("CARGO_BIN_FILE_TUFTOOL"));
 
+/// The embedded binaries, by the name they are installed as.
+const BINARIES: [(&str, &[u8]); 6] = [
+    ("bottlerocket-variant", BOTTLEROCKET_VARIANT),
+    ("buildsys", BUILDSYS),
+    ("pubsys", PUBSYS),
+    ("pubsys-setup", PUBSYS_SETUP),
+    ("testsys", TESTSYS),
+    ("tuftool", TUFTOOL),
+];
+
+/// The versions of the packages that the embedded binaries are built from, which `build.rs` finds in
+/// the lock file. A version is `None` if it could not be found.
+pub(crate) const EMBEDDED_VERSIONS: [(&str, Option<&str>); 5] = [
+    ("buildsys", option_env!("TWOLITER_BUILDSYS_VERSION")),
+    ("pubsys", option_env!("TWOLITER_PUBSYS_VERSION")),
+    ("pubsys-setup", option_env!("TWOLITER_PUBSYS_SETUP_VERSION")),
+    ("testsys", option_env!("TWOLITER_TESTSYS_VERSION")),
+    ("tuftool", option_env!("TWOLITER_TUFTOOL_VERSION")),
+];
+
+/// The component of the tools that holds Makefile.toml and the scripts and Dockerfiles that it uses,
+/// i.e. the embedded tarball. Each binary is a component of its own, named after it.
+pub(crate) const SCRIPTS: &str = "scripts";
+
+/// Written into the tools directory after a successful install. It records what was installed so
+/// that later commands can skip installing again, and so that damage to the directory is noticed.
+const TOOLS_MARKER: &str = ".twoliter-tools.json";
+
+/// The quick check hashes files up to this size. Larger files, i.e. the binaries, are only checked
+/// by size and mtime so that the check takes no more than a few milliseconds.
+const QUICK_HASH_MAX_BYTES: u64 = 1024 * 1024;
+
+/// The contents of [`TOOLS_MARKER`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct ToolsMarker {
+    /// Identifies the build of Twoliter that installed the tools.
+    install_id: String,
+    /// The components that were installed, see [`SCRIPTS`].
+    #[serde(default)]
+    components: Vec<String>,
+    files: Vec<ToolFile>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct ToolFile {
+    /// The path of the file relative to the tools directory.
+    path: String,
+    size: u64,
+    mtime_seconds: i64,
+    mtime_nanos: u32,
+    sha256: String,
+}
+
+/// Identifies the tools embedded in this build of Twoliter by a hash of their content, which
+/// `build.rs` computes.
+fn install_id() -> String {
+    format!(
+        "{}-{}",
+        env!("CARGO_PKG_VERSION"),
+        env!("TWOLITER_TOOLS_SHA256")
+    )
+}
+
+/// Every component of the tools, which is what builds need.
+fn all_components() -> Vec<&'static str> {
+    std::iter::once(SCRIPTS)
+        .chain(BINARIES.iter().map(|(name, _)| *name))
+        .collect()
+}
+
 /// Install tools into the given `tools_dir`. If you use a `TempDir` object, make sure to pass it by
 /// reference and hold on to it until you no longer need the tools to still be installed (it will
 /// auto delete when it goes out of scope).
+///
+/// If the same tools are already installed in `tools_dir` and pass a quick check against the
+/// install marker, they are left alone. Tools that fail the check are installed again.
 pub(crate) async fn install_tools(tools_dir: impl AsRef<Path>) -> Result<()> {
+    install_components(tools_dir, &all_components()).await
+}
+
+/// Like [`install_tools`], but only installs the `components` that a command needs, e.g. just
+/// [`SCRIPTS`] for a command that only runs Makefile.toml. Components that are already installed
+/// are left alone, so a later command that needs more only installs what is missing.
+pub(crate) async fn install_components(
+    tools_dir: impl AsRef<Path>,
+    components: &[&str],
+) -> Result<()> {
     let dir = tools_dir.as_ref();
-    debug!("Installing tools to '{}'", dir.display());
-    fs::remove_dir_all(dir)
-        .await
-        .context("Unable to remove tools directory before installing")?;
-    fs::create_dir_all(dir)
+    let mut installed = Vec::new();
+    match verify_tools(dir, false).await {
+        Ok(problems) if problems.is_empty() => installed = read_marker(dir).await?.components,
+        Ok(problems) => warn!(
+            "Reinstalling the tools in '{}' because they have changed:\n{}",
+            dir.display(),
+            problems.join("\n")
+        ),
+        Err(e) => debug!("Unable to reuse the tools in '{}': {:#}", dir.display(), e),
+    }
+    let missing: Vec<&str> = components
+        .iter()
+        .filter(|component| !installed.iter().any(|name| name == *component))
+        .copied()
+        .collect();
+    if missing.is_empty() {
+        debug!("Tools are already installed in '{}'", dir.display());
+        return Ok(());
+    }
+    if installed.is_empty() {
+        fs::remove_dir_all(dir)
+            .await
+            .context("Unable to remove tools directory before installing")?;
+        fs::create_dir_all(dir)
+            .await
+            .context("Unable to create directory for tools")?;
+    }
+    debug!("Installing {} to '{}'", missing.join(", "), dir.display());
+
+    // The files in the tarball give the canonical mtime.
+    let mtime = if missing.contains(&SCRIPTS) {
+        unpack_tarball(dir, &[])
+            .await
+            .context("Unable to install tools")?
+            .1
+    } else {
+        tarball_mtime()?
+    };
+
+    for (name, data) in BINARIES {
+        if missing.contains(&name) {
+            write_bin(name, data, &dir, mtime).await?;
+        }
+    }
+
+    // Apply the mtime to the directory now that the writes are done.
+    set_file_mtime(dir, mtime).context(format!("Unable to set mtime for '{}'", dir.display()))?;
+
+    installed.extend(missing.iter().map(|name| name.to_string()));
+    write_marker(dir, &list_files(dir)?, installed).await
+}
+
+/// The names of the embedded tools, i.e. the binaries and the scripts and files that support them.
+pub(crate) fn tool_names() -> Result<Vec<String>> {
+    let mut names: Vec<String> = tarball_names()?;
+    names.extend(BINARIES.iter().map(|(name, _)| name.to_string()));
+    names.sort();
+    Ok(names)
+}
+
+/// Installs the tools into `dest`, which may be any directory, for use outside of Twoliter. Unlike
+/// [`install_tools`], nothing else in `dest` is removed. If `only` is not empty, just those tools
+/// are installed. The install marker lists the tools from earlier exports by the same Twoliter as
+/// well. It is an error if `dest` holds tools from a different build of Twoliter, unless `force`,
+/// in which case those tools are removed first. Returns the names of the installed tools.
+pub(crate) async fn export_tools(dest: &Path, only: &[String], force: bool) -> Result<Vec<String>> {
+    let names = tool_names()?;
+    for name in only {
+        ensure!(
+            names.contains(name),
+            "'{}' is not one of the embedded tools, which are: {}",
+            name,
+            names.join(", ")
+        );
+    }
+    let mut components = Vec::new();
+    let mut files: Vec<String> = match read_marker(dest).await {
+        Ok(marker) if marker.install_id == install_id() => {
+            components = marker.components;
+            marker.files.into_iter().map(|file| file.path).collect()
+        }
+        Ok(marker) => {
+            ensure!(
+                force,
+                "'{}' contains tools from a different version of Twoliter, use --force to replace \
+                them",
+                dest.display()
+            );
+            for file in marker.files {
+                let _ = tokio::fs::remove_file(dest.join(file.path)).await;
+            }
+            Vec::new()
+        }
+        Err(_) => Vec::new(),
+    };
+    fs::create_dir_all(dest)
         .await
         .context("Unable to create directory for tools")?;
 
-    // Write out the embedded tools and scripts.
-    unpack_tarball(dir)
+    let (mut installed, mtime) = unpack_tarball(dest, only)
         .await
         .context("Unable to install tools")?;
+    for (name, data) in BINARIES {
+        if only.is_empty() || only.iter().any(|only| only == name) {
+            write_bin(name, data, dest, mtime).await?;
+            installed.push(name.to_string());
+        }
+    }
+    installed.sort();
 
-    // Pick one of the embedded files for use as the canonical mtime.
-    let metadata = fs::metadata(dir.join("build.Dockerfile"))
-        .await
-        .context("Unable to get Dockerfile metadata")?;
-    let mtime = FileTime::from_last_modification_time(&metadata);
+    files.extend(installed.iter().cloned());
+    files.sort();
+    files.dedup();
+    // Single scripts do not make up the scripts component.
+    components.extend(
+        all_components()
+            .into_iter()
+            .filter(|name| {
+                if *name == SCRIPTS {
+                    only.is_empty()
+                } else {
+                    installed.iter().any(|installed| installed == name)
+                }
+            })
+            .map(str::to_string),
+    );
+    let paths: Vec<PathBuf> = files.iter().map(|file| dest.join(file)).collect();
+    write_marker(dest, &paths, components).await?;
+    Ok(installed)
+}
 
-    write_bin("bottlerocket-variant", BOTTLEROCKET_VARIANT, &dir, mtime).await?;
-    write_bin("buildsys", BUILDSYS, &dir, mtime).await?;
-    write_bin("pubsys", PUBSYS, &dir, mtime).await?;
-    write_bin("pubsys-setup", PUBSYS_SETUP, &dir, mtime).await?;
-    write_bin("testsys", TESTSYS, &dir, mtime).await?;
-    write_bin("tuftool", TUFTOOL, &dir, mtime).await?;
+async fn read_marker(tools_dir: &Path) -> Result<ToolsMarker> {
+    let data = fs::read(tools_dir.join(TOOLS_MARKER)).await?;
+    serde_json::from_slice(&data).context(format!(
+        "Unable to parse the install marker in '{}'",
+        tools_dir.display()
+    ))
+}
 
-    // Apply the mtime to the directory now that the writes are done.
-    set_file_mtime(dir, mtime).context(format!("Unable to set mtime for '{}'", dir.display()))?;
+/// Checks the tools installed in `tools_dir` against the install marker and returns a description
+/// of each file that is missing or has changed. When `full` is `false` only small files are hashed.
+/// It is an error if there is no marker or if the tools came from a different build of Twoliter.
+pub(crate) async fn verify_tools(tools_dir: impl AsRef<Path>, full: bool) -> Result<Vec<String>> {
+    let dir = tools_dir.as_ref();
+    let marker = read_marker(dir).await?;
+    ensure!(
+        marker.install_id == install_id(),
+        "The tools in '{}' were installed by a different version of Twoliter",
+        dir.display()
+    );
 
-    Ok(())
+    let mut problems = Vec::new();
+    for file in &marker.files {
+        let path = dir.join(&file.path);
+        let Ok(metadata) = tokio::fs::metadata(&path).await else {
+            problems.push(format!("'{}' is missing", file.path));
+            continue;
+        };
+        let mtime = FileTime::from_last_modification_time(&metadata);
+        let unchanged = if full || file.size <= QUICK_HASH_MAX_BYTES {
+            hash_file(path).await?.0 == file.sha256
+        } else {
+            metadata.len() == file.size
+                && mtime.unix_seconds() == file.mtime_seconds
+                && mtime.nanoseconds() == file.mtime_nanos
+        };
+        if !unchanged {
+            problems.push(format!("'{}' has changed", file.path));
+        }
+    }
+    Ok(problems)
+}
+
+/// Records the freshly installed files at `paths`, and the `components` they make up, in the install
+/// marker in `tools_dir`.
+async fn write_marker(
+    tools_dir: &Path,
+    paths: &[PathBuf],
+    mut components: Vec<String>,
+) -> Result<()> {
+    let mut files = Vec::new();
+    for path in paths {
+        let relative = path
+            .strip_prefix(tools_dir)
+            .context(format!("Expected '{}' to be in the tools", path.display()))?
+            .to_string_lossy()
+            .to_string();
+        let metadata = fs::metadata(&path).await?;
+        let mtime = FileTime::from_last_modification_time(&metadata);
+        let (sha256, size) = hash_file(path.clone()).await?;
+        files.push(ToolFile {
+            path: relative,
+            size,
+            mtime_seconds: mtime.unix_seconds(),
+            mtime_nanos: mtime.nanoseconds(),
+            sha256,
+        });
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    components.sort();
+    components.dedup();
+    let marker = ToolsMarker {
+        install_id: install_id(),
+        components,
+        files,
+    };
+    let data =
+        serde_json::to_vec_pretty(&marker).context("Unable to serialize the install marker")?;
+    fs::write(tools_dir.join(TOOLS_MARKER), data).await
+}
+
+/// Lists the files below `dir`, other than the install marker.
+fn list_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let entries =
+        std::fs::read_dir(dir).context(format!("Unable to read directory '{}'", dir.display()))?;
+    for entry in entries {
+        let path = entry
+            .context(format!("Unable to read directory '{}'", dir.display()))?
+            .path();
+        if path.is_dir() {
+            files.extend(list_files(&path)?);
+        } else if path.file_name().is_some_and(|name| name != TOOLS_MARKER) {
+            files.push(path);
+        }
+    }
+    Ok(files)
 }
 
 async fn write_bin(name: &str, data: &[u8], dir: impl AsRef<Path>, mtime: FileTime) -> Result<()> {
@@ -83,16 +365,88 @@ async fn write_bin(name: &str, data: &[u8], dir: impl AsRef<Path>, mtime: FileTi
     .context("Unable to run and join async task for reading handle time".to_string())?
 }
 
-async fn unpack_tarball(tools_dir: impl AsRef<Path>) -> Result<()> {
+/// Unpacks the embedded tarball into `tools_dir`, or only the files named in `only` if it is not
+/// empty. Returns the names of the unpacked files and the mtime of the files in the tarball.
+async fn unpack_tarball(
+    tools_dir: impl AsRef<Path>,
+    only: &[String],
+) -> Result<(Vec<String>, FileTime)> {
     let tools_dir = tools_dir.as_ref();
-    let tar = ZlibDecoder::new(TAR_GZ_DATA);
-    let mut archive = Archive::new(tar);
-    archive.unpack(tools_dir).context(format!(
-        "Unable to unpack tarball into directory '{}'",
-        tools_dir.display()
-    ))?;
+    let context = || {
+        format!(
+            "Unable to unpack tarball into directory '{}'",
+            tools_dir.display()
+        )
+    };
+    let mut archive = Archive::new(ZlibDecoder::new(TAR_GZ_DATA));
+    let mut unpacked = Vec::new();
+    let mut mtime = FileTime::now();
+    for entry in archive.entries().with_context(context)? {
+        let mut entry = entry.with_context(context)?;
+        let name = entry
+            .path()
+            .with_context(context)?
+            .to_string_lossy()
+            .to_string();
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        if name == "build.Dockerfile" {
+            mtime =
+                FileTime::from_unix_time(entry.header().mtime().with_context(context)? as i64, 0);
+        }
+        if !only.is_empty() && !only.contains(&name) {
+            continue;
+        }
+        entry.unpack_in(tools_dir).with_context(context)?;
+        unpacked.push(name);
+    }
     debug!("Installed tools to '{}'", tools_dir.display());
-    Ok(())
+    Ok((unpacked, mtime))
+}
+
+/// The mtime of the files in the embedded tarball, without unpacking it.
+fn tarball_mtime() -> Result<FileTime> {
+    let mut archive = Archive::new(ZlibDecoder::new(TAR_GZ_DATA));
+    for entry in archive
+        .entries()
+        .context("Unable to read the embedded tarball")?
+    {
+        let entry = entry.context("Unable to read the embedded tarball")?;
+        if entry
+            .path()
+            .is_ok_and(|path| path == Path::new("build.Dockerfile"))
+        {
+            let mtime = entry
+                .header()
+                .mtime()
+                .context("Unable to read the embedded tarball")?;
+            return Ok(FileTime::from_unix_time(mtime as i64, 0));
+        }
+    }
+    Ok(FileTime::now())
+}
+
+/// The names of the files in the embedded tarball.
+fn tarball_names() -> Result<Vec<String>> {
+    let mut archive = Archive::new(ZlibDecoder::new(TAR_GZ_DATA));
+    let mut names = Vec::new();
+    for entry in archive
+        .entries()
+        .context("Unable to read the embedded tarball")?
+    {
+        let entry = entry.context("Unable to read the embedded tarball")?;
+        if entry.header().entry_type().is_file() {
+            names.push(
+                entry
+                    .path()
+                    .context("Unable to read the embedded tarball")?
+                    .to_string_lossy()
+                    .to_string(),
+            );
+        }
+    }
+    Ok(names)
 }
 
 #[tokio::test]
@@ -136,4 +490,142 @@ async fn test_install_tools() {
     let buildsys_mtime = FileTime::from_last_modification_time(&buildsys_metadata);
 
     assert_eq!(dockerfile_mtime, buildsys_mtime);
+
+    // The marker identifies the tools by the hash of their content.
+    let marker = read_marker(&toolsdir).await.unwrap();
+    let hash = marker.install_id.rsplit('-').next().unwrap();
+    assert_eq!(hash.len(), 64);
+    assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+}
+
+#[tokio::test]
+async fn test_verify_tools() {
+    let tempdir = tempfile::TempDir::new().unwrap();
+    let toolsdir = tempdir.path().join("tools");
+    assert!(verify_tools(&toolsdir, false).await.is_err());
+    install_tools(&toolsdir).await.unwrap();
+    assert!(verify_tools(&toolsdir, false).await.unwrap().is_empty());
+    assert!(verify_tools(&toolsdir, true).await.unwrap().is_empty());
+
+    fs::write(toolsdir.join("Makefile.toml"), "edited")
+        .await
+        .unwrap();
+    fs::remove_file(toolsdir.join("rpm2img")).await.unwrap();
+    let problems = verify_tools(&toolsdir, false).await.unwrap();
+    assert_eq!(
+        problems,
+        vec![
+            "'Makefile.toml' has changed".to_string(),
+            "'rpm2img' is missing".to_string()
+        ]
+    );
+
+    // Installing again repairs the damage.
+    install_tools(&toolsdir).await.unwrap();
+    assert!(verify_tools(&toolsdir, true).await.unwrap().is_empty());
+    assert!(toolsdir.join("rpm2img").is_file());
+}
+
+#[tokio::test]
+async fn test_install_components() {
+    let tempdir = tempfile::TempDir::new().unwrap();
+    let toolsdir = tempdir.path().join("tools");
+    install_components(&toolsdir, &[SCRIPTS]).await.unwrap();
+    assert!(toolsdir.join("Makefile.toml").is_file());
+    assert!(toolsdir.join("rpm2img").is_file());
+    assert!(!toolsdir.join("buildsys").exists());
+    assert!(!toolsdir.join("pubsys").exists());
+    assert_eq!(read_marker(&toolsdir).await.unwrap().components, [SCRIPTS]);
+
+    // Only the missing component is added, and what was installed is left alone.
+    fs::write(toolsdir.join("stray"), "kept").await.unwrap();
+    install_components(&toolsdir, &[SCRIPTS, "buildsys"])
+        .await
+        .unwrap();
+    assert!(toolsdir.join("buildsys").is_file());
+    assert!(!toolsdir.join("pubsys").exists());
+    assert!(toolsdir.join("stray").is_file());
+    assert_eq!(
+        read_marker(&toolsdir).await.unwrap().components,
+        ["buildsys", SCRIPTS]
+    );
+    assert!(verify_tools(&toolsdir, true).await.unwrap().is_empty());
+    let buildsys_mtime = FileTime::from_last_modification_time(
+        &fs::metadata(toolsdir.join("buildsys")).await.unwrap(),
+    );
+    let dockerfile_mtime = FileTime::from_last_modification_time(
+        &fs::metadata(toolsdir.join("build.Dockerfile"))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(buildsys_mtime, dockerfile_mtime);
+
+    // Installing everything afterwards gives the same tools as installing them at once.
+    install_tools(&toolsdir).await.unwrap();
+    assert!(toolsdir.join("pubsys").is_file());
+    assert!(toolsdir.join("tuftool").is_file());
+    let marker = read_marker(&toolsdir).await.unwrap();
+    assert_eq!(marker.components.len(), BINARIES.len() + 1);
+    assert!(verify_tools(&toolsdir, true).await.unwrap().is_empty());
+
+    // A damaged install starts over with what is needed.
+    fs::remove_file(toolsdir.join("Makefile.toml"))
+        .await
+        .unwrap();
+    install_components(&toolsdir, &[SCRIPTS]).await.unwrap();
+    assert!(toolsdir.join("Makefile.toml").is_file());
+    assert!(!toolsdir.join("buildsys").exists());
+    assert_eq!(read_marker(&toolsdir).await.unwrap().components, [SCRIPTS]);
+}
+
+#[tokio::test]
+async fn test_export_tools() {
+    let tempdir = tempfile::TempDir::new().unwrap();
+    let dest = tempdir.path().join("bin");
+    fs::create_dir_all(&dest).await.unwrap();
+    fs::write(dest.join("mine"), "not a tool").await.unwrap();
+
+    let names = tool_names().unwrap();
+    assert!(names.contains(&"buildsys".to_string()));
+    assert!(names.contains(&"rpm2img".to_string()));
+    assert!(export_tools(&dest, &["nope".to_string()], false)
+        .await
+        .is_err());
+
+    // Only the chosen tools are installed, and nothing else in the directory is touched.
+    let only = ["buildsys".to_string(), "rpm2img".to_string()];
+    let installed = export_tools(&dest, &only, false).await.unwrap();
+    assert_eq!(installed, only);
+    assert!(dest.join("buildsys").is_file());
+    assert!(dest.join("rpm2img").is_file());
+    assert!(!dest.join("pubsys").exists());
+    assert!(!dest.join("Makefile.toml").exists());
+    assert!(dest.join("mine").is_file());
+    assert!(verify_tools(&dest, true).await.unwrap().is_empty());
+
+    // A second export adds to the marker.
+    export_tools(&dest, &["tuftool".to_string()], false)
+        .await
+        .unwrap();
+    let marker = read_marker(&dest).await.unwrap();
+    let files: Vec<&str> = marker.files.iter().map(|file| file.path.as_str()).collect();
+    assert_eq!(files, ["buildsys", "rpm2img", "tuftool"]);
+
+    // Tools from another version of Twoliter are only replaced with `force`.
+    let mut marker = marker;
+    marker.install_id = "0.0.1-1".to_string();
+    fs::write(
+        dest.join(TOOLS_MARKER),
+        serde_json::to_vec(&marker).unwrap(),
+    )
+    .await
+    .unwrap();
+    assert!(export_tools(&dest, &[], false).await.is_err());
+    let installed = export_tools(&dest, &["pubsys".to_string()], true)
+        .await
+        .unwrap();
+    assert_eq!(installed, ["pubsys"]);
+    assert!(!dest.join("buildsys").exists());
+    assert!(dest.join("mine").is_file());
+    assert!(verify_tools(&dest, true).await.unwrap().is_empty());
 }
@@ -0,0 +1,497 @@
+use crate::common::fs;
+use crate::kit::{build_metadata, read_cargo_toml};
+use crate::project::{Project, VariantConfig};
+use anyhow::{bail, ensure, Context, Error, Result};
+pub(crate) use buildsys_config::ImageLayout;
+use buildsys_config::IMAGE_FEATURES;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use toml::{Table, Value};
+
+/// The name of the directory, relative to the project directory, that holds variants.
+pub(crate) const VARIANTS_DIRECTORY: &str = "variants";
+
+/// Written next to a variant's images to record the image features that it was built with.
+pub(crate) const IMAGE_FEATURES_JSON: &str = "image-features.json";
+
+/// The `Cargo.toml` of a variant in the project's `variants` directory.
+#[derive(Debug, Clone)]
+pub(crate) struct VariantManifest {
+    toml: Table,
+}
+
+impl VariantManifest {
+    /// Returns the path to the `Cargo.toml` of the variant named `name`.
+    pub(crate) fn path_for(project: &Project, name: &str) -> PathBuf {
+        project
+            .project_dir()
+            .join(VARIANTS_DIRECTORY)
+            .join(name)
+            .join("Cargo.toml")
+    }
+
+    /// Loads the variant manifest at `path`.
+    pub(crate) async fn load_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        ensure!(
+            fs::metadata(path).await.is_ok(),
+            "Unable to find the variant manifest '{}'",
+            path.display()
+        );
+        Ok(Self {
+            toml: read_cargo_toml(path).await?,
+        })
+    }
+
+    /// The `[package.metadata.build-variant]` table.
+    pub(crate) fn build_variant(&self) -> Option<&Table> {
+        build_metadata(&self.toml, "build-variant")
+    }
+
+    /// The `[package.metadata.build-variant.image-layout]` table.
+    pub(crate) fn image_layout(&self) -> Result<ImageLayout> {
+        self.build_variant()
+            .and_then(|build_variant| build_variant.get("image-layout"))
+            .map(|layout| {
+                layout
+                    .clone()
+                    .try_into()
+                    .context("Unable to parse the image layout of the variant manifest")
+            })
+            .unwrap_or_else(|| Ok(ImageLayout::default()))
+    }
+
+    /// Checks the layout that buildsys will build with `overrides` on top of the manifest's own. A
+    /// publish size hint smaller than the OS and data images together would be ignored, so a hint
+    /// given in the overrides must leave room for both. With a `unified` plan the data size is
+    /// still used, for the data partition on the OS disk.
+    pub(crate) fn check_image_layout(&self, overrides: &ImageLayout) -> Result<()> {
+        overrides.validate()?;
+        let Some(hint) = overrides.publish_image_size_hint_gib else {
+            return Ok(());
+        };
+        let layout = self.image_layout()?.overlay(overrides);
+        let os = layout
+            .os_image_size_gib
+            .unwrap_or(DEFAULT_OS_IMAGE_SIZE_GIB);
+        let data = layout
+            .data_image_size_gib
+            .unwrap_or(DEFAULT_DATA_IMAGE_SIZE_GIB);
+        ensure!(
+            u32::from(hint) >= u32::from(os) + u32::from(data),
+            "The image layout setting 'publish-image-size-hint-gib' is {} GiB, which is smaller \
+            than the {} GiB OS image and {} GiB data image together. Raise it to at least {} GiB",
+            hint,
+            os,
+            data,
+            u32::from(os) + u32::from(data)
+        );
+        Ok(())
+    }
+
+    /// The `[package.metadata.build-variant.image-features]` table, e.g. `fips = true`.
+    pub(crate) fn image_features(&self) -> BTreeMap<String, bool> {
+        self.build_variant()
+            .and_then(|build_variant| build_variant.get("image-features"))
+            .and_then(Value::as_table)
+            .map(|features| {
+                features
+                    .iter()
+                    .filter_map(|(name, enabled)| Some((name.clone(), enabled.as_bool()?)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Returns the names of the variants in the project's `variants` directory, i.e. the subdirectories
+/// that contain a `Cargo.toml`, sorted by name.
+pub(crate) async fn local_variants(project: &Project) -> Result<Vec<String>> {
+    let variants_dir = project.project_dir().join(VARIANTS_DIRECTORY);
+    if !variants_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut entries = tokio::fs::read_dir(&variants_dir).await.context(format!(
+        "Unable to read directory '{}'",
+        variants_dir.display()
+    ))?;
+    let mut variants = Vec::new();
+    while let Some(entry) = entries.next_entry().await.context(format!(
+        "Unable to read directory '{}'",
+        variants_dir.display()
+    ))? {
+        if entry.path().join("Cargo.toml").is_file() {
+            variants.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    variants.sort();
+    Ok(variants)
+}
+
+/// A variant as `twoliter variant list` shows it.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct VariantListing {
+    pub(crate) name: String,
+    #[serde(flatten)]
+    pub(crate) parts: VariantParts,
+    /// The image features that the variant's manifest turns on.
+    pub(crate) image_features: Vec<String>,
+}
+
+/// Describes each of the variants in the project's `variants` directory, sorted by name, with the
+/// parts that a build would use. Variants whose manifest cannot be loaded, or whose parts cannot be
+/// worked out, are skipped with a warning.
+pub(crate) async fn list(project: &Project) -> Result<Vec<VariantListing>> {
+    let mut listings = Vec::new();
+    for name in local_variants(project).await? {
+        let listing = VariantManifest::load_path(VariantManifest::path_for(project, &name))
+            .await
+            .and_then(|manifest| {
+                Ok(VariantListing {
+                    parts: VariantParts::resolve(&name, &project.variant(&name))?,
+                    image_features: manifest
+                        .image_features()
+                        .into_iter()
+                        .filter(|(_, enabled)| *enabled)
+                        .map(|(feature, _)| feature)
+                        .collect(),
+                    name: name.clone(),
+                })
+            });
+        match listing {
+            Ok(listing) => listings.push(listing),
+            Err(e) => log::warn!("Skipping variant '{}': {:#}", name, e),
+        }
+    }
+    Ok(listings)
+}
+
+/// The parts of a variant that buildsys uses to decide what goes into its image.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct VariantParts {
+    pub(crate) platform: String,
+    pub(crate) runtime: String,
+    pub(crate) family: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) flavor: Option<String>,
+}
+
+impl VariantParts {
+    /// Works out the parts of the variant `name`. Parts given in `config` are used as they are. The
+    /// others are derived from the name in the same way as `bottlerocket-variant`, which expects
+    /// `<platform>-<runtime>[-<version>[-<flavor>]]`.
+    pub(crate) fn resolve(name: &str, config: &VariantConfig) -> Result<Self> {
+        let derived = Self::derive(name);
+        let derived_part = |part: fn(&Self) -> String| -> Result<String> {
+            derived.as_ref().map(part).map_err(|e| {
+                anyhow::anyhow!(
+                    "{}. Please rename the variant, or set variant-platform, variant-runtime and \
+                    variant-family in the [variant.{}] section of Twoliter.toml or with \
+                    --variant-platform, --variant-runtime and --variant-family",
+                    e,
+                    name
+                )
+            })
+        };
+        let platform = match &config.variant_platform {
+            Some(platform) => platform.clone(),
+            None => derived_part(|parts| parts.platform.clone())?,
+        };
+        let runtime = match &config.variant_runtime {
+            Some(runtime) => runtime.clone(),
+            None => derived_part(|parts| parts.runtime.clone())?,
+        };
+        let family = match &config.variant_family {
+            Some(family) => family.clone(),
+            None => derived_part(|parts| parts.family.clone())?,
+        };
+        let flavor = config
+            .variant_flavor
+            .clone()
+            .or_else(|| derived.as_ref().ok().and_then(|parts| parts.flavor.clone()));
+        Ok(Self {
+            platform,
+            runtime,
+            family,
+            flavor,
+        })
+    }
+
+    fn derive(name: &str) -> Result<Self> {
+        let parts: Vec<_> = name.split('-').collect();
+        ensure!(
+            parts.len() >= 2 && parts.iter().take(4).all(|part| !part.is_empty()),
+            "Unable to derive the platform and runtime of the variant '{}' from its name, which \
+            should have the form <platform>-<runtime>[-<version>[-<flavor>]]",
+            name
+        );
+        Ok(Self {
+            platform: parts[0].to_string(),
+            runtime: parts[1].to_string(),
+            family: format!("{}-{}", parts[0], parts[1]),
+            flavor: parts.get(3).map(|flavor| flavor.to_string()),
+        })
+    }
+
+    /// The environment variables that pass these parts to `cargo make`.
+    pub(crate) fn envs(&self) -> Vec<(&'static str, String)> {
+        let mut envs = vec![
+            ("BUILDSYS_VARIANT_PLATFORM", self.platform.clone()),
+            ("BUILDSYS_VARIANT_RUNTIME", self.runtime.clone()),
+            ("BUILDSYS_VARIANT_FAMILY", self.family.clone()),
+        ];
+        if let Some(flavor) = &self.flavor {
+            envs.push(("BUILDSYS_VARIANT_FLAVOR", flavor.clone()));
+        }
+        envs
+    }
+}
+
+/// An image feature turned on or off for a single build, given as `name`, `name=on` or `name=off`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct ImageFeatureOverride {
+    pub(crate) name: String,
+    pub(crate) enabled: bool,
+}
+
+impl FromStr for ImageFeatureOverride {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, state) = s.split_once('=').unwrap_or((s, "on"));
+        ensure!(
+            IMAGE_FEATURES.contains(&name),
+            "Unknown image feature '{}', expected one of: {}",
+            name,
+            IMAGE_FEATURES.join(", ")
+        );
+        let enabled = match state {
+            "on" => true,
+            "off" => false,
+            _ => bail!(
+                "Unable to parse image feature '{}', expected '{}=on' or '{}=off'",
+                s,
+                name,
+                name
+            ),
+        };
+        Ok(Self {
+            name: name.to_string(),
+            enabled,
+        })
+    }
+}
+
+impl Display for ImageFeatureOverride {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let state = if self.enabled { "on" } else { "off" };
+        write!(f, "{}={}", self.name, state)
+    }
+}
+
+/// The sizes that buildsys uses when a variant's `Cargo.toml` does not give them.
+const DEFAULT_OS_IMAGE_SIZE_GIB: u16 = 2;
+const DEFAULT_DATA_IMAGE_SIZE_GIB: u16 = 1;
+
+/// The image features of a build, as recorded in `image-features.json`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ImageFeatures {
+    /// Every feature named in the variant's manifest or overridden, and whether it was enabled.
+    pub(crate) image_features: BTreeMap<String, bool>,
+    /// The features given with `--image-feature`.
+    pub(crate) overrides: BTreeMap<String, bool>,
+}
+
+impl ImageFeatures {
+    pub(crate) fn new(manifest: &VariantManifest, overrides: &[ImageFeatureOverride]) -> Self {
+        let overrides: BTreeMap<_, _> = overrides
+            .iter()
+            .map(|o| (o.name.clone(), o.enabled))
+            .collect();
+        let mut image_features = manifest.image_features();
+        image_features.extend(overrides.clone());
+        Self {
+            image_features,
+            overrides,
+        }
+    }
+
+    /// Writes `image-features.json` into `dir`.
+    pub(crate) async fn write(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let json =
+            serde_json::to_string_pretty(self).context("Unable to serialize image features")?;
+        fs::write(dir.as_ref().join(IMAGE_FEATURES_JSON), json).await
+    }
+}
+
+#[test]
+fn test_variant_parts() {
+    let parts = VariantParts::resolve("aws-k8s-1.28-nvidia", &VariantConfig::default()).unwrap();
+    assert_eq!(
+        parts,
+        VariantParts {
+            platform: "aws".to_string(),
+            runtime: "k8s".to_string(),
+            family: "aws-k8s".to_string(),
+            flavor: Some("nvidia".to_string()),
+        }
+    );
+
+    let config = VariantConfig {
+        variant_runtime: Some("ecs".to_string()),
+        ..Default::default()
+    };
+    let parts = VariantParts::resolve("metal-dev", &config).unwrap();
+    assert_eq!(parts.platform, "metal");
+    assert_eq!(parts.runtime, "ecs");
+    assert_eq!(parts.family, "metal-dev");
+    assert_eq!(parts.flavor, None);
+
+    // A name that cannot be split needs every part that would have been derived from it.
+    let err = VariantParts::resolve("appliance", &config)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("[variant.appliance]"), "{}", err);
+    let config = VariantConfig {
+        variant_platform: Some("metal".to_string()),
+        variant_runtime: Some("k8s".to_string()),
+        variant_family: Some("metal-k8s".to_string()),
+        ..Default::default()
+    };
+    let parts = VariantParts::resolve("appliance", &config).unwrap();
+    assert_eq!(parts.family, "metal-k8s");
+    assert_eq!(parts.envs().len(), 3);
+}
+
+#[test]
+fn test_image_feature_override() {
+    let parse = |s: &str| s.parse::<ImageFeatureOverride>();
+    assert_eq!(parse("fips").unwrap().to_string(), "fips=on");
+    assert_eq!(
+        parse("systemd-networkd=off").unwrap(),
+        ImageFeatureOverride {
+            name: "systemd-networkd".to_string(),
+            enabled: false
+        }
+    );
+    let err = parse("erofs-root=on").unwrap_err().to_string();
+    assert!(
+        err.contains("expected one of: grub-set-private-var"),
+        "{}",
+        err
+    );
+    assert!(parse("fips=yes").is_err());
+}
+
+#[test]
+fn test_image_features() {
+    let manifest = VariantManifest {
+        toml: toml::from_str(
+            r#"
+            [package.metadata.build-variant.image-features]
+            fips = true
+            systemd-networkd = true
+            "#,
+        )
+        .unwrap(),
+    };
+    let overrides = [
+        "fips=off".parse().unwrap(),
+        "uefi-secure-boot".parse().unwrap(),
+    ];
+    let features = ImageFeatures::new(&manifest, &overrides);
+    let json = serde_json::to_value(features).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "image-features": {
+                "fips": false,
+                "systemd-networkd": true,
+                "uefi-secure-boot": true,
+            },
+            "overrides": {
+                "fips": false,
+                "uefi-secure-boot": true,
+            },
+        })
+    );
+}
+
+#[test]
+fn test_image_layout() {
+    let manifest = VariantManifest {
+        toml: toml::from_str(
+            r#"
+            [package.metadata.build-variant.image-layout]
+            os-image-size-gib = 4
+            data-image-size-gib = 20
+            partition-plan = "split"
+            "#,
+        )
+        .unwrap(),
+    };
+    let project: ImageLayout = toml::from_str(
+        r#"
+        publish-image-size-hint-gib = 30
+        partition-plan = "unified"
+        "#,
+    )
+    .unwrap();
+    let cli: ImageLayout = "os-image-size-gib=8, data-image-size-gib=10"
+        .parse()
+        .unwrap();
+    let layout = project.overlay(&cli);
+    assert_eq!(
+        layout.to_string(),
+        "os-image-size-gib=8,data-image-size-gib=10,publish-image-size-hint-gib=30,\
+        partition-plan=unified"
+    );
+    manifest.check_image_layout(&layout).unwrap();
+
+    // The publish size must leave room for the manifest's 20 GiB data image.
+    let err = manifest
+        .check_image_layout(&project.overlay(&"os-image-size-gib=16".parse().unwrap()))
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("at least 36 GiB"), "{}", err);
+
+    assert!("os-image-size-gib=0".parse::<ImageLayout>().is_err());
+    assert!("os-image-size-gib=-1".parse::<ImageLayout>().is_err());
+    assert!("os-image-size=4".parse::<ImageLayout>().is_err());
+    assert!("partition-plan=mirrored".parse::<ImageLayout>().is_err());
+    assert!("".parse::<ImageLayout>().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_list() {
+    let temp_dir = crate::test::copy_project_to_temp_dir("local-kit").await;
+    let project = Project::load(temp_dir.path().join("Twoliter.toml"))
+        .await
+        .unwrap();
+    let unnamed = temp_dir.path().join("variants/unnamed");
+    fs::create_dir_all(&unnamed).await.unwrap();
+    fs::write(
+        unnamed.join("Cargo.toml"),
+        "[package]\nname = \"unnamed\"\n",
+    )
+    .await
+    .unwrap();
+
+    let variants = list(&project).await.unwrap();
+    assert_eq!(variants.len(), 1);
+    assert_eq!(variants[0].name, "hello-ootb");
+    assert_eq!(variants[0].parts.family, "hello-ootb");
+    assert_eq!(variants[0].parts.flavor, None);
+    assert!(variants[0]
+        .image_features
+        .contains(&"uefi-secure-boot".to_string()));
+    let json = serde_json::to_value(&variants[0]).unwrap();
+    assert_eq!(json["platform"], "hello");
+    assert!(json.get("flavor").is_none());
+}